@@ -0,0 +1,188 @@
+//! Timings for the protocol layer: packet encoding and decoding, and the
+//! pipelined read and write loops run against an in-memory server.
+//!
+//! Run with `cargo bench`, optionally followed by a name filter such as
+//! `cargo bench -- decode`. Criterion keeps the previous run's results under
+//! `target/criterion` and reports any change against them.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use ferric_ftp::sftp::constants::*;
+use ferric_ftp::sftp::error::SftpError;
+use ferric_ftp::sftp::packet::{ClientPacket, ServerPacket, SftpWriter};
+use ferric_ftp::sftp::protocol::SftpProtocol;
+use ferric_ftp::sftp::session::TransportLayer;
+use ferric_ftp::sftp::types::FileAttributes;
+use std::collections::VecDeque;
+use std::hint::black_box;
+
+// Size of the file the pipeline benchmarks read and write
+const TRANSFER_SIZE: usize = 16 * 1024 * 1024;
+
+/// Answers READs from `file` and acknowledges every WRITE, as a server with
+/// no latency would.
+struct LoopbackTransport {
+    file: Vec<u8>,
+    replies: VecDeque<ServerPacket>,
+    next_request_id: u32,
+}
+
+impl LoopbackTransport {
+    fn new(file: Vec<u8>) -> Self {
+        Self {
+            file,
+            replies: VecDeque::new(),
+            next_request_id: 0,
+        }
+    }
+
+    fn status(request_id: u32, status_code: u32) -> ServerPacket {
+        ServerPacket::Status {
+            request_id,
+            status_code,
+            message: String::new(),
+        }
+    }
+}
+
+impl TransportLayer for LoopbackTransport {
+    fn send_packet(&mut self, packet: ClientPacket) -> Result<(), SftpError> {
+        let reply = match packet {
+            ClientPacket::Read {
+                request_id,
+                offset,
+                len,
+                ..
+            } => {
+                let start = (offset as usize).min(self.file.len());
+                let end = (start + len as usize).min(self.file.len());
+                match start == end {
+                    true => Self::status(request_id, 1), // SSH_FX_EOF
+                    false => ServerPacket::Data {
+                        request_id,
+                        data: self.file[start..end].to_vec(),
+                    },
+                }
+            }
+            ClientPacket::Write { request_id, .. } => Self::status(request_id, 0),
+            other => panic!("Unexpected {:?}", other),
+        };
+        self.replies.push_back(reply);
+        Ok(())
+    }
+
+    fn receive_packet(&mut self) -> Result<ServerPacket, SftpError> {
+        self.replies
+            .pop_front()
+            .ok_or_else(|| SftpError::ClientError("No reply queued".into()))
+    }
+
+    fn next_request_id(&mut self) -> u32 {
+        let id = self.next_request_id;
+        self.next_request_id += 1;
+        id
+    }
+}
+
+// A NAME reply listing `count` files, as a READDIR on a large directory
+// returns
+fn name_packet(count: u32) -> Vec<u8> {
+    let attrs = FileAttributes {
+        size: Some(123_456),
+        uid: Some(1000),
+        gid: Some(1000),
+        permissions: Some(0o100644),
+        access_time: Some(1_700_000_000),
+        modify_time: Some(1_700_000_000),
+        ..Default::default()
+    };
+
+    let mut payload = vec![SSH_FXP_NAME];
+    payload.write_u32(1);
+    payload.write_u32(count);
+    for index in 0..count {
+        let name = format!("file-{:06}.dat", index);
+        payload.write_string(&name);
+        payload.write_string(&format!(
+            "-rw-r--r--    1 user     group      123456 Nov 14 22:13 {}",
+            name
+        ));
+        payload.write_attrs(&attrs, SFTP_MIN_VERSION);
+    }
+
+    let mut packet = Vec::new();
+    packet.write_bytes(&payload);
+    packet
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+
+    let write = ClientPacket::Write {
+        request_id: 1,
+        handle: vec![0; 8],
+        offset: 0,
+        data: vec![0x5a; DEFAULT_CHUNK_SIZE as usize],
+    };
+    group.throughput(Throughput::Bytes(DEFAULT_CHUNK_SIZE as u64));
+    group.bench_function("write_32k", |b| b.iter(|| black_box(&write).to_bytes()));
+
+    let stat = ClientPacket::Stat {
+        request_id: 1,
+        path: "/srv/data/reports/2024/summary.csv".to_string(),
+    };
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("stat", |b| b.iter(|| black_box(&stat).to_bytes()));
+
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+
+    let name = name_packet(1000);
+    group.throughput(Throughput::Bytes(name.len() as u64));
+    group.bench_function("name_1000", |b| {
+        b.iter(|| ServerPacket::from_bytes(black_box(&name)).unwrap())
+    });
+
+    let mut data = vec![SSH_FXP_DATA];
+    data.write_u32(1);
+    data.write_bytes(&vec![0x5a; DEFAULT_CHUNK_SIZE as usize]);
+    let mut data_packet = Vec::new();
+    data_packet.write_bytes(&data);
+    group.throughput(Throughput::Bytes(DEFAULT_CHUNK_SIZE as u64));
+    group.bench_function("data_32k", |b| {
+        b.iter(|| ServerPacket::from_bytes(black_box(&data_packet)).unwrap())
+    });
+
+    group.finish();
+}
+
+fn pipeline(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pipeline");
+    // Each run moves a whole file, so fewer samples keep the run short
+    group.sample_size(20);
+    group.throughput(Throughput::Bytes(TRANSFER_SIZE as u64));
+
+    let mut protocol = SftpProtocol::new(LoopbackTransport::new(vec![0x5a; TRANSFER_SIZE]));
+    group.bench_function("read_16m", |b| {
+        b.iter(|| {
+            let read = protocol.read_to_writer(&[7], &mut std::io::sink()).unwrap();
+            assert_eq!(read, TRANSFER_SIZE as u64);
+        })
+    });
+
+    let source = vec![0x5a; TRANSFER_SIZE];
+    let mut protocol = SftpProtocol::new(LoopbackTransport::new(Vec::new()));
+    group.bench_function("write_16m", |b| {
+        b.iter(|| {
+            let written = protocol.write_all(&[7], &source).unwrap();
+            assert_eq!(written, TRANSFER_SIZE as u64);
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode, pipeline);
+criterion_main!(benches);
@@ -0,0 +1,313 @@
+use chrono::{DateTime, Local};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::Path;
+use tar::{EntryType, Header};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+// Tar stores file contents in blocks of this many bytes
+const BLOCK_SIZE: u64 = 512;
+
+/// Kind of archive written, chosen by the archive's file name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl Format {
+    /// `.tar`, `.tar.gz` or `.tgz`, or `.zip`; anything else is `None`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.to_string_lossy();
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Format::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(Format::Tar)
+        } else if name.ends_with(".zip") {
+            Some(Format::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+enum Inner<W: Write + Seek> {
+    Tar(tar::Builder<W>),
+    TarGz(tar::Builder<GzEncoder<W>>),
+    Zip(Box<ZipWriter<W>>),
+}
+
+/// Streaming tar or zip writer.
+///
+/// Entries are written as they come, so a directory tree can be archived
+/// while it is being downloaded without any of it touching the disk. Each
+/// file's size is declared up front; `start_file` is followed by writes of
+/// its contents and then `end_file`.
+pub struct ArchiveWriter<W: Write + Seek> {
+    inner: Inner<W>,
+    // Size declared for the current file
+    size: u64,
+    // Bytes the current file still has to receive
+    remaining: u64,
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+    pub fn new(out: W, format: Format) -> Self {
+        let inner = match format {
+            Format::Tar => Inner::Tar(tar::Builder::new(out)),
+            Format::TarGz => Inner::TarGz(tar::Builder::new(GzEncoder::new(
+                out,
+                Compression::default(),
+            ))),
+            Format::Zip => Inner::Zip(Box::new(ZipWriter::new(out))),
+        };
+        Self {
+            inner,
+            size: 0,
+            remaining: 0,
+        }
+    }
+
+    pub fn append_dir(&mut self, path: &str, mode: u32, mtime: u64) -> io::Result<()> {
+        let name = format!("{}/", path.trim_end_matches('/'));
+        match &mut self.inner {
+            Inner::Tar(tar) => append_header(tar, &name, EntryType::Directory, 0, mode, mtime),
+            Inner::TarGz(tar) => append_header(tar, &name, EntryType::Directory, 0, mode, mtime),
+            Inner::Zip(zip) => Ok(zip.add_directory(name, zip_options(0, mode, mtime))?),
+        }
+    }
+
+    /// Starts a file holding exactly `size` bytes, which must then be
+    /// written before `end_file`.
+    pub fn start_file(&mut self, path: &str, size: u64, mode: u32, mtime: u64) -> io::Result<()> {
+        match &mut self.inner {
+            Inner::Tar(tar) => append_header(tar, path, EntryType::Regular, size, mode, mtime)?,
+            Inner::TarGz(tar) => append_header(tar, path, EntryType::Regular, size, mode, mtime)?,
+            Inner::Zip(zip) => zip.start_file(path, zip_options(size, mode, mtime))?,
+        }
+        self.size = size;
+        self.remaining = size;
+        Ok(())
+    }
+
+    /// Completes the current file. A file that came up short, say because
+    /// it shrank while being read, is padded with zeros to its declared size,
+    /// which in a tar keeps the entries after it readable. Returns how many
+    /// bytes were filled in that way.
+    pub fn end_file(&mut self) -> io::Result<u64> {
+        let missing = self.remaining;
+        write_zeros(self, missing)?;
+        let padding = (BLOCK_SIZE - self.size % BLOCK_SIZE) % BLOCK_SIZE;
+        match &mut self.inner {
+            Inner::Tar(tar) => write_zeros(tar.get_mut(), padding)?,
+            Inner::TarGz(tar) => write_zeros(tar.get_mut(), padding)?,
+            Inner::Zip(_) => {}
+        }
+        self.size = 0;
+        Ok(missing)
+    }
+
+    /// Writes the end of the archive and returns the underlying writer,
+    /// flushed.
+    pub fn finish(self) -> io::Result<W> {
+        let mut out = match self.inner {
+            Inner::Tar(tar) => tar.into_inner()?,
+            Inner::TarGz(tar) => tar.into_inner()?.finish()?,
+            Inner::Zip(zip) => zip.finish()?,
+        };
+        out.flush()?;
+        Ok(out)
+    }
+}
+
+impl<W: Write + Seek> Write for ArchiveWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file grew past the size declared for it",
+            ));
+        }
+        let written = match &mut self.inner {
+            Inner::Tar(tar) => tar.get_mut().write(buf)?,
+            Inner::TarGz(tar) => tar.get_mut().write(buf)?,
+            Inner::Zip(zip) => zip.write(buf)?,
+        };
+        self.remaining -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            Inner::Tar(tar) => tar.get_mut().flush(),
+            Inner::TarGz(tar) => tar.get_mut().flush(),
+            Inner::Zip(zip) => zip.flush(),
+        }
+    }
+}
+
+/// Creates an archive at `path` in the format its name asks for.
+pub fn create(path: &Path) -> io::Result<ArchiveWriter<BufWriter<File>>> {
+    let format = Format::from_path(path).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "archive name must end in .tar, .tar.gz, .tgz or .zip",
+        )
+    })?;
+    Ok(ArchiveWriter::new(
+        BufWriter::new(File::create(path)?),
+        format,
+    ))
+}
+
+// Writes a tar header declaring `size` bytes; the contents are written
+// straight after it as they arrive, rather than read from a source here
+fn append_header<W: Write>(
+    tar: &mut tar::Builder<W>,
+    path: &str,
+    entry_type: EntryType,
+    size: u64,
+    mode: u32,
+    mtime: u64,
+) -> io::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_size(size);
+    header.set_mode(mode & 0o7777);
+    header.set_mtime(mtime);
+    tar.append_data(&mut header, path, io::empty())
+}
+
+fn zip_options(size: u64, mode: u32, mtime: u64) -> SimpleFileOptions {
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .unix_permissions(mode & 0o7777)
+        .large_file(size >= u32::MAX as u64);
+    // Zip times are local and only run from 1980 to 2107
+    let modified = i64::try_from(mtime)
+        .ok()
+        .and_then(|mtime| DateTime::from_timestamp(mtime, 0))
+        .and_then(|time| zip::DateTime::try_from(time.with_timezone(&Local).naive_local()).ok());
+    match modified {
+        Some(modified) => options.last_modified_time(modified),
+        None => options,
+    }
+}
+
+fn write_zeros<W: Write>(out: &mut W, mut count: u64) -> io::Result<()> {
+    let zeros = [0u8; BLOCK_SIZE as usize];
+    while count > 0 {
+        let len = count.min(BLOCK_SIZE) as usize;
+        out.write_all(&zeros[..len])?;
+        count -= len as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    fn tar_entries<R: Read>(archive: R) -> Vec<(String, u32, Vec<u8>)> {
+        let mut archive = tar::Archive::new(archive);
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().into_owned();
+                let mode = entry.header().mode().unwrap();
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+                (path, mode, data)
+            })
+            .collect()
+    }
+
+    fn write_sample(format: Format) -> Vec<u8> {
+        let mut archive = ArchiveWriter::new(Cursor::new(Vec::new()), format);
+        archive.append_dir("logs", 0o755, 0).unwrap();
+        archive
+            .start_file("logs/app.log", 5, 0o100644, 1_700_000_000)
+            .unwrap();
+        archive.write_all(b"hello").unwrap();
+        assert_eq!(archive.end_file().unwrap(), 0);
+        archive.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_tar_entries_read_back() {
+        let archive = write_sample(Format::Tar);
+        assert_eq!(
+            tar_entries(&archive[..]),
+            vec![
+                ("logs/".to_string(), 0o755, Vec::new()),
+                ("logs/app.log".to_string(), 0o644, b"hello".to_vec()),
+            ]
+        );
+
+        let gzipped = write_sample(Format::TarGz);
+        assert_eq!(&gzipped[..2], &[0x1f, 0x8b]);
+        let entries = tar_entries(flate2::read::GzDecoder::new(&gzipped[..]));
+        assert_eq!(entries[1].2, b"hello");
+    }
+
+    #[test]
+    fn test_zip_entries_read_back() {
+        let archive = write_sample(Format::Zip);
+        let mut zip = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(zip.len(), 2);
+        assert!(zip.by_name("logs/").unwrap().is_dir());
+
+        let mut file = zip.by_name("logs/app.log").unwrap();
+        assert_eq!(file.unix_mode().unwrap() & 0o7777, 0o644);
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello");
+    }
+
+    #[test]
+    fn test_short_file_is_padded_and_long_file_refused() {
+        let mut archive = ArchiveWriter::new(Cursor::new(Vec::new()), Format::Tar);
+        archive.start_file("a", 4, 0o644, 0).unwrap();
+        archive.write_all(b"ab").unwrap();
+        assert!(archive.write_all(b"cde").is_err());
+        assert_eq!(archive.end_file().unwrap(), 2);
+        archive.start_file("b", 1, 0o644, 0).unwrap();
+        archive.write_all(b"z").unwrap();
+        archive.end_file().unwrap();
+        let archive = archive.finish().unwrap().into_inner();
+
+        let entries = tar_entries(&archive[..]);
+        assert_eq!(entries[0].2, b"ab\0\0");
+        assert_eq!(entries[1].2, b"z");
+    }
+
+    #[test]
+    fn test_long_name_survives() {
+        let path = format!("{}/data.bin", "d".repeat(120));
+        let mut archive = ArchiveWriter::new(Cursor::new(Vec::new()), Format::Tar);
+        archive.start_file(&path, 0, 0o644, 0).unwrap();
+        archive.end_file().unwrap();
+        let archive = archive.finish().unwrap().into_inner();
+
+        assert_eq!(tar_entries(&archive[..])[0].0, path);
+    }
+
+    #[test]
+    fn test_format_from_name() {
+        assert_eq!(Format::from_path(Path::new("a.tar")), Some(Format::Tar));
+        assert_eq!(Format::from_path(Path::new("a.tgz")), Some(Format::TarGz));
+        assert_eq!(
+            Format::from_path(Path::new("a.tar.gz")),
+            Some(Format::TarGz)
+        );
+        assert_eq!(Format::from_path(Path::new("a.zip")), Some(Format::Zip));
+        assert_eq!(Format::from_path(Path::new("a.rar")), None);
+    }
+}
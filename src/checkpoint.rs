@@ -0,0 +1,122 @@
+//! Progress records for batch scripts.
+//!
+//! After each command of a `-b` script succeeds, the number of completed
+//! commands is written to a checkpoint file along with a digest of the
+//! script. Rerunning with `--resume` skips that many commands, so a long
+//! batch of transfers that died partway through does not start over.
+
+use crate::checksum::{self, Digest, Sha256};
+use crate::sftp::error::SftpError;
+use std::path::{Path, PathBuf};
+
+pub struct Checkpoint {
+    path: PathBuf,
+    script_digest: String,
+    completed: usize,
+}
+
+impl Checkpoint {
+    /// Tracks progress through `script` in the file at `path`. With
+    /// `resume`, progress saved by an earlier run of the same script is
+    /// picked up; a missing file just means nothing has run yet. Without
+    /// it, any saved progress is ignored and overwritten.
+    pub fn open(path: &Path, script: &str, resume: bool) -> Result<Self, SftpError> {
+        let mut hasher = Sha256::new();
+        hasher.update(script.as_bytes());
+        let mut checkpoint = Self {
+            path: path.to_path_buf(),
+            script_digest: checksum::to_hex(&hasher.finalize()),
+            completed: 0,
+        };
+        if !resume || !path.exists() {
+            return Ok(checkpoint);
+        }
+
+        let saved = std::fs::read_to_string(path)?;
+        let (digest, completed) = parse(&saved).ok_or_else(|| {
+            SftpError::ClientError(format!("Malformed checkpoint {}", path.display()).into())
+        })?;
+        if digest != checkpoint.script_digest {
+            return Err(SftpError::ClientError(
+                format!(
+                    "Checkpoint {} was written for a different script",
+                    path.display()
+                )
+                .into(),
+            ));
+        }
+        checkpoint.completed = completed;
+        Ok(checkpoint)
+    }
+
+    /// Number of commands an earlier run finished.
+    pub fn completed(&self) -> usize {
+        self.completed
+    }
+
+    /// Records that the first `completed` commands have succeeded.
+    pub fn record(&mut self, completed: usize) -> Result<(), SftpError> {
+        self.completed = completed;
+
+        // Written aside and renamed over the old file, so a crash mid-write
+        // can't leave a checkpoint that fails to parse
+        let mut staging = self.path.clone().into_os_string();
+        staging.push(".tmp");
+        std::fs::write(
+            &staging,
+            format!(
+                "script sha256:{}\ncompleted {}\n",
+                self.script_digest, completed
+            ),
+        )?;
+        std::fs::rename(&staging, &self.path)?;
+        Ok(())
+    }
+
+    /// Removes the checkpoint once the whole script has run, so the next
+    /// run starts from the top.
+    pub fn finish(self) -> Result<(), SftpError> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn parse(saved: &str) -> Option<(&str, usize)> {
+    let mut lines = saved.lines();
+    let digest = lines.next()?.strip_prefix("script sha256:")?;
+    let completed = lines.next()?.strip_prefix("completed ")?.parse().ok()?;
+    Some((digest, completed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume_picks_up_recorded_progress() {
+        let path = std::env::temp_dir().join(format!("ferric_checkpoint_{}", std::process::id()));
+        let script = "cd /srv\nget a.iso\nget b.iso\n";
+
+        let mut checkpoint = Checkpoint::open(&path, script, false).unwrap();
+        checkpoint.record(2).unwrap();
+
+        assert_eq!(
+            Checkpoint::open(&path, script, true).unwrap().completed(),
+            2
+        );
+        assert_eq!(
+            Checkpoint::open(&path, script, false).unwrap().completed(),
+            0
+        );
+        assert!(Checkpoint::open(&path, "get c.iso\n", true).is_err());
+
+        checkpoint.finish().unwrap();
+        assert!(!path.exists());
+        assert_eq!(
+            Checkpoint::open(&path, script, true).unwrap().completed(),
+            0
+        );
+    }
+}
@@ -0,0 +1,46 @@
+pub use sha2::{Digest, Sha256};
+
+/// Name of the digest used for checksums, as understood by check-file.
+pub const ALGORITHM: &str = "sha256";
+
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn sha256_hex(data: &[u8]) -> String {
+        to_hex(&Sha256::digest(data))
+    }
+
+    #[test]
+    fn test_known_digests() {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn test_streamed_writes_match_single_update() {
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+
+        let mut streamed = Sha256::new();
+        for chunk in data.chunks(37) {
+            streamed.write_all(chunk).unwrap();
+        }
+
+        assert_eq!(to_hex(&streamed.finalize()), sha256_hex(&data));
+    }
+}
@@ -1,6 +1,10 @@
+use crate::ignore::IgnoreRules;
+use memmap2::Mmap;
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::io::BufWriter;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
 
 pub fn write_to_file(filename: &PathBuf, data: &[u8]) -> std::io::Result<()> {
     let mut f = File::create(filename)?;
@@ -14,3 +18,341 @@ pub fn read_from_file(filename: &PathBuf) -> std::io::Result<Vec<u8>> {
     f.read_to_end(&mut data)?;
     Ok(data)
 }
+
+pub fn create_writer(filename: &Path) -> std::io::Result<BufWriter<File>> {
+    Ok(BufWriter::new(File::create(filename)?))
+}
+
+// Zero runs at least this long are skipped over rather than written
+const HOLE_BLOCK_SIZE: usize = 4096;
+
+/// Writer for downloads that leaves runs of zeros as holes.
+///
+/// Zero blocks are seeked over instead of written, so sparse files such as VM
+/// images keep their holes and take less time and disk to write. `finish`
+/// must be called at the end to fix up the final length.
+pub struct SparseWriter {
+    file: BufWriter<File>,
+    // Logical position, including any hole not yet seeked over
+    position: u64,
+    pending_hole: u64,
+}
+
+/// Creates `filename` for a download, extending it to `size` up front when
+/// the final size is known so the filesystem can lay it out in one go.
+pub fn create_sparse_writer(filename: &Path, size: Option<u64>) -> std::io::Result<SparseWriter> {
+    let file = File::create(filename)?;
+    if let Some(size) = size {
+        file.set_len(size)?;
+    }
+    Ok(SparseWriter {
+        file: BufWriter::new(file),
+        position: 0,
+        pending_hole: 0,
+    })
+}
+
+/// Reopens a partially downloaded `filename` to continue writing at
+/// `offset`, discarding anything past it.
+pub fn resume_sparse_writer(
+    filename: &Path,
+    offset: u64,
+    size: Option<u64>,
+) -> std::io::Result<SparseWriter> {
+    let mut file = std::fs::OpenOptions::new().write(true).open(filename)?;
+    file.set_len(offset)?;
+    if let Some(size) = size {
+        file.set_len(size.max(offset))?;
+    }
+    file.seek(std::io::SeekFrom::Start(offset))?;
+    Ok(SparseWriter {
+        file: BufWriter::new(file),
+        position: offset,
+        pending_hole: 0,
+    })
+}
+
+/// Creates `filename` at its final `size`, for several writers to fill in
+/// disjoint ranges through `open_segment_writer`.
+pub fn create_preallocated(filename: &Path, size: u64) -> std::io::Result<()> {
+    File::create(filename)?.set_len(size)
+}
+
+/// Opens a file made by `create_preallocated` to write from `offset`. The
+/// length is already final, so the writer is flushed rather than finished.
+pub fn open_segment_writer(filename: &Path, offset: u64) -> std::io::Result<SparseWriter> {
+    let mut file = std::fs::OpenOptions::new().write(true).open(filename)?;
+    file.seek(std::io::SeekFrom::Start(offset))?;
+    Ok(SparseWriter {
+        file: BufWriter::new(file),
+        position: offset,
+        pending_hole: 0,
+    })
+}
+
+impl SparseWriter {
+    /// Flushes buffered data and sets the file length to the number of bytes
+    /// written, which both materialises a trailing hole and trims the
+    /// preallocation if the transfer stopped early.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().set_len(self.position)
+    }
+}
+
+impl Write for SparseWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for block in buf.chunks(HOLE_BLOCK_SIZE) {
+            if block.iter().all(|&byte| byte == 0) {
+                self.pending_hole += block.len() as u64;
+            } else {
+                if self.pending_hole > 0 {
+                    self.file
+                        .seek(std::io::SeekFrom::Current(self.pending_hole as i64))?;
+                    self.pending_hole = 0;
+                }
+                self.file.write_all(block)?;
+            }
+            self.position += block.len() as u64;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Size and modification time of a regular file found by `walk_tree`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalEntry {
+    pub size: u64,
+    pub modify_time: Option<u64>,
+}
+
+/// Recursively lists the regular files under `root`, keyed by their path
+/// relative to `root`. Symlinks are not followed, and excluded directories
+/// are not descended into.
+pub fn walk_tree(root: &Path, ignore: &IgnoreRules) -> std::io::Result<Vec<(PathBuf, LocalEntry)>> {
+    let mut entries = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(relative) = pending.pop() {
+        for entry in std::fs::read_dir(root.join(&relative))? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let path = relative.join(entry.file_name());
+            if ignore.is_ignored(&path, file_type.is_dir()) {
+                continue;
+            }
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_file() {
+                let metadata = entry.metadata()?;
+                let modify_time = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|elapsed| elapsed.as_secs());
+                entries.push((
+                    path,
+                    LocalEntry {
+                        size: metadata.len(),
+                        modify_time,
+                    },
+                ));
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Sets the modification time of `filename` to `secs` past the epoch.
+pub fn set_modified(filename: &Path, secs: u64) -> std::io::Result<()> {
+    let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+    File::options()
+        .write(true)
+        .open(filename)?
+        .set_modified(time)
+}
+
+/// Expands wildcards in `pattern`, which may appear in any component and
+/// include `**` for any depth of directories, returning the matching regular
+/// files in name order. A path without wildcards is returned as given.
+/// `ignore_case` follows `set matching`, as it does for remote names.
+pub fn expand_glob(pattern: &Path, ignore_case: bool) -> std::io::Result<Vec<PathBuf>> {
+    let text = match pattern.to_str() {
+        Some(text) if crate::glob::is_pattern(text) => text,
+        _ => return Ok(vec![pattern.to_path_buf()]),
+    };
+    let paths = glob::glob_with(text, crate::glob::options(ignore_case))
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.msg))?;
+
+    let mut matches = Vec::new();
+    for path in paths {
+        let path = path.map_err(std::io::Error::from)?;
+        if path.is_file() {
+            matches.push(path);
+        }
+    }
+    matches.sort();
+    Ok(matches)
+}
+
+/// Read-only memory map of a local file used as an upload source, so WRITE
+/// packets are filled straight from the page cache.
+pub struct MappedFile {
+    map: Mmap,
+}
+
+/// Maps `filename` read-only.
+///
+/// # Safety
+///
+/// The mapping is only a view of the file on disk, so it must not change
+/// while the `MappedFile` is alive. If another process truncates the file,
+/// touching a page past the new end raises SIGBUS and kills this process;
+/// other writes change bytes behind a slice that safe code assumes is
+/// immutable. Only map files nothing else writes to for the duration.
+pub unsafe fn map_file(filename: &Path) -> std::io::Result<MappedFile> {
+    let file = File::open(filename)?;
+    // SAFETY: the caller guarantees the file is left alone while mapped
+    let map = unsafe { Mmap::map(&file)? };
+    Ok(MappedFile { map })
+}
+
+impl Deref for MappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_file_matches_contents() {
+        let path = std::env::temp_dir().join(format!("ferric_map_{}", std::process::id()));
+        write_to_file(&path, b"mapped contents").unwrap();
+
+        // SAFETY: nothing else touches the scratch file while it is mapped
+        let mapped = unsafe { map_file(&path) }.unwrap();
+        assert_eq!(&mapped[..], b"mapped contents");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_map_empty_file() {
+        let path = std::env::temp_dir().join(format!("ferric_map_empty_{}", std::process::id()));
+        write_to_file(&path, b"").unwrap();
+
+        // SAFETY: nothing else touches the scratch file while it is mapped
+        let mapped = unsafe { map_file(&path) }.unwrap();
+        assert!(mapped.is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_walk_tree_skips_ignored_paths() {
+        let root = std::env::temp_dir().join(format!("ferric_walk_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        write_to_file(&root.join("a.txt"), b"abc").unwrap();
+        write_to_file(&root.join("sub").join("b.txt"), b"de").unwrap();
+
+        std::fs::create_dir_all(root.join("build")).unwrap();
+        write_to_file(&root.join("build").join("out.bin"), b"x").unwrap();
+        write_to_file(&root.join("sub").join("c.tmp"), b"x").unwrap();
+
+        let ignore = IgnoreRules::parse("build/\n*.tmp\n").unwrap();
+        let mut entries = walk_tree(&root, &ignore).unwrap();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let summary: Vec<(PathBuf, u64)> = entries
+            .into_iter()
+            .map(|(path, entry)| (path, entry.size))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (PathBuf::from("a.txt"), 3),
+                (PathBuf::from("sub").join("b.txt"), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_glob_matches_regular_files() {
+        let root = std::env::temp_dir().join(format!("ferric_glob_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("dir.csv")).unwrap();
+        write_to_file(&root.join("b.csv"), b"").unwrap();
+        write_to_file(&root.join("a.csv"), b"").unwrap();
+        write_to_file(&root.join("c.txt"), b"").unwrap();
+        write_to_file(&root.join("dir.csv").join("d.csv"), b"").unwrap();
+
+        let matches = expand_glob(&root.join("*.csv"), false).unwrap();
+        let literal = expand_glob(&root.join("c.txt"), false).unwrap();
+        let nested = expand_glob(&root.join("**").join("*.csv"), false).unwrap();
+        let folded = expand_glob(&root.join("*.CSV"), true).unwrap();
+        let exact = expand_glob(&root.join("*.CSV"), false).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(matches, vec![root.join("a.csv"), root.join("b.csv")]);
+        assert_eq!(literal, vec![root.join("c.txt")]);
+        assert_eq!(folded, matches);
+        assert!(exact.is_empty());
+        assert_eq!(
+            nested,
+            vec![
+                root.join("a.csv"),
+                root.join("b.csv"),
+                root.join("dir.csv").join("d.csv")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sparse_writer_round_trips_zero_runs() {
+        let path = std::env::temp_dir().join(format!("ferric_sparse_{}", std::process::id()));
+        let mut data = vec![0u8; 3 * HOLE_BLOCK_SIZE];
+        data[HOLE_BLOCK_SIZE + 7] = 42;
+
+        let mut writer = create_sparse_writer(&path, Some(data.len() as u64)).unwrap();
+        writer.write_all(&data).unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(read_from_file(&path).unwrap(), data);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sparse_writer_trims_preallocation_on_early_finish() {
+        let path = std::env::temp_dir().join(format!("ferric_sparse_trim_{}", std::process::id()));
+
+        let mut writer = create_sparse_writer(&path, Some(1000)).unwrap();
+        writer.write_all(b"partial").unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(read_from_file(&path).unwrap(), b"partial".to_vec());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_resume_sparse_writer_continues_at_offset() {
+        let path = std::env::temp_dir().join(format!("ferric_resume_{}", std::process::id()));
+        write_to_file(&path, b"abcdXXXX").unwrap();
+
+        let mut writer = resume_sparse_writer(&path, 4, Some(8)).unwrap();
+        writer.write_all(b"efgh").unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(read_from_file(&path).unwrap(), b"abcdefgh".to_vec());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
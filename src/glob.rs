@@ -0,0 +1,58 @@
+//! Shell-style wildcard matching for file names.
+//!
+//! Remote names and local paths follow the same rules, those of the glob
+//! crate: `*`, `?` and bracket classes (`[abc]`, `[a-z]`, `[!x]`), with a
+//! metacharacter made literal by bracketing it (`[*]`). Patterns here are
+//! matched against a single path component; callers split paths and list
+//! directories themselves.
+
+use ::glob::{MatchOptions, Pattern};
+
+/// True if `text` contains any wildcard characters.
+pub fn is_pattern(text: &str) -> bool {
+    text.contains(['*', '?', '['])
+}
+
+/// Options for the `set matching` setting. With `ignore_case` set, letters
+/// match regardless of case, which suits servers backed by Windows
+/// filesystems.
+pub fn options(ignore_case: bool) -> MatchOptions {
+    MatchOptions {
+        case_sensitive: !ignore_case,
+        ..Default::default()
+    }
+}
+
+/// Matches `name` against `pattern`. A malformed pattern, such as one with
+/// an unterminated `[`, only matches itself.
+pub fn matches(pattern: &str, name: &str, ignore_case: bool) -> bool {
+    match Pattern::new(pattern) {
+        Ok(compiled) => compiled.matches_with(name, options(ignore_case)),
+        Err(_) => pattern == name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("*.log", "server.log", false));
+        assert!(!matches("*.log", "server.log.1", false));
+        assert!(matches("data-??.csv", "data-07.csv", false));
+        assert!(matches("*a*b*", "xxaxxbxx", false));
+        assert!(matches("report[0-9].txt", "report3.txt", false));
+        assert!(!matches("report[!0-9].txt", "report3.txt", false));
+        assert!(matches("[*]literal", "*literal", false));
+        assert!(!matches("[*]literal", "xliteral", false));
+        assert!(matches("odd[name", "odd[name", false));
+    }
+
+    #[test]
+    fn test_case_insensitive_matching() {
+        assert!(!matches("*.LOG", "server.log", false));
+        assert!(matches("*.LOG", "server.log", true));
+        assert!(matches("[A-C]*", "beta", true));
+    }
+}
@@ -0,0 +1,113 @@
+//! Gitignore-style exclusion rules for tree transfers.
+//!
+//! Ignore files follow gitignore syntax and are matched with the `ignore`
+//! crate's gitignore implementation, against paths relative to the root of
+//! the tree. When several lines match, the last one wins, and nothing inside
+//! an excluded directory can be re-included.
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::io;
+use std::path::Path;
+
+/// File looked for at the root of a tree being transferred.
+pub const IGNORE_FILE_NAME: &str = ".ferricignore";
+
+/// Patterns read from an ignore file.
+#[derive(Debug, Clone)]
+pub struct IgnoreRules {
+    rules: Gitignore,
+}
+
+impl Default for IgnoreRules {
+    fn default() -> Self {
+        Self {
+            rules: Gitignore::empty(),
+        }
+    }
+}
+
+impl IgnoreRules {
+    /// Fails on a line that is not a valid pattern.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut builder = GitignoreBuilder::new("");
+        for line in text.lines() {
+            builder.add_line(None, line).map_err(invalid)?;
+        }
+        Ok(Self {
+            rules: builder.build().map_err(invalid)?,
+        })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// True if `path`, relative to the root of the tree, is excluded either
+    /// itself or through one of the directories holding it.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if self.rules.is_empty() {
+            return false;
+        }
+        path.ancestors()
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .any(|ancestor| {
+                let is_dir = is_dir || ancestor != path;
+                self.rules.matched(ancestor, is_dir).is_ignore()
+            })
+    }
+}
+
+fn invalid(e: ignore::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignored(rules: &IgnoreRules, path: &str) -> bool {
+        rules.is_ignored(Path::new(path), false)
+    }
+
+    #[test]
+    fn test_unanchored_and_anchored_patterns() {
+        let rules = IgnoreRules::parse("# build output\n*.o\n/target\ndocs/*.tmp\n\n").unwrap();
+        assert!(ignored(&rules, "main.o"));
+        assert!(ignored(&rules, "src/lib/util.o"));
+        assert!(ignored(&rules, "target/debug/app"));
+        assert!(!ignored(&rules, "crates/target/app"));
+        assert!(ignored(&rules, "docs/notes.tmp"));
+        assert!(!ignored(&rules, "docs/old/notes.tmp"));
+        assert!(!ignored(&rules, "README.md"));
+    }
+
+    #[test]
+    fn test_directory_only_and_double_star() {
+        let rules = IgnoreRules::parse("cache/\nlogs/**/*.gz\nbuild/**\n").unwrap();
+        assert!(ignored(&rules, "app/cache/entry"));
+        assert!(!ignored(&rules, "app/cache"));
+        assert!(rules.is_ignored(Path::new("app/cache"), true));
+        assert!(ignored(&rules, "logs/old.gz"));
+        assert!(ignored(&rules, "logs/2024/01/old.gz"));
+        assert!(!ignored(&rules, "other/logs.gz"));
+        // `dir/**` matches what is inside the directory, not the directory
+        assert!(ignored(&rules, "build/out.bin"));
+        assert!(!rules.is_ignored(Path::new("build"), true));
+    }
+
+    #[test]
+    fn test_negation_and_excluded_parents() {
+        let rules = IgnoreRules::parse(
+            "*.log\n!keep.log\nvendor/\n!vendor/patched.c\n\\#notes\nspace\\ \n",
+        )
+        .unwrap();
+        assert!(ignored(&rules, "debug.log"));
+        assert!(!ignored(&rules, "keep.log"));
+        // A file inside an excluded directory stays excluded
+        assert!(ignored(&rules, "vendor/patched.c"));
+        assert!(ignored(&rules, "#notes"));
+        // An escaped trailing space is part of the name
+        assert!(ignored(&rules, "space "));
+        assert!(!ignored(&rules, "space"));
+    }
+}
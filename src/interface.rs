@@ -1,5 +1,18 @@
+use crate::sftp::constants::BENCH_DEFAULT_SIZE;
 use crate::sftp::error::SftpError;
+use crate::sftp::types::{
+    parse_duration, parse_size, BenchDirection, ByteRange, ConflictPolicy, ListingOrder, Overwrite,
+    PolicyOverride, Priority, QueueAction, SessionPath, SnapshotAction, SortKey, SyncDirection,
+    SyncOptions,
+};
 use crate::sftp::SftpCommand;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
 use std::io;
 use std::io::prelude::*;
 use std::path::PathBuf;
@@ -8,6 +21,57 @@ const PROMPT: &str = "🦀sftp > ";
 const DEFAULT_LS_PATH: &str = ".";
 const DEFAULT_CD_PATH: &str = "/";
 
+// Canonical command names and the synonyms accepted for them. Any
+// unambiguous prefix of a name or synonym is accepted as well.
+const COMMANDS: &[(&str, &[&str])] = &[
+    ("ls", &["dir", "list"]),
+    ("tree", &[]),
+    ("cd", &["chdir"]),
+    ("get", &[]),
+    ("reget", &[]),
+    ("mget", &[]),
+    ("put", &[]),
+    ("reput", &[]),
+    ("mput", &[]),
+    ("rename", &["mv"]),
+    ("cp", &["copy"]),
+    ("rget", &[]),
+    ("transfer", &[]),
+    ("mkdir", &[]),
+    ("rmdir", &[]),
+    ("rm", &["del", "delete"]),
+    ("chmod", &[]),
+    ("touch", &[]),
+    ("ln", &["symlink"]),
+    ("readlink", &[]),
+    ("verify", &[]),
+    ("sync", &["mirror"]),
+    ("watch", &[]),
+    ("checksum", &[]),
+    ("grep", &[]),
+    ("cat", &[]),
+    ("tail", &[]),
+    ("snapshot", &[]),
+    ("open", &[]),
+    ("bench", &[]),
+    ("queue", &[]),
+    ("pause", &[]),
+    ("resume", &[]),
+    ("stat", &[]),
+    ("df", &[]),
+    ("realpath", &[]),
+    ("set", &[]),
+    ("pwd", &[]),
+    ("version", &[]),
+    ("extensions", &[]),
+    ("stats", &[]),
+    ("connect", &[]),
+    ("close", &[]),
+    ("sessions", &[]),
+    ("help", &["?"]),
+    ("bye", &["quit", "exit", "q"]),
+];
+
 pub struct CommandInterface;
 
 impl CommandInterface {
@@ -16,46 +80,915 @@ impl CommandInterface {
     }
 
     pub fn parse_next_input() -> Result<SftpCommand, SftpError> {
+        match Self::read_next_line()? {
+            Some(line) => Self::parse_input(&line),
+            None => Ok(SftpCommand::Bye),
+        }
+    }
+
+    /// Prompts for and reads one line, returning `None` at end of input.
+    pub fn read_next_line() -> Result<Option<String>, SftpError> {
         print!("{}", PROMPT);
-        io::stdout()
-            .flush()
-            .map_err(|e| SftpError::IoError(e.into()))?;
+        io::stdout().flush().map_err(SftpError::IoError)?;
 
         let mut input_buffer = String::new();
-        io::stdin()
+        let read = io::stdin()
             .read_line(&mut input_buffer)
-            .map_err(|e| SftpError::IoError(e.into()))?;
+            .map_err(SftpError::IoError)?;
+
+        Ok((read > 0).then_some(input_buffer))
+    }
+
+    /// Asks a yes/no question; anything but `y` or `yes` is taken as no.
+    pub fn confirm(question: &str) -> bool {
+        print!("{} [y/N] ", question);
+        if io::stdout().flush().is_err() {
+            return false;
+        }
+
+        let mut answer = String::new();
+        match io::stdin().read_line(&mut answer) {
+            Ok(_) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+            Err(_) => false,
+        }
+    }
+
+    /// Expands `$VAR` and `${VAR}` using `lookup`; `$$` produces a literal `$`.
+    pub fn interpolate<F>(input: &str, lookup: F) -> Result<String, SftpError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut output = String::with_capacity(input.len());
+        let mut chars = input.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                output.push(c);
+                continue;
+            }
+
+            let name: String = match chars.peek() {
+                Some('$') => {
+                    chars.next();
+                    output.push('$');
+                    continue;
+                }
+                Some('{') => {
+                    chars.next();
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => {
+                                return Err(SftpError::InvalidCommand("Unterminated ${ in command"))
+                            }
+                        }
+                    }
+                    name
+                }
+                _ => {
+                    let mut name = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if c.is_ascii_alphanumeric() || c == '_' {
+                            name.push(c);
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    name
+                }
+            };
+
+            if name.is_empty() {
+                output.push('$');
+                continue;
+            }
+
+            let value = lookup(&name).ok_or(SftpError::UndefinedVariable(name))?;
+            output.push_str(&value);
+        }
 
-        Self::parse_input(&input_buffer)
+        Ok(output)
+    }
+
+    /// Maps a typed command word to its canonical name.
+    pub fn resolve_command(word: &str) -> Result<&'static str, SftpError> {
+        let names = |(name, synonyms): &(&'static str, &'static [&'static str])| {
+            std::iter::once(*name).chain(synonyms.iter().copied())
+        };
+
+        if let Some((name, _)) = COMMANDS.iter().find(|c| names(c).any(|n| n == word)) {
+            return Ok(name);
+        }
+
+        let mut matched: Option<&'static str> = None;
+        for command in COMMANDS {
+            if names(command).any(|n| n.starts_with(word)) {
+                if matched.is_some_and(|m| m != command.0) {
+                    return Err(SftpError::InvalidCommand("Ambiguous command"));
+                }
+                matched = Some(command.0);
+            }
+        }
+        matched.ok_or(SftpError::UnexpectedCommand)
+    }
+
+    /// Command names and synonyms starting with `prefix`, for tab completion.
+    pub fn complete_command(prefix: &str) -> Vec<String> {
+        let mut names: Vec<String> = COMMANDS
+            .iter()
+            .flat_map(|(name, synonyms)| std::iter::once(*name).chain(synonyms.iter().copied()))
+            .filter(|name| name.starts_with(prefix))
+            .map(String::from)
+            .collect();
+        names.sort();
+        names
     }
 
     pub fn parse_input(input: &str) -> Result<SftpCommand, SftpError> {
         let mut tokens = input.split_whitespace();
 
-        match tokens.next() {
+        match tokens.next().map(Self::resolve_command).transpose()? {
             Some("ls") => {
-                let path = PathBuf::from(tokens.next().unwrap_or(DEFAULT_LS_PATH));
-                Ok(SftpCommand::Ls { path: Some(path) })
+                let mut order = ListingOrder::default();
+                let mut path = None;
+                for token in tokens {
+                    match token {
+                        "-S" => order.key = SortKey::Size,
+                        "-t" => order.key = SortKey::Time,
+                        "-r" => order.reverse = true,
+                        "--group-directories-first" => order.directories_first = true,
+                        flag if flag.starts_with('-') => {
+                            return Err(SftpError::InvalidCommand("Unknown ls option"))
+                        }
+                        other => path = Some(PathBuf::from(other)),
+                    }
+                }
+                // Any ordering flag means sorting, and names are the fallback key
+                if !order.is_unsorted() && order.key == SortKey::Unsorted {
+                    order.key = SortKey::Name;
+                }
+
+                Ok(SftpCommand::Ls {
+                    path: Some(path.unwrap_or_else(|| PathBuf::from(DEFAULT_LS_PATH))),
+                    order,
+                })
             }
             Some("cd") => {
                 let path = PathBuf::from(tokens.next().unwrap_or(DEFAULT_CD_PATH));
                 Ok(SftpCommand::Cd { path: Some(path) })
             }
-            Some("get") => {
-                let remote_path = PathBuf::from(
-                    tokens
-                        .next()
-                        .ok_or(SftpError::InvalidCommand("Missing remote path"))?,
-                );
+            Some(command @ ("get" | "reget")) => {
+                let mut resume = command == "reget";
+                let mut recursive = false;
+                let mut policy = PolicyOverride::default();
+                let mut range: Option<ByteRange> = None;
+                let mut archive: Option<PathBuf> = None;
+                let mut ignore_file: Option<PathBuf> = None;
+                let mut journal: Option<PathBuf> = None;
+                let mut paths: Vec<PathBuf> = Vec::new();
+                while let Some(token) = tokens.next() {
+                    match token {
+                        "--resume" => resume = true,
+                        "-r" | "-R" | "--recursive" => recursive = true,
+                        "--offset" => {
+                            let value = tokens
+                                .next()
+                                .ok_or(SftpError::InvalidCommand("Missing offset"))?;
+                            range.get_or_insert_with(ByteRange::default).offset =
+                                ByteRange::parse_offset(value)?;
+                        }
+                        "--length" => {
+                            let value = tokens
+                                .next()
+                                .ok_or(SftpError::InvalidCommand("Missing length"))?;
+                            range.get_or_insert_with(ByteRange::default).length =
+                                Some(parse_size(value)?);
+                        }
+                        "--archive" => {
+                            let value = tokens
+                                .next()
+                                .ok_or(SftpError::InvalidCommand("Missing archive name"))?;
+                            archive = Some(PathBuf::from(value));
+                        }
+                        "--ignore-file" => {
+                            let value = tokens
+                                .next()
+                                .ok_or(SftpError::InvalidCommand("Missing ignore file"))?;
+                            ignore_file = Some(PathBuf::from(value));
+                        }
+                        "--journal" => {
+                            let value = tokens
+                                .next()
+                                .ok_or(SftpError::InvalidCommand("Missing journal file"))?;
+                            journal = Some(PathBuf::from(value));
+                        }
+                        "--timeout" => {
+                            let value = tokens
+                                .next()
+                                .ok_or(SftpError::InvalidCommand("Missing timeout"))?;
+                            policy.timeout = Some(parse_duration(value)?);
+                        }
+                        "--retries" => {
+                            let value = tokens
+                                .next()
+                                .ok_or(SftpError::InvalidCommand("Missing retry count"))?;
+                            policy.retries = Some(value.parse().map_err(|_| {
+                                SftpError::InvalidCommand("Expected a number of retries")
+                            })?);
+                        }
+                        token => match Overwrite::from_flag(token) {
+                            Some(overwrite) => policy.overwrite = Some(overwrite),
+                            None => paths.push(PathBuf::from(token)),
+                        },
+                    }
+                }
+                if paths.is_empty() {
+                    return Err(SftpError::InvalidCommand("Missing remote path"));
+                }
+                if ignore_file.is_some() && !recursive {
+                    return Err(SftpError::InvalidCommand(
+                        "--ignore-file only applies to get -r",
+                    ));
+                }
+                if journal.is_some() && (!recursive || archive.is_some()) {
+                    return Err(SftpError::InvalidCommand(
+                        "--journal only applies to get -r into a directory",
+                    ));
+                }
+
+                if let Some(archive) = archive {
+                    if !recursive || resume || range.is_some() || paths.len() != 1 {
+                        return Err(SftpError::InvalidCommand(
+                            "An archive is made from a single directory with get -r",
+                        ));
+                    }
+                    return Ok(SftpCommand::GetArchive {
+                        remote_path: paths.swap_remove(0),
+                        archive,
+                        ignore_file,
+                        policy,
+                    });
+                }
+
+                if let Some(range) = range {
+                    let to_stdout = paths.last().is_some_and(|path| path.as_os_str() == "-");
+                    if recursive || resume || to_stdout || paths.len() > 2 {
+                        return Err(SftpError::InvalidCommand(
+                            "A byte range is downloaded from a single file",
+                        ));
+                    }
+                    let mut paths = paths.into_iter();
+                    return Ok(SftpCommand::GetRange {
+                        remote_path: paths.next().unwrap_or_default(),
+                        local_path: paths.next(),
+                        range,
+                        policy,
+                    });
+                }
+
+                // `get remote -` writes the file to stdout, like cat
+                if paths.last().is_some_and(|path| path.as_os_str() == "-") {
+                    if recursive || resume || paths.len() != 2 {
+                        return Err(SftpError::InvalidCommand(
+                            "Only a single file can be written to stdout",
+                        ));
+                    }
+                    return Ok(SftpCommand::Cat {
+                        path: paths.swap_remove(0),
+                    });
+                }
+
+                if recursive {
+                    if resume {
+                        return Err(SftpError::InvalidCommand("Cannot resume a directory"));
+                    }
+                    if paths.len() > 2 {
+                        return Err(SftpError::InvalidCommand("Get one directory at a time"));
+                    }
+                    let mut paths = paths.into_iter();
+                    return Ok(SftpCommand::GetRecursive {
+                        remote_path: paths.next().unwrap_or_default(),
+                        local_path: paths.next(),
+                        ignore_file,
+                        journal,
+                        policy,
+                    });
+                }
+
+                if resume {
+                    if paths.len() > 2 {
+                        return Err(SftpError::InvalidCommand("Resume one file at a time"));
+                    }
+                    let mut paths = paths.into_iter();
+                    return Ok(SftpCommand::Reget {
+                        remote_path: paths.next().unwrap_or_default(),
+                        local_path: paths.next(),
+                        policy,
+                    });
+                }
 
-                let local_path = tokens.next().map(PathBuf::from);
+                // Like cp/scp, more than two paths means the last is a directory
+                if paths.len() > 2 {
+                    let local_dir = paths.pop().unwrap_or_default();
+                    return Ok(SftpCommand::GetMany {
+                        remote_paths: paths,
+                        local_dir,
+                        policy,
+                    });
+                }
 
+                let mut paths = paths.into_iter();
                 Ok(SftpCommand::Get {
+                    remote_path: paths.next().unwrap_or_default(),
+                    local_path: paths.next(),
+                    policy,
+                })
+            }
+            Some("mget") => {
+                let mut policy = PolicyOverride::default();
+                let mut paths = Vec::new();
+                for token in tokens {
+                    match Overwrite::from_flag(token) {
+                        Some(overwrite) => policy.overwrite = Some(overwrite),
+                        None => paths.push(token),
+                    }
+                }
+                let mut paths = paths.into_iter();
+                let pattern = paths
+                    .next()
+                    .map(unquote)
+                    .ok_or(SftpError::InvalidCommand("Missing pattern"))?;
+                Ok(SftpCommand::Mget {
+                    pattern: PathBuf::from(pattern),
+                    local_dir: paths.next().map(PathBuf::from),
+                    policy,
+                })
+            }
+            Some(command @ ("put" | "reput")) => {
+                let mut exclusive = false;
+                let mut resume = command == "reput";
+                let mut policy = PolicyOverride::default();
+                let mut paths = Vec::new();
+                for token in tokens {
+                    match token {
+                        "--exclusive" | "-x" => exclusive = true,
+                        "--resume" => resume = true,
+                        token => match Overwrite::from_flag(token) {
+                            Some(overwrite) => policy.overwrite = Some(overwrite),
+                            None => paths.push(PathBuf::from(token)),
+                        },
+                    }
+                }
+                if exclusive && policy.overwrite.is_some() {
+                    return Err(SftpError::InvalidCommand(
+                        "--exclusive already refuses existing files",
+                    ));
+                }
+
+                // Like cp/scp, more than two paths means the last is a directory
+                if paths.len() > 2 {
+                    if resume {
+                        return Err(SftpError::InvalidCommand("Resume one file at a time"));
+                    }
+                    let remote_dir = paths.pop().unwrap_or_default();
+                    return Ok(SftpCommand::PutMany {
+                        local_paths: paths,
+                        remote_dir,
+                        exclusive,
+                        policy,
+                    });
+                }
+
+                let mut paths = paths.into_iter();
+                let local_path = paths
+                    .next()
+                    .ok_or(SftpError::InvalidCommand("Missing local path"))?;
+                let remote_path = paths.next();
+                if resume {
+                    if exclusive {
+                        return Err(SftpError::InvalidCommand(
+                            "Cannot resume into a file that must not exist",
+                        ));
+                    }
+                    return Ok(SftpCommand::Reput {
+                        local_path,
+                        remote_path,
+                    });
+                }
+                Ok(SftpCommand::Put {
+                    local_path,
                     remote_path,
+                    exclusive,
+                    policy,
+                })
+            }
+            Some("verify") => {
+                let mut checksum = false;
+                let mut paths = Vec::new();
+                for token in tokens {
+                    match token {
+                        "--checksum" | "-c" => checksum = true,
+                        path => paths.push(PathBuf::from(path)),
+                    }
+                }
+
+                let mut paths = paths.into_iter();
+                Ok(SftpCommand::Verify {
+                    local_path: paths
+                        .next()
+                        .ok_or(SftpError::InvalidCommand("Missing local path"))?,
+                    remote_path: paths.next().unwrap_or_else(|| PathBuf::from(".")),
+                    checksum,
+                })
+            }
+            Some("sync") => {
+                let mut direction = None;
+                let mut both = false;
+                let mut conflict = None;
+                let mut options = SyncOptions::default();
+                let mut paths = Vec::new();
+                while let Some(token) = tokens.next() {
+                    match token {
+                        "--up" => direction = Some(SyncDirection::Up),
+                        "--down" => direction = Some(SyncDirection::Down),
+                        "--both" => both = true,
+                        "--conflict" => {
+                            let value = tokens
+                                .next()
+                                .ok_or(SftpError::InvalidCommand("Missing conflict policy"))?;
+                            conflict = Some(ConflictPolicy::parse(value)?);
+                        }
+                        "--delete" => options.delete = true,
+                        "--force" => options.force = true,
+                        "--checksum" | "-c" => options.checksum = true,
+                        "--ignore-file" => {
+                            let value = tokens
+                                .next()
+                                .ok_or(SftpError::InvalidCommand("Missing ignore file"))?;
+                            options.ignore_file = Some(PathBuf::from(value));
+                        }
+                        "--journal" => {
+                            let value = tokens
+                                .next()
+                                .ok_or(SftpError::InvalidCommand("Missing journal file"))?;
+                            options.journal = Some(PathBuf::from(value));
+                        }
+                        "--report" => {
+                            let value = tokens
+                                .next()
+                                .ok_or(SftpError::InvalidCommand("Missing report file"))?;
+                            options.report = Some(PathBuf::from(value));
+                        }
+                        path => paths.push(PathBuf::from(path)),
+                    }
+                }
+                if both {
+                    if direction.is_some() || options.delete || options.checksum {
+                        return Err(SftpError::InvalidCommand(
+                            "--both takes neither a direction, --delete nor --checksum",
+                        ));
+                    }
+                    if paths.len() != 2 {
+                        return Err(SftpError::InvalidCommand(
+                            "Expected a local and a remote directory",
+                        ));
+                    }
+                    options.conflict = conflict.unwrap_or_default();
+                    let remote_path = paths.swap_remove(1);
+                    return Ok(SftpCommand::SyncBoth {
+                        local_path: paths.swap_remove(0),
+                        remote_path,
+                        options,
+                    });
+                }
+                if conflict.is_some() {
+                    return Err(SftpError::InvalidCommand(
+                        "--conflict only applies to sync --both",
+                    ));
+                }
+                let direction = direction
+                    .ok_or(SftpError::InvalidCommand("Expected --up, --down or --both"))?;
+                if options.force && !options.delete {
+                    return Err(SftpError::InvalidCommand(
+                        "--force only applies to --delete",
+                    ));
+                }
+                if paths.len() != 2 {
+                    return Err(SftpError::InvalidCommand(
+                        "Expected a source and a destination",
+                    ));
+                }
+
+                // Paths are given source first, as with cp
+                let (source, destination) = (paths.swap_remove(0), paths.swap_remove(0));
+                let (local_path, remote_path) = match direction {
+                    SyncDirection::Up => (source, destination),
+                    SyncDirection::Down => (destination, source),
+                };
+                Ok(SftpCommand::Sync {
+                    direction,
                     local_path,
+                    remote_path,
+                    options,
+                })
+            }
+            Some("watch") => {
+                let mut delete = false;
+                let mut paths = Vec::new();
+                for token in tokens {
+                    match token {
+                        "--delete" => delete = true,
+                        path => paths.push(PathBuf::from(path)),
+                    }
+                }
+                if paths.len() != 2 {
+                    return Err(SftpError::InvalidCommand(
+                        "Expected a local and a remote directory",
+                    ));
+                }
+                let remote_path = paths.swap_remove(1);
+                Ok(SftpCommand::Watch {
+                    local_path: paths.swap_remove(0),
+                    remote_path,
+                    delete,
                 })
             }
+            Some("checksum") => {
+                let path = tokens
+                    .next()
+                    .ok_or(SftpError::InvalidCommand("Missing path"))?;
+                Ok(SftpCommand::Checksum {
+                    path: PathBuf::from(path),
+                })
+            }
+            Some("grep") => {
+                let pattern = tokens
+                    .next()
+                    .ok_or(SftpError::InvalidCommand("Missing pattern"))?;
+                let path = tokens
+                    .next()
+                    .ok_or(SftpError::InvalidCommand("Missing path"))?;
+                Ok(SftpCommand::Grep {
+                    pattern: pattern.to_string(),
+                    path: PathBuf::from(path),
+                })
+            }
+            Some("tail") => {
+                let mut follow = false;
+                let mut path = None;
+                for token in tokens {
+                    match token {
+                        "-f" | "--follow" => follow = true,
+                        other => path = Some(PathBuf::from(other)),
+                    }
+                }
+
+                Ok(SftpCommand::Tail {
+                    path: path.ok_or(SftpError::InvalidCommand("Missing path"))?,
+                    follow,
+                })
+            }
+            Some("cat") => {
+                let path = tokens
+                    .next()
+                    .ok_or(SftpError::InvalidCommand("Missing path"))?;
+                Ok(SftpCommand::Cat {
+                    path: PathBuf::from(path),
+                })
+            }
+            Some("queue") => match tokens.next() {
+                None | Some("list") => Ok(SftpCommand::Queue(QueueAction::List)),
+                Some("run") => Ok(SftpCommand::Queue(QueueAction::Run)),
+                Some("add") => {
+                    let mut priority = Priority::Normal;
+                    let mut rest: Vec<&str> = tokens.collect();
+                    if rest.first() == Some(&"--priority") {
+                        let value = rest
+                            .get(1)
+                            .ok_or(SftpError::InvalidCommand("Missing priority"))?;
+                        priority = Priority::parse(value)?;
+                        rest.drain(..2);
+                    }
+
+                    let description = rest.join(" ");
+                    let transfer = Self::parse_input(&description)?;
+                    if !matches!(
+                        transfer,
+                        SftpCommand::Get { .. }
+                            | SftpCommand::Reget { .. }
+                            | SftpCommand::GetMany { .. }
+                            | SftpCommand::Put { .. }
+                            | SftpCommand::Reput { .. }
+                            | SftpCommand::PutMany { .. }
+                    ) {
+                        return Err(SftpError::InvalidCommand("Only get and put can be queued"));
+                    }
+                    Ok(SftpCommand::Queue(QueueAction::Add {
+                        priority,
+                        transfer: Box::new(transfer),
+                        description,
+                    }))
+                }
+                Some(_) => Err(SftpError::InvalidCommand("Expected add, list or run")),
+            },
+            Some("bench") => {
+                let mut size = BENCH_DEFAULT_SIZE;
+                let mut direction = BenchDirection::Both;
+                while let Some(token) = tokens.next() {
+                    let value = tokens
+                        .next()
+                        .ok_or(SftpError::InvalidCommand("Option is missing its value"))?;
+                    match token {
+                        "--size" => size = parse_size(value)?,
+                        "--direction" => direction = BenchDirection::parse(value)?,
+                        _ => return Err(SftpError::InvalidCommand("Unknown bench option")),
+                    }
+                }
+                if size == 0 {
+                    return Err(SftpError::InvalidCommand("Size must be above zero"));
+                }
+
+                Ok(SftpCommand::Bench { size, direction })
+            }
+            Some("open") => {
+                let path = tokens
+                    .next()
+                    .ok_or(SftpError::InvalidCommand("Missing path"))?;
+                Ok(SftpCommand::Open {
+                    path: PathBuf::from(path),
+                })
+            }
+            Some("snapshot") => {
+                let action = match tokens.next() {
+                    Some("save") => SnapshotAction::Save,
+                    Some("diff") => SnapshotAction::Diff,
+                    _ => return Err(SftpError::InvalidCommand("Expected save or diff")),
+                };
+                let file = tokens
+                    .next()
+                    .ok_or(SftpError::InvalidCommand("Missing snapshot file"))?;
+                Ok(SftpCommand::Snapshot {
+                    action,
+                    file: PathBuf::from(file),
+                    path: tokens.next().map(PathBuf::from),
+                })
+            }
+            Some(command @ ("pause" | "resume")) => {
+                let id = tokens
+                    .next()
+                    .and_then(|id| id.trim_start_matches('#').parse().ok())
+                    .ok_or(SftpError::InvalidCommand("Expected a job number"))?;
+                Ok(SftpCommand::Queue(match command {
+                    "pause" => QueueAction::Pause(id),
+                    _ => QueueAction::Resume(id),
+                }))
+            }
+            Some("cp") => {
+                let [source, destination]: [PathBuf; 2] = tokens
+                    .map(PathBuf::from)
+                    .collect::<Vec<_>>()
+                    .try_into()
+                    .map_err(|_| SftpError::InvalidCommand("Expected source and destination"))?;
+                Ok(SftpCommand::Copy {
+                    source,
+                    destination,
+                })
+            }
+            Some("rget") => {
+                let source = tokens
+                    .next()
+                    .map(SessionPath::parse)
+                    .filter(|source| source.session.is_some())
+                    .ok_or(SftpError::InvalidCommand("Expected session:path to fetch"))?;
+                let destination = SessionPath::parse(tokens.next().unwrap_or(DEFAULT_LS_PATH));
+                Ok(SftpCommand::Transfer {
+                    source,
+                    destination,
+                })
+            }
+            Some("transfer") => {
+                let [source, destination]: [SessionPath; 2] = tokens
+                    .map(SessionPath::parse)
+                    .collect::<Vec<_>>()
+                    .try_into()
+                    .map_err(|_| SftpError::InvalidCommand("Expected source and destination"))?;
+                Ok(SftpCommand::Transfer {
+                    source,
+                    destination,
+                })
+            }
+            Some("connect") => {
+                let destination = tokens
+                    .next()
+                    .ok_or(SftpError::InvalidCommand("Missing destination"))?;
+                Ok(SftpCommand::Connect {
+                    destination: destination.to_string(),
+                })
+            }
+            Some("close") => {
+                let session = tokens
+                    .next()
+                    .ok_or(SftpError::InvalidCommand("Missing session"))?;
+                Ok(SftpCommand::Close {
+                    session: session.to_string(),
+                })
+            }
+            Some("sessions") => Ok(SftpCommand::Sessions),
+            Some("mkdir") => {
+                let path = tokens
+                    .next()
+                    .ok_or(SftpError::InvalidCommand("Missing path"))?;
+                Ok(SftpCommand::MkDir {
+                    path: PathBuf::from(path),
+                })
+            }
+            Some("touch") => {
+                let path = tokens
+                    .next()
+                    .ok_or(SftpError::InvalidCommand("Missing path"))?;
+                Ok(SftpCommand::Touch {
+                    path: PathBuf::from(path),
+                })
+            }
+            Some("rmdir") => {
+                let path = tokens
+                    .next()
+                    .ok_or(SftpError::InvalidCommand("Missing path"))?;
+                Ok(SftpCommand::RmDir {
+                    path: PathBuf::from(path),
+                })
+            }
+            Some("rm") => {
+                let mut recursive = false;
+                let mut path = None;
+                for token in tokens {
+                    match token {
+                        "-r" | "-R" | "--recursive" => recursive = true,
+                        other => path = Some(PathBuf::from(other)),
+                    }
+                }
+
+                Ok(SftpCommand::Rm {
+                    path: path.ok_or(SftpError::InvalidCommand("Missing path"))?,
+                    recursive,
+                })
+            }
+            Some("tree") => {
+                let mut depth = None;
+                let mut path = None;
+                while let Some(token) = tokens.next() {
+                    match token {
+                        "-L" => {
+                            let value = tokens
+                                .next()
+                                .and_then(|value| value.parse::<usize>().ok())
+                                .filter(|value| *value > 0)
+                                .ok_or(SftpError::InvalidCommand(
+                                    "Depth must be a positive number",
+                                ))?;
+                            depth = Some(value);
+                        }
+                        flag if flag.starts_with('-') => {
+                            return Err(SftpError::InvalidCommand("Unknown tree option"))
+                        }
+                        other => path = Some(PathBuf::from(other)),
+                    }
+                }
+
+                Ok(SftpCommand::Tree { path, depth })
+            }
+            Some("df") => {
+                let mut human = false;
+                let mut path = None;
+                for token in tokens {
+                    match token {
+                        "-h" => human = true,
+                        flag if flag.starts_with('-') => {
+                            return Err(SftpError::InvalidCommand("Unknown df option"))
+                        }
+                        other => path = Some(PathBuf::from(other)),
+                    }
+                }
+
+                Ok(SftpCommand::Df { path, human })
+            }
+            Some("chmod") => {
+                let mut tokens = tokens.peekable();
+                let recursive = tokens.next_if_eq(&"-R").is_some();
+                let mode = tokens
+                    .next()
+                    .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+                    .filter(|mode| *mode <= 0o7777)
+                    .ok_or(SftpError::InvalidCommand(
+                        "Expected an octal mode such as 644",
+                    ))?;
+                let path = tokens
+                    .next()
+                    .ok_or(SftpError::InvalidCommand("Missing path"))?;
+                Ok(SftpCommand::Chmod {
+                    mode,
+                    path: PathBuf::from(path),
+                    recursive,
+                })
+            }
+            Some("ln") => {
+                let mut symbolic = false;
+                let mut paths = Vec::new();
+                for token in tokens {
+                    match token {
+                        "-s" | "--symbolic" => symbolic = true,
+                        path => paths.push(path),
+                    }
+                }
+                let [target, link_path]: [&str; 2] = paths
+                    .try_into()
+                    .map_err(|_| SftpError::InvalidCommand("Expected target and link paths"))?;
+                match symbolic {
+                    true => Ok(SftpCommand::Symlink {
+                        target: target.to_string(),
+                        link_path: PathBuf::from(link_path),
+                    }),
+                    false => Ok(SftpCommand::HardLink {
+                        target: PathBuf::from(target),
+                        link_path: PathBuf::from(link_path),
+                    }),
+                }
+            }
+            Some("readlink") => {
+                let path = tokens
+                    .next()
+                    .ok_or(SftpError::InvalidCommand("Missing path"))?;
+                Ok(SftpCommand::ReadLink {
+                    path: PathBuf::from(path),
+                })
+            }
+            Some("mput") => {
+                let mut policy = PolicyOverride::default();
+                let mut patterns = Vec::new();
+                for token in tokens {
+                    match Overwrite::from_flag(token) {
+                        Some(overwrite) => policy.overwrite = Some(overwrite),
+                        None => patterns.push(PathBuf::from(unquote(token))),
+                    }
+                }
+                if patterns.is_empty() {
+                    return Err(SftpError::InvalidCommand("Missing pattern"));
+                }
+                Ok(SftpCommand::Mput { patterns, policy })
+            }
+            Some("rename") => {
+                let mut overwrite = false;
+                let mut paths = Vec::new();
+                for token in tokens {
+                    match token {
+                        "--overwrite" | "-f" => overwrite = true,
+                        path => paths.push(PathBuf::from(path)),
+                    }
+                }
+
+                let [old_path, new_path]: [PathBuf; 2] = paths
+                    .try_into()
+                    .map_err(|_| SftpError::InvalidCommand("Expected old and new paths"))?;
+                Ok(SftpCommand::Rename {
+                    old_path,
+                    new_path,
+                    overwrite,
+                })
+            }
+            Some("stat") => {
+                let mut follow = false;
+                let mut path = None;
+                for token in tokens {
+                    match token {
+                        "-L" | "--dereference" => follow = true,
+                        other => path = Some(PathBuf::from(other)),
+                    }
+                }
+
+                Ok(SftpCommand::Stat {
+                    path: path.ok_or(SftpError::InvalidCommand("Missing path"))?,
+                    follow,
+                })
+            }
+            Some("realpath") => {
+                let path = PathBuf::from(tokens.next().unwrap_or(DEFAULT_LS_PATH));
+                Ok(SftpCommand::RealPath { path })
+            }
+            Some("set") => {
+                let key = tokens.next().map(String::from);
+                let value = tokens.next().map(String::from);
+                if key.is_some() && value.is_none() {
+                    return Err(SftpError::InvalidCommand("Missing setting value"));
+                }
+                Ok(SftpCommand::Set { key, value })
+            }
             Some("pwd") => Ok(SftpCommand::Pwd),
+            Some("version") => Ok(SftpCommand::Version),
+            Some("stats") => Ok(SftpCommand::Stats),
+            Some("extensions") => Ok(SftpCommand::Extensions),
             Some("bye") => Ok(SftpCommand::Bye),
             Some("help") => Ok(SftpCommand::Help),
             Some(_) => Err(SftpError::UnexpectedCommand),
@@ -64,27 +997,714 @@ impl CommandInterface {
     }
 }
 
+/// Reads REPL lines with history and tab completion: command names for the
+/// first word, and remote paths for the words after it, looked up through
+/// `list`, which returns the paths a partial one could complete to.
+pub struct LineEditor<F: Fn(&str) -> Vec<String>> {
+    editor: Editor<Completion<F>, DefaultHistory>,
+}
+
+impl<F: Fn(&str) -> Vec<String>> LineEditor<F> {
+    pub fn new(list: F) -> Result<Self, SftpError> {
+        let mut editor = Editor::new().map_err(readline_error)?;
+        editor.set_helper(Some(Completion { list }));
+        Ok(Self { editor })
+    }
+
+    /// Prompts for and reads one line, returning `None` at end of input.
+    pub fn read_line(&mut self) -> Result<Option<String>, SftpError> {
+        match self.editor.readline(PROMPT) {
+            Ok(line) => {
+                self.editor
+                    .add_history_entry(line.as_str())
+                    .map_err(readline_error)?;
+                Ok(Some(line))
+            }
+            // Ctrl-C drops the line being typed, as in a shell
+            Err(ReadlineError::Interrupted) => Ok(Some(String::new())),
+            Err(ReadlineError::Eof) => Ok(None),
+            Err(e) => Err(readline_error(e)),
+        }
+    }
+}
+
+fn readline_error(e: ReadlineError) -> SftpError {
+    match e {
+        ReadlineError::Io(e) => SftpError::IoError(e),
+        e => SftpError::IoError(io::Error::other(e)),
+    }
+}
+
+struct Completion<F> {
+    list: F,
+}
+
+impl<F: Fn(&str) -> Vec<String>> Completer for Completion<F> {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        // Words are split on whitespace, as parse_input splits them
+        let start = line[..pos]
+            .rfind(char::is_whitespace)
+            .map_or(0, |space| space + 1);
+        let word = &line[start..pos];
+
+        let candidates = if line[..start].trim().is_empty() {
+            CommandInterface::complete_command(word)
+        } else if word.starts_with('-') {
+            Vec::new()
+        } else {
+            (self.list)(word)
+        };
+        Ok((start, candidates))
+    }
+}
+
+impl<F> Hinter for Completion<F> {
+    type Hint = String;
+}
+
+impl<F> Highlighter for Completion<F> {}
+
+impl<F> Validator for Completion<F> {}
+
+impl<F: Fn(&str) -> Vec<String>> Helper for Completion<F> {}
+
+// Nothing is expanded by a shell before the command sees it, but patterns
+// quoted out of habit should still match
+fn unquote(token: &str) -> &str {
+    ['\'', '"']
+        .into_iter()
+        .find_map(|quote| {
+            token
+                .strip_prefix(quote)
+                .and_then(|rest| rest.strip_suffix(quote))
+        })
+        .unwrap_or(token)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Duration;
+
+    fn complete(line: &str) -> (usize, Vec<String>) {
+        let completion = Completion {
+            list: |partial: &str| vec![format!("{}report.csv", partial)],
+        };
+        let history = DefaultHistory::new();
+        completion
+            .complete(line, line.len(), &Context::new(&history))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_complete_command_names_then_remote_paths() {
+        assert_eq!(
+            CommandInterface::complete_command("re"),
+            vec!["readlink", "realpath", "reget", "rename", "reput", "resume"]
+        );
+        assert_eq!(complete("  che"), (2, vec!["checksum".to_string()]));
+        assert_eq!(
+            complete("get -r docs/"),
+            (7, vec!["docs/report.csv".to_string()])
+        );
+        assert_eq!(complete("get --res"), (4, Vec::new()));
+    }
 
     #[test]
     fn test_parse_ls() {
         let command = CommandInterface::parse_input("ls").unwrap();
-        if let SftpCommand::Ls { path } = command {
+        if let SftpCommand::Ls { path, .. } = command {
             assert_eq!(path, Some(PathBuf::from(".")));
         } else {
             panic!("Expected Ls command");
         }
     }
 
+    #[test]
+    fn test_parse_tree() {
+        let command = CommandInterface::parse_input("tree -L 2 /var/www").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Tree { path: Some(ref path), depth: Some(2) }
+                if path == &PathBuf::from("/var/www")
+        ));
+        assert!(matches!(
+            CommandInterface::parse_input("tree").unwrap(),
+            SftpCommand::Tree {
+                path: None,
+                depth: None
+            }
+        ));
+        assert!(CommandInterface::parse_input("tree -L 0").is_err());
+        assert!(CommandInterface::parse_input("tree -L").is_err());
+    }
+
     #[test]
     fn test_parse_ls_path() {
         let command = CommandInterface::parse_input("ls test").unwrap();
-        if let SftpCommand::Ls { path } = command {
+        if let SftpCommand::Ls { path, .. } = command {
             assert_eq!(path, Some(PathBuf::from("test")));
         } else {
             panic!("Expected Ls command");
         }
     }
+
+    #[test]
+    fn test_parse_realpath_defaults_to_current_dir() {
+        let command = CommandInterface::parse_input("realpath").unwrap();
+        if let SftpCommand::RealPath { path } = command {
+            assert_eq!(path, PathBuf::from("."));
+        } else {
+            panic!("Expected RealPath command");
+        }
+    }
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "RELEASE_DIR" => Some("/srv/releases".to_string()),
+            "V" => Some("1.2".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_interpolate_variables() {
+        let line =
+            CommandInterface::interpolate("get $RELEASE_DIR/app-${V}.tar.gz", lookup).unwrap();
+        assert_eq!(line, "get /srv/releases/app-1.2.tar.gz");
+    }
+
+    #[test]
+    fn test_interpolate_escapes_and_bare_dollar() {
+        let line = CommandInterface::interpolate("ls $$HOME $ x", lookup).unwrap();
+        assert_eq!(line, "ls $HOME $ x");
+    }
+
+    #[test]
+    fn test_interpolate_undefined_variable() {
+        let result = CommandInterface::interpolate("get $NOPE", lookup);
+        assert!(matches!(result, Err(SftpError::UndefinedVariable(name)) if name == "NOPE"));
+    }
+
+    #[test]
+    fn test_resolve_synonyms_and_prefixes() {
+        assert_eq!(CommandInterface::resolve_command("dir").unwrap(), "ls");
+        assert_eq!(CommandInterface::resolve_command("di").unwrap(), "ls");
+        assert_eq!(CommandInterface::resolve_command("q").unwrap(), "bye");
+        assert_eq!(CommandInterface::resolve_command("exit").unwrap(), "bye");
+        assert_eq!(CommandInterface::resolve_command("del").unwrap(), "rm");
+        assert_eq!(CommandInterface::resolve_command("delete").unwrap(), "rm");
+        assert_eq!(
+            CommandInterface::resolve_command("vers").unwrap(),
+            "version"
+        );
+        // An exact name wins over longer commands sharing the prefix
+        assert_eq!(CommandInterface::resolve_command("stat").unwrap(), "stat");
+    }
+
+    #[test]
+    fn test_resolve_rejects_ambiguous_and_unknown() {
+        assert!(matches!(
+            CommandInterface::resolve_command("s"),
+            Err(SftpError::InvalidCommand(_))
+        ));
+        assert!(matches!(
+            CommandInterface::resolve_command("frobnicate"),
+            Err(SftpError::UnexpectedCommand)
+        ));
+    }
+
+    #[test]
+    fn test_parse_get_multiple_sources() {
+        let command = CommandInterface::parse_input("get a.txt b.txt out/").unwrap();
+        if let SftpCommand::GetMany {
+            remote_paths,
+            local_dir,
+            ..
+        } = command
+        {
+            assert_eq!(
+                remote_paths,
+                vec![PathBuf::from("a.txt"), PathBuf::from("b.txt")]
+            );
+            assert_eq!(local_dir, PathBuf::from("out/"));
+        } else {
+            panic!("Expected GetMany command");
+        }
+    }
+
+    #[test]
+    fn test_parse_put_multiple_sources() {
+        let command = CommandInterface::parse_input("put -x a.txt b.txt /upload").unwrap();
+        if let SftpCommand::PutMany {
+            local_paths,
+            remote_dir,
+            exclusive,
+            ..
+        } = command
+        {
+            assert_eq!(
+                local_paths,
+                vec![PathBuf::from("a.txt"), PathBuf::from("b.txt")]
+            );
+            assert_eq!(remote_dir, PathBuf::from("/upload"));
+            assert!(exclusive);
+        } else {
+            panic!("Expected PutMany command");
+        }
+
+        assert!(CommandInterface::parse_input("reput a.txt b.txt /upload").is_err());
+    }
+
+    #[test]
+    fn test_parse_get_recursive() {
+        let command = CommandInterface::parse_input("get -r logs backup").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::GetRecursive { ref remote_path, local_path: Some(ref local_path), .. }
+                if remote_path == &PathBuf::from("logs") && local_path == &PathBuf::from("backup")
+        ));
+
+        let command = CommandInterface::parse_input("get -r --ignore-file skip.txt logs").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::GetRecursive { ignore_file: Some(ref file), .. }
+                if file == &PathBuf::from("skip.txt")
+        ));
+
+        assert!(CommandInterface::parse_input("get -r --resume logs").is_err());
+        assert!(CommandInterface::parse_input("get -r a b c").is_err());
+        assert!(CommandInterface::parse_input("get --ignore-file skip.txt logs").is_err());
+
+        let command = CommandInterface::parse_input("get -r --journal logs.journal logs").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::GetRecursive { journal: Some(ref file), .. }
+                if file == &PathBuf::from("logs.journal")
+        ));
+        assert!(CommandInterface::parse_input("get --journal j logs").is_err());
+    }
+
+    #[test]
+    fn test_parse_mput_patterns() {
+        let command = CommandInterface::parse_input("mput *.csv 'data/*.json'").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Mput { ref patterns, .. }
+                if patterns == &[PathBuf::from("*.csv"), PathBuf::from("data/*.json")]
+        ));
+        assert!(CommandInterface::parse_input("mput").is_err());
+    }
+
+    #[test]
+    fn test_parse_mget_strips_quotes() {
+        for input in ["mget '*.log'", "mget \"*.log\"", "mget *.log"] {
+            let command = CommandInterface::parse_input(input).unwrap();
+            assert!(matches!(
+                command,
+                SftpCommand::Mget { ref pattern, local_dir: None, .. } if pattern == &PathBuf::from("*.log")
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_overwrite_policies() {
+        let command = CommandInterface::parse_input("mget --newer-only *.log logs").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Mget { ref pattern, local_dir: Some(_), policy }
+                if pattern == &PathBuf::from("*.log")
+                    && policy.overwrite == Some(Overwrite::NewerOnly)
+        ));
+
+        let command = CommandInterface::parse_input("put --skip report.csv").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Put { exclusive: false, policy, .. }
+                if policy.overwrite == Some(Overwrite::Skip)
+        ));
+
+        let command = CommandInterface::parse_input("get -r --prompt logs").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::GetRecursive { policy, .. } if policy.overwrite == Some(Overwrite::Prompt)
+        ));
+
+        assert!(CommandInterface::parse_input("put --exclusive --overwrite a.txt").is_err());
+    }
+
+    #[test]
+    fn test_parse_mv_overwrite() {
+        let command = CommandInterface::parse_input("mv --overwrite a.txt b.txt").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Rename { overwrite: true, ref old_path, ref new_path }
+                if old_path == &PathBuf::from("a.txt") && new_path == &PathBuf::from("b.txt")
+        ));
+        assert!(CommandInterface::parse_input("mv a.txt").is_err());
+    }
+
+    #[test]
+    fn test_parse_queue_add_with_priority() {
+        let command =
+            CommandInterface::parse_input("queue add --priority high get app.conf").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Queue(QueueAction::Add {
+                priority: Priority::High,
+                ref transfer,
+                ref description,
+            }) if matches!(**transfer, SftpCommand::Get { .. }) && description == "get app.conf"
+        ));
+
+        assert!(CommandInterface::parse_input("queue add cd /tmp").is_err());
+    }
+
+    #[test]
+    fn test_parse_get_resume() {
+        for input in ["reget big.iso", "get --resume big.iso"] {
+            let command = CommandInterface::parse_input(input).unwrap();
+            assert!(matches!(
+                command,
+                SftpCommand::Reget { ref remote_path, local_path: None, .. }
+                    if remote_path == &PathBuf::from("big.iso")
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_put_resume() {
+        for input in ["reput big.iso", "put --resume big.iso"] {
+            let command = CommandInterface::parse_input(input).unwrap();
+            assert!(matches!(
+                command,
+                SftpCommand::Reput { ref local_path, remote_path: None }
+                    if local_path == &PathBuf::from("big.iso")
+            ));
+        }
+        assert!(CommandInterface::parse_input("reput --exclusive big.iso").is_err());
+    }
+
+    #[test]
+    fn test_parse_ls_group_directories_first() {
+        let command =
+            CommandInterface::parse_input("ls -t --group-directories-first logs").unwrap();
+        if let SftpCommand::Ls { path, order } = command {
+            assert_eq!(path, Some(PathBuf::from("logs")));
+            assert_eq!(order.key, SortKey::Time);
+            assert!(order.directories_first);
+        } else {
+            panic!("Expected Ls command");
+        }
+
+        let command = CommandInterface::parse_input("ls --group-directories-first").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Ls {
+                order: ListingOrder {
+                    key: SortKey::Name,
+                    ..
+                },
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_parse_grep() {
+        let command = CommandInterface::parse_input("grep ERROR logs/*.log").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Grep { ref pattern, ref path }
+                if pattern == "ERROR" && path == &PathBuf::from("logs/*.log")
+        ));
+
+        assert!(CommandInterface::parse_input("grep ERROR").is_err());
+    }
+
+    #[test]
+    fn test_parse_tail_follow() {
+        let command = CommandInterface::parse_input("tail -f /var/log/app.log").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Tail { ref path, follow: true } if path == &PathBuf::from("/var/log/app.log")
+        ));
+        assert!(CommandInterface::parse_input("tail -f").is_err());
+    }
+
+    #[test]
+    fn test_parse_get_byte_range() {
+        let command = CommandInterface::parse_input("get --offset -10M big.log tail.log").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::GetRange { ref remote_path, local_path: Some(_), range, .. }
+                if remote_path == &PathBuf::from("big.log")
+                    && range == ByteRange { offset: -10 << 20, length: None }
+        ));
+
+        let command = CommandInterface::parse_input("get --length 4K big.log").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::GetRange { range, .. }
+                if range == ByteRange { offset: 0, length: Some(4096) }
+        ));
+
+        assert!(CommandInterface::parse_input("get --offset 1K big.log -").is_err());
+        assert!(CommandInterface::parse_input("get -r --length 1K logs").is_err());
+        assert!(CommandInterface::parse_input("get --offset soon big.log").is_err());
+    }
+
+    #[test]
+    fn test_parse_sync_directions() {
+        let command = CommandInterface::parse_input("sync --up site /var/www").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Sync { direction: SyncDirection::Up, ref local_path, ref remote_path, .. }
+                if local_path == &PathBuf::from("site") && remote_path == &PathBuf::from("/var/www")
+        ));
+
+        let command = CommandInterface::parse_input("sync /var/www site --down").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Sync { direction: SyncDirection::Down, ref local_path, ref remote_path, .. }
+                if local_path == &PathBuf::from("site") && remote_path == &PathBuf::from("/var/www")
+        ));
+
+        let command = CommandInterface::parse_input("mirror --up --delete site /var/www").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Sync {
+                options: SyncOptions {
+                    delete: true,
+                    force: false,
+                    ..
+                },
+                ..
+            }
+        ));
+
+        let command = CommandInterface::parse_input("sync -c --down /var/www site").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Sync {
+                options: SyncOptions { checksum: true, .. },
+                ..
+            }
+        ));
+
+        assert!(CommandInterface::parse_input("sync --up --force site /var/www").is_err());
+        assert!(CommandInterface::parse_input("sync site /var/www").is_err());
+        assert!(CommandInterface::parse_input("sync --up site").is_err());
+    }
+
+    #[test]
+    fn test_parse_two_way_sync() {
+        let command = CommandInterface::parse_input(
+            "sync --both --conflict keep-both --report sync.json site /var/www",
+        )
+        .unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::SyncBoth { ref local_path, ref remote_path, ref options }
+                if local_path == &PathBuf::from("site")
+                    && remote_path == &PathBuf::from("/var/www")
+                    && options.conflict == ConflictPolicy::KeepBoth
+                    && options.report == Some(PathBuf::from("sync.json"))
+        ));
+
+        for (name, policy) in [
+            ("local-wins", ConflictPolicy::LocalWins),
+            ("remote-wins", ConflictPolicy::RemoteWins),
+            ("rename-both", ConflictPolicy::RenameBoth),
+        ] {
+            let line = format!("sync --both --conflict {} site /var/www", name);
+            assert!(matches!(
+                CommandInterface::parse_input(&line).unwrap(),
+                SftpCommand::SyncBoth { ref options, .. } if options.conflict == policy
+            ));
+        }
+
+        assert!(CommandInterface::parse_input("sync --both --delete site /var/www").is_err());
+        assert!(CommandInterface::parse_input("sync --both --conflict coin a b").is_err());
+        assert!(CommandInterface::parse_input("sync --up --conflict prompt a b").is_err());
+    }
+
+    #[test]
+    fn test_parse_watch() {
+        let command = CommandInterface::parse_input("watch --delete site /var/www").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Watch { ref local_path, ref remote_path, delete: true }
+                if local_path == &PathBuf::from("site") && remote_path == &PathBuf::from("/var/www")
+        ));
+        assert!(CommandInterface::parse_input("watch site").is_err());
+    }
+
+    #[test]
+    fn test_parse_get_into_archive() {
+        let command =
+            CommandInterface::parse_input("get -r --archive backup.tar.gz /var/www").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::GetArchive { ref remote_path, ref archive, .. }
+                if remote_path == &PathBuf::from("/var/www")
+                    && archive == &PathBuf::from("backup.tar.gz")
+        ));
+
+        assert!(CommandInterface::parse_input("get --archive out.tar www").is_err());
+        assert!(CommandInterface::parse_input("get -r --archive out.tar www local").is_err());
+        assert!(CommandInterface::parse_input("get -r www --archive").is_err());
+    }
+
+    #[test]
+    fn test_parse_cat_and_get_to_stdout() {
+        for input in ["cat app.conf", "get app.conf -"] {
+            let command = CommandInterface::parse_input(input).unwrap();
+            assert!(matches!(
+                command,
+                SftpCommand::Cat { ref path } if path == &PathBuf::from("app.conf")
+            ));
+        }
+        assert!(CommandInterface::parse_input("cat").is_err());
+        assert!(CommandInterface::parse_input("get -r logs -").is_err());
+        assert!(CommandInterface::parse_input("get a b -").is_err());
+    }
+
+    #[test]
+    fn test_parse_get_policy_override() {
+        let command =
+            CommandInterface::parse_input("get --timeout 30s --retries 3 bigfile").unwrap();
+        if let SftpCommand::Get {
+            remote_path,
+            local_path,
+            policy,
+        } = command
+        {
+            assert_eq!(remote_path, PathBuf::from("bigfile"));
+            assert_eq!(local_path, None);
+            assert_eq!(policy.timeout, Some(Duration::from_secs(30)));
+            assert_eq!(policy.retries, Some(3));
+        } else {
+            panic!("Expected Get command");
+        }
+
+        assert!(CommandInterface::parse_input("get --retries many bigfile").is_err());
+        assert!(CommandInterface::parse_input("get bigfile --timeout").is_err());
+    }
+
+    #[test]
+    fn test_parse_snapshot() {
+        let command = CommandInterface::parse_input("snapshot diff before.json /srv").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Snapshot {
+                action: SnapshotAction::Diff,
+                ref file,
+                path: Some(ref path),
+            } if file == &PathBuf::from("before.json") && path == &PathBuf::from("/srv")
+        ));
+        assert!(CommandInterface::parse_input("snapshot take x.json").is_err());
+    }
+
+    #[test]
+    fn test_parse_bench() {
+        assert!(matches!(
+            CommandInterface::parse_input("bench").unwrap(),
+            SftpCommand::Bench {
+                size: BENCH_DEFAULT_SIZE,
+                direction: BenchDirection::Both,
+            }
+        ));
+        assert!(matches!(
+            CommandInterface::parse_input("bench --size 8M --direction down").unwrap(),
+            SftpCommand::Bench {
+                size: 8388608,
+                direction: BenchDirection::Download,
+            }
+        ));
+        assert!(CommandInterface::parse_input("bench --size").is_err());
+        assert!(CommandInterface::parse_input("bench --direction sideways").is_err());
+    }
+
+    #[test]
+    fn test_parse_chmod_octal_mode() {
+        let command = CommandInterface::parse_input("chmod 0755 bin/run").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Chmod { mode: 0o755, ref path, recursive: false }
+                if path == &PathBuf::from("bin/run")
+        ));
+        assert!(matches!(
+            CommandInterface::parse_input("chmod -R 750 site").unwrap(),
+            SftpCommand::Chmod {
+                mode: 0o750,
+                recursive: true,
+                ..
+            }
+        ));
+        assert!(CommandInterface::parse_input("chmod 789 x").is_err());
+        assert!(CommandInterface::parse_input("chmod 17777 x").is_err());
+        assert!(CommandInterface::parse_input("chmod 644").is_err());
+    }
+
+    #[test]
+    fn test_parse_touch() {
+        let command = CommandInterface::parse_input("touch ready.flag").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Touch { ref path } if path == &PathBuf::from("ready.flag")
+        ));
+        assert!(CommandInterface::parse_input("touch").is_err());
+    }
+
+    #[test]
+    fn test_parse_ln_symbolic_and_hard() {
+        let command = CommandInterface::parse_input("ln -s ../shared/app.conf app.conf").unwrap();
+        assert!(matches!(
+            command,
+            SftpCommand::Symlink { ref target, ref link_path }
+                if target == "../shared/app.conf" && link_path == &PathBuf::from("app.conf")
+        ));
+        assert!(matches!(
+            CommandInterface::parse_input("ln a b").unwrap(),
+            SftpCommand::HardLink { .. }
+        ));
+        assert!(CommandInterface::parse_input("ln -s a").is_err());
+    }
+
+    #[test]
+    fn test_parse_rget_and_transfer() {
+        let SftpCommand::Transfer {
+            source,
+            destination,
+        } = CommandInterface::parse_input("rget session2:/srv/app.conf").unwrap()
+        else {
+            panic!("Expected Transfer command");
+        };
+        assert_eq!(source.session.as_deref(), Some("session2"));
+        assert_eq!(source.path, PathBuf::from("/srv/app.conf"));
+        assert_eq!(destination.session, None);
+        assert_eq!(destination.path, PathBuf::from("."));
+        assert!(CommandInterface::parse_input("rget /srv/app.conf .").is_err());
+
+        let SftpCommand::Transfer {
+            source,
+            destination,
+        } = CommandInterface::parse_input("transfer host1:/a backup.example:b/c").unwrap()
+        else {
+            panic!("Expected Transfer command");
+        };
+        assert_eq!(source.session.as_deref(), Some("host1"));
+        assert_eq!(destination.session.as_deref(), Some("backup.example"));
+        assert_eq!(destination.path, PathBuf::from("b/c"));
+        // A colon after a slash is part of the path
+        assert_eq!(SessionPath::parse("logs/a:b").session, None);
+    }
 }
@@ -0,0 +1,166 @@
+//! Completed-file records for tree transfers.
+//!
+//! `get -r` and `sync` given `--journal` append a line to the journal for
+//! every file that finishes. If the run is interrupted, running the same
+//! command again skips the files already listed instead of transferring
+//! the whole tree over again. The journal is removed once a run completes.
+
+use crate::sftp::error::SftpError;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub struct Journal {
+    path: PathBuf,
+    done: HashSet<String>,
+    // Shared by the workers of a parallel transfer
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// Opens the journal at `path` for `operation`, a description of the
+    /// transfer it belongs to. Files recorded by an earlier run of the same
+    /// operation count as done; a journal left by a different one is an
+    /// error rather than being silently discarded.
+    pub fn open(path: &Path, operation: &str) -> Result<Self, SftpError> {
+        let header = format!("operation {}\n", escape(operation));
+        let mut done = HashSet::new();
+        let mut kept = 0;
+        match std::fs::read_to_string(path) {
+            Ok(saved) if !saved.is_empty() => {
+                if !saved.starts_with(&header) {
+                    return Err(SftpError::ClientError(
+                        format!(
+                            "Journal {} was written for a different transfer",
+                            path.display()
+                        )
+                        .into(),
+                    ));
+                }
+                // A line cut short by a crash has no newline and is dropped
+                let complete = &saved[..saved.rfind('\n').map_or(0, |end| end + 1)];
+                kept = complete.len() as u64;
+                done = complete
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("done "))
+                    .map(unescape)
+                    .collect();
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        if kept == 0 {
+            file.set_len(0)?;
+            file.write_all(header.as_bytes())?;
+        } else {
+            file.set_len(kept)?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            done,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Number of files an earlier run finished.
+    pub fn completed(&self) -> usize {
+        self.done.len()
+    }
+
+    pub fn is_done(&self, entry: &str) -> bool {
+        self.done.contains(entry)
+    }
+
+    /// Records that `entry` has been transferred in full.
+    pub fn record(&self, entry: &str) -> Result<(), SftpError> {
+        let line = format!("done {}\n", escape(entry));
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| SftpError::ClientError("Journal lock poisoned".into()))?;
+        // One write per line, so a crash can only cut off the last one
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Removes the journal once every file has been transferred, so the
+    /// next run starts afresh.
+    pub fn finish(self) -> Result<(), SftpError> {
+        drop(self.file);
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Keeps every record on one line whatever characters its path holds
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                out.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                out.push('\\');
+                chars.next();
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rerun_skips_recorded_files() {
+        let path = std::env::temp_dir().join(format!("ferric_journal_{}", std::process::id()));
+        let operation = "get -r /srv/data -> /backup/data";
+
+        let journal = Journal::open(&path, operation).unwrap();
+        journal.record("/srv/data/a.bin").unwrap();
+        journal.record("/srv/data/odd\nname\\").unwrap();
+        drop(journal);
+        // A record torn off by a crash is not trusted
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"done /srv/data/b.b").unwrap();
+
+        let journal = Journal::open(&path, operation).unwrap();
+        journal.record("/srv/data/c.bin").unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&path, operation).unwrap();
+        assert_eq!(journal.completed(), 3);
+        assert!(journal.is_done("/srv/data/c.bin"));
+        assert!(journal.is_done("/srv/data/a.bin"));
+        assert!(journal.is_done("/srv/data/odd\nname\\"));
+        assert!(!journal.is_done("/srv/data/b.b"));
+        assert!(Journal::open(&path, "sync up a -> b").is_err());
+
+        journal.finish().unwrap();
+        assert!(!path.exists());
+        assert_eq!(Journal::open(&path, operation).unwrap().completed(), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
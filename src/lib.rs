@@ -1,2 +1,11 @@
+pub mod archive;
+pub mod checkpoint;
+pub mod checksum;
 pub mod filesystem;
+pub mod glob;
+pub mod ignore;
+pub mod interface;
+pub mod journal;
+pub mod script;
 pub mod sftp;
+pub mod terminal;
@@ -1,57 +1,267 @@
-use crate::sftp::constants::*;
-use crate::sftp::session::SftpSession;
-use crate::sftp::SftpClient;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
 use env_logger::Builder;
-use interface::CommandInterface;
-use log::{error, info, LevelFilter};
+use ferric_ftp::checkpoint::Checkpoint;
+use ferric_ftp::interface::{CommandInterface, LineEditor};
+use ferric_ftp::script;
+use ferric_ftp::sftp::constants::*;
+use ferric_ftp::sftp::error::SftpError;
+use ferric_ftp::sftp::process::ProcessTransport;
+use ferric_ftp::sftp::progress::{JsonProgress, TerminalProgress};
+use ferric_ftp::sftp::session::{SftpSession, TransportLayer};
+use ferric_ftp::sftp::types::{parse_size, SftpStatus};
+use ferric_ftp::sftp::{SftpClient, SftpCommand};
+use log::{error, info, warn, LevelFilter};
 use ssh2::Session;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::IsTerminal;
 use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::ExitCode;
 
-mod filesystem;
-mod interface;
-mod sftp;
+// Server the built-in SSH client connects to
+const HOST: &str = "test.rebex.net";
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Why is it so confusing to initialise a logger??
-    let mut builder = Builder::from_default_env();
-    builder
-        .default_format()
-        .filter(None, LevelFilter::Debug)
-        .target(env_logger::Target::Pipe(Box::new(
-            std::fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open("ferric_ftp.log")
-                .unwrap(),
-        )))
-        .init();
+// Process exit codes, so scripts wrapping batch runs can branch on the cause
+const EXIT_FAILURE: u8 = 1;
+const EXIT_CONNECTION: u8 = 3;
+const EXIT_AUTH: u8 = 4;
+const EXIT_NOT_FOUND: u8 = 5;
+const EXIT_PERMISSION: u8 = 6;
+const EXIT_PARTIAL_TRANSFER: u8 = 7;
 
-    //let tcp = TcpStream::connect("localhost:2222")?;
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    /// Emit newline-delimited JSON progress events on stderr
+    #[arg(long)]
+    progress_json: bool,
 
-    let tcp = TcpStream::connect("test.rebex.net:22")?;
+    /// Hide the progress bar drawn on stderr when it is a terminal
+    #[arg(long, conflicts_with = "progress_json")]
+    no_progress: bool,
 
-    let mut session = Session::new()?;
-    session.set_tcp_stream(tcp);
-    session.handshake()?;
-    session.userauth_password("demo", "password")?;
+    /// Run the commands in FILE instead of prompting
+    #[arg(short = 'b', long = "batch", value_name = "FILE")]
+    batch: Option<PathBuf>,
 
-    //session.userauth_password("sftptest", "pass")?;
+    /// Run the rhai script in FILE, which calls client operations such as
+    /// ls, get and put, instead of prompting
+    #[arg(long, value_name = "FILE", conflicts_with = "batch")]
+    script: Option<PathBuf>,
 
-    info!("SSH connection successful!");
+    /// Record batch progress in FILE after each command that succeeds
+    #[arg(long, value_name = "FILE", requires = "batch")]
+    checkpoint: Option<PathBuf>,
+
+    /// Skip the batch commands the checkpoint file records as done
+    #[arg(long, requires = "checkpoint")]
+    resume: bool,
+
+    /// Define a parameter usable as $NAME in commands
+    #[arg(short = 'D', long = "define", value_name = "NAME=VALUE", value_parser = parse_define)]
+    defines: Vec<(String, String)>,
+
+    /// Connect by running `ssh -s DESTINATION sftp`, so ssh handles
+    /// authentication and its own configuration
+    #[arg(long, value_name = "DESTINATION", conflicts_with = "proxy_command")]
+    ssh: Option<String>,
+
+    /// Run COMMAND through the shell and speak SFTP over its stdin/stdout
+    #[arg(long, value_name = "COMMAND")]
+    proxy_command: Option<String>,
+
+    /// Highest SFTP protocol version to offer the server (3-6)
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = SFTP_SUPPORTED_VERSION,
+        value_parser = clap::value_parser!(u32).range(SFTP_MIN_VERSION as i64..=SFTP_MAX_VERSION as i64)
+    )]
+    sftp_version: u32,
+
+    /// Longest packet to accept from the server, e.g. 256K or 1M
+    #[arg(
+        long,
+        value_name = "SIZE",
+        default_value_t = DEFAULT_MAX_PACKET_SIZE,
+        value_parser = parse_packet_size
+    )]
+    max_packet_size: u32,
+
+    /// Bytes per read or write request, e.g. 64K; adapts to the link if unset
+    #[arg(long, value_name = "SIZE", value_parser = parse_chunk_size)]
+    chunk_size: Option<u32>,
+}
+
+enum Failure {
+    Connection(Box<dyn std::error::Error>),
+    Auth(Box<dyn std::error::Error>),
+    Command(SftpError),
+}
+
+impl Failure {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Failure::Connection(_) => EXIT_CONNECTION,
+            Failure::Auth(_) => EXIT_AUTH,
+            Failure::Command(e) => command_exit_code(e),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Connection(e) => write!(f, "Connection failed: {}", e),
+            Failure::Auth(e) => write!(f, "Authentication failed: {}", e),
+            Failure::Command(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+fn command_exit_code(error: &SftpError) -> u8 {
+    match error {
+        SftpError::ServerError { code, .. } if *code == SftpStatus::NoSuchFile as u32 => {
+            EXIT_NOT_FOUND
+        }
+        SftpError::ServerError { code, .. } if *code == SftpStatus::PermissionDenied as u32 => {
+            EXIT_PERMISSION
+        }
+        SftpError::ServerError { code, .. }
+            if *code == SftpStatus::NoConnection as u32
+                || *code == SftpStatus::ConnectionLost as u32 =>
+        {
+            EXIT_CONNECTION
+        }
+        SftpError::PartialTransfer { .. } => EXIT_PARTIAL_TRANSFER,
+        _ => EXIT_FAILURE,
+    }
+}
+
+fn parse_packet_size(input: &str) -> Result<u32, String> {
+    let size = parse_size(input).map_err(|e| e.to_string())?;
+    u32::try_from(size).map_err(|_| format!("{} is too large for a packet", input))
+}
+
+// The upper bound depends on --max-packet-size, so main checks it once both
+// have been parsed
+fn parse_chunk_size(input: &str) -> Result<u32, String> {
+    match parse_size(input).map_err(|e| e.to_string())? {
+        0 => Err("chunk size must be at least 1 byte".to_string()),
+        size => u32::try_from(size).map_err(|_| format!("{} is too large for a chunk", input)),
+    }
+}
+
+fn parse_define(input: &str) -> Result<(String, String), String> {
+    input
+        .split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected NAME=VALUE, got '{}'", input))
+}
+
+// Parameters from -D take precedence over the environment
+fn parse_line<T: TransportLayer>(
+    client: &SftpClient<T>,
+    line: &str,
+    params: &HashMap<String, String>,
+) -> Result<SftpCommand, SftpError> {
+    if !client.settings.interpolate {
+        return CommandInterface::parse_input(line);
+    }
+
+    let line = CommandInterface::interpolate(line, |name| {
+        params
+            .get(name)
+            .cloned()
+            .or_else(|| std::env::var(name).ok())
+    })?;
+    CommandInterface::parse_input(&line)
+}
+
+fn run_batch<T: TransportLayer>(
+    client: &mut SftpClient<T>,
+    path: &PathBuf,
+    checkpoint: Option<&PathBuf>,
+    resume: bool,
+    params: &HashMap<String, String>,
+) -> Result<(), SftpError> {
+    let script = std::fs::read_to_string(path)?;
+    let mut checkpoint = checkpoint
+        .map(|file| Checkpoint::open(file, &script, resume))
+        .transpose()?;
+    let done = checkpoint.as_ref().map_or(0, Checkpoint::completed);
+
+    let commands = script
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+    for (step, line) in commands.enumerate() {
+        let cmd = parse_line(client, line, params)?;
+
+        // Directory and setting changes are cheap and later steps rely on
+        // them, so they are run again even when already done
+        if step < done && !cmd.changes_session() {
+            println!("sftp> {} (done, skipped)", line);
+            continue;
+        }
+        println!("sftp> {}", line);
 
-    let mut channel = session.channel_session()?;
-    channel.subsystem("sftp")?;
-    let mut sftp_client =
-        SftpClient::new(SftpSession::new(channel, SFTP_SUPPORTED_VERSION)?, None)?;
+        info!("Got command: {:?}", cmd);
+        let keep_going = client.execute_command(&cmd)?;
+        if let Some(checkpoint) = checkpoint.as_mut() {
+            checkpoint.record(done.max(step + 1))?;
+        }
+        if !keep_going {
+            break;
+        }
+    }
+
+    match checkpoint {
+        Some(checkpoint) => checkpoint.finish(),
+        None => Ok(()),
+    }
+}
 
+fn run_interactive<T: TransportLayer>(
+    client: &mut SftpClient<T>,
+    params: &HashMap<String, String>,
+) -> Result<(), SftpError> {
     CommandInterface::greet();
+    client.set_confirm(Some(Box::new(CommandInterface::confirm)));
 
+    // Ctrl-C while a command runs pauses its transfer, keeping what has
+    // arrived; a second one before it stops quits as usual
+    let pause = client.pause_handle();
+    let handler = pause.clone();
+    let installed = ctrlc::set_handler(move || {
+        if handler.is_paused() {
+            std::process::exit(130);
+        }
+        handler.pause();
+    });
+    if let Err(e) = installed {
+        warn!("Ctrl-C will not pause transfers: {}", e);
+    }
+
+    // Completion lists remote directories while a line is being typed, and
+    // the loop runs commands once it has been read, so the two never overlap
+    let client = RefCell::new(client);
+    let mut editor = LineEditor::new(|partial: &str| client.borrow_mut().complete_path(partial))?;
     loop {
-        match CommandInterface::parse_next_input() {
+        let Some(line) = editor.read_line()? else {
+            break;
+        };
+        let mut client = client.borrow_mut();
+
+        match parse_line(&client, &line, params) {
             Ok(ref cmd) => {
                 info!("Got command: {:?}", cmd);
 
-                match sftp_client.execute_command(cmd) {
+                pause.resume();
+                match client.execute_command(cmd) {
                     Ok(success) => {
                         if !success {
                             break;
@@ -70,3 +280,128 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     Ok(())
 }
+
+fn connect() -> Result<Session, Failure> {
+    //let tcp = TcpStream::connect("localhost:2222")?;
+
+    let tcp = TcpStream::connect((HOST, 22)).map_err(|e| Failure::Connection(e.into()))?;
+
+    let mut session = Session::new().map_err(|e| Failure::Connection(e.into()))?;
+    session.set_tcp_stream(tcp);
+    session
+        .handshake()
+        .map_err(|e| Failure::Connection(e.into()))?;
+    session
+        .userauth_password("demo", "password")
+        .map_err(|e| Failure::Auth(e.into()))?;
+
+    //session.userauth_password("sftptest", "pass")?;
+
+    info!("SSH connection successful!");
+    Ok(session)
+}
+
+fn run(cli: Cli) -> Result<(), Failure> {
+    if let Some(ref destination) = cli.ssh {
+        let transport = ProcessTransport::ssh(destination, cli.sftp_version)
+            .map_err(|e| Failure::Connection(e.into()))?;
+        let host = ProcessTransport::host(destination).to_string();
+        return run_client(transport, Some(&host), cli);
+    }
+    if let Some(ref command) = cli.proxy_command {
+        let transport = ProcessTransport::shell(command, cli.sftp_version)
+            .map_err(|e| Failure::Connection(e.into()))?;
+        return run_client(transport, None, cli);
+    }
+
+    let session = connect()?;
+
+    let mut channel = session
+        .channel_session()
+        .map_err(|e| Failure::Connection(e.into()))?;
+    channel
+        .subsystem("sftp")
+        .map_err(|e| Failure::Connection(e.into()))?;
+    let mut sftp_session = SftpSession::new(channel, cli.sftp_version).map_err(Failure::Command)?;
+    sftp_session.set_server_banner(session.banner());
+    sftp_session.set_ssh_session(session.clone());
+    run_client(sftp_session, Some(HOST), cli)
+}
+
+// `host` names the server in `host:path` arguments, when it is known
+fn run_client<T: TransportLayer + 'static>(
+    mut transport: T,
+    host: Option<&str>,
+    cli: Cli,
+) -> Result<(), Failure> {
+    transport.set_max_packet_size(cli.max_packet_size);
+    let mut sftp_client = SftpClient::new(transport, None).map_err(Failure::Command)?;
+    if let Some(host) = host {
+        sftp_client.set_host(host);
+    }
+    if cli.progress_json {
+        sftp_client.set_progress(Some(Box::new(JsonProgress::stderr())));
+    } else if !cli.no_progress && std::io::stderr().is_terminal() {
+        sftp_client.set_progress(Some(Box::new(TerminalProgress::stderr())));
+    }
+    if cli.chunk_size.is_some() {
+        sftp_client.set_chunk_size(cli.chunk_size);
+    }
+
+    let params: HashMap<String, String> = cli.defines.into_iter().collect();
+    if let Some(ref path) = cli.script {
+        let source = std::fs::read_to_string(path).map_err(|e| Failure::Command(e.into()))?;
+        return script::run(sftp_client, &source, &params).map_err(Failure::Command);
+    }
+    match cli.batch {
+        Some(ref path) => run_batch(
+            &mut sftp_client,
+            path,
+            cli.checkpoint.as_ref(),
+            cli.resume,
+            &params,
+        ),
+        None => run_interactive(&mut sftp_client, &params),
+    }
+    .map_err(Failure::Command)
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    // Larger chunks would make the server send DATA packets over the limit
+    let limit = max_chunk_size(cli.max_packet_size);
+    if cli.chunk_size.is_some_and(|size| size > limit) {
+        Cli::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!(
+                    "--chunk-size must be at most {} bytes so replies fit in --max-packet-size",
+                    limit
+                ),
+            )
+            .exit();
+    }
+
+    // Why is it so confusing to initialise a logger??
+    let mut builder = Builder::from_default_env();
+    builder
+        .default_format()
+        .filter(None, LevelFilter::Debug)
+        .target(env_logger::Target::Pipe(Box::new(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open("ferric_ftp.log")
+                .unwrap(),
+        )))
+        .init();
+
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            error!("{}", failure);
+            eprintln!("{}", failure);
+            ExitCode::from(failure.exit_code())
+        }
+    }
+}
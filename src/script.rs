@@ -0,0 +1,241 @@
+//! Automation scripts run with `--script`.
+//!
+//! Scripts are written in rhai, so they can loop and branch over what the
+//! server returns, e.g. `for f in ls("/out") { get("/out/" + f) }`. Remote
+//! paths are resolved against the working directory, as in the REPL, and
+//! parameters defined with `-D NAME=VALUE` are constants named `NAME`.
+//!
+//! The functions available are `ls([path])`, `stat(path)`, `exists(path)`,
+//! `pwd()`, `cd(path)`, `get(remote[, local])`, `put(local[, remote])`,
+//! `mkdir(path)`, `rmdir(path)`, `rm(path)`, `rename(old, new)` and
+//! `run(command)`, which runs any REPL command line. A failing call stops
+//! the script unless it is caught with `try`/`catch`.
+
+use crate::interface::CommandInterface;
+use crate::sftp::error::SftpError;
+use crate::sftp::session::TransportLayer;
+use crate::sftp::types::{PolicyOverride, SftpStatus};
+use crate::sftp::{SftpClient, SftpCommand};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+type Shared<T> = Rc<RefCell<SftpClient<T>>>;
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// Runs the script `source` against `client`.
+///
+/// When the script stops on an error from the client, that error is
+/// returned, so a batch run and a script fail with the same exit codes.
+pub fn run<T: TransportLayer + 'static>(
+    client: SftpClient<T>,
+    source: &str,
+    params: &HashMap<String, String>,
+) -> Result<(), SftpError> {
+    let client = Rc::new(RefCell::new(client));
+    // The client error behind the most recent failed call
+    let failure = Rc::new(RefCell::new(None));
+    let engine = engine(&client, &failure);
+
+    let mut scope = Scope::new();
+    for (name, value) in params {
+        scope.push_constant(name.as_str(), value.clone());
+    }
+
+    engine
+        .run_with_scope(&mut scope, source)
+        .map_err(|e| match failure.take() {
+            Some(failure) => failure,
+            None => SftpError::ClientError(format!("Script failed: {}", e).into()),
+        })
+}
+
+fn engine<T: TransportLayer + 'static>(
+    client: &Shared<T>,
+    failure: &Rc<RefCell<Option<SftpError>>>,
+) -> Engine {
+    let mut engine = Engine::new();
+
+    // Runs `f` against the client, recording any error so `run` can return
+    // it in place of the script error it becomes. Each call clears the last
+    // one, which the script may have caught and moved on from
+    let call = {
+        let client = client.clone();
+        let failure = failure.clone();
+        move |f: &dyn Fn(&mut SftpClient<T>) -> Result<Dynamic, SftpError>| -> ScriptResult<Dynamic> {
+            failure.take();
+            f(&mut client.borrow_mut()).map_err(|e| {
+                let message = e.to_string();
+                *failure.borrow_mut() = Some(e);
+                message.into()
+            })
+        }
+    };
+    let execute = {
+        let call = call.clone();
+        move |command: SftpCommand| {
+            call(&|client| client.execute_command(&command).map(|_| Dynamic::UNIT))
+        }
+    };
+
+    let ls = {
+        let call = call.clone();
+        move |path: &str| {
+            call(&|client| {
+                let names: Array = client
+                    .read_dir(Path::new(path))?
+                    .into_iter()
+                    .map(|file| Dynamic::from(file.name))
+                    .collect();
+                Ok(names.into())
+            })
+        }
+    };
+    engine.register_fn("ls", {
+        let ls = ls.clone();
+        move || ls(".")
+    });
+    engine.register_fn("ls", ls);
+
+    engine.register_fn("stat", {
+        let call = call.clone();
+        move |path: &str| {
+            call(&|client| {
+                let attrs = client.stat(Path::new(path))?;
+                let mut map = Map::new();
+                map.insert("size".into(), (attrs.size.unwrap_or(0) as i64).into());
+                map.insert(
+                    "mtime".into(),
+                    attrs
+                        .modify_time
+                        .map_or(Dynamic::UNIT, |t| (t as i64).into()),
+                );
+                map.insert("dir".into(), attrs.is_directory.into());
+                map.insert("file".into(), attrs.is_regular_file.into());
+                Ok(map.into())
+            })
+        }
+    });
+
+    engine.register_fn("exists", {
+        let call = call.clone();
+        move |path: &str| {
+            call(&|client| match client.stat(Path::new(path)) {
+                Ok(_) => Ok(true.into()),
+                Err(SftpError::ServerError { code, .. })
+                    if code == SftpStatus::NoSuchFile as u32 =>
+                {
+                    Ok(false.into())
+                }
+                Err(e) => Err(e),
+            })
+        }
+    });
+
+    engine.register_fn("pwd", {
+        let client = client.clone();
+        move || {
+            client
+                .borrow()
+                .resolve_path(Path::new("."))
+                .to_string_lossy()
+                .into_owned()
+        }
+    });
+
+    engine.register_fn("cd", {
+        let execute = execute.clone();
+        move |path: &str| {
+            execute(SftpCommand::Cd {
+                path: Some(PathBuf::from(path)),
+            })
+        }
+    });
+
+    let get = {
+        let execute = execute.clone();
+        move |remote: &str, local: Option<&str>| {
+            execute(SftpCommand::Get {
+                remote_path: PathBuf::from(remote),
+                local_path: local.map(PathBuf::from),
+                policy: PolicyOverride::default(),
+            })
+        }
+    };
+    engine.register_fn("get", {
+        let get = get.clone();
+        move |remote: &str| get(remote, None)
+    });
+    engine.register_fn("get", move |remote: &str, local: &str| {
+        get(remote, Some(local))
+    });
+
+    let put = {
+        let execute = execute.clone();
+        move |local: &str, remote: Option<&str>| {
+            execute(SftpCommand::Put {
+                local_path: PathBuf::from(local),
+                remote_path: remote.map(PathBuf::from),
+                exclusive: false,
+                policy: PolicyOverride::default(),
+            })
+        }
+    };
+    engine.register_fn("put", {
+        let put = put.clone();
+        move |local: &str| put(local, None)
+    });
+    engine.register_fn("put", move |local: &str, remote: &str| {
+        put(local, Some(remote))
+    });
+
+    engine.register_fn("mkdir", {
+        let execute = execute.clone();
+        move |path: &str| {
+            execute(SftpCommand::MkDir {
+                path: PathBuf::from(path),
+            })
+        }
+    });
+
+    engine.register_fn("rmdir", {
+        let execute = execute.clone();
+        move |path: &str| {
+            execute(SftpCommand::RmDir {
+                path: PathBuf::from(path),
+            })
+        }
+    });
+
+    engine.register_fn("rm", {
+        let execute = execute.clone();
+        move |path: &str| {
+            execute(SftpCommand::Rm {
+                path: PathBuf::from(path),
+                recursive: false,
+            })
+        }
+    });
+
+    engine.register_fn("rename", {
+        let execute = execute.clone();
+        move |old: &str, new: &str| {
+            execute(SftpCommand::Rename {
+                old_path: PathBuf::from(old),
+                new_path: PathBuf::from(new),
+                overwrite: false,
+            })
+        }
+    });
+
+    engine.register_fn("run", move |line: &str| {
+        call(&|client| {
+            let command = CommandInterface::parse_input(line)?;
+            client.execute_command(&command).map(|_| Dynamic::UNIT)
+        })
+    });
+
+    engine
+}
@@ -0,0 +1,308 @@
+use super::constants::SFTP_MIN_VERSION;
+use super::error::SftpError;
+use super::packet::{ClientPacket, ServerPacket, SftpPacketInfo, SftpWriter};
+use super::session::TransportLayer;
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+/// A fault applied to one response from the wrapped transport.
+pub enum Fault {
+    /// Swallow the response, as if it never arrived.
+    Drop,
+    /// Deliver only the first `keep` bytes of the encoded packet.
+    Truncate(usize),
+    /// Deliver an unrelated packet before the real response.
+    Interleave(ServerPacket),
+}
+
+/// Failure-injection wrapper around another transport.
+///
+/// Faults are keyed by the index of the `receive_packet` call they apply to
+/// (starting at 0), and an optional fixed latency is added to every receive.
+/// Truncated packets are re-encoded and decoded through `ServerPacket`, so they
+/// exercise the real parser rather than a canned error.
+pub struct ChaosTransport<T: TransportLayer> {
+    inner: T,
+    latency: Option<Duration>,
+    faults: HashMap<usize, Fault>,
+    pending: Option<ServerPacket>,
+    receive_count: usize,
+}
+
+impl<T: TransportLayer> ChaosTransport<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            latency: None,
+            faults: HashMap::new(),
+            pending: None,
+            receive_count: 0,
+        }
+    }
+
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    pub fn inject(mut self, receive_index: usize, fault: Fault) -> Self {
+        self.faults.insert(receive_index, fault);
+        self
+    }
+}
+
+impl<T: TransportLayer> TransportLayer for ChaosTransport<T> {
+    fn send_packet(&mut self, packet: ClientPacket) -> Result<(), SftpError> {
+        self.inner.send_packet(packet)
+    }
+
+    fn receive_packet(&mut self) -> Result<ServerPacket, SftpError> {
+        if let Some(latency) = self.latency {
+            thread::sleep(latency);
+        }
+
+        // Deliver the real response held back by an earlier Interleave
+        if let Some(packet) = self.pending.take() {
+            return Ok(packet);
+        }
+
+        let index = self.receive_count;
+        self.receive_count += 1;
+
+        match self.faults.remove(&index) {
+            None => self.inner.receive_packet(),
+            Some(Fault::Drop) => {
+                self.inner.receive_packet()?;
+                Err(SftpError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "Response dropped",
+                )))
+            }
+            Some(Fault::Truncate(keep)) => {
+                let bytes = encode_server_packet(&self.inner.receive_packet()?);
+                ServerPacket::from_bytes(&bytes[..keep.min(bytes.len())])
+            }
+            Some(Fault::Interleave(stray)) => {
+                self.pending = Some(self.inner.receive_packet()?);
+                Ok(stray)
+            }
+        }
+    }
+
+    fn next_request_id(&mut self) -> u32 {
+        self.inner.next_request_id()
+    }
+
+    fn version(&self) -> u32 {
+        self.inner.version()
+    }
+
+    fn extensions(&self) -> &[(String, String)] {
+        self.inner.extensions()
+    }
+
+    fn server_banner(&self) -> Option<&str> {
+        self.inner.server_banner()
+    }
+}
+
+/// Encodes a server packet in wire format, the inverse of `from_bytes`.
+pub fn encode_server_packet(packet: &ServerPacket) -> Vec<u8> {
+    let mut payload = vec![packet.packet_type()];
+
+    match packet {
+        ServerPacket::Version {
+            version,
+            extensions,
+        } => {
+            payload.write_u32(*version);
+            for (name, data) in extensions {
+                payload.write_string(name);
+                payload.write_string(data);
+            }
+        }
+        ServerPacket::Handle { request_id, handle } => {
+            payload.write_u32(*request_id);
+            payload.write_bytes(handle);
+        }
+        ServerPacket::Name { request_id, files } => {
+            payload.write_u32(*request_id);
+            payload.write_u32(files.len() as u32);
+            for file in files {
+                payload.write_string(&file.name);
+                payload.write_string(&file.display_name);
+                payload.write_attrs(&file.attrs, SFTP_MIN_VERSION);
+            }
+        }
+        ServerPacket::Status {
+            request_id,
+            status_code,
+            message,
+        } => {
+            payload.write_u32(*request_id);
+            payload.write_u32(*status_code);
+            payload.write_string(message);
+            payload.write_bytes(b"");
+        }
+        ServerPacket::Attrs { request_id, attrs } => {
+            payload.write_u32(*request_id);
+            payload.write_attrs(attrs, SFTP_MIN_VERSION);
+        }
+        ServerPacket::Data { request_id, data } => {
+            payload.write_u32(*request_id);
+            payload.write_bytes(data);
+        }
+        ServerPacket::ExtendedReply { request_id, data } => {
+            payload.write_u32(*request_id);
+            payload.extend_from_slice(data);
+        }
+        ServerPacket::Unknown { .. } => {}
+    }
+
+    let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+    bytes.extend(payload);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sftp::mock::MockTransport;
+    use crate::sftp::protocol::SftpProtocol;
+    use crate::sftp::types::{FileAttributes, FileInfo, SftpStatus};
+    use std::time::Instant;
+
+    fn realpath_exchange(request_id: u32, path: &str) -> MockTransport {
+        MockTransport::new()
+            .expect_request(ClientPacket::RealPath {
+                request_id,
+                path: path.to_string(),
+            })
+            .respond_with(ServerPacket::Name {
+                request_id,
+                files: vec![FileInfo {
+                    name: path.to_string(),
+                    display_name: path.to_string(),
+                    attrs: FileAttributes::default(),
+                }],
+            })
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let bytes = encode_server_packet(&ServerPacket::Status {
+            request_id: 9,
+            status_code: SftpStatus::Eof as u32,
+            message: "End of file".to_string(),
+        });
+
+        match ServerPacket::from_bytes(&bytes).unwrap() {
+            ServerPacket::Status {
+                request_id,
+                status_code,
+                message,
+            } => {
+                assert_eq!(request_id, 9);
+                assert_eq!(status_code, SftpStatus::Eof as u32);
+                assert_eq!(message, "End of file");
+            }
+            other => panic!("Expected Status packet, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_latency_does_not_change_result() {
+        let transport = ChaosTransport::new(realpath_exchange(0, "/home"))
+            .with_latency(Duration::from_millis(20));
+        let mut protocol = SftpProtocol::new(transport);
+
+        let started = Instant::now();
+        assert_eq!(protocol.realpath("/home").unwrap(), "/home");
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_dropped_response_is_an_error() {
+        let transport = ChaosTransport::new(realpath_exchange(0, "/home")).inject(0, Fault::Drop);
+        let mut protocol = SftpProtocol::new(transport);
+
+        assert!(matches!(
+            protocol.realpath("/home"),
+            Err(SftpError::IoError(e)) if e.kind() == std::io::ErrorKind::TimedOut
+        ));
+    }
+
+    #[test]
+    fn test_truncated_packet_does_not_panic() {
+        let full_len = encode_server_packet(&ServerPacket::Name {
+            request_id: 0,
+            files: vec![FileInfo {
+                name: "/home".to_string(),
+                display_name: "/home".to_string(),
+                attrs: FileAttributes::default(),
+            }],
+        })
+        .len();
+
+        for keep in 0..full_len {
+            let transport =
+                ChaosTransport::new(realpath_exchange(0, "/home")).inject(0, Fault::Truncate(keep));
+            let mut protocol = SftpProtocol::new(transport);
+
+            assert!(protocol.realpath("/home").is_err(), "kept {} bytes", keep);
+        }
+    }
+
+    #[test]
+    fn test_interleaved_response_is_rejected() {
+        let transport = ChaosTransport::new(realpath_exchange(0, "/home")).inject(
+            0,
+            Fault::Interleave(ServerPacket::Handle {
+                request_id: 41,
+                handle: vec![1],
+            }),
+        );
+        let mut protocol = SftpProtocol::new(transport);
+
+        assert!(matches!(
+            protocol.realpath("/home"),
+            Err(SftpError::UnexpectedResponse(_))
+        ));
+    }
+
+    #[test]
+    fn test_reply_to_another_request_is_rejected_then_skipped() {
+        let late_reply = ServerPacket::Name {
+            request_id: 41,
+            files: vec![FileInfo {
+                name: "/elsewhere".to_string(),
+                display_name: "/elsewhere".to_string(),
+                attrs: FileAttributes::default(),
+            }],
+        };
+        let mock = realpath_exchange(0, "/home")
+            .expect_request(ClientPacket::RealPath {
+                request_id: 1,
+                path: "/tmp".to_string(),
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 1,
+                files: vec![FileInfo {
+                    name: "/tmp".to_string(),
+                    display_name: "/tmp".to_string(),
+                    attrs: FileAttributes::default(),
+                }],
+            });
+        let transport = ChaosTransport::new(mock).inject(0, Fault::Interleave(late_reply));
+        let mut protocol = SftpProtocol::new(transport);
+
+        assert!(matches!(
+            protocol.realpath("/home"),
+            Err(SftpError::UnexpectedResponse(_))
+        ));
+        // The real answer to the first request is discarded, not taken as
+        // the answer to the next one
+        assert_eq!(protocol.realpath("/tmp").unwrap(), "/tmp");
+    }
+}
@@ -1,18 +1,68 @@
 use super::constants::*;
 use super::error::SftpError;
+use super::grep::GrepWriter;
+use super::pipeline::PauseHandle;
+use super::process::ProcessTransport;
+use super::progress::{
+    format_bytes, AggregateProgress, Meter, ProgressObserver, ProgressReader, ProgressWriter,
+};
 use super::protocol::SftpProtocol;
+use super::remote_path;
 use super::session::TransportLayer;
-use super::types::{DirectoryCache, FileInfo, SftpCommand};
-use crate::filesystem;
-use log::info;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use super::snapshot::{Snapshot, SnapshotEntry};
+use super::sync::{self, Outcome, Resolution, SyncAction, SyncEntry, SyncReport, TwoWayAction};
+use super::types::{
+    BenchDirection, ByteRange, ConflictPolicy, DirectoryCache, FileAttributes, FileInfo,
+    ListingOrder, Overwrite, PolicyOverride, QueueAction, SessionPath, SessionStats, Settings,
+    SftpCommand, SftpStatus, SnapshotAction, SortKey, SyncDirection, SyncOptions, TransferQueue,
+};
+use crate::archive::{self, ArchiveWriter};
+use crate::checksum::{self, Digest, Sha256};
+use crate::filesystem::{self, LocalEntry};
+use crate::glob;
+use crate::ignore::{IgnoreRules, IGNORE_FILE_NAME};
+use crate::journal::Journal;
+use crate::terminal;
+use chrono::{DateTime, Local};
+use log::{info, warn};
+use notify::{EventKind, RecursiveMode, Watcher};
+use regex::bytes::Regex;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Answers a yes/no question put to the user.
+pub type Confirm = Box<dyn FnMut(&str) -> bool + Send>;
+
+/// A session opened alongside the first one, whatever its transport.
+pub type PeerClient = SftpClient<Box<dyn TransportLayer>>;
+
+// A session opened with `connect`, named `session2` and up; the client's
+// own is `session1`
+struct Peer {
+    name: String,
+    host: String,
+    client: PeerClient,
+}
 
 pub struct SftpClient<T: TransportLayer> {
     protocol: SftpProtocol<T>,
     pub working_dir: PathBuf,
     pub directory_cache: HashMap<PathBuf, DirectoryCache>,
-    pub current_listing: Vec<FileInfo>,
+    pub current_listing: Arc<Vec<FileInfo>>,
+    pub settings: Settings,
+    progress: Option<Box<dyn ProgressObserver>>,
+    confirm: Option<Confirm>,
+    pub queue: TransferQueue,
+    host: Option<String>,
+    sessions: Vec<Peer>,
+    next_session: u32,
     //pub handles: HashMap<String, Vec<u8>>,
 }
 
@@ -20,69 +70,171 @@ impl<T: TransportLayer> SftpClient<T> {
     pub fn new(transport: T, initial_path: Option<&str>) -> Result<Self, SftpError> {
         let mut protocol = SftpProtocol::new(transport);
         let working_dir = PathBuf::from(protocol.realpath(initial_path.unwrap_or("/"))?);
+        // Limits only guide transfer sizes, so a server that fails to report
+        // them is still usable
+        if let Err(e) = protocol.load_limits() {
+            warn!("Could not read server limits: {}", e);
+        }
 
         Ok(Self {
             protocol,
             working_dir,
             directory_cache: HashMap::new(),
-            current_listing: Vec::new(),
+            current_listing: Arc::new(Vec::new()),
+            settings: Settings::default(),
+            progress: None,
+            confirm: None,
+            queue: TransferQueue::default(),
+            host: None,
+            sessions: Vec::new(),
+            next_session: 2,
             //handles: HashMap::new(),
         })
     }
 
-    pub fn resolve_path(&self, path: &PathBuf) -> PathBuf {
-        if path.is_absolute() {
-            return path.clone();
-        }
+    /// Reports the progress of every transfer to `progress`.
+    pub fn set_progress(&mut self, progress: Option<Box<dyn ProgressObserver>>) {
+        self.progress = progress;
+    }
+
+    /// Asks yes/no questions through `confirm`, as the REPL does. Without
+    /// it the `prompt` overwrite policy skips files that already exist.
+    pub fn set_confirm(&mut self, confirm: Option<Confirm>) {
+        self.confirm = confirm;
+    }
+
+    pub fn stats(&self) -> &SessionStats {
+        self.protocol.stats()
+    }
+
+    /// Handle for pausing the transfer in progress, e.g. from a Ctrl-C
+    /// handler. A paused download or upload stops with its place saved, and
+    /// a queued one is held at that offset until it is resumed.
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.protocol.pause_handle()
+    }
+
+    /// Names the server this session is connected to, so that transfers
+    /// can refer to it as `host:path` as well as `session1:path`.
+    pub fn set_host(&mut self, host: &str) {
+        self.host = Some(host.to_string());
+    }
 
-        let path_str = path.to_string_lossy();
+    /// Keeps `client`, connected to `host`, open alongside this session for
+    /// transfers between the two, and returns the name it was given. Ctrl-C
+    /// pauses its transfers along with this session's.
+    pub fn add_session(&mut self, host: &str, mut client: PeerClient) -> String {
+        let name = format!("session{}", self.next_session);
+        self.next_session += 1;
+        client.protocol.set_pause_handle(self.pause_handle());
+        self.sessions.push(Peer {
+            name: name.clone(),
+            host: host.to_string(),
+            client,
+        });
+        name
+    }
 
-        match path_str.as_ref() {
-            "." => self.working_dir.clone(),
-            ".." => self.get_parent_directory(),
-            _ => self.working_dir.join(path),
+    /// Fixes the bytes per READ/WRITE request, or with `None` lets downloads
+    /// adapt it to the link.
+    pub fn set_chunk_size(&mut self, chunk_size: Option<u32>) {
+        self.settings.chunk_size = chunk_size;
+        match chunk_size {
+            Some(chunk_size) => self.protocol.set_chunk_size(chunk_size),
+            None => self.protocol.set_adaptive_chunk_size(),
         }
     }
 
-    fn get_parent_directory(&self) -> PathBuf {
-        let components: Vec<String> = self
-            .working_dir
-            .to_string_lossy()
-            .split('/')
-            .filter(|s| !s.is_empty())
-            .map(|s| s.to_string())
+    /// Resolves `path` against the working directory. Remote paths are
+    /// joined with `/` on every platform, never with `std::path` rules.
+    pub fn resolve_path(&self, path: &Path) -> PathBuf {
+        PathBuf::from(remote_path::join(
+            &self.working_dir.to_string_lossy(),
+            &path.to_string_lossy(),
+        ))
+    }
+
+    /// Entries of the remote directory `path`, without `.` and `..`.
+    pub fn read_dir(&mut self, path: &Path) -> Result<Vec<FileInfo>, SftpError> {
+        let target_path = self.resolve_path(path);
+        let handle = self.protocol.open_dir(&target_path.to_string_lossy())?;
+        let files = self.read_entire_directory(&handle, |_| {});
+        self.protocol.close(handle)?;
+        Ok(files?
+            .into_iter()
+            .filter(|file| file.name != "." && file.name != "..")
+            .collect())
+    }
+
+    /// Attributes of the remote file `path`, following symbolic links.
+    pub fn stat(&mut self, path: &Path) -> Result<FileAttributes, SftpError> {
+        let target_path = self.resolve_path(path);
+        self.protocol.stat(&target_path.to_string_lossy())
+    }
+
+    /// Remote paths `partial` could be completed to, for tab completion: the
+    /// entries of its directory whose names start with its last component,
+    /// with `/` after directories. Names are compared ignoring case under
+    /// `set matching case-insensitive`.
+    pub fn complete_path(&mut self, partial: &str) -> Vec<String> {
+        let (dir, prefix) = match partial.rfind('/') {
+            Some(slash) => partial.split_at(slash + 1),
+            None => ("", partial),
+        };
+        let Ok(files) = self.read_dir(Path::new(dir)) else {
+            return Vec::new();
+        };
+
+        let ignore_case = self.settings.case_insensitive;
+        let starts_with = |name: &str| match ignore_case {
+            true => name.to_lowercase().starts_with(&prefix.to_lowercase()),
+            false => name.starts_with(prefix),
+        };
+        let mut candidates: Vec<String> = files
+            .into_iter()
+            .filter(|file| starts_with(&file.name))
+            .map(|file| match file.attrs.is_directory {
+                true => format!("{}{}/", dir, file.name),
+                false => format!("{}{}", dir, file.name),
+            })
             .collect();
+        candidates.sort();
+        candidates
+    }
 
-        if components.is_empty() {
-            PathBuf::from("/")
-        } else {
-            let mut new_components = components;
-            new_components.pop();
-            if new_components.is_empty() {
-                PathBuf::from("/")
-            } else {
-                PathBuf::from(format!("/{}", new_components.join("/")))
-            }
+    fn display_current_listing(&self, order: &ListingOrder) {
+        let mut files: Vec<&FileInfo> = self.current_listing.iter().collect();
+        order.sort(&mut files);
+        for file in files {
+            println!("{}", terminal::escape(&file.display_name));
         }
     }
 
-    fn display_current_listing(&self) {
-        for file in self.current_listing.clone() {
-            println!("{}", file.display_name);
+    fn display_files(files: &[FileInfo]) {
+        for file in files {
+            println!("{}", terminal::escape(&file.display_name));
         }
     }
 
     pub fn execute_command(&mut self, cmd: &SftpCommand) -> Result<bool, SftpError> {
         info!("Executing command: {:?}", cmd);
         match cmd {
-            SftpCommand::Ls { path } => {
-                self.list_directory(path.as_ref())?;
+            SftpCommand::Ls { path, order } => {
+                self.list_directory(path.as_ref(), order)?;
+                Ok(true)
+            }
+            SftpCommand::Tree { path, depth } => {
+                self.show_tree(path.as_ref(), *depth)?;
                 Ok(true)
             }
             SftpCommand::Cd { path } => {
                 self.change_directory(path.as_ref())?;
                 Ok(true)
             }
+            SftpCommand::Set { key, value } => {
+                self.change_setting(key.as_deref(), value.as_deref())?;
+                Ok(true)
+            }
             SftpCommand::Pwd => {
                 self.print_working_directory()?;
                 Ok(true)
@@ -90,15 +242,294 @@ impl<T: TransportLayer> SftpClient<T> {
             SftpCommand::Get {
                 remote_path,
                 local_path,
+                policy,
+            } => {
+                self.get_with_retries(remote_path, local_path.as_ref(), 0, policy)?;
+                Ok(true)
+            }
+            SftpCommand::Reget {
+                remote_path,
+                local_path,
+                policy,
+            } => {
+                self.reget_file(remote_path, local_path.as_ref(), policy)?;
+                Ok(true)
+            }
+            SftpCommand::GetMany {
+                remote_paths,
+                local_dir,
+                policy,
+            } => {
+                self.get_files(remote_paths, local_dir, policy)?;
+                Ok(true)
+            }
+            SftpCommand::GetRecursive {
+                remote_path,
+                local_path,
+                ignore_file,
+                journal,
+                policy,
             } => {
-                self.get_file(remote_path, local_path.as_ref())?;
+                self.get_tree(
+                    remote_path,
+                    local_path.as_ref(),
+                    ignore_file.as_deref(),
+                    journal.as_deref(),
+                    policy,
+                )?;
+                Ok(true)
+            }
+            SftpCommand::GetArchive {
+                remote_path,
+                archive,
+                ignore_file,
+                policy,
+            } => {
+                self.get_archive(remote_path, archive, ignore_file.as_deref(), policy)?;
+                Ok(true)
+            }
+            SftpCommand::GetRange {
+                remote_path,
+                local_path,
+                range,
+                policy,
+            } => {
+                self.get_range(remote_path, local_path.as_ref(), range, policy)?;
+                Ok(true)
+            }
+            SftpCommand::Mget {
+                pattern,
+                local_dir,
+                policy,
+            } => {
+                self.get_matching(pattern, local_dir.as_ref(), policy)?;
                 Ok(true)
             }
             SftpCommand::Put {
                 local_path,
                 remote_path,
+                exclusive,
+                policy,
+            } => {
+                let mode = match exclusive {
+                    true => PutMode::Exclusive,
+                    false => PutMode::Replace,
+                };
+                self.put_file(local_path, remote_path.as_ref(), mode, policy)?;
+                Ok(true)
+            }
+            SftpCommand::PutMany {
+                local_paths,
+                remote_dir,
+                exclusive,
+                policy,
+            } => {
+                let mode = match exclusive {
+                    true => PutMode::Exclusive,
+                    false => PutMode::Replace,
+                };
+                self.put_files(local_paths, remote_dir, mode, policy)?;
+                Ok(true)
+            }
+            SftpCommand::Reput {
+                local_path,
+                remote_path,
+            } => {
+                self.put_file(
+                    local_path,
+                    remote_path.as_ref(),
+                    PutMode::Resume,
+                    &PolicyOverride::default(),
+                )?;
+                Ok(true)
+            }
+            SftpCommand::Mput { patterns, policy } => {
+                self.put_matching(patterns, policy)?;
+                Ok(true)
+            }
+            SftpCommand::Verify {
+                local_path,
+                remote_path,
+                checksum,
+            } => {
+                self.verify(local_path, remote_path, *checksum)?;
+                Ok(true)
+            }
+            SftpCommand::Sync {
+                direction,
+                local_path,
+                remote_path,
+                options,
+            } => {
+                self.sync(*direction, local_path, remote_path, options)?;
+                Ok(true)
+            }
+            SftpCommand::SyncBoth {
+                local_path,
+                remote_path,
+                options,
+            } => {
+                self.sync_both(local_path, remote_path, options)?;
+                Ok(true)
+            }
+            SftpCommand::Watch {
+                local_path,
+                remote_path,
+                delete,
+            } => {
+                self.watch(local_path, remote_path, *delete)?;
+                Ok(true)
+            }
+            SftpCommand::Queue(action) => {
+                self.handle_queue(action)?;
+                Ok(true)
+            }
+            SftpCommand::Snapshot { action, file, path } => {
+                let root = path.as_ref().map(|path| self.resolve_path(path));
+                match action {
+                    SnapshotAction::Save => self.save_snapshot(file, root)?,
+                    SnapshotAction::Diff => self.diff_snapshot(file, root)?,
+                }
+                Ok(true)
+            }
+            SftpCommand::Grep { pattern, path } => {
+                self.grep(pattern, path)?;
+                Ok(true)
+            }
+            SftpCommand::Cat { path } => {
+                let target = self.resolve_path(path);
+                let target = target
+                    .to_str()
+                    .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+                self.cat(target, &mut std::io::stdout().lock())?;
+                Ok(true)
+            }
+            SftpCommand::Tail { path, follow } => {
+                let target = self.resolve_path(path);
+                let target = target
+                    .to_str()
+                    .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+                self.tail(target, *follow, &mut std::io::stdout())?;
+                Ok(true)
+            }
+            SftpCommand::Open { path } => {
+                self.open_locally(path)?;
+                Ok(true)
+            }
+            SftpCommand::Bench { size, direction } => {
+                self.bench(*size, *direction)?;
+                Ok(true)
+            }
+            SftpCommand::Checksum { path } => {
+                self.print_checksum(path)?;
+                Ok(true)
+            }
+            SftpCommand::Copy {
+                source,
+                destination,
+            } => {
+                self.copy(source, destination)?;
+                Ok(true)
+            }
+            SftpCommand::Transfer {
+                source,
+                destination,
+            } => {
+                self.transfer(source, destination)?;
+                Ok(true)
+            }
+            SftpCommand::Connect { destination } => {
+                self.connect(destination)?;
+                Ok(true)
+            }
+            SftpCommand::Close { session } => {
+                match self.find_session(session)? {
+                    Some(index) => drop(self.sessions.remove(index)),
+                    None => return Err(SftpError::InvalidCommand("Cannot close session1")),
+                }
+                Ok(true)
+            }
+            SftpCommand::Sessions => {
+                println!("session1  {}", self.host.as_deref().unwrap_or("-"));
+                for peer in &self.sessions {
+                    println!("{}  {}", peer.name, peer.host);
+                }
+                Ok(true)
+            }
+            SftpCommand::MkDir { path } => {
+                self.make_dir(path)?;
+                Ok(true)
+            }
+            SftpCommand::RmDir { path } => {
+                self.remove_dir(path)?;
+                Ok(true)
+            }
+            SftpCommand::Rm { path, recursive } => {
+                self.remove(path, *recursive)?;
+                Ok(true)
+            }
+            SftpCommand::Df { path, human } => {
+                self.disk_free(path.as_deref(), *human)?;
+                Ok(true)
+            }
+            SftpCommand::Chmod {
+                mode,
+                path,
+                recursive: false,
+            } => {
+                self.chmod(*mode, path)?;
+                Ok(true)
+            }
+            SftpCommand::Chmod {
+                mode,
+                path,
+                recursive: true,
+            } => {
+                self.chmod_recursive(*mode, path)?;
+                Ok(true)
+            }
+            SftpCommand::Touch { path } => {
+                self.touch(path)?;
+                Ok(true)
+            }
+            SftpCommand::Symlink { target, link_path } => {
+                self.symlink(target, link_path)?;
+                Ok(true)
+            }
+            SftpCommand::HardLink { target, link_path } => {
+                self.hardlink(target, link_path)?;
+                Ok(true)
+            }
+            SftpCommand::ReadLink { path } => {
+                self.print_link_target(path)?;
+                Ok(true)
+            }
+            SftpCommand::Rename {
+                old_path,
+                new_path,
+                overwrite,
             } => {
-                self.put_file(remote_path, local_path.as_ref())?;
+                self.rename(old_path, new_path, *overwrite)?;
+                Ok(true)
+            }
+            SftpCommand::RealPath { path } => {
+                self.print_real_path(path)?;
+                Ok(true)
+            }
+            SftpCommand::Stat { path, follow } => {
+                self.stat_file(path, *follow)?;
+                Ok(true)
+            }
+            SftpCommand::Version => {
+                self.show_version()?;
+                Ok(true)
+            }
+            SftpCommand::Extensions => {
+                self.show_extensions()?;
+                Ok(true)
+            }
+            SftpCommand::Stats => {
+                self.show_stats()?;
                 Ok(true)
             }
             SftpCommand::Help => {
@@ -109,15 +540,20 @@ impl<T: TransportLayer> SftpClient<T> {
         }
     }
 
-    fn list_directory(&mut self, path: Option<&PathBuf>) -> Result<(), SftpError> {
+    fn list_directory(
+        &mut self,
+        path: Option<&PathBuf>,
+        order: &ListingOrder,
+    ) -> Result<(), SftpError> {
         let target_path = match path {
             Some(p) => self.resolve_path(p),
             None => self.working_dir.clone(),
         };
 
         if let Some(cache) = self.directory_cache.get(&target_path) {
-            self.current_listing = cache.files.clone();
-            self.display_current_listing();
+            self.current_listing = Arc::clone(&cache.files);
+            self.protocol.stats_mut().cache_hits += 1;
+            self.display_current_listing(order);
             return Ok(());
         }
 
@@ -126,9 +562,19 @@ impl<T: TransportLayer> SftpClient<T> {
             .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
 
         let handle = self.protocol.open_dir(path_str)?;
-        let files = self.read_entire_directory(&handle)?;
+        // Unsorted listings print each READDIR page as it arrives rather than
+        // after the whole directory has been read
+        let streaming = order.is_unsorted();
+        let files = Arc::new(self.read_entire_directory(&handle, |page| {
+            if streaming {
+                Self::display_files(page)
+            }
+        })?);
         self.protocol.close(handle)?;
-        self.current_listing = files.clone();
+        self.current_listing = Arc::clone(&files);
+        if !streaming {
+            self.display_current_listing(order);
+        }
         self.directory_cache.insert(
             target_path,
             DirectoryCache {
@@ -137,96 +583,3782 @@ impl<T: TransportLayer> SftpClient<T> {
             },
         );
 
-        self.display_current_listing();
         Ok(())
     }
 
-    fn read_entire_directory(&mut self, handle: &[u8]) -> Result<Vec<FileInfo>, SftpError> {
-        let mut all_files = Vec::new();
-
-        loop {
-            let files = self.protocol.read_dir(handle)?;
-            if files.is_empty() {
-                break;
-            }
-            all_files.extend(files);
-        }
-
-        Ok(all_files)
-    }
-
-    fn change_directory(&mut self, path: Option<&PathBuf>) -> Result<(), SftpError> {
-        let target_path = match path {
+    /// Prints the tree under `path` with box-drawing branches, directories
+    /// ahead of files, going at most `depth` levels down. Symbolic links are
+    /// shown with their target but not followed.
+    fn show_tree(&mut self, path: Option<&PathBuf>, depth: Option<usize>) -> Result<(), SftpError> {
+        let root = match path {
             Some(p) => self.resolve_path(p),
             None => self.working_dir.clone(),
         };
-
-        let path_str = target_path
+        let root_str = root
             .to_str()
             .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
 
-        let attrs = self.protocol.stat(path_str)?;
-        if !attrs.is_directory {
-            return Err(SftpError::NotADirectory(path_str.to_string()));
-        }
-
-        self.working_dir = PathBuf::from(path_str);
-        self.current_listing.clear();
+        println!("{}", terminal::escape(root_str));
+        let mut counts = (0, 0);
+        self.print_tree(root_str, "", depth, &mut counts)?;
+        println!("\n{} directories, {} files", counts.0, counts.1);
         Ok(())
     }
 
-    fn print_working_directory(&self) -> Result<(), SftpError> {
-        print!("{}", self.working_dir.display());
+    // Prints the entries of `dir`, each after `prefix`, counting the
+    // directories and files seen
+    fn print_tree(
+        &mut self,
+        dir: &str,
+        prefix: &str,
+        depth: Option<usize>,
+        counts: &mut (usize, usize),
+    ) -> Result<(), SftpError> {
+        let handle = self.protocol.open_dir(dir)?;
+        let files = self.read_entire_directory(&handle, |_| {});
+        self.protocol.close(handle)?;
+        let files = files?;
+
+        let mut entries: Vec<&FileInfo> = files
+            .iter()
+            .filter(|file| file.name != "." && file.name != "..")
+            .collect();
+        let order = ListingOrder {
+            key: SortKey::Name,
+            reverse: false,
+            directories_first: true,
+        };
+        order.sort(&mut entries);
+
+        for (i, file) in entries.iter().enumerate() {
+            let (branch, indent) = match i + 1 == entries.len() {
+                true => ("└── ", "    "),
+                false => ("├── ", "│   "),
+            };
+            let path = remote_path::join(dir, &file.name);
+            let name = terminal::escape(&file.name);
+            let target = match file.attrs.is_symlink {
+                true => self.protocol.readlink(&path).ok(),
+                false => None,
+            };
+            match target {
+                Some(target) => println!(
+                    "{}{}{} -> {}",
+                    prefix,
+                    branch,
+                    name,
+                    terminal::escape(&target)
+                ),
+                None => println!("{}{}{}", prefix, branch, name),
+            }
+
+            if !file.attrs.is_directory {
+                counts.1 += 1;
+                continue;
+            }
+            counts.0 += 1;
+            if depth.is_some_and(|depth| depth <= 1) {
+                continue;
+            }
+            let prefix = format!("{}{}", prefix, indent);
+            // An unreadable directory is noted and the rest still shown
+            if let Err(e) = self.print_tree(&path, &prefix, depth.map(|d| d - 1), counts) {
+                println!("{}[{}]", prefix, e);
+            }
+        }
         Ok(())
     }
 
-    fn show_help(&self) -> Result<(), SftpError> {
-        println!("Available commands:\nls - list files in current directory\ncd - change current directory\nget - download file\nput - upload file\nbye - exit");
-        Ok(())
+    fn read_entire_directory<F>(
+        &mut self,
+        handle: &[u8],
+        on_page: F,
+    ) -> Result<Vec<FileInfo>, SftpError>
+    where
+        F: FnMut(&[FileInfo]),
+    {
+        self.protocol.read_dir_all(handle, on_page)
     }
 
-    fn put_file(
+    /// Recursively lists the regular files under `root`, keyed by their path
+    /// relative to `root`. Excluded directories are not descended into.
+    fn walk_remote(
         &mut self,
-        remote_path: &PathBuf,
-        local_path: Option<&PathBuf>,
-    ) -> Result<(), SftpError> {
-        todo!()
+        root: &Path,
+        ignore: &IgnoreRules,
+    ) -> Result<Vec<(PathBuf, FileAttributes)>, SftpError> {
+        let mut entries = Vec::new();
+        let mut pending = vec![PathBuf::new()];
+
+        while let Some(relative) = pending.pop() {
+            let dir = remote_path::join_relative(&root.to_string_lossy(), &relative);
+
+            let handle = self.protocol.open_dir(&dir)?;
+            let files = self.read_entire_directory(&handle, |_| {});
+            self.protocol.close(handle)?;
+
+            for file in files? {
+                if file.name == "." || file.name == ".." {
+                    continue;
+                }
+                let path = relative.join(&file.name);
+                if ignore.is_ignored(&path, file.attrs.is_directory) {
+                    continue;
+                }
+                if file.attrs.is_directory {
+                    pending.push(path);
+                } else if file.attrs.is_regular_file {
+                    entries.push((path, file.attrs));
+                }
+            }
+        }
+
+        Ok(entries)
     }
-    fn get_file(
+
+    /// Ignore rules for a download of the tree at `root`: those in
+    /// `ignore_file` when one is named, otherwise the tree's own
+    /// `.ferricignore` if it has one.
+    fn remote_ignore_rules(
         &mut self,
-        remote_path: &PathBuf,
-        local_path: Option<&PathBuf>,
+        ignore_file: Option<&Path>,
+        root: &str,
+    ) -> Result<IgnoreRules, SftpError> {
+        if let Some(file) = ignore_file {
+            return Ok(IgnoreRules::load(file)?);
+        }
+        let path = remote_path::join(root, IGNORE_FILE_NAME);
+        let handle = match self.protocol.open(&path, SSH_FXF_READ) {
+            Err(SftpError::ServerError { code, .. }) if code == SftpStatus::NoSuchFile as u32 => {
+                return Ok(IgnoreRules::default())
+            }
+            result => result?,
+        };
+        let mut text = Vec::new();
+        let result = self.protocol.read_to_writer(&handle, &mut text);
+        self.protocol.close(handle)?;
+        result?;
+        Ok(IgnoreRules::parse(&String::from_utf8_lossy(&text))?)
+    }
+
+    fn verify(
+        &mut self,
+        local_path: &Path,
+        remote_path: &Path,
+        checksum: bool,
     ) -> Result<(), SftpError> {
-        let target_path = self.resolve_path(remote_path);
+        let remote_root = self.resolve_path(remote_path);
 
-        let path_str = target_path
+        let local: BTreeMap<PathBuf, LocalEntry> =
+            filesystem::walk_tree(local_path, &IgnoreRules::default())?
+                .into_iter()
+                .collect();
+        let mut remote: BTreeMap<PathBuf, FileAttributes> = self
+            .walk_remote(&remote_root, &IgnoreRules::default())?
+            .into_iter()
+            .collect();
+        let remote_count = remote.len();
+
+        let mut differences = 0;
+        for (path, entry) in &local {
+            let name = path.to_string_lossy();
+            let name = terminal::escape(&name);
+            let Some(attrs) = remote.remove(path) else {
+                println!("missing remote: {}", name);
+                differences += 1;
+                continue;
+            };
+
+            let remote_size = attrs.size.unwrap_or_default();
+            if remote_size != entry.size {
+                println!(
+                    "size differs: {} (local {}, remote {})",
+                    name, entry.size, remote_size
+                );
+                differences += 1;
+            } else if checksum {
+                // Timestamps are ignored here; content is what matters
+                let local_digest = local_checksum(&local_path.join(path))?;
+                let remote_file = remote_path::join_relative(&remote_root.to_string_lossy(), path);
+                if local_digest != self.remote_checksum(&remote_file)? {
+                    println!("content differs: {}", name);
+                    differences += 1;
+                }
+            } else if let (Some(local_time), Some(remote_time)) =
+                (entry.modify_time, attrs.modify_time)
+            {
+                if local_time != remote_time as u64 {
+                    println!(
+                        "mtime differs: {} (local {}, remote {})",
+                        name,
+                        format_timestamp(local_time),
+                        format_timestamp(remote_time.into())
+                    );
+                    differences += 1;
+                }
+            }
+        }
+        for path in remote.keys() {
+            println!(
+                "missing local: {}",
+                terminal::escape(&path.to_string_lossy())
+            );
+            differences += 1;
+        }
+
+        println!(
+            "{} local and {} remote files compared, {} difference(s)",
+            local.len(),
+            remote_count,
+            differences
+        );
+        match differences {
+            0 => Ok(()),
+            count => Err(SftpError::VerifyFailed(count)),
+        }
+    }
+
+    /// Makes the destination tree match the source, transferring only files
+    /// that are missing from it or differ, then prints what was done. A
+    /// destination directory that does not exist yet is created. With
+    /// `delete`, destination files the source lacks are removed afterwards.
+    fn sync(
+        &mut self,
+        direction: SyncDirection,
+        local_path: &Path,
+        remote_path: &Path,
+        options: &SyncOptions,
+    ) -> Result<(), SftpError> {
+        let remote_root = self.resolve_path(remote_path);
+        let remote_root_str = remote_root
             .to_str()
             .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
 
-        let file_handle: Vec<u8> = self.protocol.open(path_str, SSH_FXF_READ)?;
-        let data: Vec<u8> = self.protocol.read(&file_handle)?;
+        // The source tree's rules apply to both sides, so a mirroring sync
+        // leaves ignored destination files alone too
+        let ignore = match direction {
+            SyncDirection::Up => local_ignore_rules(options.ignore_file.as_deref(), local_path)?,
+            SyncDirection::Down => {
+                self.remote_ignore_rules(options.ignore_file.as_deref(), remote_root_str)?
+            }
+        };
 
-        let target_local_path: PathBuf = match local_path {
-            Some(path) => {
-                if path.is_dir() {
-                    let file_name = remote_path
-                        .file_name()
-                        .ok_or_else(|| SftpError::InvalidCommand("No filename in remote path"))?;
-                    path.join(file_name)
-                } else {
-                    path.clone()
-                }
+        let local: BTreeMap<PathBuf, SyncEntry> = match filesystem::walk_tree(local_path, &ignore) {
+            Ok(entries) => entries
+                .into_iter()
+                .map(|(path, entry)| (path, entry.into()))
+                .collect(),
+            Err(e) if direction == SyncDirection::Down && e.kind() == ErrorKind::NotFound => {
+                BTreeMap::new()
             }
-            None => {
-                let file_name = remote_path
-                    .file_name()
-                    .ok_or_else(|| SftpError::InvalidCommand("No filename in remote path"))?;
-                PathBuf::from(".").join(file_name)
+            Err(e) => return Err(e.into()),
+        };
+        let remote: BTreeMap<PathBuf, SyncEntry> = match self.walk_remote(&remote_root, &ignore) {
+            Ok(entries) => entries
+                .iter()
+                .map(|(path, attrs)| (path.clone(), attrs.into()))
+                .collect(),
+            Err(SftpError::ServerError { code, .. })
+                if direction == SyncDirection::Up && code == SftpStatus::NoSuchFile as u32 =>
+            {
+                BTreeMap::new()
+            }
+            Err(e) => return Err(e),
+        };
+        let (source, destination) = match direction {
+            SyncDirection::Up => (&local, &remote),
+            SyncDirection::Down => (&remote, &local),
+        };
+        // Content decides between files of the same size, so times are ignored
+        let tolerance = match options.checksum {
+            true => u64::MAX,
+            false => self.settings.sync_tolerance.as_secs(),
+        };
+        let mut actions = sync::plan(source, destination, tolerance);
+        if options.checksum {
+            for (path, action) in &mut actions {
+                if *action != SyncAction::Skip {
+                    continue;
+                }
+                let local_digest = local_checksum(&local_path.join(&*path))?;
+                let remote_file = remote_path::join_relative(remote_root_str, path);
+                if local_digest != self.remote_checksum(&remote_file)? {
+                    *action = SyncAction::Update;
+                }
             }
+        }
+        let doomed = match options.delete {
+            true => sync::extraneous(source, destination),
+            false => Vec::new(),
         };
+        let delete = options.force || self.confirm_sync_deletes(&doomed);
 
-        filesystem::write_to_file(&target_local_path, &data).map_err(|e| SftpError::IoError(e))?;
+        let mut report = SyncReport::default();
+        let mut transfers = Vec::new();
+        for (path, action) in &actions {
+            if *action == SyncAction::Skip {
+                report.record(path, Outcome::Skip, None);
+                continue;
+            }
+            report.record(path, (*action).into(), Some(direction));
+            let remote_file = remote_path::join_relative(remote_root_str, path);
+            let local_file = local_path.join(path);
+            let SyncEntry { size, modify_time } = source[path];
+            let size = Some(size);
+            transfers.push(match direction {
+                SyncDirection::Up => Transfer::Upload {
+                    local_path: local_file,
+                    remote_path: remote_file,
+                    size,
+                    modify_time,
+                },
+                SyncDirection::Down => Transfer::Download {
+                    remote_path: remote_file,
+                    local_path: local_file,
+                    size,
+                    modify_time,
+                },
+            });
+        }
 
-        Ok(())
+        let created = actions
+            .iter()
+            .filter(|(_, action)| *action == SyncAction::Create)
+            .map(|(path, _)| path);
+        match direction {
+            SyncDirection::Up => self.make_sync_dirs(remote_root_str, created, destination)?,
+            SyncDirection::Down => {
+                for path in created {
+                    if let Some(parent) = local_path.join(path).parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                }
+            }
+        }
+
+        let policy = PolicyOverride {
+            overwrite: Some(Overwrite::Replace),
+            ..PolicyOverride::default()
+        };
+        let journal = match &options.journal {
+            Some(file) => {
+                let operation = format!(
+                    "sync {:?} {} {}",
+                    direction,
+                    std::path::absolute(local_path)?.display(),
+                    remote_root_str
+                );
+                Some(open_journal(file, &operation)?)
+            }
+            None => None,
+        };
+        let mut result = self
+            .run_transfer_batch(transfers, &policy, journal.as_ref())
+            .and_then(|outcome| report_transfers(&mut report, local_path, outcome));
+        if let (Ok(()), Some(journal)) = (&result, journal) {
+            journal.finish()?;
+        }
+
+        if delete {
+            for path in &doomed {
+                let removed = match direction {
+                    SyncDirection::Up => self.remove(
+                        Path::new(&remote_path::join_relative(remote_root_str, path)),
+                        false,
+                    ),
+                    SyncDirection::Down => {
+                        std::fs::remove_file(local_path.join(path)).map_err(SftpError::from)
+                    }
+                };
+                let index = report.record(path, Outcome::Delete, Some(direction));
+                match removed {
+                    Ok(()) => println!("deleted {}", terminal::escape(&path.to_string_lossy())),
+                    Err(e) => {
+                        println!("{}: {}", terminal::escape(&path.to_string_lossy()), e);
+                        report.files[index].error = Some(e.to_string());
+                        result = result.and(Err(e));
+                    }
+                }
+            }
+        }
+        println!("Sync: {}", report);
+        let mode = match direction {
+            SyncDirection::Up => "up",
+            SyncDirection::Down => "down",
+        };
+        save_report(&report, options, mode, local_path, remote_root_str)?;
+        result
+    }
+
+    /// Brings changes made on either side since the last two-way sync over to
+    /// the other, deletions included, and records what both sides then hold
+    /// as the baseline for the next run. Files changed on both sides are
+    /// settled by the conflict policy.
+    fn sync_both(
+        &mut self,
+        local_path: &Path,
+        remote_path: &Path,
+        options: &SyncOptions,
+    ) -> Result<(), SftpError> {
+        let remote_root = self.resolve_path(remote_path);
+        let remote_root_str = remote_root
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+        let ignore = local_ignore_rules(options.ignore_file.as_deref(), local_path)?;
+        let baseline_file = local_path.join(sync::BASELINE_FILE_NAME);
+        let baseline = load_baseline(&baseline_file, remote_root_str)?;
+
+        let mut local: BTreeMap<PathBuf, SyncEntry> =
+            match filesystem::walk_tree(local_path, &ignore) {
+                Ok(entries) => entries
+                    .into_iter()
+                    .map(|(path, entry)| (path, entry.into()))
+                    .collect(),
+                Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => return Err(e.into()),
+            };
+        let mut remote: BTreeMap<PathBuf, SyncEntry> = match self.walk_remote(&remote_root, &ignore)
+        {
+            Ok(entries) => entries
+                .iter()
+                .map(|(path, attrs)| (path.clone(), attrs.into()))
+                .collect(),
+            Err(SftpError::ServerError { code, .. }) if code == SftpStatus::NoSuchFile as u32 => {
+                BTreeMap::new()
+            }
+            Err(e) => return Err(e),
+        };
+        local.remove(Path::new(sync::BASELINE_FILE_NAME));
+        remote.remove(Path::new(sync::BASELINE_FILE_NAME));
+
+        let tolerance = self.settings.sync_tolerance.as_secs();
+        let mut report = SyncReport::default();
+        let mut transfers = Vec::new();
+        let mut deletions = Vec::new();
+        // What both sides will hold once everything has gone through
+        let mut next_baseline = BTreeMap::new();
+        let upload = |path: &Path, entry: SyncEntry| Transfer::Upload {
+            local_path: local_path.join(path),
+            remote_path: remote_path::join_relative(remote_root_str, path),
+            size: Some(entry.size),
+            modify_time: entry.modify_time,
+        };
+        let download = |path: &Path, entry: SyncEntry| Transfer::Download {
+            remote_path: remote_path::join_relative(remote_root_str, path),
+            local_path: local_path.join(path),
+            size: Some(entry.size),
+            modify_time: entry.modify_time,
+        };
+
+        for (path, action) in sync::plan_two_way(&local, &remote, &baseline, tolerance) {
+            let resolution = match action {
+                TwoWayAction::Conflict => {
+                    report.conflicts += 1;
+                    match options.conflict {
+                        ConflictPolicy::Prompt => self.ask_about_conflict(&path),
+                        policy => sync::resolve(policy, &local[&path], &remote[&path]),
+                    }
+                }
+                TwoWayAction::Upload => Resolution::KeepLocal,
+                TwoWayAction::Download => Resolution::KeepRemote,
+                TwoWayAction::Skip => {
+                    report.record(&path, Outcome::Skip, None);
+                    if let Some(entry) = local.get(&path).or(remote.get(&path)) {
+                        next_baseline.insert(path, *entry);
+                    }
+                    continue;
+                }
+                TwoWayAction::DeleteLocal | TwoWayAction::DeleteRemote => {
+                    deletions.push((path, action));
+                    continue;
+                }
+            };
+
+            match resolution {
+                Resolution::KeepLocal => {
+                    let entry = local[&path];
+                    let outcome = match remote.contains_key(&path) {
+                        true => Outcome::Update,
+                        false => Outcome::Create,
+                    };
+                    report.record(&path, outcome, Some(SyncDirection::Up));
+                    transfers.push(upload(&path, entry));
+                    next_baseline.insert(path, entry);
+                }
+                Resolution::KeepRemote => {
+                    let entry = remote[&path];
+                    let outcome = match local.contains_key(&path) {
+                        true => Outcome::Update,
+                        false => Outcome::Create,
+                    };
+                    report.record(&path, outcome, Some(SyncDirection::Down));
+                    transfers.push(download(&path, entry));
+                    next_baseline.insert(path, entry);
+                }
+                Resolution::KeepBoth => {
+                    let aside = sync::conflict_name(&path);
+                    std::fs::rename(local_path.join(&path), local_path.join(&aside))?;
+                    println!(
+                        "{}: local copy kept as {}",
+                        terminal::escape(&path.to_string_lossy()),
+                        terminal::escape(&aside.to_string_lossy())
+                    );
+                    report.record(&path, Outcome::Update, Some(SyncDirection::Down));
+                    report.record(&aside, Outcome::Create, Some(SyncDirection::Up));
+                    transfers.push(download(&path, remote[&path]));
+                    transfers.push(upload(&aside, local[&path]));
+                    next_baseline.insert(path.clone(), remote[&path]);
+                    next_baseline.insert(aside, local[&path]);
+                }
+                Resolution::RenameBoth => {
+                    let local_copy = sync::tagged_name(&path, "local");
+                    let remote_copy = sync::tagged_name(&path, "remote");
+                    std::fs::rename(local_path.join(&path), local_path.join(&local_copy))?;
+                    self.protocol.rename(
+                        &remote_path::join_relative(remote_root_str, &path),
+                        &remote_path::join_relative(remote_root_str, &remote_copy),
+                    )?;
+                    println!(
+                        "{}: copies kept as {} and {}",
+                        terminal::escape(&path.to_string_lossy()),
+                        terminal::escape(&local_copy.to_string_lossy()),
+                        terminal::escape(&remote_copy.to_string_lossy())
+                    );
+                    report.record(&local_copy, Outcome::Create, Some(SyncDirection::Up));
+                    report.record(&remote_copy, Outcome::Create, Some(SyncDirection::Down));
+                    transfers.push(upload(&local_copy, local[&path]));
+                    transfers.push(download(&remote_copy, remote[&path]));
+                    next_baseline.insert(local_copy, local[&path]);
+                    next_baseline.insert(remote_copy, remote[&path]);
+                }
+                Resolution::Unresolved => {
+                    report.record(&path, Outcome::Unresolved, None);
+                    println!(
+                        "conflict left unresolved: {}",
+                        terminal::escape(&path.to_string_lossy())
+                    );
+                    if let Some(entry) = baseline.get(&path) {
+                        next_baseline.insert(path, *entry);
+                    }
+                }
+            }
+        }
+
+        let mut uploaded = Vec::new();
+        for transfer in &transfers {
+            match transfer {
+                Transfer::Upload {
+                    local_path: file, ..
+                } => uploaded.extend(file.strip_prefix(local_path).map(Path::to_path_buf)),
+                Transfer::Download {
+                    local_path: file, ..
+                } => {
+                    if let Some(parent) = file.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                }
+            }
+        }
+        self.make_sync_dirs(remote_root_str, uploaded.iter(), &remote)?;
+
+        let policy = PolicyOverride {
+            overwrite: Some(Overwrite::Replace),
+            ..PolicyOverride::default()
+        };
+        let journal = match &options.journal {
+            Some(file) => {
+                let operation = format!(
+                    "sync both {} {}",
+                    std::path::absolute(local_path)?.display(),
+                    remote_root_str
+                );
+                Some(open_journal(file, &operation)?)
+            }
+            None => None,
+        };
+        let mut result = self
+            .run_transfer_batch(transfers, &policy, journal.as_ref())
+            .and_then(|outcome| report_transfers(&mut report, local_path, outcome));
+        if let (Ok(()), Some(journal)) = (&result, journal) {
+            journal.finish()?;
+        }
+
+        for (path, action) in deletions {
+            let (removed, side) = match action {
+                TwoWayAction::DeleteRemote => (
+                    self.remove(
+                        Path::new(&remote_path::join_relative(remote_root_str, &path)),
+                        false,
+                    ),
+                    SyncDirection::Up,
+                ),
+                _ => (
+                    std::fs::remove_file(local_path.join(&path)).map_err(SftpError::from),
+                    SyncDirection::Down,
+                ),
+            };
+            let index = report.record(&path, Outcome::Delete, Some(side));
+            let name = terminal::escape(&path.to_string_lossy()).into_owned();
+            match removed {
+                Ok(()) => match side {
+                    SyncDirection::Up => println!("deleted {} (remote)", name),
+                    SyncDirection::Down => println!("deleted {} (local)", name),
+                },
+                Err(e) => {
+                    println!("{}: {}", name, e);
+                    report.files[index].error = Some(e.to_string());
+                    result = result.and(Err(e));
+                    // Still to be deleted next time
+                    if let Some(entry) = baseline.get(&path) {
+                        next_baseline.insert(path, *entry);
+                    }
+                }
+            }
+        }
+
+        // After a failed transfer the old baseline stays, so whatever did not
+        // make it is still seen as changed next time
+        if result.is_ok() {
+            save_baseline(&baseline_file, remote_root_str, &next_baseline)?;
+        }
+        println!("Sync: {}", report);
+        save_report(&report, options, "both", local_path, remote_root_str)?;
+        result
+    }
+
+    // Asks which copy of a file changed on both sides to keep
+    fn ask_about_conflict(&mut self, path: &Path) -> Resolution {
+        let name = path.to_string_lossy();
+        let question = format!(
+            "{} changed on both sides. Keep the local copy (no keeps the remote one)?",
+            terminal::escape(&name)
+        );
+        match self.confirm.as_mut() {
+            Some(confirm) => match confirm(&question) {
+                true => Resolution::KeepLocal,
+                false => Resolution::KeepRemote,
+            },
+            None => Resolution::Unresolved,
+        }
+    }
+
+    /// Uploads files created or changed under `local_path` as they happen,
+    /// and with `delete` removes the remote copies of files deleted locally.
+    /// Paths in the tree's `.ferricignore` are left alone. Runs until Ctrl-C
+    /// returns to the prompt.
+    fn watch(
+        &mut self,
+        local_path: &Path,
+        remote_path: &Path,
+        delete: bool,
+    ) -> Result<(), SftpError> {
+        let remote_root = self.resolve_path(remote_path);
+        let remote_root = remote_root
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?
+            .to_string();
+        // Events name files under the canonical path, whatever was watched
+        let local_root = local_path.canonicalize()?;
+        let ignore = local_ignore_rules(None, &local_root)?;
+        match self.make_dir(Path::new(&remote_root)) {
+            Ok(()) | Err(SftpError::AlreadyExists(_)) => {}
+            Err(e) => return Err(e),
+        }
+
+        let (sender, events) = mpsc::channel();
+        let mut watcher =
+            notify::recommended_watcher(sender).map_err(|e| SftpError::ClientError(Box::new(e)))?;
+        watcher
+            .watch(&local_root, RecursiveMode::Recursive)
+            .map_err(|e| SftpError::ClientError(Box::new(e)))?;
+        println!(
+            "Watching {} for changes to upload to {}",
+            local_path.display(),
+            terminal::escape(&remote_root)
+        );
+
+        let pause = self.protocol.pause_handle();
+        let mut known_dirs = BTreeSet::new();
+        loop {
+            if pause.is_paused() {
+                pause.resume();
+                break;
+            }
+            let event = match events.recv_timeout(WATCH_POLL_INTERVAL) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let mut changed = BTreeSet::new();
+            let mut next = Some(event);
+            while let Some(event) = next {
+                match event {
+                    // Reads and opens don't change anything worth uploading
+                    Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
+                    Ok(event) => changed.extend(event.paths),
+                    Err(e) => warn!("watch: {}", e),
+                }
+                next = events.recv_timeout(WATCH_SETTLE_TIME).ok();
+            }
+            self.upload_local_changes(
+                &local_root,
+                &remote_root,
+                &changed,
+                &ignore,
+                delete,
+                &mut known_dirs,
+            );
+        }
+        Ok(())
+    }
+
+    // Brings the remote copies of `changed`, absolute paths under
+    // `local_root`, up to date with the local files. A failure is reported
+    // and the watch carries on, since the file may well change again
+    fn upload_local_changes(
+        &mut self,
+        local_root: &Path,
+        remote_root: &str,
+        changed: &BTreeSet<PathBuf>,
+        ignore: &IgnoreRules,
+        delete: bool,
+        known_dirs: &mut BTreeSet<PathBuf>,
+    ) {
+        let policy = PolicyOverride {
+            overwrite: Some(Overwrite::Replace),
+            ..PolicyOverride::default()
+        };
+        for path in changed {
+            let Ok(relative) = path.strip_prefix(local_root) else {
+                continue;
+            };
+            if relative.as_os_str().is_empty() {
+                continue;
+            }
+            let remote = PathBuf::from(remote_path::join_relative(remote_root, relative));
+            let name = relative.to_string_lossy();
+            let name = terminal::escape(&name);
+
+            let result = match std::fs::symlink_metadata(path) {
+                Ok(metadata) if ignore.is_ignored(relative, metadata.is_dir()) => continue,
+                Ok(metadata) if metadata.is_dir() => {
+                    self.make_watched_dirs(remote_root, relative, known_dirs)
+                }
+                Ok(metadata) if metadata.is_file() => relative
+                    .parent()
+                    .map_or(Ok(()), |dir| {
+                        self.make_watched_dirs(remote_root, dir, known_dirs)
+                    })
+                    .and_then(|()| self.put_file(path, Some(&remote), PutMode::Replace, &policy))
+                    .map(|()| println!("uploaded {}", name)),
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::NotFound && delete => {
+                    if ignore.is_ignored(relative, false) {
+                        continue;
+                    }
+                    known_dirs.retain(|dir| !dir.starts_with(relative));
+                    match self.remove(&remote, true) {
+                        // Never uploaded, like an editor's scratch file
+                        Err(SftpError::ServerError { code, .. })
+                            if code == SftpStatus::NoSuchFile as u32 =>
+                        {
+                            continue
+                        }
+                        result => result.map(|()| println!("deleted {}", name)),
+                    }
+                }
+                Err(_) => continue,
+            };
+            if let Err(e) = result {
+                println!("{}: {}", name, e);
+            }
+        }
+    }
+
+    // Creates `dir` under `root` and any of its parents not yet made by
+    // this watch
+    fn make_watched_dirs(
+        &mut self,
+        root: &str,
+        dir: &Path,
+        known_dirs: &mut BTreeSet<PathBuf>,
+    ) -> Result<(), SftpError> {
+        let mut missing: Vec<&Path> = dir
+            .ancestors()
+            .filter(|dir| !dir.as_os_str().is_empty() && !known_dirs.contains(*dir))
+            .collect();
+        missing.reverse();
+        for dir in missing {
+            let path = PathBuf::from(remote_path::join_relative(root, dir));
+            match self.make_dir(&path) {
+                Ok(()) | Err(SftpError::AlreadyExists(_)) => {}
+                Err(e) => return Err(e),
+            }
+            known_dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    // Lists the files a mirroring sync would delete and asks whether to go
+    // ahead. Without a way to ask, as in batch mode, nothing is deleted
+    // unless --force was given
+    fn confirm_sync_deletes(&mut self, doomed: &[PathBuf]) -> bool {
+        if doomed.is_empty() {
+            return false;
+        }
+        for path in doomed {
+            println!("would delete {}", terminal::escape(&path.to_string_lossy()));
+        }
+        match self.confirm.as_mut() {
+            Some(confirm) => confirm(&format!("Delete these {} file(s)?", doomed.len())),
+            None => {
+                println!("Not deleting anything; run again with --force to delete these");
+                false
+            }
+        }
+    }
+
+    // Creates the remote directories that files about to be uploaded under
+    // `root` will need. Directories holding a file of `existing` are known to
+    // be there; any others may be, which the server says by refusing mkdir
+    fn make_sync_dirs<'a>(
+        &mut self,
+        root: &str,
+        created: impl Iterator<Item = &'a PathBuf>,
+        existing: &BTreeMap<PathBuf, SyncEntry>,
+    ) -> Result<(), SftpError> {
+        let present: BTreeSet<&Path> = existing.keys().flat_map(|path| path.ancestors()).collect();
+        let needed: BTreeSet<&Path> = created
+            .flat_map(|path| path.ancestors().skip(1))
+            .filter(|dir| !present.contains(dir))
+            .collect();
+
+        // Parents sort ahead of their children, so each mkdir has somewhere to go
+        for dir in needed {
+            let path = PathBuf::from(remote_path::join_relative(root, dir));
+            match self.make_dir(&path) {
+                Ok(()) | Err(SftpError::AlreadyExists(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the tree under `root` with sizes and times. Hashes are taken
+    /// only when `hash` is set, since that means reading every file.
+    fn take_snapshot(&mut self, root: &Path, hash: bool) -> Result<Snapshot, SftpError> {
+        let root_str = root
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+        let mut snapshot = Snapshot::new(root_str);
+
+        for (path, attrs) in self.walk_remote(root, &IgnoreRules::default())? {
+            let sha256 = match hash {
+                true => Some(self.remote_checksum(&remote_path::join_relative(root_str, &path))?),
+                false => None,
+            };
+            snapshot.entries.insert(
+                remote_path::to_slash(&path),
+                SnapshotEntry {
+                    size: attrs.size.unwrap_or_default(),
+                    modify_time: attrs.modify_time.map(u64::from),
+                    sha256,
+                },
+            );
+        }
+        Ok(snapshot)
+    }
+
+    fn save_snapshot(&mut self, file: &Path, root: Option<PathBuf>) -> Result<(), SftpError> {
+        let root = root.unwrap_or_else(|| self.working_dir.clone());
+        let snapshot = self.take_snapshot(&root, true)?;
+        std::fs::write(file, snapshot.to_json())?;
+        println!(
+            "{} files under {} saved to {}",
+            snapshot.entries.len(),
+            root.display(),
+            file.display()
+        );
+        Ok(())
+    }
+
+    /// Compares the live tree with a saved snapshot. Without an explicit
+    /// path the tree the snapshot was taken from is compared.
+    fn diff_snapshot(&mut self, file: &Path, root: Option<PathBuf>) -> Result<(), SftpError> {
+        let saved = Snapshot::from_json(&std::fs::read_to_string(file)?)?;
+        let root = root.unwrap_or_else(|| PathBuf::from(&saved.root));
+
+        // Only files whose size still matches need hashing, which the diff
+        // asks for as it goes
+        let live = self.take_snapshot(&root, false)?;
+        let drift = saved.diff(&live, |path| {
+            self.remote_checksum(&remote_path::join(&root.to_string_lossy(), path))
+        })?;
+
+        for change in &drift {
+            println!("{}", terminal::escape(&change.to_string()));
+        }
+        println!(
+            "{} saved and {} live files compared, {} difference(s)",
+            saved.entries.len(),
+            live.entries.len(),
+            drift.len()
+        );
+        match drift.len() {
+            0 => Ok(()),
+            count => Err(SftpError::DriftDetected(count)),
+        }
+    }
+
+    /// Hashes a remote file, letting the server do it when it supports
+    /// check-file and otherwise streaming the contents through a local hasher
+    /// without writing them anywhere.
+    pub fn remote_checksum(&mut self, path: &str) -> Result<String, SftpError> {
+        if self.protocol.supports_extension(EXT_CHECK_FILE) {
+            match self.protocol.check_file(path, checksum::ALGORITHM) {
+                Err(SftpError::ServerError { code, .. })
+                    if code == SftpStatus::OpUnsupported as u32 => {}
+                result => return result.map(|digest| checksum::to_hex(&digest)),
+            }
+        }
+
+        let handle = self.protocol.open(path, SSH_FXF_READ)?;
+        let mut hasher = Sha256::new();
+        let result = self.protocol.read_to_writer(&handle, &mut hasher);
+        self.protocol.close(handle)?;
+        result?;
+
+        Ok(checksum::to_hex(&hasher.finalize()))
+    }
+
+    fn print_checksum(&mut self, path: &Path) -> Result<(), SftpError> {
+        let target_path = self.resolve_path(path);
+        let path_str = target_path
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        // Same layout as sha256sum so the output can be compared directly
+        println!("{}  {}", self.remote_checksum(path_str)?, path_str);
+        Ok(())
+    }
+
+    /// Resolves `path`, expanding wildcards in its last component against the
+    /// remote directory. Only regular files are returned for a pattern.
+    fn expand_remote_glob(&mut self, path: &Path) -> Result<Vec<String>, SftpError> {
+        let target = self.resolve_path(path);
+        let target_str = target
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        let (dir, pattern) = remote_path::split(target_str);
+        if !glob::is_pattern(pattern) {
+            return Ok(vec![target_str.to_string()]);
+        }
+
+        let handle = self.protocol.open_dir(dir)?;
+        let files = self.read_entire_directory(&handle, |_| {});
+        self.protocol.close(handle)?;
+
+        let ignore_case = self.settings.case_insensitive;
+        let mut matches: Vec<String> = files?
+            .into_iter()
+            .filter(|file| file.attrs.is_regular_file)
+            .filter(|file| glob::matches(pattern, &file.name, ignore_case))
+            .map(|file| remote_path::join(dir, &file.name))
+            .collect();
+        if matches.is_empty() {
+            return Err(SftpError::NoMatches(target_str.to_string()));
+        }
+        matches.sort();
+        Ok(matches)
+    }
+
+    /// Searches remote files line by line without downloading them to disk.
+    fn grep(&mut self, pattern: &str, path: &Path) -> Result<(), SftpError> {
+        let regex = Regex::new(pattern).map_err(|e| SftpError::ClientError(e.into()))?;
+
+        for file in self.expand_remote_glob(path)? {
+            let handle = self.protocol.open(&file, SSH_FXF_READ)?;
+            let name = terminal::escape(&file);
+            let mut grep = GrepWriter::new(&regex, &name, std::io::stdout().lock());
+            let result = self.protocol.read_to_writer(&handle, &mut grep);
+            self.protocol.close(handle)?;
+            result?;
+            grep.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Prints the last lines of a remote file. With `follow`, keeps polling
+    /// its size and prints whatever is appended until Ctrl-C, reopening the
+    /// path when the file is truncated or rotated away.
+    pub fn tail<W: Write>(
+        &mut self,
+        path: &str,
+        follow: bool,
+        out: &mut W,
+    ) -> Result<(), SftpError> {
+        let mut handle = self.protocol.open(path, SSH_FXF_READ)?;
+        let result = match self.tail_handle(path, &mut handle, follow, out) {
+            // Ctrl-C is how following ends, so it returns to the prompt
+            // rather than failing the command
+            Err(e) if e.is_paused() => {
+                self.protocol.pause_handle().resume();
+                Ok(())
+            }
+            result => result,
+        };
+        self.protocol.close(handle)?;
+        result
+    }
+
+    /// Writes the contents of `path` to `out` chunk by chunk as they arrive,
+    /// without staging the file on disk.
+    pub fn cat<W: Write>(&mut self, path: &str, out: &mut W) -> Result<(), SftpError> {
+        let handle = self.protocol.open(path, SSH_FXF_READ)?;
+        let result = self.protocol.read_to_writer(&handle, out);
+        self.protocol.close(handle)?;
+        result?;
+        out.flush()?;
+        Ok(())
+    }
+
+    fn tail_handle<W: Write>(
+        &mut self,
+        path: &str,
+        handle: &mut Vec<u8>,
+        follow: bool,
+        out: &mut W,
+    ) -> Result<(), SftpError> {
+        let size = self.protocol.stat(path)?.size.unwrap_or(0);
+        let start = size.saturating_sub(TAIL_BLOCK_SIZE);
+        let block = self.protocol.read_range(handle, start, size - start)?;
+        out.write_all(&block[last_lines_start(&block, TAIL_LINES)..])?;
+        out.flush()?;
+
+        if !follow {
+            return Ok(());
+        }
+
+        let mut offset = start + block.len() as u64;
+        let pause = self.protocol.pause_handle();
+        loop {
+            thread::sleep(TAIL_POLL_INTERVAL);
+            if pause.is_paused() {
+                return Err(SftpError::Paused);
+            }
+            let size = match self.protocol.stat(path) {
+                Ok(attrs) => attrs.size.unwrap_or(offset),
+                // Between a log being rotated away and its replacement appearing
+                Err(SftpError::ServerError { code, .. })
+                    if code == SftpStatus::NoSuchFile as u32 =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            };
+            // Appends only grow the open file, so the path being smaller than
+            // it means the path now names a different file
+            let open_size = self.protocol.fstat(handle)?.size.unwrap_or(size);
+            if size < offset || size < open_size {
+                if open_size > offset {
+                    self.protocol.read_to_writer_at(handle, offset, out)?;
+                }
+                eprintln!("tail: {}: file truncated or replaced, reopening", path);
+                let old = std::mem::replace(handle, self.protocol.open(path, SSH_FXF_READ)?);
+                self.protocol.close(old)?;
+                offset = 0;
+            }
+            if size > offset {
+                offset += self.protocol.read_to_writer_at(handle, offset, out)?;
+                out.flush()?;
+            }
+        }
+    }
+
+    /// Downloads `path` into a per-session temporary directory and hands it
+    /// to the desktop's default application for its type.
+    fn open_locally(&mut self, path: &Path) -> Result<(), SftpError> {
+        let dir = opened_files_dir();
+        std::fs::create_dir_all(&dir)?;
+        let local = local_target(path, Some(&dir))?;
+        // The temporary copy is always refreshed
+        let policy = PolicyOverride {
+            overwrite: Some(Overwrite::Replace),
+            ..PolicyOverride::default()
+        };
+        self.get_with_retries(path, Some(&dir), 0, &policy)?;
+
+        let (program, args): (&str, &[&str]) = match std::env::consts::OS {
+            "macos" => ("open", &[]),
+            // start treats its first quoted argument as a window title
+            "windows" => ("cmd", &["/C", "start", ""]),
+            _ => ("xdg-open", &[]),
+        };
+        Command::new(program)
+            .args(args)
+            .arg(&local)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        println!("Opened {}", local.display());
+        Ok(())
+    }
+
+    /// Times small requests and bulk transfers against a scratch file in the
+    /// working directory, so chunk size and pipelining can be tuned against
+    /// real numbers. The scratch file is removed afterwards, even on error.
+    fn bench(&mut self, size: u64, direction: BenchDirection) -> Result<(), SftpError> {
+        let path = remote_path::join(
+            &self.working_dir.to_string_lossy(),
+            &format!(".ferric-bench-{}", std::process::id()),
+        );
+        let result = self.run_bench(&path, size, direction);
+        let removed = self.protocol.remove(&path);
+        result.and(removed)
+    }
+
+    fn run_bench(
+        &mut self,
+        path: &str,
+        size: u64,
+        direction: BenchDirection,
+    ) -> Result<(), SftpError> {
+        let dir = self.working_dir.to_string_lossy().to_string();
+        let mut samples = Vec::with_capacity(BENCH_LATENCY_SAMPLES);
+        for _ in 0..BENCH_LATENCY_SAMPLES {
+            let start = Instant::now();
+            self.protocol.stat(&dir)?;
+            samples.push(start.elapsed());
+        }
+        samples.sort();
+        println!(
+            "latency:  min {:.1} ms, median {:.1} ms, max {:.1} ms ({} requests)",
+            millis(samples[0]),
+            millis(samples[samples.len() / 2]),
+            millis(samples[samples.len() - 1]),
+            samples.len()
+        );
+
+        // Downloads need something to read, so the file is written either
+        // way and only reported when uploads were asked for
+        let block = vec![0x5a; BENCH_BLOCK_SIZE];
+        let start = Instant::now();
+        let handle = self
+            .protocol
+            .open(path, SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC)?;
+        let mut written = 0;
+        let result = loop {
+            if written >= size {
+                break Ok(());
+            }
+            let length = (size - written).min(block.len() as u64) as usize;
+            match self
+                .protocol
+                .write_all_at(&handle, written, &block[..length])
+            {
+                Ok(bytes) => written += bytes,
+                Err(e) => break Err(e),
+            }
+        };
+        self.protocol.close(handle)?;
+        result?;
+        if direction.uploads() {
+            print_throughput("upload:  ", written, start.elapsed());
+        }
+
+        if direction.downloads() {
+            let start = Instant::now();
+            let handle = self.protocol.open(path, SSH_FXF_READ)?;
+            let result = self.protocol.read_to_writer(&handle, &mut std::io::sink());
+            self.protocol.close(handle)?;
+            print_throughput("download:", result?, start.elapsed());
+        }
+
+        println!(
+            "settings: chunk size {} bytes, up to {} requests in flight",
+            self.protocol.chunk_size(),
+            self.protocol.max_in_flight()
+        );
+        Ok(())
+    }
+
+    fn handle_queue(&mut self, action: &QueueAction) -> Result<(), SftpError> {
+        match action {
+            QueueAction::Add {
+                priority,
+                transfer,
+                description,
+            } => {
+                let id = self
+                    .queue
+                    .push(*priority, (**transfer).clone(), description.clone());
+                println!("Queued #{} ({})", id, priority.name());
+                Ok(())
+            }
+            QueueAction::List => {
+                for job in self.queue.pending() {
+                    let state = match (job.paused, job.offset) {
+                        (false, _) => String::new(),
+                        (true, 0) => " (paused)".to_string(),
+                        (true, offset) => format!(" (paused at byte {})", offset),
+                    };
+                    println!(
+                        "#{:<4} {:<7} {}{}",
+                        job.id,
+                        job.priority.name(),
+                        job.description,
+                        state
+                    );
+                }
+                Ok(())
+            }
+            QueueAction::Pause(id) => self.queue.set_paused(*id, true),
+            QueueAction::Resume(id) => self.queue.set_paused(*id, false),
+            QueueAction::Run => {
+                // Like get with several files: keep going, report the first error
+                let mut first_error = None;
+                while let Some(mut job) = self.queue.pop_next() {
+                    println!("#{} {}", job.id, job.description);
+                    let result = match &job.command {
+                        SftpCommand::Get {
+                            remote_path,
+                            local_path,
+                            policy,
+                        } => self.get_with_retries(
+                            remote_path,
+                            local_path.as_ref(),
+                            job.offset,
+                            policy,
+                        ),
+                        SftpCommand::Put {
+                            local_path,
+                            remote_path,
+                            policy,
+                            ..
+                        } if job.offset > 0 => {
+                            self.put_file(local_path, remote_path.as_ref(), PutMode::Resume, policy)
+                        }
+                        command => self.execute_command(command).map(|_| ()),
+                    };
+
+                    let Err(e) = result else { continue };
+
+                    // Pausing keeps the job, at the offset reached if it is
+                    // a single file, and leaves the rest of the queue for the
+                    // next run
+                    if e.is_paused() {
+                        if let (
+                            SftpError::PartialTransfer { transferred, .. },
+                            SftpCommand::Get { .. } | SftpCommand::Put { .. },
+                        ) = (&e, &job.command)
+                        {
+                            job.offset += transferred;
+                        }
+                        job.paused = true;
+                        println!("#{} paused at byte {}", job.id, job.offset);
+                        self.queue.requeue(job);
+                        break;
+                    }
+                    println!("#{}: {}", job.id, e);
+
+                    // An interrupted download keeps its place so that resuming
+                    // it continues from the saved offset
+                    if let (
+                        SftpError::PartialTransfer { transferred, .. },
+                        SftpCommand::Get { .. },
+                    ) = (&e, &job.command)
+                    {
+                        job.offset += transferred;
+                        job.paused = true;
+                        println!("#{} paused at byte {}", job.id, job.offset);
+                        self.queue.requeue(job);
+                    }
+                    first_error.get_or_insert(e);
+                }
+
+                match first_error {
+                    Some(e) => Err(e),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    fn change_directory(&mut self, path: Option<&PathBuf>) -> Result<(), SftpError> {
+        let target_path = match path {
+            Some(p) => self.resolve_path(p),
+            None => self.working_dir.clone(),
+        };
+
+        let path_str = target_path
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        let attrs = self.protocol.stat(path_str)?;
+        if !attrs.is_directory {
+            return Err(SftpError::NotADirectory(path_str.to_string()));
+        }
+
+        self.working_dir = PathBuf::from(path_str);
+        self.current_listing = Arc::new(Vec::new());
+        Ok(())
+    }
+
+    fn change_setting(&mut self, key: Option<&str>, value: Option<&str>) -> Result<(), SftpError> {
+        match (key, value) {
+            (Some(key), Some(value)) => {
+                let chunk_size = self.settings.chunk_size;
+                self.settings.apply(key, value)?;
+                self.protocol.set_timeout(self.settings.retry.timeout);
+                if key == "chunk-size" {
+                    // Larger READs would draw replies over --max-packet-size
+                    if self.settings.chunk_size > Some(self.protocol.max_chunk_size()) {
+                        self.settings.chunk_size = chunk_size;
+                        return Err(SftpError::InvalidCommand(
+                            "Expected a chunk size whose replies fit in --max-packet-size",
+                        ));
+                    }
+                    self.set_chunk_size(self.settings.chunk_size);
+                }
+                Ok(())
+            }
+            _ => {
+                for (key, value) in self.settings.entries() {
+                    println!("{} = {}", key, value);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn print_working_directory(&self) -> Result<(), SftpError> {
+        print!("{}", self.working_dir.display());
+        Ok(())
+    }
+
+    /// Copies a remote file to another remote path. Servers with copy-data
+    /// do it themselves; otherwise, or when they turn it down, the data is
+    /// read and written back through the client, which costs a round trip
+    /// over the network both ways.
+    fn copy(&mut self, source: &Path, destination: &Path) -> Result<(), SftpError> {
+        let source = self.resolve_path(source);
+        let destination = self.resolve_path(destination);
+
+        let source_str = source
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+        let destination_str = destination
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        // Opening the destination truncates it, which would empty a source
+        // that is the same file
+        if self.same_remote_file(source_str, destination_str) {
+            return Err(SftpError::InvalidCommand(
+                "Source and destination are the same file",
+            ));
+        }
+
+        let read_handle = self.protocol.open(source_str, SSH_FXF_READ)?;
+        let write_handle = match self.protocol.open(
+            destination_str,
+            SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC,
+        ) {
+            Ok(handle) => handle,
+            Err(e) => {
+                self.protocol.close(read_handle)?;
+                return Err(e);
+            }
+        };
+
+        let result = match self.protocol.copy_data(&read_handle, &write_handle) {
+            Err(SftpError::UnsupportedExtension(_)) => {
+                self.copy_through_client(&read_handle, &write_handle)
+            }
+            // Some servers advertise copy-data but refuse it for particular
+            // files, such as ones on different filesystems
+            Err(SftpError::ServerError { code, .. })
+                if code == SftpStatus::OpUnsupported as u32 =>
+            {
+                self.copy_through_client(&read_handle, &write_handle)
+            }
+            result => result,
+        };
+        self.protocol.close(read_handle)?;
+        self.protocol.close(write_handle)?;
+
+        let (parent, _) = remote_path::split(destination_str);
+        self.directory_cache.remove(Path::new(parent));
+        result
+    }
+
+    // Whether `source` and `destination` name the same file once the server
+    // has resolved `..` and symlinks. A destination that doesn't exist yet,
+    // or can't be resolved, is taken to be a different file
+    fn same_remote_file(&mut self, source: &str, destination: &str) -> bool {
+        if source == destination {
+            return true;
+        }
+        match (
+            self.protocol.realpath(source),
+            self.protocol.realpath(destination),
+        ) {
+            (Ok(source), Ok(destination)) => source == destination,
+            _ => false,
+        }
+    }
+
+    fn copy_through_client(
+        &mut self,
+        read_handle: &[u8],
+        write_handle: &[u8],
+    ) -> Result<(), SftpError> {
+        let mut offset = 0;
+        loop {
+            let block = self
+                .protocol
+                .read_range(read_handle, offset, COPY_BLOCK_SIZE)?;
+            if block.is_empty() {
+                return Ok(());
+            }
+            offset += self.protocol.write_all_at(write_handle, offset, &block)?;
+        }
+    }
+
+    /// Copies `source` on this session to `destination` on another, such as
+    /// a second server. The data passes through the client, with this
+    /// session's READs kept in flight on a helper thread while the other
+    /// session's WRITEs are pipelined from what has arrived. Returns the
+    /// number of bytes copied.
+    pub fn transfer_to<U: TransportLayer>(
+        &mut self,
+        source: &Path,
+        target: &mut SftpClient<U>,
+        destination: &Path,
+    ) -> Result<u64, SftpError> {
+        let source = self.resolve_path(source);
+        let destination = target.resolve_path(destination);
+
+        let source_str = source
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+        let destination_str = destination
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        let read_handle = self.protocol.open(source_str, SSH_FXF_READ)?;
+        let write_handle = match target.protocol.open(
+            destination_str,
+            SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC,
+        ) {
+            Ok(handle) => handle,
+            Err(e) => {
+                self.protocol.close(read_handle)?;
+                return Err(e);
+            }
+        };
+
+        let (sender, receiver) = mpsc::sync_channel(TRANSFER_PIPE_CHUNKS);
+        let protocol = &mut self.protocol;
+        let (read, written) = thread::scope(|scope| {
+            let reader =
+                scope.spawn(|| protocol.read_to_writer(&read_handle, &mut PipeWriter(sender)));
+            let written = target
+                .protocol
+                .write_from_reader(&write_handle, PipeReader::new(receiver));
+            let read = reader
+                .join()
+                .unwrap_or_else(|_| Err(SftpError::ClientError("Transfer thread panicked".into())));
+            (read, written)
+        });
+        self.protocol.close(read_handle)?;
+        target.protocol.close(write_handle)?;
+
+        let (parent, _) = remote_path::split(destination_str);
+        target.directory_cache.remove(Path::new(parent));
+        // A failed write breaks the pipe under the reader, so its error comes
+        // first. A failed read ends the pipe early, which the writer cannot
+        // tell from the end of the file.
+        let written = written?;
+        read?;
+        Ok(written)
+    }
+
+    /// Opens another session with `ssh -s <destination> sftp`.
+    fn connect(&mut self, destination: &str) -> Result<(), SftpError> {
+        let transport = ProcessTransport::ssh(destination, SFTP_SUPPORTED_VERSION)?;
+        let mut client = SftpClient::new(Box::new(transport) as Box<dyn TransportLayer>, None)?;
+        if self.settings.chunk_size.is_some() {
+            client.set_chunk_size(self.settings.chunk_size);
+        }
+
+        let host = ProcessTransport::host(destination);
+        let name = self.add_session(host, client);
+        println!("Connected to {} as {}", host, name);
+        Ok(())
+    }
+
+    // The index in `sessions` of the session called `name`, or `None` for
+    // this client's own
+    fn find_session(&self, name: &str) -> Result<Option<usize>, SftpError> {
+        if name == "session1" || self.host.as_deref() == Some(name) {
+            return Ok(None);
+        }
+        self.sessions
+            .iter()
+            .position(|peer| peer.name == name || peer.host == name)
+            .map(Some)
+            .ok_or_else(|| SftpError::ClientError(format!("No session named {}", name).into()))
+    }
+
+    /// Streams `source` to `destination`, each on whichever open session it
+    /// names, reading from one server while writing to the other.
+    fn transfer(
+        &mut self,
+        source: &SessionPath,
+        destination: &SessionPath,
+    ) -> Result<(), SftpError> {
+        let from = match &source.session {
+            Some(name) => self.find_session(name)?,
+            None => None,
+        };
+        let to = match &destination.session {
+            Some(name) => self.find_session(name)?,
+            None => None,
+        };
+
+        // Lent out so that this client and a peer can both be borrowed
+        let mut peers = std::mem::take(&mut self.sessions);
+        let result = match (from, to) {
+            (None, Some(to)) => {
+                self.transfer_into(&source.path, &mut peers[to].client, &destination.path)
+            }
+            (Some(from), None) => {
+                peers[from]
+                    .client
+                    .transfer_into(&source.path, self, &destination.path)
+            }
+            (Some(from), Some(to)) if from != to => {
+                let [source_peer, target_peer] = peers
+                    .get_disjoint_mut([from, to])
+                    .expect("two different sessions");
+                source_peer.client.transfer_into(
+                    &source.path,
+                    &mut target_peer.client,
+                    &destination.path,
+                )
+            }
+            _ => Err(SftpError::InvalidCommand(
+                "Source and destination are on the same session; use cp",
+            )),
+        };
+        self.sessions = peers;
+        result.map(|_| ())
+    }
+
+    // Like `transfer_to`, but into `destination` when that is a directory
+    fn transfer_into<U: TransportLayer>(
+        &mut self,
+        source: &Path,
+        target: &mut SftpClient<U>,
+        destination: &Path,
+    ) -> Result<u64, SftpError> {
+        let destination = match target.stat(destination) {
+            Ok(attrs) if attrs.is_directory => {
+                let source = self.resolve_path(source).to_string_lossy().into_owned();
+                let (_, name) = remote_path::split(&source);
+                destination.join(name)
+            }
+            _ => destination.to_path_buf(),
+        };
+        self.transfer_to(source, target, &destination)
+    }
+
+    fn make_dir(&mut self, path: &Path) -> Result<(), SftpError> {
+        let target_path = self.resolve_path(path);
+
+        let path_str = target_path
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        let result = match self.protocol.mkdir(path_str) {
+            // As with rename, v3 servers give no specific code for this
+            Err(SftpError::ServerError { code, .. })
+                if code == SftpStatus::FileAlreadyExists as u32
+                    || code == SftpStatus::Failure as u32
+                        && self.protocol.stat(path_str).is_ok() =>
+            {
+                Err(SftpError::AlreadyExists(path_str.to_string()))
+            }
+            result => result,
+        };
+
+        let (parent, _) = remote_path::split(path_str);
+        self.directory_cache.remove(Path::new(parent));
+        result
+    }
+
+    fn remove_dir(&mut self, path: &Path) -> Result<(), SftpError> {
+        let target_path = self.resolve_path(path);
+
+        let path_str = target_path
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        let result = match self.protocol.rmdir(path_str) {
+            // v3 has no status for a directory that still has entries, so a
+            // generic failure on a directory that is still there means that
+            Err(SftpError::ServerError { code, .. })
+                if code == SftpStatus::DirNotEmpty as u32
+                    || code == SftpStatus::Failure as u32
+                        && self
+                            .protocol
+                            .stat(path_str)
+                            .is_ok_and(|attrs| attrs.is_directory) =>
+            {
+                Err(SftpError::DirectoryNotEmpty(path_str.to_string()))
+            }
+            result => result,
+        };
+
+        let (parent, _) = remote_path::split(path_str);
+        self.directory_cache.remove(Path::new(parent));
+        self.directory_cache.remove(&target_path);
+        result
+    }
+
+    fn remove(&mut self, path: &Path, recursive: bool) -> Result<(), SftpError> {
+        let target_path = self.resolve_path(path);
+
+        let path_str = target_path
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        let result = match self.protocol.remove(path_str) {
+            Err(SftpError::ServerError { code, .. })
+                if code != SftpStatus::NoSuchFile as u32
+                    && self
+                        .protocol
+                        .stat(path_str)
+                        .is_ok_and(|attrs| attrs.is_directory) =>
+            {
+                match recursive {
+                    true => self.remove_tree(path_str).map(|(files, dirs)| {
+                        println!("Removed {} file(s) and {} directory(ies)", files, dirs)
+                    }),
+                    false => Err(SftpError::ClientError(
+                        format!("{} is a directory; use rm -r or rmdir", path_str).into(),
+                    )),
+                }
+            }
+            result => result,
+        };
+
+        let (parent, _) = remote_path::split(path_str);
+        self.directory_cache.remove(Path::new(parent));
+        result
+    }
+
+    /// Deletes `dir` and everything below it, depth first, returning how
+    /// many files and directories went. Symlinks are removed, not followed.
+    fn remove_tree(&mut self, dir: &str) -> Result<(usize, usize), SftpError> {
+        let handle = self.protocol.open_dir(dir)?;
+        let files = self.read_entire_directory(&handle, |_| {});
+        self.protocol.close(handle)?;
+
+        let (mut removed_files, mut removed_dirs) = (0, 0);
+        for file in files? {
+            if file.name == "." || file.name == ".." {
+                continue;
+            }
+            let path = remote_path::join(dir, &file.name);
+            if file.attrs.is_directory {
+                let (files, dirs) = self.remove_tree(&path)?;
+                removed_files += files;
+                removed_dirs += dirs;
+            } else {
+                self.protocol.remove(&path)?;
+                removed_files += 1;
+            }
+        }
+
+        self.protocol.rmdir(dir)?;
+        self.directory_cache.remove(Path::new(dir));
+        Ok((removed_files, removed_dirs + 1))
+    }
+
+    fn rename(
+        &mut self,
+        old_path: &Path,
+        new_path: &Path,
+        overwrite: bool,
+    ) -> Result<(), SftpError> {
+        let old_target = self.resolve_path(old_path);
+        let new_target = self.resolve_path(new_path);
+
+        let old_str = old_target
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+        let new_str = new_target
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        let result = match overwrite {
+            true => self.protocol.posix_rename(old_str, new_str),
+            false => match self.protocol.rename(old_str, new_str) {
+                // v3 servers report an existing target as a generic failure,
+                // so check whether that is what happened
+                Err(SftpError::ServerError { code, .. })
+                    if code == SftpStatus::FileAlreadyExists as u32
+                        || code == SftpStatus::Failure as u32
+                            && self.protocol.stat(new_str).is_ok() =>
+                {
+                    Err(SftpError::AlreadyExists(new_str.to_string()))
+                }
+                result => result,
+            },
+        };
+
+        // Both parents' listings change, and a renamed directory takes any
+        // cached listings below it along to the new name
+        for path in [old_str, new_str] {
+            let (parent, _) = remote_path::split(path);
+            self.directory_cache.remove(Path::new(parent));
+        }
+        self.directory_cache
+            .retain(|cached, _| !cached.starts_with(&old_target));
+        result
+    }
+
+    fn chmod(&mut self, mode: u32, path: &Path) -> Result<(), SftpError> {
+        let target_path = self.resolve_path(path);
+
+        let path_str = target_path
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        println!("Changing mode on {}", path_str);
+        let attrs = FileAttributes {
+            permissions: Some(mode),
+            ..Default::default()
+        };
+        let result = self.protocol.setstat(path_str, attrs);
+        let (parent, _) = remote_path::split(path_str);
+        self.directory_cache.remove(Path::new(parent));
+        result
+    }
+
+    /// Sets `mode` on `path` and everything below it, pipelining the
+    /// requests, then says how many entries were changed and how many
+    /// failed. Symbolic links inside the tree are left alone, as setting a
+    /// mode through one would change whatever it points at.
+    fn chmod_recursive(&mut self, mode: u32, path: &Path) -> Result<(), SftpError> {
+        let target_path = self.resolve_path(path);
+
+        let path_str = target_path
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        let mut paths = vec![path_str.to_string()];
+        let mut failures = Vec::new();
+        if self.protocol.stat(path_str)?.is_directory {
+            let mut pending = vec![path_str.to_string()];
+            while let Some(dir) = pending.pop() {
+                let files = match self.protocol.open_dir(&dir) {
+                    Ok(handle) => {
+                        let files = self.read_entire_directory(&handle, |_| {});
+                        self.protocol.close(handle)?;
+                        files
+                    }
+                    Err(e) => Err(e),
+                };
+                let files = match files {
+                    Ok(files) => files,
+                    Err(e) => {
+                        failures.push((dir, e));
+                        continue;
+                    }
+                };
+                for file in files {
+                    if file.name == "." || file.name == ".." || file.attrs.is_symlink {
+                        continue;
+                    }
+                    let child = remote_path::join(&dir, &file.name);
+                    if file.attrs.is_directory {
+                        pending.push(child.clone());
+                    }
+                    paths.push(child);
+                }
+            }
+        }
+
+        // Contents go before the directory holding them, so a mode that
+        // takes away search permission does not lock out the rest
+        paths.reverse();
+        let attrs = FileAttributes {
+            permissions: Some(mode),
+            ..Default::default()
+        };
+        let results = self.protocol.setstat_all(&paths, &attrs)?;
+        let mut changed = 0;
+        for (path, result) in paths.into_iter().zip(results) {
+            match result {
+                Ok(()) => changed += 1,
+                Err(e) => failures.push((path, e)),
+            }
+        }
+
+        self.directory_cache
+            .retain(|dir, _| !dir.starts_with(&target_path));
+        let (parent, _) = remote_path::split(path_str);
+        self.directory_cache.remove(Path::new(parent));
+
+        for (path, e) in &failures {
+            println!("{}: {}", terminal::escape(path), e);
+        }
+        println!(
+            "Mode {:04o} set on {} entries, {} failed",
+            mode,
+            changed,
+            failures.len()
+        );
+        match failures.into_iter().next() {
+            Some((_, e)) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Creates `path` as an empty file, or if it already exists, sets its
+    /// access and modification times to now.
+    fn touch(&mut self, path: &Path) -> Result<(), SftpError> {
+        let target_path = self.resolve_path(path);
+
+        let path_str = target_path
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        // CREAT|EXCL never truncates a file someone else created meanwhile
+        let pflags = SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_EXCL;
+        let result = match self.protocol.open(path_str, pflags) {
+            Ok(handle) => self.protocol.close(handle),
+            // As with mkdir, v3 servers give no specific code for this
+            Err(SftpError::ServerError { code, .. })
+                if code == SftpStatus::FileAlreadyExists as u32
+                    || code == SftpStatus::Failure as u32
+                        && self.protocol.stat(path_str).is_ok() =>
+            {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_secs() as u32);
+                let attrs = FileAttributes {
+                    access_time: Some(now),
+                    modify_time: Some(now),
+                    ..Default::default()
+                };
+                self.protocol.setstat(path_str, attrs)
+            }
+            Err(e) => Err(e),
+        };
+
+        let (parent, _) = remote_path::split(path_str);
+        self.directory_cache.remove(Path::new(parent));
+        result
+    }
+
+    fn symlink(&mut self, target: &str, link_path: &Path) -> Result<(), SftpError> {
+        let link_target = self.resolve_path(link_path);
+
+        let link_str = link_target
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        let result = self.protocol.symlink(target, link_str);
+        let (parent, _) = remote_path::split(link_str);
+        self.directory_cache.remove(Path::new(parent));
+        result
+    }
+
+    fn hardlink(&mut self, target: &Path, link_path: &Path) -> Result<(), SftpError> {
+        let target = self.resolve_path(target);
+        let link_target = self.resolve_path(link_path);
+
+        let target_str = target
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+        let link_str = link_target
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        let result = self.protocol.hardlink(target_str, link_str);
+        let (parent, _) = remote_path::split(link_str);
+        self.directory_cache.remove(Path::new(parent));
+        result
+    }
+
+    fn print_link_target(&mut self, path: &Path) -> Result<(), SftpError> {
+        let target_path = self.resolve_path(path);
+
+        let path_str = target_path
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        println!("{}", terminal::escape(&self.protocol.readlink(path_str)?));
+        Ok(())
+    }
+
+    /// Prints what the server resolves `path` to. Other commands collapse
+    /// `..` themselves before asking the server, which differs from the
+    /// server's answer when `..` follows a symlink, so that case is pointed
+    /// out.
+    fn print_real_path(&mut self, path: &Path) -> Result<(), SftpError> {
+        let path = path.to_string_lossy();
+        // Sent as typed, only made absolute, so the server sees every `..`
+        let request = match remote_path::is_absolute(&path) {
+            true => path.to_string(),
+            false => format!(
+                "{}/{}",
+                self.working_dir.to_string_lossy().trim_end_matches('/'),
+                path
+            ),
+        };
+
+        let resolved = self.protocol.realpath(&request)?;
+        println!("{}", terminal::escape(&resolved));
+        let lexical = remote_path::join(&self.working_dir.to_string_lossy(), &path);
+        if remote_path::normalize(&resolved) != lexical {
+            println!(
+                "(other commands take {} to mean {})",
+                terminal::escape(&path),
+                terminal::escape(&lexical)
+            );
+        }
+        Ok(())
+    }
+
+    /// Prints attributes of `path`, or with `follow`, of whatever a symlink
+    /// there points at.
+    fn stat_file(&mut self, path: &Path, follow: bool) -> Result<(), SftpError> {
+        let target_path = self.resolve_path(path);
+
+        let path_str = target_path
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        let attrs = match follow {
+            true => self.protocol.stat(path_str)?,
+            false => self.protocol.lstat(path_str)?,
+        };
+
+        match attrs.is_symlink {
+            true => println!(
+                "  File: {} -> {}",
+                path_str,
+                terminal::escape(&self.protocol.readlink(path_str)?)
+            ),
+            false => println!("  File: {}", path_str),
+        }
+        println!("  Type: {}", attrs.file_type.description());
+        match attrs.size {
+            Some(size) if size >= 1024 => println!("  Size: {} ({})", size, format_bytes(size)),
+            Some(size) => println!("  Size: {}", size),
+            None => {}
+        }
+        if let Some(perms) = attrs.permissions {
+            println!(
+                "Access: ({:04o}/{})",
+                perms & 0o7777,
+                attrs.permissions_string()
+            );
+        }
+        if let (Some(uid), Some(gid)) = (attrs.uid, attrs.gid) {
+            println!(" Owner: uid={} gid={}", uid, gid);
+        }
+        if let (Some(owner), Some(group)) = (&attrs.owner, &attrs.group) {
+            println!(
+                " Owner: {} group={}",
+                terminal::escape(owner),
+                terminal::escape(group)
+            );
+        }
+        if let Some(atime) = attrs.access_time {
+            println!("Access: {}", format_timestamp(atime.into()));
+        }
+        if let Some(mtime) = attrs.modify_time {
+            println!("Modify: {}", format_timestamp(mtime.into()));
+        }
+        for (name, value) in &attrs.extended {
+            println!(
+                "Extended: {} = {}",
+                terminal::escape(name),
+                terminal::escape(&String::from_utf8_lossy(value))
+            );
+        }
+        Ok(())
+    }
+
+    fn disk_free(&mut self, path: Option<&Path>, human: bool) -> Result<(), SftpError> {
+        let target_path = match path {
+            Some(p) => self.resolve_path(p),
+            None => self.working_dir.clone(),
+        };
+
+        let path_str = target_path
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        let usage = self.protocol.statvfs(path_str)?;
+        let size = |bytes: u64| match human {
+            true => format_bytes(bytes),
+            false => (bytes / 1024).to_string(),
+        };
+
+        println!(
+            "{:>12} {:>12} {:>12} {:>5}",
+            if human { "Size" } else { "1K-blocks" },
+            "Used",
+            "Avail",
+            "Use%"
+        );
+        println!(
+            "{:>12} {:>12} {:>12} {:>4}%",
+            size(usage.total_bytes()),
+            size(usage.used_bytes()),
+            size(usage.available_bytes()),
+            usage.capacity_percent()
+        );
+        Ok(())
+    }
+
+    fn show_version(&self) -> Result<(), SftpError> {
+        let transport = self.protocol.transport();
+
+        println!("SFTP protocol version: {}", transport.version());
+        println!(
+            "Server banner: {}",
+            transport.server_banner().unwrap_or("unknown")
+        );
+
+        let extensions = transport.extensions();
+        if extensions.is_empty() {
+            println!("Extensions: none");
+        } else {
+            println!("Extensions:");
+            for (name, data) in extensions {
+                println!("  {} ({})", terminal::escape(name), terminal::escape(data));
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the extensions the server advertised, one `name data` pair per
+    /// line so scripts can check for a feature.
+    fn show_extensions(&self) -> Result<(), SftpError> {
+        for (name, data) in self.protocol.transport().extensions() {
+            println!("{} {}", terminal::escape(name), terminal::escape(data));
+        }
+        Ok(())
+    }
+
+    fn show_stats(&self) -> Result<(), SftpError> {
+        let stats = self.stats();
+        let uptime = stats.uptime().as_secs();
+
+        println!(
+            "Uptime: {}h {}m {}s",
+            uptime / 3600,
+            (uptime / 60) % 60,
+            uptime % 60
+        );
+        println!("Requests sent: {}", stats.requests);
+        println!("Bytes downloaded: {}", stats.bytes_received);
+        println!("Bytes uploaded: {}", stats.bytes_sent);
+        println!("Directory cache hits: {}", stats.cache_hits);
+        println!("Open handles: {}", stats.open_handles);
+        Ok(())
+    }
+
+    fn show_help(&self) -> Result<(), SftpError> {
+        println!("Available commands:\nls - list files in current directory (-S, -t, -r, --group-directories-first)\ntree - show a remote directory tree (-L <depth> to limit it)\ncd - change current directory\nget - download file (-r for a directory, -r --archive out.tar.gz or out.zip to pack it into an archive, -r skips paths listed in .ferricignore or --ignore-file, -r --journal <file> to resume an interrupted one, --resume or reget to continue a partial one, --offset/--length for part of it, --timeout/--retries to override the policy, --skip/--newer-only/--prompt/--overwrite for existing files)\nmget - download every file matching a wildcard pattern (same existing-file flags as get)\nput - upload file (--exclusive to fail if it exists, --resume or reput to continue a partial one, --skip/--newer-only/--prompt/--overwrite for existing files)\nmput - upload every local file matching a wildcard pattern (same existing-file flags as put)\nrename - rename a file (--overwrite to replace the target)\ncp - copy a remote file to another remote path\nconnect - open another session (ssh destination), listed by sessions and closed with close\nrget/transfer - stream a file between sessions (rget session2:/path [dest], transfer host1:/a host2:/b)\nmkdir - create a remote directory\nrmdir - remove an empty remote directory\nrm - remove a remote file (-r to remove a directory and its contents)\nchmod - change the permissions of a remote file (-R for a whole tree)\ntouch - create an empty remote file, or set an existing one's times to now\nln - create a hard link (-s for a symbolic link)\nreadlink - show where a symbolic link points\nverify - compare a local tree with a remote one\nsync - copy only new or changed files (--up local remote, --down remote local, --delete to mirror, --checksum to compare contents, --ignore-file in place of .ferricignore, --journal <file> to resume, --report <file> for a JSON report, --both local remote to sync both ways with --conflict newer-wins|larger-wins|local-wins|remote-wins|keep-both|rename-both|prompt)\nwatch - upload local changes as they happen (--delete to remove deleted files)\nchecksum - print the SHA-256 of a remote file\ngrep - search remote files for a regex\ncat - print a remote file to stdout (also get <remote> -)\ntail - show the end of a remote file (-f to follow it)\nsnapshot - save a remote tree listing, or diff the server against one\nopen - download a file and open it with the default application\nbench - measure throughput and latency (--size 100M, --direction up|down|both)\nqueue - queue transfers (add [--priority high] get|put ...), list or run them (Ctrl-C pauses the running one)\npause/resume - hold or release a queued transfer\nstat - show file attributes (-L to follow a symlink)\ndf - show free space on the remote filesystem (-h for readable sizes)\nrealpath - resolve a path on the server\nset - show or change a setting\nversion - show negotiated protocol details\nextensions - list protocol extensions the server supports\nstats - show session counters\nbye - exit");
+        Ok(())
+    }
+
+    fn put_file(
+        &mut self,
+        local_path: &Path,
+        remote_path: Option<&PathBuf>,
+        mode: PutMode,
+        policy: &PolicyOverride,
+    ) -> Result<(), SftpError> {
+        let target_path = match remote_path {
+            Some(path) => self.resolve_path(path),
+            None => {
+                let file_name = local_path
+                    .file_name()
+                    .ok_or(SftpError::InvalidCommand("No filename in local path"))?;
+                self.resolve_path(&PathBuf::from(file_name))
+            }
+        };
+
+        let path_str = target_path
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        if mode == PutMode::Replace {
+            let transfer = Transfer::Upload {
+                local_path: local_path.to_path_buf(),
+                remote_path: path_str.to_string(),
+                size: None,
+                modify_time: None,
+            };
+            if !self.may_overwrite(&transfer, policy)? {
+                return Ok(());
+            }
+        }
+
+        let result = match mode {
+            PutMode::Resume => self.resume_upload(local_path, path_str),
+            mode => self.upload(local_path, path_str, mode == PutMode::Exclusive),
+        };
+        if let (Err(e), Some(progress)) = (&result, self.progress.as_mut()) {
+            progress.error(path_str, &e.to_string());
+        }
+
+        // Any cached listing of the target directory is now stale
+        let (parent, _) = remote_path::split(path_str);
+        self.directory_cache.remove(Path::new(parent));
+        result
+    }
+
+    fn put_files(
+        &mut self,
+        local_paths: &[PathBuf],
+        remote_dir: &Path,
+        mode: PutMode,
+        policy: &PolicyOverride,
+    ) -> Result<(), SftpError> {
+        let target_dir = self.resolve_path(remote_dir);
+        let dir_str = target_dir
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?
+            .to_string();
+        if !self.protocol.stat(&dir_str)?.is_directory {
+            return Err(SftpError::NotADirectory(dir_str));
+        }
+
+        // Keep going after a failure, like cp, but report the first error
+        let mut first_error = None;
+        for local_path in local_paths {
+            let result = match local_path.file_name() {
+                Some(name) => {
+                    let target =
+                        PathBuf::from(remote_path::join(&dir_str, &name.to_string_lossy()));
+                    self.put_file(local_path, Some(&target), mode, policy)
+                }
+                None => Err(SftpError::InvalidCommand("No filename in local path")),
+            };
+            if let Err(e) = result {
+                println!("{}: {}", local_path.display(), e);
+                first_error.get_or_insert(e);
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Continues an upload from the size of the existing remote file, after
+    /// checking that what is already there matches the local file.
+    fn resume_upload(&mut self, local_path: &Path, remote_path: &str) -> Result<(), SftpError> {
+        let mut source = std::fs::File::open(local_path)?;
+        let size = source.metadata()?.len();
+
+        // Without TRUNC the bytes already uploaded are kept; CREAT lets a
+        // reput of a file that never got started upload it from scratch
+        let pflags = SSH_FXF_READ | SSH_FXF_WRITE | SSH_FXF_CREAT;
+        let file_handle = self.protocol.open(remote_path, pflags)?;
+
+        let offset = match self.protocol.fstat(&file_handle) {
+            Ok(attrs) => attrs.size.unwrap_or(0),
+            Err(e) => {
+                self.protocol.close(file_handle)?;
+                return Err(e);
+            }
+        };
+        if offset > 0 {
+            if let Err(e) = self.check_resume_tail(&file_handle, remote_path, local_path, offset) {
+                self.protocol.close(file_handle)?;
+                return Err(e);
+            }
+        }
+        source.seek(SeekFrom::Start(offset))?;
+
+        let remaining = Some(size.saturating_sub(offset));
+        if let Some(progress) = self.progress.as_mut() {
+            progress.start(remote_path, remaining);
+        }
+
+        let mut reader =
+            ProgressReader::new(source, self.progress.as_deref_mut(), remote_path, remaining);
+        let result = self
+            .protocol
+            .write_from_reader_at(&file_handle, offset, &mut reader);
+        let sent = reader.bytes_read();
+        self.protocol.close(file_handle)?;
+        let bytes = paused_upload(result, remote_path, sent)?;
+
+        if let Some(progress) = self.progress.as_mut() {
+            progress.done(remote_path, bytes);
+        }
+        Ok(())
+    }
+
+    /// Uploads every local regular file matching `patterns` into the remote
+    /// working directory, several at once when more channels can be opened.
+    fn put_matching(
+        &mut self,
+        patterns: &[PathBuf],
+        policy: &PolicyOverride,
+    ) -> Result<(), SftpError> {
+        let mut transfers = Vec::new();
+        for pattern in patterns {
+            let matches = filesystem::expand_glob(pattern, self.settings.case_insensitive)?;
+            if matches.is_empty() {
+                return Err(SftpError::NoMatches(pattern.display().to_string()));
+            }
+            for local_path in matches {
+                let file_name = local_path
+                    .file_name()
+                    .ok_or(SftpError::InvalidCommand("No filename in local path"))?;
+                let remote_path = self.resolve_path(Path::new(file_name));
+                transfers.push(Transfer::Upload {
+                    size: std::fs::metadata(&local_path).ok().map(|m| m.len()),
+                    local_path,
+                    remote_path: remote_path.to_string_lossy().into_owned(),
+                    modify_time: None,
+                });
+            }
+        }
+
+        self.run_transfers(transfers, policy, None).map(|_| ())
+    }
+
+    fn upload(
+        &mut self,
+        local_path: &Path,
+        remote_path: &str,
+        exclusive: bool,
+    ) -> Result<(), SftpError> {
+        let source = std::fs::File::open(local_path)?;
+        let size = source.metadata()?.len();
+        let mapped = match self.settings.mmap {
+            // SAFETY: as with any upload, the file is expected to stay put
+            // until it has been sent; one truncated meanwhile ends the process
+            true => Some(unsafe { filesystem::map_file(local_path)? }),
+            false => None,
+        };
+
+        // CREAT|EXCL makes the server refuse to open a file that already
+        // exists, which gives create-once semantics in a single round trip
+        let pflags = SSH_FXF_WRITE
+            | SSH_FXF_CREAT
+            | if exclusive {
+                SSH_FXF_EXCL
+            } else {
+                SSH_FXF_TRUNC
+            };
+        let file_handle = match self.protocol.open(remote_path, pflags) {
+            Err(SftpError::ServerError { code, .. })
+                if exclusive
+                    && (code == SftpStatus::FileAlreadyExists as u32
+                        || code == SftpStatus::Failure as u32
+                            && self.protocol.stat(remote_path).is_ok()) =>
+            {
+                return Err(SftpError::AlreadyExists(remote_path.to_string()));
+            }
+            result => result?,
+        };
+
+        if let Some(progress) = self.progress.as_mut() {
+            progress.start(remote_path, Some(size));
+        }
+
+        let progress = self.progress.as_deref_mut();
+        let (result, sent) = match &mapped {
+            Some(mapped) => {
+                let mut meter = Meter::new(progress, remote_path, Some(size));
+                let result = self
+                    .protocol
+                    .write_all_reporting(&file_handle, 0, mapped, |len| meter.add(len));
+                (result, meter.bytes())
+            }
+            None => {
+                let mut reader = ProgressReader::new(source, progress, remote_path, Some(size));
+                let result = self.protocol.write_from_reader(&file_handle, &mut reader);
+                (result, reader.bytes_read())
+            }
+        };
+        self.protocol.close(file_handle)?;
+        let bytes = paused_upload(result, remote_path, sent)?;
+
+        if let Some(progress) = self.progress.as_mut() {
+            progress.done(remote_path, bytes);
+        }
+        Ok(())
+    }
+
+    fn get_files(
+        &mut self,
+        remote_paths: &[PathBuf],
+        local_dir: &PathBuf,
+        policy: &PolicyOverride,
+    ) -> Result<(), SftpError> {
+        if !local_dir.is_dir() {
+            return Err(SftpError::InvalidCommand(
+                "Destination must be a directory when getting multiple files",
+            ));
+        }
+
+        // Keep going after a failure, like cp, but report the first error
+        let mut first_error = None;
+        for remote_path in remote_paths {
+            if let Err(e) = self.get_with_retries(remote_path, Some(local_dir), 0, policy) {
+                println!("{}: {}", remote_path.display(), e);
+                first_error.get_or_insert(e);
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Downloads every regular file matching the wildcards in the last
+    /// component of `pattern` into `local_dir`, several at once when more
+    /// channels can be opened.
+    fn get_matching(
+        &mut self,
+        pattern: &Path,
+        local_dir: Option<&PathBuf>,
+        policy: &PolicyOverride,
+    ) -> Result<(), SftpError> {
+        let local_dir = local_dir.cloned().unwrap_or_else(|| PathBuf::from("."));
+        if !local_dir.is_dir() {
+            return Err(SftpError::InvalidCommand(
+                "Destination must be a directory when getting multiple files",
+            ));
+        }
+
+        let mut transfers = Vec::new();
+        for remote_path in self.expand_remote_glob(pattern)? {
+            transfers.push(Transfer::Download {
+                local_path: local_target(Path::new(&remote_path), Some(&local_dir))?,
+                remote_path,
+                size: None,
+                modify_time: None,
+            });
+        }
+
+        self.run_transfers(transfers, policy, None).map(|_| ())
+    }
+
+    /// Downloads the remote directory `remote_path` and everything below it,
+    /// recreating the directory structure locally. Only regular files are
+    /// fetched; sockets, devices and the like are skipped with a warning, and
+    /// paths excluded by the ignore rules are skipped silently.
+    fn get_tree(
+        &mut self,
+        remote_path: &Path,
+        local_path: Option<&PathBuf>,
+        ignore_file: Option<&Path>,
+        journal: Option<&Path>,
+        policy: &PolicyOverride,
+    ) -> Result<(), SftpError> {
+        let target_path = self.resolve_path(remote_path);
+
+        let path_str = target_path
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        let local_root = local_target(&target_path, local_path)?;
+        let ignore = self.remote_ignore_rules(ignore_file, path_str)?;
+        let mut transfers = Vec::new();
+        let dirs = self.plan_tree(
+            path_str,
+            &local_root,
+            Path::new(""),
+            &ignore,
+            &mut transfers,
+        )?;
+        let journal = match journal {
+            Some(file) => Some(open_journal(
+                file,
+                &format!(
+                    "get -r {} -> {}",
+                    path_str,
+                    std::path::absolute(&local_root)?.display()
+                ),
+            )?),
+            None => None,
+        };
+        let files = self.run_transfers(transfers, policy, journal.as_ref())?;
+        if let Some(journal) = journal {
+            journal.finish()?;
+        }
+        println!("Downloaded {} file(s) in {} directory(ies)", files, dirs);
+        Ok(())
+    }
+
+    // Recreates `dir`, found at `relative` in the tree, and the directories
+    // below it under `local_dir`, adding a download for each regular file to
+    // `transfers`. Returns how many directories there were
+    fn plan_tree(
+        &mut self,
+        dir: &str,
+        local_dir: &Path,
+        relative: &Path,
+        ignore: &IgnoreRules,
+        transfers: &mut Vec<Transfer>,
+    ) -> Result<usize, SftpError> {
+        let handle = self.protocol.open_dir(dir)?;
+        let files = self.read_entire_directory(&handle, |_| {});
+        self.protocol.close(handle)?;
+        let files = files?;
+        std::fs::create_dir_all(local_dir)?;
+
+        let mut dirs = 1;
+        for file in files {
+            if file.name == "." || file.name == ".." {
+                continue;
+            }
+            let path = remote_path::join(dir, &file.name);
+            // A name with a separator in it could land outside the target
+            if file.name.contains('/') || file.name.contains(std::path::MAIN_SEPARATOR) {
+                println!("Skipping {}: invalid name", terminal::escape(&path));
+                continue;
+            }
+            let local = local_dir.join(&file.name);
+            let relative = relative.join(&file.name);
+            if ignore.is_ignored(&relative, file.attrs.is_directory) {
+                continue;
+            }
+
+            if file.attrs.is_directory {
+                dirs += self.plan_tree(&path, &local, &relative, ignore, transfers)?;
+            } else if file.attrs.is_regular_file {
+                transfers.push(Transfer::Download {
+                    remote_path: path,
+                    local_path: local,
+                    size: file.attrs.size,
+                    modify_time: None,
+                });
+            } else {
+                println!(
+                    "Skipping {}: {}",
+                    terminal::escape(&path),
+                    file.attrs.file_type.description()
+                );
+            }
+        }
+
+        Ok(dirs)
+    }
+
+    /// Downloads the tree under `remote_path` straight into a tar archive,
+    /// compressed when named `.tar.gz` or `.tgz`, or a zip archive when named
+    /// `.zip`, so a backup of many small
+    /// files never lands on disk as separate files. Entries go one at a
+    /// time, since the archive is a single stream.
+    fn get_archive(
+        &mut self,
+        remote_path: &Path,
+        archive: &Path,
+        ignore_file: Option<&Path>,
+        policy: &PolicyOverride,
+    ) -> Result<(), SftpError> {
+        let target_path = self.resolve_path(remote_path);
+        let path_str = target_path
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        let transfer = Transfer::Download {
+            remote_path: path_str.to_string(),
+            local_path: archive.to_path_buf(),
+            size: None,
+            modify_time: None,
+        };
+        if !self.may_overwrite(&transfer, policy)? {
+            return Ok(());
+        }
+        let attrs = self.protocol.stat(path_str)?;
+        if !attrs.is_directory {
+            return Err(SftpError::ClientError(
+                format!("{} is not a directory", path_str).into(),
+            ));
+        }
+
+        // Entries are named from the directory itself down, as tar does
+        let (_, name) = remote_path::split(path_str.trim_end_matches('/'));
+        let name = if name.is_empty() { "root" } else { name };
+        let ignore = self.remote_ignore_rules(ignore_file, path_str)?;
+        let mut writer = archive::create(archive)?;
+        let retry = self.settings.retry.with_override(policy);
+        self.protocol.set_timeout(retry.timeout);
+        let mut counts = (0, 0);
+        let result = self.archive_tree(path_str, name, &attrs, &ignore, &mut writer, &mut counts);
+        self.protocol.set_timeout(self.settings.retry.timeout);
+        // The archive is closed even after a failure, so what made it in
+        // can still be read
+        let finished = writer.finish();
+        result?;
+        finished?;
+
+        println!(
+            "Archived {} file(s) in {} directory(ies) into {}",
+            counts.0,
+            counts.1,
+            archive.display()
+        );
+        Ok(())
+    }
+
+    // Adds `dir` to `out` as `name`, then everything below it that is not
+    // ignored, counting the files and directories written into `counts`
+    fn archive_tree<W: Write + Seek>(
+        &mut self,
+        dir: &str,
+        name: &str,
+        attrs: &FileAttributes,
+        ignore: &IgnoreRules,
+        out: &mut ArchiveWriter<W>,
+        counts: &mut (usize, usize),
+    ) -> Result<(), SftpError> {
+        let handle = self.protocol.open_dir(dir)?;
+        let files = self.read_entire_directory(&handle, |_| {});
+        self.protocol.close(handle)?;
+        let files = files?;
+
+        out.append_dir(
+            name,
+            attrs.permissions.unwrap_or(0o755),
+            attrs.modify_time.unwrap_or(0) as u64,
+        )?;
+        counts.1 += 1;
+
+        for file in files {
+            if file.name == "." || file.name == ".." {
+                continue;
+            }
+            let path = remote_path::join(dir, &file.name);
+            if file.name.contains('/') {
+                println!("Skipping {}: invalid name", terminal::escape(&path));
+                continue;
+            }
+            let entry = format!("{}/{}", name, file.name);
+            // Entry names start with the archived directory's own name
+            let relative: PathBuf = Path::new(&entry).components().skip(1).collect();
+            if ignore.is_ignored(&relative, file.attrs.is_directory) {
+                continue;
+            }
+
+            if file.attrs.is_directory {
+                self.archive_tree(&path, &entry, &file.attrs, ignore, out, counts)?;
+            } else if file.attrs.is_regular_file {
+                self.archive_file(&path, &entry, &file.attrs, out)?;
+                counts.0 += 1;
+            } else {
+                println!(
+                    "Skipping {}: {}",
+                    terminal::escape(&path),
+                    file.attrs.file_type.description()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn archive_file<W: Write + Seek>(
+        &mut self,
+        path: &str,
+        entry: &str,
+        attrs: &FileAttributes,
+        out: &mut ArchiveWriter<W>,
+    ) -> Result<(), SftpError> {
+        let handle = self.protocol.open(path, SSH_FXF_READ)?;
+        // The header needs the size before any of the data
+        let size = match attrs.size {
+            Some(size) => Ok(size),
+            None => self
+                .protocol
+                .fstat(&handle)
+                .map(|attrs| attrs.size.unwrap_or(0)),
+        };
+        let result = size.and_then(|size| {
+            out.start_file(
+                entry,
+                size,
+                attrs.permissions.unwrap_or(0o644),
+                attrs.modify_time.unwrap_or(0) as u64,
+            )?;
+            if let Some(progress) = self.progress.as_mut() {
+                progress.start(path, Some(size));
+            }
+            let mut writer =
+                ProgressWriter::new(&mut *out, self.progress.as_deref_mut(), path, Some(size));
+            self.protocol
+                .read_range_to_writer(&handle, 0, size, &mut writer)
+        });
+        self.protocol.close(handle)?;
+        let bytes = result?;
+
+        let missing = out.end_file()?;
+        if missing > 0 {
+            println!(
+                "{}: file shrank while it was read, padded with {} zero byte(s)",
+                terminal::escape(path),
+                missing
+            );
+        }
+        if let Some(progress) = self.progress.as_mut() {
+            progress.done(path, bytes);
+        }
+        Ok(())
+    }
+
+    /// Runs `transfers` as `run_transfer_batch` does, returning the first
+    /// failure or else how many files the overwrite policy let through.
+    fn run_transfers(
+        &mut self,
+        planned: Vec<Transfer>,
+        policy: &PolicyOverride,
+        journal: Option<&Journal>,
+    ) -> Result<usize, SftpError> {
+        let outcome = self.run_transfer_batch(planned, policy, journal)?;
+        let count = outcome.len();
+        match outcome.into_iter().find_map(|(_, error)| error) {
+            Some(e) => Err(e),
+            None => Ok(count),
+        }
+    }
+
+    /// Runs `transfers` across up to `set parallel` channels at once, each
+    /// worker taking the next file as it finishes one, with their progress
+    /// reported as a single total. Without extra channels the files go one
+    /// at a time through the usual get and put. Failures are reported as
+    /// they happen and the rest carry on. Returns the files the overwrite
+    /// policy let through, each with the error it failed with, if any.
+    fn run_transfer_batch(
+        &mut self,
+        planned: Vec<Transfer>,
+        policy: &PolicyOverride,
+        journal: Option<&Journal>,
+    ) -> Result<Vec<(Transfer, Option<SftpError>)>, SftpError> {
+        let mut planned = planned;
+        if let Some(journal) = journal {
+            let before = planned.len();
+            planned.retain(|transfer| !journal.is_done(&transfer.journal_entry()));
+            if planned.len() < before {
+                println!(
+                    "Skipping {} file(s) the journal records as done",
+                    before - planned.len()
+                );
+            }
+        }
+
+        // Settle every existing destination up front, so prompts are not
+        // interleaved with transfer output
+        let mut pending = Vec::with_capacity(planned.len());
+        for transfer in planned {
+            if self.may_overwrite(&transfer, policy)? {
+                pending.push(transfer);
+            }
+        }
+        let transfers = &pending[..];
+        let policy = &PolicyOverride {
+            overwrite: Some(Overwrite::Replace),
+            ..*policy
+        };
+
+        let workers = self.settings.parallel.min(transfers.len());
+        let siblings = self.open_siblings(workers.saturating_sub(1));
+        if siblings.is_empty() {
+            let failures = self.run_transfers_in_turn(transfers, policy, journal);
+            return Ok(pair_failures(pending, failures));
+        }
+
+        let retry = self.settings.retry.with_override(policy);
+        info!(
+            "Transferring {} files over {} channels",
+            transfers.len(),
+            siblings.len() + 1
+        );
+
+        let label = format!("{} files", transfers.len());
+        let total = transfers.iter().map(Transfer::size).sum();
+        let progress = AggregateProgress::new(self.progress.as_deref_mut(), &label, total);
+        let next = AtomicUsize::new(0);
+
+        // Each worker pulls files off the shared list until none are left
+        let work = |protocol: &mut SftpProtocol<T>| {
+            protocol.set_timeout(retry.timeout);
+            let mut failures = Vec::new();
+            loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(transfer) = transfers.get(index) else {
+                    break;
+                };
+                println!("{}", transfer);
+                let mut attempt = 0;
+                let result = loop {
+                    match run_transfer(protocol, transfer, &progress) {
+                        Err(e) if attempt < retry.retries && e.is_transient() => attempt += 1,
+                        result => break result,
+                    }
+                }
+                .and_then(|()| journal.map_or(Ok(()), |j| j.record(&transfer.journal_entry())));
+                if let Err(e) = result {
+                    println!("{}: {}", transfer.source(), e);
+                    failures.push((index, e));
+                }
+            }
+            failures
+        };
+
+        let protocol = &mut self.protocol;
+        let (first, others) = thread::scope(|scope| {
+            let workers: Vec<_> = siblings
+                .into_iter()
+                .map(|mut sibling| {
+                    scope.spawn(move || {
+                        let failures = work(&mut sibling);
+                        (failures, sibling.stats().clone())
+                    })
+                })
+                .collect();
+            let first = work(protocol);
+            let others: Vec<_> = workers
+                .into_iter()
+                .map(|worker| worker.join().ok())
+                .collect();
+            (first, others)
+        });
+        self.protocol.set_timeout(self.settings.retry.timeout);
+        progress.finish();
+
+        let mut failures = first;
+        let mut panicked = false;
+        for worker in others {
+            let Some((errors, stats)) = worker else {
+                panicked = true;
+                continue;
+            };
+            let own = self.protocol.stats_mut();
+            own.bytes_received += stats.bytes_received;
+            own.bytes_sent += stats.bytes_sent;
+            own.requests += stats.requests;
+            failures.extend(errors);
+        }
+
+        // Any cached listing of a directory uploaded into is now stale
+        for transfer in transfers {
+            if let Transfer::Upload { remote_path, .. } = transfer {
+                let (parent, _) = remote_path::split(remote_path);
+                self.directory_cache.remove(Path::new(parent));
+            }
+        }
+
+        // A lost worker leaves no record of which of its files made it
+        if panicked {
+            return Err(SftpError::ClientError("Transfer thread panicked".into()));
+        }
+        Ok(pair_failures(pending, failures))
+    }
+
+    // Returns the index of every transfer that failed, with its error
+    fn run_transfers_in_turn(
+        &mut self,
+        transfers: &[Transfer],
+        policy: &PolicyOverride,
+        journal: Option<&Journal>,
+    ) -> Vec<(usize, SftpError)> {
+        // Keep going after a failure, like get with several files
+        let mut failures = Vec::new();
+        for (index, transfer) in transfers.iter().enumerate() {
+            println!("{}", transfer);
+            let result = match transfer {
+                Transfer::Download {
+                    remote_path,
+                    local_path,
+                    ..
+                } => self.get_with_retries(Path::new(remote_path), Some(local_path), 0, policy),
+                Transfer::Upload {
+                    local_path,
+                    remote_path,
+                    ..
+                } => self.put_file(
+                    local_path,
+                    Some(&PathBuf::from(remote_path)),
+                    PutMode::Replace,
+                    policy,
+                ),
+            }
+            .and_then(|()| transfer.stamp(&mut self.protocol))
+            .and_then(|()| journal.map_or(Ok(()), |j| j.record(&transfer.journal_entry())));
+            if let Err(e) = result {
+                println!("{}: {}", transfer.source(), e);
+                failures.push((index, e));
+            }
+        }
+        failures
+    }
+
+    /// Applies the overwrite policy to `transfer`, returning whether it
+    /// should go ahead. A destination that does not exist yet is always
+    /// written.
+    fn may_overwrite(
+        &mut self,
+        transfer: &Transfer,
+        policy: &PolicyOverride,
+    ) -> Result<bool, SftpError> {
+        let overwrite = policy.overwrite.unwrap_or(self.settings.overwrite);
+        if overwrite == Overwrite::Replace {
+            return Ok(true);
+        }
+
+        // Modification times of the source and the existing destination
+        let (source_time, destination_time, destination) = match transfer {
+            Transfer::Download {
+                remote_path,
+                local_path,
+                ..
+            } => {
+                let local = match std::fs::metadata(local_path) {
+                    Ok(metadata) => metadata,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+                    Err(e) => return Err(e.into()),
+                };
+                let remote_time = match overwrite {
+                    Overwrite::NewerOnly => self.protocol.stat(remote_path)?.modify_time,
+                    _ => None,
+                };
+                (
+                    remote_time.map(u64::from),
+                    modified_secs(&local),
+                    local_path.display().to_string(),
+                )
+            }
+            Transfer::Upload {
+                local_path,
+                remote_path,
+                ..
+            } => {
+                let remote = match self.protocol.stat(remote_path) {
+                    Ok(attrs) => attrs,
+                    Err(SftpError::ServerError { code, .. })
+                        if code == SftpStatus::NoSuchFile as u32 =>
+                    {
+                        return Ok(true)
+                    }
+                    Err(e) => return Err(e),
+                };
+                (
+                    modified_secs(&std::fs::metadata(local_path)?),
+                    remote.modify_time.map(u64::from),
+                    terminal::escape(remote_path).into_owned(),
+                )
+            }
+        };
+
+        let proceed = match overwrite {
+            Overwrite::Replace => true,
+            Overwrite::Skip => false,
+            // Without both times there is no telling which is newer
+            Overwrite::NewerOnly => matches!(
+                (source_time, destination_time),
+                (Some(source), Some(existing)) if source > existing
+            ),
+            Overwrite::Prompt => match self.confirm.as_mut() {
+                Some(confirm) => confirm(&format!("Overwrite {}?", destination)),
+                None => false,
+            },
+        };
+        if !proceed {
+            let reason = match overwrite {
+                Overwrite::NewerOnly => "existing file is not older",
+                _ => "already exists",
+            };
+            println!("Skipping {}: {}", destination, reason);
+        }
+        Ok(proceed)
+    }
+
+    /// Downloads only `range` of `remote_path`, such as the last 10M of a
+    /// large log, into a local file holding just those bytes. A failed
+    /// attempt that may be transient starts the range over.
+    fn get_range(
+        &mut self,
+        remote_path: &Path,
+        local_path: Option<&PathBuf>,
+        range: &ByteRange,
+        policy: &PolicyOverride,
+    ) -> Result<(), SftpError> {
+        let target_path = self.resolve_path(remote_path);
+        let path_str = target_path
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+        let local = local_target(remote_path, local_path)?;
+
+        let transfer = Transfer::Download {
+            remote_path: path_str.to_string(),
+            local_path: local.clone(),
+            size: None,
+            modify_time: None,
+        };
+        if !self.may_overwrite(&transfer, policy)? {
+            return Ok(());
+        }
+
+        let retry = self.settings.retry.with_override(policy);
+        self.protocol.set_timeout(retry.timeout);
+        let mut attempt = 0;
+        let result = loop {
+            match self.download_range(path_str, &local, range) {
+                Err(e) if attempt < retry.retries && e.is_transient() => {
+                    attempt += 1;
+                    println!(
+                        "{}: {}, retrying ({}/{})",
+                        remote_path.display(),
+                        e,
+                        attempt,
+                        retry.retries
+                    );
+                }
+                result => break result,
+            }
+        };
+        self.protocol.set_timeout(self.settings.retry.timeout);
+
+        if let (Err(e), Some(progress)) = (&result, self.progress.as_mut()) {
+            progress.error(path_str, &e.to_string());
+        }
+        result
+    }
+
+    fn download_range(
+        &mut self,
+        remote_path: &str,
+        local_path: &Path,
+        range: &ByteRange,
+    ) -> Result<(), SftpError> {
+        let file_handle = self.protocol.open(remote_path, SSH_FXF_READ)?;
+        let span = self
+            .protocol
+            .fstat(&file_handle)
+            .and_then(|attrs| range.resolve(attrs.size));
+        let (offset, length) = match span {
+            Ok(span) => span,
+            Err(e) => {
+                self.protocol.close(file_handle)?;
+                return Err(e);
+            }
+        };
+        let file = filesystem::create_sparse_writer(local_path, length)?;
+
+        if let Some(progress) = self.progress.as_mut() {
+            progress.start(remote_path, length);
+        }
+        let mut writer =
+            ProgressWriter::new(file, self.progress.as_deref_mut(), remote_path, length);
+        let result = self.protocol.read_range_to_writer(
+            &file_handle,
+            offset,
+            length.unwrap_or(u64::MAX),
+            &mut writer,
+        );
+        self.protocol.close(file_handle)?;
+        let bytes = writer.bytes();
+        writer.into_inner().finish()?;
+        result?;
+
+        if let Some(progress) = self.progress.as_mut() {
+            progress.done(remote_path, bytes);
+        }
+        Ok(())
+    }
+
+    /// Continues a download from the size of the existing local file.
+    fn reget_file(
+        &mut self,
+        remote_path: &Path,
+        local_path: Option<&PathBuf>,
+        policy: &PolicyOverride,
+    ) -> Result<(), SftpError> {
+        let local = local_target(remote_path, local_path)?;
+        let offset = match std::fs::metadata(&local) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        self.get_with_retries(remote_path, Some(&local), offset, policy)
+    }
+
+    /// Runs `get_file` under the global retry policy with `policy` applied on
+    /// top, retrying failures that may be transient. A retry after a partial
+    /// transfer continues from where the failed attempt stopped.
+    fn get_with_retries(
+        &mut self,
+        remote_path: &Path,
+        local_path: Option<&PathBuf>,
+        offset: u64,
+        policy: &PolicyOverride,
+    ) -> Result<(), SftpError> {
+        // A resumed download extends the local file rather than replacing it
+        if offset == 0 {
+            let transfer = Transfer::Download {
+                remote_path: self
+                    .resolve_path(remote_path)
+                    .to_string_lossy()
+                    .into_owned(),
+                local_path: local_target(remote_path, local_path)?,
+                size: None,
+                modify_time: None,
+            };
+            if !self.may_overwrite(&transfer, policy)? {
+                return Ok(());
+            }
+        }
+
+        let retry = self.settings.retry.with_override(policy);
+        self.protocol.set_timeout(retry.timeout);
+
+        let mut position = offset;
+        let mut attempt = 0;
+        let result = loop {
+            match self.get_file(remote_path, local_path, position) {
+                Err(e) if attempt < retry.retries && e.is_transient() => {
+                    attempt += 1;
+                    if let SftpError::PartialTransfer { transferred, .. } = &e {
+                        position += transferred;
+                    }
+                    println!(
+                        "{}: {}, retrying ({}/{})",
+                        remote_path.display(),
+                        e,
+                        attempt,
+                        retry.retries
+                    );
+                }
+                result => break result,
+            }
+        };
+        self.protocol.set_timeout(self.settings.retry.timeout);
+
+        // Report progress across all attempts, so a queued download that
+        // gives up is paused at the right place
+        match result {
+            Err(SftpError::PartialTransfer {
+                path,
+                transferred,
+                reason,
+            }) => Err(SftpError::PartialTransfer {
+                path,
+                transferred: position - offset + transferred,
+                reason,
+            }),
+            Err(e) if position > offset => Err(SftpError::PartialTransfer {
+                path: remote_path.display().to_string(),
+                transferred: position - offset,
+                reason: Box::new(e),
+            }),
+            result => result,
+        }
+    }
+
+    /// Downloads `remote_path`. A non-zero `offset` continues an earlier
+    /// download that stopped after that many bytes.
+    fn get_file(
+        &mut self,
+        remote_path: &Path,
+        local_path: Option<&PathBuf>,
+        offset: u64,
+    ) -> Result<(), SftpError> {
+        let target_path = self.resolve_path(remote_path);
+
+        let path_str = target_path
+            .to_str()
+            .ok_or_else(|| SftpError::ClientError("Invalid UTF-8 in path".into()))?;
+
+        let target_local_path = local_target(remote_path, local_path)?;
+
+        let result = self.download(path_str, &target_local_path, offset);
+        if let (Err(e), Some(progress)) = (&result, self.progress.as_mut()) {
+            progress.error(path_str, &e.to_string());
+        }
+        result
+    }
+
+    /// Guards against appending to a local file that is not actually a
+    /// prefix of the remote one: the last few KB before `offset` are hashed on
+    /// both sides and must match.
+    fn check_resume_tail(
+        &mut self,
+        handle: &[u8],
+        remote_path: &str,
+        local_path: &Path,
+        offset: u64,
+    ) -> Result<(), SftpError> {
+        let tail = RESUME_CHECK_BYTES.min(offset);
+        let start = offset - tail;
+
+        let mut local = std::fs::File::open(local_path)?;
+        if local.metadata()?.len() < offset {
+            return Err(SftpError::ResumeMismatch(remote_path.to_string()));
+        }
+        local.seek(SeekFrom::Start(start))?;
+        let mut local_hash = Sha256::new();
+        std::io::copy(&mut BufReader::new(local).take(tail), &mut local_hash)?;
+
+        let remote = self.protocol.read_range(handle, start, tail)?;
+        let mut remote_hash = Sha256::new();
+        remote_hash.update(&remote);
+
+        if local_hash.finalize() != remote_hash.finalize() {
+            return Err(SftpError::ResumeMismatch(remote_path.to_string()));
+        }
+        Ok(())
+    }
+
+    fn download(
+        &mut self,
+        remote_path: &str,
+        local_path: &Path,
+        offset: u64,
+    ) -> Result<(), SftpError> {
+        // The size drives both progress reporting and preallocation
+        let total = self.protocol.stat(remote_path).ok().and_then(|a| a.size);
+
+        if let (0, Some(total)) = (offset, total) {
+            let siblings = self.open_siblings(segment_count(self.settings.segments, total) - 1);
+            if !siblings.is_empty() {
+                return self.download_segments(remote_path, local_path, total, siblings);
+            }
+        }
+
+        let file_handle: Vec<u8> = self.protocol.open(remote_path, SSH_FXF_READ)?;
+        if offset > 0 {
+            if let Err(e) = self.check_resume_tail(&file_handle, remote_path, local_path, offset) {
+                self.protocol.close(file_handle)?;
+                return Err(e);
+            }
+        }
+        let file = match offset {
+            0 => filesystem::create_sparse_writer(local_path, total)?,
+            offset => filesystem::resume_sparse_writer(local_path, offset, total)?,
+        };
+
+        if let Some(progress) = self.progress.as_mut() {
+            progress.start(remote_path, total);
+        }
+        let mut writer =
+            ProgressWriter::new(file, self.progress.as_deref_mut(), remote_path, total);
+
+        // Chunks go straight to disk so memory use stays bounded by the chunk
+        // size regardless of how large the remote file is.
+        let result = self
+            .protocol
+            .read_to_writer_at(&file_handle, offset, &mut writer);
+        self.protocol.close(file_handle)?;
+        let bytes = writer.bytes();
+        writer.into_inner().finish()?;
+        if let Err(e) = result {
+            return Err(match bytes {
+                0 => e,
+                transferred => SftpError::PartialTransfer {
+                    path: remote_path.to_string(),
+                    transferred,
+                    reason: Box::new(e),
+                },
+            });
+        }
+
+        if let Some(progress) = self.progress.as_mut() {
+            progress.done(remote_path, bytes);
+        }
+        Ok(())
+    }
+
+    // As many extra channels as can be opened, up to `count`
+    fn open_siblings(&mut self, count: usize) -> Vec<SftpProtocol<T>> {
+        let mut siblings = Vec::new();
+        while siblings.len() < count {
+            match self.protocol.open_sibling() {
+                Some(Ok(sibling)) => siblings.push(sibling),
+                Some(Err(e)) => {
+                    warn!("Could not open another channel: {}", e);
+                    break;
+                }
+                None => break,
+            }
+        }
+        siblings
+    }
+
+    /// Downloads `remote_path` as disjoint ranges at once, one on this
+    /// client's channel and one on each of `siblings`, written straight into
+    /// place in `local_path`. On a long fat network each channel's window
+    /// caps its throughput, so several together go faster than one.
+    ///
+    /// Ranges finish out of order, so a failure is not resumable and the
+    /// download starts over on retry.
+    fn download_segments(
+        &mut self,
+        remote_path: &str,
+        local_path: &Path,
+        total: u64,
+        siblings: Vec<SftpProtocol<T>>,
+    ) -> Result<(), SftpError> {
+        let ranges = split_range(total, siblings.len() + 1);
+        info!("Downloading {} in {} segments", remote_path, ranges.len());
+        filesystem::create_preallocated(local_path, total)?;
+        if let Some(progress) = self.progress.as_mut() {
+            progress.start(remote_path, Some(total));
+        }
+
+        let protocol = &mut self.protocol;
+        let (first, others) = thread::scope(|scope| {
+            let workers: Vec<_> = siblings
+                .into_iter()
+                .zip(&ranges[1..])
+                .map(|(mut sibling, &(start, len))| {
+                    scope.spawn(move || {
+                        let result =
+                            download_range(&mut sibling, remote_path, local_path, start, len);
+                        (result, sibling.stats().clone())
+                    })
+                })
+                .collect();
+            let (start, len) = ranges[0];
+            let first = download_range(protocol, remote_path, local_path, start, len);
+            let others: Vec<_> = workers
+                .into_iter()
+                .map(|worker| {
+                    worker.join().unwrap_or_else(|_| {
+                        let panicked = SftpError::ClientError("Download thread panicked".into());
+                        (Err(panicked), SessionStats::new())
+                    })
+                })
+                .collect();
+            (first, others)
+        });
+
+        let mut result = first;
+        for (other, stats) in others {
+            let own = self.protocol.stats_mut();
+            own.bytes_received += stats.bytes_received;
+            own.requests += stats.requests;
+            result = result.and(other);
+        }
+        result?;
+
+        if let Some(progress) = self.progress.as_mut() {
+            progress.done(remote_path, total);
+        }
+        Ok(())
+    }
+}
+
+/// One file of a multi-file transfer.
+enum Transfer {
+    Download {
+        remote_path: String,
+        local_path: PathBuf,
+        size: Option<u64>,
+        // Source mtime to give the finished file, as sync does
+        modify_time: Option<u64>,
+    },
+    Upload {
+        local_path: PathBuf,
+        remote_path: String,
+        size: Option<u64>,
+        modify_time: Option<u64>,
+    },
+}
+
+impl Transfer {
+    fn size(&self) -> Option<u64> {
+        match self {
+            Transfer::Download { size, .. } | Transfer::Upload { size, .. } => *size,
+        }
+    }
+
+    // How the journal of a tree transfer names this file
+    fn journal_entry(&self) -> String {
+        match self {
+            Transfer::Download { remote_path, .. } => remote_path.clone(),
+            Transfer::Upload { local_path, .. } => local_path.to_string_lossy().into_owned(),
+        }
+    }
+
+    // The file being read, as named in error messages
+    fn source(&self) -> String {
+        match self {
+            Transfer::Download { remote_path, .. } => terminal::escape(remote_path).into_owned(),
+            Transfer::Upload { local_path, .. } => local_path.display().to_string(),
+        }
+    }
+
+    /// Gives the written file the source's modification time, if the
+    /// transfer carries one, so a later sync sees the two as the same.
+    fn stamp<T: TransportLayer>(&self, protocol: &mut SftpProtocol<T>) -> Result<(), SftpError> {
+        match self {
+            Transfer::Download {
+                local_path,
+                modify_time: Some(time),
+                ..
+            } => filesystem::set_modified(local_path, *time).map_err(SftpError::from),
+            Transfer::Upload {
+                remote_path,
+                modify_time: Some(time),
+                ..
+            } => protocol.setstat(
+                remote_path,
+                FileAttributes {
+                    modify_time: Some(*time as u32),
+                    ..FileAttributes::default()
+                },
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Display for Transfer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transfer::Download {
+                remote_path,
+                local_path,
+                ..
+            } => write!(
+                f,
+                "Fetching {} to {}",
+                terminal::escape(remote_path),
+                local_path.display()
+            ),
+            Transfer::Upload {
+                local_path,
+                remote_path,
+                ..
+            } => write!(
+                f,
+                "Uploading {} to {}",
+                local_path.display(),
+                terminal::escape(remote_path)
+            ),
+        }
+    }
+}
+
+// Matches the failures reported by index back up with their transfers
+fn pair_failures(
+    transfers: Vec<Transfer>,
+    failures: Vec<(usize, SftpError)>,
+) -> Vec<(Transfer, Option<SftpError>)> {
+    let mut errors: Vec<Option<SftpError>> = transfers.iter().map(|_| None).collect();
+    for (index, e) in failures {
+        errors[index] = Some(e);
+    }
+    transfers.into_iter().zip(errors).collect()
+}
+
+/// Moves one file of a parallel transfer over `protocol`, replacing
+/// whatever is at the destination.
+fn run_transfer<T: TransportLayer>(
+    protocol: &mut SftpProtocol<T>,
+    transfer: &Transfer,
+    progress: &AggregateProgress,
+) -> Result<(), SftpError> {
+    match transfer {
+        Transfer::Download {
+            remote_path,
+            local_path,
+            size,
+            ..
+        } => {
+            let handle = protocol.open(remote_path, SSH_FXF_READ)?;
+            let file = filesystem::create_sparse_writer(local_path, *size)?;
+            let mut writer = progress.tally(file);
+            let result = protocol.read_to_writer(&handle, &mut writer);
+            protocol.close(handle)?;
+            writer.into_inner().finish()?;
+            result?;
+        }
+        Transfer::Upload {
+            local_path,
+            remote_path,
+            ..
+        } => {
+            let source = std::fs::File::open(local_path)?;
+            let pflags = SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC;
+            let handle = protocol.open(remote_path, pflags)?;
+            let result = protocol.write_from_reader(&handle, progress.tally(source));
+            protocol.close(handle)?;
+            result?;
+        }
+    }
+    transfer.stamp(protocol)
+}
+
+/// Downloads the `len` bytes at `start` into the same range of `local_path`.
+fn download_range<T: TransportLayer>(
+    protocol: &mut SftpProtocol<T>,
+    remote_path: &str,
+    local_path: &Path,
+    start: u64,
+    len: u64,
+) -> Result<(), SftpError> {
+    let handle = protocol.open(remote_path, SSH_FXF_READ)?;
+    let mut writer = filesystem::open_segment_writer(local_path, start)?;
+    let result = protocol.read_range_to_writer(&handle, start, len, &mut writer);
+    protocol.close(handle)?;
+    writer.flush()?;
+    if result? < len {
+        return Err(SftpError::ClientError(
+            format!("{} shrank during the download", remote_path).into(),
+        ));
+    }
+    Ok(())
+}
+
+/// How many ranges to split a `total` byte download into: up to
+/// `segments`, but none smaller than `SEGMENT_MIN_SIZE`.
+fn segment_count(segments: usize, total: u64) -> usize {
+    let fit = (total / SEGMENT_MIN_SIZE).min(MAX_SEGMENTS as u64) as usize;
+    segments.min(fit).max(1)
+}
+
+/// Splits `total` bytes into `count` contiguous `(start, len)` ranges,
+/// the first ones a byte longer when it does not divide evenly.
+fn split_range(total: u64, count: usize) -> Vec<(u64, u64)> {
+    let count = count as u64;
+    let mut start = 0;
+    (0..count)
+        .map(|index| {
+            let len = total / count + u64::from(index < total % count);
+            let range = (start, len);
+            start += len;
+            range
+        })
+        .collect()
+}
+
+/// Hands each chunk a download writes to the other end of a `transfer_to`.
+struct PipeWriter(SyncSender<Vec<u8>>);
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .send(buf.to_vec())
+            .map_err(|_| std::io::ErrorKind::BrokenPipe)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads the chunks sent by a `PipeWriter`, ending once it is dropped.
+struct PipeReader {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl PipeReader {
+    fn new(receiver: Receiver<Vec<u8>>) -> Self {
+        Self {
+            receiver,
+            chunk: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.position);
+        buf[..len].copy_from_slice(&self.chunk[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+impl<T: TransportLayer> Drop for SftpClient<T> {
+    fn drop(&mut self) {
+        if self.settings.cleanup_opened {
+            // Best effort: a viewer may still have one of the files open
+            let _ = std::fs::remove_dir_all(opened_files_dir());
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn print_throughput(label: &str, bytes: u64, elapsed: Duration) {
+    let mib = bytes as f64 / (1024.0 * 1024.0);
+    println!(
+        "{} {:.1} MiB in {:.2} s ({:.1} MiB/s)",
+        label,
+        mib,
+        elapsed.as_secs_f64(),
+        mib / elapsed.as_secs_f64().max(f64::EPSILON)
+    );
+}
+
+/// Index in `data` where its last `lines` lines begin. A trailing newline
+/// does not count as the start of an empty last line.
+fn last_lines_start(data: &[u8], lines: usize) -> usize {
+    let body = data.strip_suffix(b"\n").unwrap_or(data);
+    body.iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, &byte)| byte == b'\n')
+        .nth(lines.saturating_sub(1))
+        .map_or(0, |(index, _)| index + 1)
+}
+
+/// Where `open` puts downloads; one directory per client process so that
+/// cleanup on exit only touches this session's files.
+fn opened_files_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("ferric-ftp-{}", std::process::id()))
+}
+
+// How an upload treats a file already at the remote path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PutMode {
+    Replace,
+    Exclusive,
+    Resume,
+}
+
+// A paused upload has had everything it read acknowledged, so, like an
+// interrupted download, it can be continued after the `sent` bytes
+fn paused_upload(
+    result: Result<u64, SftpError>,
+    remote_path: &str,
+    sent: u64,
+) -> Result<u64, SftpError> {
+    match result {
+        Err(SftpError::Paused) if sent > 0 => Err(SftpError::PartialTransfer {
+            path: remote_path.to_string(),
+            transferred: sent,
+            reason: Box::new(SftpError::Paused),
+        }),
+        result => result,
+    }
+}
+
+// Seconds since the epoch at which a local file was last modified
+fn modified_secs(metadata: &std::fs::Metadata) -> Option<u64> {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|elapsed| elapsed.as_secs())
+}
+
+/// Where a download of `remote_path` lands: `local_path` itself, inside it
+/// if it is a directory, or the current directory by default.
+fn local_target(remote_path: &Path, local_path: Option<&PathBuf>) -> Result<PathBuf, SftpError> {
+    match local_path {
+        Some(path) if !path.is_dir() => Ok(path.clone()),
+        _ => {
+            let file_name = remote_path
+                .file_name()
+                .ok_or(SftpError::InvalidCommand("No filename in remote path"))?;
+            Ok(local_path
+                .cloned()
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join(file_name))
+        }
+    }
+}
+
+/// Reads the baseline a two-way sync recorded at `file`, which is empty
+/// before the first run. A baseline kept for another remote tree would make
+/// every difference look like a change, so it is refused.
+fn load_baseline(
+    file: &Path,
+    remote_root: &str,
+) -> Result<BTreeMap<PathBuf, SyncEntry>, SftpError> {
+    let text = match std::fs::read_to_string(file) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let snapshot = Snapshot::from_json(&text)?;
+    if snapshot.root != remote_root {
+        return Err(SftpError::ClientError(
+            format!(
+                "{} records a sync with {}, not {}",
+                file.display(),
+                snapshot.root,
+                remote_root
+            )
+            .into(),
+        ));
+    }
+    Ok(snapshot
+        .entries
+        .into_iter()
+        .map(|(path, entry)| {
+            let entry = SyncEntry {
+                size: entry.size,
+                modify_time: entry.modify_time,
+            };
+            (PathBuf::from(path), entry)
+        })
+        .collect())
+}
+
+fn save_baseline(
+    file: &Path,
+    remote_root: &str,
+    entries: &BTreeMap<PathBuf, SyncEntry>,
+) -> Result<(), SftpError> {
+    let mut snapshot = Snapshot::new(remote_root);
+    for (path, entry) in entries {
+        snapshot.entries.insert(
+            remote_path::to_slash(path),
+            SnapshotEntry {
+                size: entry.size,
+                modify_time: entry.modify_time,
+                sha256: None,
+            },
+        );
+    }
+    if let Some(parent) = file.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(file, snapshot.to_json())?;
+    Ok(())
+}
+
+// Fills in how each transfer of a sync went, matching it to its file in the
+// report by local path, and returns the first failure
+fn report_transfers(
+    report: &mut SyncReport,
+    local_root: &Path,
+    outcome: Vec<(Transfer, Option<SftpError>)>,
+) -> Result<(), SftpError> {
+    let index: HashMap<(PathBuf, SyncDirection), usize> = report
+        .files
+        .iter()
+        .enumerate()
+        .filter(|(_, file)| file.outcome != Outcome::Delete)
+        .filter_map(|(i, file)| Some(((file.path.clone(), file.direction?), i)))
+        .collect();
+
+    let mut first_error = None;
+    for (transfer, error) in outcome {
+        let (local_file, direction) = match &transfer {
+            Transfer::Upload { local_path, .. } => (local_path, SyncDirection::Up),
+            Transfer::Download { local_path, .. } => (local_path, SyncDirection::Down),
+        };
+        let Some(&i) = local_file
+            .strip_prefix(local_root)
+            .ok()
+            .and_then(|path| index.get(&(path.to_path_buf(), direction)))
+        else {
+            continue;
+        };
+        match error {
+            None => report.files[i].bytes = transfer.size().unwrap_or_default(),
+            Some(e) => {
+                report.files[i].error = Some(e.to_string());
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+/// Writes the report of a sync as JSON to the file given with `--report`,
+/// whether or not every file made it.
+fn save_report(
+    report: &SyncReport,
+    options: &SyncOptions,
+    mode: &str,
+    local_root: &Path,
+    remote_root: &str,
+) -> Result<(), SftpError> {
+    let Some(file) = &options.report else {
+        return Ok(());
+    };
+    let local_root = std::path::absolute(local_root)?;
+    std::fs::write(file, report.to_json(mode, &local_root, remote_root))?;
+    Ok(())
+}
+
+/// Opens the journal of a tree transfer, saying how much an earlier run
+/// already did.
+fn open_journal(file: &Path, operation: &str) -> Result<Journal, SftpError> {
+    let journal = Journal::open(file, operation)?;
+    if journal.completed() > 0 {
+        println!(
+            "Resuming from {}: {} file(s) already done",
+            file.display(),
+            journal.completed()
+        );
+    }
+    Ok(journal)
+}
+
+/// Ignore rules for an upload of the tree at `root`: those in `ignore_file`
+/// when one is named, otherwise the tree's own `.ferricignore` if it has one.
+fn local_ignore_rules(ignore_file: Option<&Path>, root: &Path) -> Result<IgnoreRules, SftpError> {
+    let result = match ignore_file {
+        Some(file) => IgnoreRules::load(file),
+        None => match IgnoreRules::load(&root.join(IGNORE_FILE_NAME)) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(IgnoreRules::default()),
+            result => result,
+        },
+    };
+    Ok(result?)
+}
+
+fn local_checksum(path: &Path) -> Result<String, SftpError> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut BufReader::new(std::fs::File::open(path)?), &mut hasher)?;
+    Ok(checksum::to_hex(&hasher.finalize()))
+}
+
+// Seconds past the epoch as local time, or as the bare number when they are
+// past anything chrono can show
+fn format_timestamp(secs: u64) -> String {
+    match i64::try_from(secs)
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+    {
+        Some(time) => time
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S %z")
+            .to_string(),
+        None => secs.to_string(),
     }
 }
@@ -1,20 +1,91 @@
+use std::time::Duration;
+
+// Version offered in INIT unless --sftp-version asks for a later one
 pub const SFTP_SUPPORTED_VERSION: u32 = 3;
+// Oldest and newest versions this client can speak
+pub const SFTP_MIN_VERSION: u32 = 3;
+pub const SFTP_MAX_VERSION: u32 = 6;
+
+// Longest server packet accepted; a larger length field is taken to be
+// corruption rather than allocated
+pub const DEFAULT_MAX_PACKET_SIZE: u32 = 256 * 1024;
+// Largest amount of file data requested (and held in memory) per READ
+pub const DEFAULT_CHUNK_SIZE: u32 = 32768;
+// Type, request id and data length ahead of the data in a DATA packet
+pub const DATA_HEADER_LEN: u32 = 9;
+// Largest chunk whose DATA reply still fits in a packet of `max_packet_size`
+pub const fn max_chunk_size(max_packet_size: u32) -> u32 {
+    max_packet_size.saturating_sub(DATA_HEADER_LEN)
+}
+// Bytes before the resume point compared with the remote file before resuming
+pub const RESUME_CHECK_BYTES: u64 = 8192;
+// Lines printed by tail, taken from at most the last TAIL_BLOCK_SIZE bytes
+pub const TAIL_LINES: usize = 10;
+pub const TAIL_BLOCK_SIZE: u64 = 8192;
+// How often tail -f checks the remote file for appended data
+pub const TAIL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Quiet time watch waits for after a change, so a file saved in several
+// steps is uploaded once
+pub const WATCH_SETTLE_TIME: Duration = Duration::from_millis(300);
+// How often watch checks for Ctrl-C while no changes arrive
+pub const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(200);
+// Bytes read and written per step when cp copies through the client
+pub const COPY_BLOCK_SIZE: u64 = 1024 * 1024;
+// Chunks buffered between the two sessions of a remote-to-remote transfer
+pub const TRANSFER_PIPE_CHUNKS: usize = 16;
+// Amount transferred each way by bench unless --size is given
+pub const BENCH_DEFAULT_SIZE: u64 = 100 * 1024 * 1024;
+// Bytes handed to each write while bench uploads its scratch file
+pub const BENCH_BLOCK_SIZE: usize = 1024 * 1024;
+// Round trips timed by bench to estimate request latency
+pub const BENCH_LATENCY_SAMPLES: usize = 20;
+// Most channels a segmented download may use, and the smallest range worth
+// giving one of them
+pub const MAX_SEGMENTS: usize = 16;
+pub const SEGMENT_MIN_SIZE: u64 = 4 * 1024 * 1024;
+// Files mget, mput and get -r move at once unless `set parallel` says
+// otherwise, each on its own channel, and the most that may be asked for
+pub const DEFAULT_PARALLEL_TRANSFERS: usize = 4;
+pub const MAX_PARALLEL_TRANSFERS: usize = 16;
 
 // SFTP Protocol message types
 pub const SSH_FXP_INIT: u8 = 1;
 pub const SSH_FXP_VERSION: u8 = 2;
 pub const SSH_FXP_OPEN: u8 = 3;
 pub const SSH_FXP_READ: u8 = 5;
+pub const SSH_FXP_WRITE: u8 = 6;
+pub const SSH_FXP_LSTAT: u8 = 7;
+pub const SSH_FXP_FSTAT: u8 = 8;
+pub const SSH_FXP_SETSTAT: u8 = 9;
+pub const SSH_FXP_FSETSTAT: u8 = 10;
+pub const SSH_FXP_REMOVE: u8 = 13;
+pub const SSH_FXP_MKDIR: u8 = 14;
+pub const SSH_FXP_RMDIR: u8 = 15;
 pub const SSH_FXP_CLOSE: u8 = 4;
 pub const SSH_FXP_OPENDIR: u8 = 11;
 pub const SSH_FXP_READDIR: u8 = 12;
 pub const SSH_FXP_REALPATH: u8 = 16;
 pub const SSH_FXP_STAT: u8 = 17;
+pub const SSH_FXP_RENAME: u8 = 18;
+pub const SSH_FXP_READLINK: u8 = 19;
+pub const SSH_FXP_SYMLINK: u8 = 20;
+pub const SSH_FXP_LINK: u8 = 21; // v6, replaces SYMLINK
 pub const SSH_FXP_STATUS: u8 = 101;
 pub const SSH_FXP_HANDLE: u8 = 102;
 pub const SSH_FXP_DATA: u8 = 103;
 pub const SSH_FXP_NAME: u8 = 104;
 pub const SSH_FXP_ATTRS: u8 = 105;
+pub const SSH_FXP_EXTENDED: u8 = 200;
+pub const SSH_FXP_EXTENDED_REPLY: u8 = 201;
+
+// Extensions advertised in SSH_FXP_VERSION
+pub const EXT_POSIX_RENAME: &str = "posix-rename@openssh.com";
+pub const EXT_CHECK_FILE: &str = "check-file";
+pub const EXT_CHECK_FILE_NAME: &str = "check-file-name";
+pub const EXT_STATVFS: &str = "statvfs@openssh.com";
+pub const EXT_HARDLINK: &str = "hardlink@openssh.com";
+pub const EXT_COPY_DATA: &str = "copy-data";
+pub const EXT_LIMITS: &str = "limits@openssh.com";
 
 // File attribute flags
 pub const SSH_FILEXFER_ATTR_SIZE: u32 = 0x00000001;
@@ -23,6 +94,33 @@ pub const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x00000004;
 pub const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x00000008;
 pub const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x80000000;
 
+// File attribute flags from v4 on. UIDGID and ACMODTIME are gone, replaced
+// by owner/group names and separate 64-bit times
+pub const SSH_FILEXFER_ATTR_ACCESSTIME: u32 = 0x00000008;
+pub const SSH_FILEXFER_ATTR_CREATETIME: u32 = 0x00000010;
+pub const SSH_FILEXFER_ATTR_MODIFYTIME: u32 = 0x00000020;
+pub const SSH_FILEXFER_ATTR_ACL: u32 = 0x00000040;
+pub const SSH_FILEXFER_ATTR_OWNERGROUP: u32 = 0x00000080;
+pub const SSH_FILEXFER_ATTR_SUBSECOND_TIMES: u32 = 0x00000100;
+pub const SSH_FILEXFER_ATTR_BITS: u32 = 0x00000200; // v5
+pub const SSH_FILEXFER_ATTR_ALLOCATION_SIZE: u32 = 0x00000400; // v6
+pub const SSH_FILEXFER_ATTR_TEXT_HINT: u32 = 0x00000800; // v6
+pub const SSH_FILEXFER_ATTR_MIME_TYPE: u32 = 0x00001000; // v6
+pub const SSH_FILEXFER_ATTR_LINK_COUNT: u32 = 0x00002000; // v6
+pub const SSH_FILEXFER_ATTR_UNTRANSLATED_NAME: u32 = 0x00004000; // v6
+pub const SSH_FILEXFER_ATTR_CTIME: u32 = 0x00008000; // v6
+
+// File types, sent as a byte ahead of the attributes from v4 on
+pub const SSH_FILEXFER_TYPE_REGULAR: u8 = 1;
+pub const SSH_FILEXFER_TYPE_DIRECTORY: u8 = 2;
+pub const SSH_FILEXFER_TYPE_SYMLINK: u8 = 3;
+pub const SSH_FILEXFER_TYPE_SPECIAL: u8 = 4;
+pub const SSH_FILEXFER_TYPE_UNKNOWN: u8 = 5;
+pub const SSH_FILEXFER_TYPE_SOCKET: u8 = 6;
+pub const SSH_FILEXFER_TYPE_CHAR_DEVICE: u8 = 7;
+pub const SSH_FILEXFER_TYPE_BLOCK_DEVICE: u8 = 8;
+pub const SSH_FILEXFER_TYPE_FIFO: u8 = 9;
+
 // Unix file permissions
 pub const S_IFMT: u32 = 0o170000; // bit mask for the file type bit field
 pub const S_IFDIR: u32 = 0o040000; // directory
@@ -37,6 +135,22 @@ pub const S_IFSOCK: u32 = 0o140000; // socket
 pub const SSH_FXF_READ: u32 = 0x00000001;
 pub const SSH_FXF_WRITE: u32 = 0x00000002;
 //pub const SSH_FXF_APPEND: u32 = 0x00000004;
-//pub const SSH_FXF_CREAT: u32 = 0x00000008;
-//pub const SSH_FXF_TRUNC: u32 = 0x00000010;
-//pub const SSH_FXF_EXCL: u32 = 0x00000020;
+pub const SSH_FXF_CREAT: u32 = 0x00000008;
+pub const SSH_FXF_TRUNC: u32 = 0x00000010;
+pub const SSH_FXF_EXCL: u32 = 0x00000020;
+
+// v5+ OPEN: desired-access bits and the disposition that replaces
+// CREAT/TRUNC/EXCL
+pub const ACE4_READ_DATA: u32 = 0x00000001;
+pub const ACE4_WRITE_DATA: u32 = 0x00000002;
+pub const ACE4_READ_ATTRIBUTES: u32 = 0x00000080;
+pub const ACE4_WRITE_ATTRIBUTES: u32 = 0x00000100;
+pub const SSH_FXF_CREATE_NEW: u32 = 0x00000000;
+pub const SSH_FXF_CREATE_TRUNCATE: u32 = 0x00000001;
+pub const SSH_FXF_OPEN_EXISTING: u32 = 0x00000002;
+pub const SSH_FXF_OPEN_OR_CREATE: u32 = 0x00000003;
+pub const SSH_FXF_TRUNCATE_EXISTING: u32 = 0x00000004;
+
+// v5+ RENAME flags
+pub const SSH_FXF_RENAME_OVERWRITE: u32 = 0x00000001;
+pub const SSH_FXF_RENAME_ATOMIC: u32 = 0x00000002;
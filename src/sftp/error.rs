@@ -1,3 +1,4 @@
+use crate::terminal;
 use std::fmt;
 
 #[derive(Debug)]
@@ -8,13 +9,38 @@ pub enum SftpError {
         request_id: u32,
         message: String,
     },
-    ClientError(Box<dyn std::error::Error>),
+    ClientError(Box<dyn std::error::Error + Send + Sync>),
     NotADirectory(String),
     UnexpectedPacket(&'static str),
     UnexpectedResponse(&'static str),
     //UnknownError,
     UnexpectedCommand,
     InvalidCommand(&'static str),
+    UndefinedVariable(String),
+    AlreadyExists(String),
+    DirectoryNotEmpty(String),
+    UnsupportedExtension(&'static str),
+    PacketTooLarge {
+        length: u32,
+        limit: u32,
+    },
+    /// A server packet whose contents don't fit its length field;
+    /// `packet_type` is 0 if there wasn't even a type byte.
+    MalformedPacket {
+        packet_type: u8,
+        detail: String,
+    },
+    VerifyFailed(usize),
+    DriftDetected(usize),
+    ResumeMismatch(String),
+    NoMatches(String),
+    PartialTransfer {
+        path: String,
+        transferred: u64,
+        reason: Box<SftpError>,
+    },
+    /// A transfer stopped because it was asked to pause.
+    Paused,
 }
 
 // Implement Display for SftpError
@@ -29,7 +55,9 @@ impl fmt::Display for SftpError {
             } => write!(
                 f,
                 "Server error (code: {}, request_id: {}): {}",
-                code, request_id, message
+                code,
+                request_id,
+                terminal::escape(message)
             ),
             SftpError::ClientError(e) => write!(f, "Client error: {}", e),
             SftpError::NotADirectory(path) => write!(f, "Not a directory: {}", path),
@@ -38,6 +66,64 @@ impl fmt::Display for SftpError {
             //SftpError::UnknownError => write!(f, "Unknown error"),
             SftpError::UnexpectedCommand => write!(f, "Unexpected command"),
             SftpError::InvalidCommand(msg) => write!(f, "Invalid command: {}", msg),
+            SftpError::UndefinedVariable(name) => write!(f, "Undefined variable: {}", name),
+            SftpError::AlreadyExists(path) => write!(f, "Already exists: {}", path),
+            SftpError::DirectoryNotEmpty(path) => write!(f, "Directory not empty: {}", path),
+            SftpError::UnsupportedExtension(name) => {
+                write!(f, "Server does not support the {} extension", name)
+            }
+            SftpError::PacketTooLarge { length, limit } => write!(
+                f,
+                "Server sent a {} byte packet, over the {} byte limit",
+                length, limit
+            ),
+            SftpError::MalformedPacket {
+                packet_type,
+                detail,
+            } => write!(f, "Malformed packet of type {}: {}", packet_type, detail),
+            SftpError::ResumeMismatch(path) => write!(
+                f,
+                "Local copy of {} does not match the remote file; not resuming",
+                path
+            ),
+            SftpError::NoMatches(pattern) => write!(f, "No files match {}", pattern),
+            SftpError::DriftDetected(count) => {
+                write!(f, "Snapshot differs in {} place(s)", count)
+            }
+            SftpError::VerifyFailed(count) => write!(f, "Verify found {} difference(s)", count),
+            SftpError::PartialTransfer {
+                path,
+                transferred,
+                reason,
+            } => write!(
+                f,
+                "Transfer of {} stopped after {} bytes: {}",
+                path, transferred, reason
+            ),
+            SftpError::Paused => write!(f, "Paused"),
+        }
+    }
+}
+
+impl SftpError {
+    /// True for failures that another attempt may get past, such as a timed
+    /// out reply or a dropped connection, as opposed to a refusal from the
+    /// server or a mistake in the command.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SftpError::IoError(_) => true,
+            SftpError::PartialTransfer { reason, .. } => !reason.is_paused(),
+            _ => false,
+        }
+    }
+
+    /// True for a transfer that stopped on request, possibly after moving
+    /// some of the file, rather than because something went wrong.
+    pub fn is_paused(&self) -> bool {
+        match self {
+            SftpError::Paused => true,
+            SftpError::PartialTransfer { reason, .. } => reason.is_paused(),
+            _ => false,
         }
     }
 }
@@ -47,6 +133,7 @@ impl std::error::Error for SftpError {
         match self {
             SftpError::IoError(e) => Some(e),
             SftpError::ClientError(e) => Some(e.as_ref()),
+            SftpError::PartialTransfer { reason, .. } => Some(reason.as_ref()),
             _ => None,
         }
     }
@@ -0,0 +1,87 @@
+use regex::bytes::Regex;
+use std::io::{self, Write};
+
+/// Writer that scans a file for lines matching `regex` as it streams past,
+/// printing each match as `file:line`. Only the current partial line is
+/// buffered, so files of any size can be searched.
+pub struct GrepWriter<'a, O: Write> {
+    regex: &'a Regex,
+    file: &'a str,
+    out: O,
+    line: Vec<u8>,
+    matches: usize,
+}
+
+impl<'a, O: Write> GrepWriter<'a, O> {
+    pub fn new(regex: &'a Regex, file: &'a str, out: O) -> Self {
+        Self {
+            regex,
+            file,
+            out,
+            line: Vec::new(),
+            matches: 0,
+        }
+    }
+
+    /// Checks the final line if the file did not end with a newline, and
+    /// returns the number of matching lines.
+    pub fn finish(mut self) -> io::Result<usize> {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.check_line(&line)?;
+        }
+        Ok(self.matches)
+    }
+
+    fn check_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if self.regex.is_match(line) {
+            self.matches += 1;
+            writeln!(self.out, "{}:{}", self.file, String::from_utf8_lossy(line))?;
+        }
+        Ok(())
+    }
+}
+
+impl<O: Write> Write for GrepWriter<'_, O> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+            if self.line.is_empty() {
+                self.check_line(&rest[..end])?;
+            } else {
+                self.line.extend_from_slice(&rest[..end]);
+                let line = std::mem::take(&mut self.line);
+                self.check_line(&line)?;
+            }
+            rest = &rest[end + 1..];
+        }
+        self.line.extend_from_slice(rest);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_lines_split_across_writes() {
+        let regex = Regex::new("ERROR").unwrap();
+        let mut out = Vec::new();
+
+        let mut grep = GrepWriter::new(&regex, "app.log", &mut out);
+        grep.write_all(b"ok\nfirst ER").unwrap();
+        grep.write_all(b"ROR here\nfine\r\nlast ERROR").unwrap();
+        assert_eq!(grep.finish().unwrap(), 2);
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "app.log:first ERROR here\napp.log:last ERROR\n"
+        );
+    }
+}
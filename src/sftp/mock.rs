@@ -0,0 +1,153 @@
+use super::constants::{DEFAULT_MAX_PACKET_SIZE, SFTP_SUPPORTED_VERSION};
+use super::error::SftpError;
+use super::packet::ServerPacket;
+use super::packet::{ClientPacket, SftpPacketInfo};
+use super::session::TransportLayer;
+use std::collections::VecDeque;
+
+/// Scripted transport for unit tests.
+///
+/// Every packet the client sends is checked byte-for-byte against the next
+/// expected request, so a wrong path, handle, request_id or pflags fails the
+/// test. Responses can be packets or injected errors, and any expectation or
+/// response left unused when the mock is dropped also fails the test.
+pub struct MockTransport {
+    expected_requests: VecDeque<ClientPacket>,
+    responses: VecDeque<Result<ServerPacket, SftpError>>,
+    request_id_counter: u32,
+    version: u32,
+    extensions: Vec<(String, String)>,
+    channels: VecDeque<MockTransport>,
+    max_packet_size: u32,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self {
+            expected_requests: VecDeque::new(),
+            responses: VecDeque::new(),
+            request_id_counter: 0,
+            version: SFTP_SUPPORTED_VERSION,
+            extensions: Vec::new(),
+            channels: VecDeque::new(),
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+        }
+    }
+
+    pub fn expect_request(mut self, packet: ClientPacket) -> Self {
+        self.expected_requests.push_back(packet);
+        self
+    }
+
+    pub fn respond_with(mut self, response: ServerPacket) -> Self {
+        self.responses.push_back(Ok(response));
+        self
+    }
+
+    /// Acts as if `version` had been negotiated; requests are compared in
+    /// that version's wire format.
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Advertises an extension as if the server had listed it in VERSION.
+    pub fn with_extension(mut self, name: &str, data: &str) -> Self {
+        self.extensions.push((name.to_string(), data.to_string()));
+        self
+    }
+
+    /// Hands out `channel` from the next `open_channel`, which otherwise
+    /// reports that no more channels can be opened.
+    pub fn with_channel(mut self, channel: MockTransport) -> Self {
+        self.channels.push_back(channel);
+        self
+    }
+
+    /// Makes the next `receive_packet` fail, e.g. to simulate a dropped
+    /// connection or a truncated frame.
+    pub fn fail_with(mut self, error: SftpError) -> Self {
+        self.responses.push_back(Err(error));
+        self
+    }
+}
+
+impl TransportLayer for MockTransport {
+    fn send_packet(&mut self, packet: ClientPacket) -> Result<(), SftpError> {
+        let expected = self
+            .expected_requests
+            .pop_front()
+            .unwrap_or_else(|| panic!("Unexpected {} sent: {:?}", packet.packet_name(), packet));
+
+        assert_eq!(
+            expected.packet_name(),
+            packet.packet_name(),
+            "Wrong packet type: expected {:?}, got {:?}",
+            expected,
+            packet
+        );
+        assert_eq!(
+            expected.to_bytes_for(self.version),
+            packet.to_bytes_for(self.version),
+            "Packet fields differ: expected {:?}, got {:?}",
+            expected,
+            packet
+        );
+        Ok(())
+    }
+
+    fn receive_packet(&mut self) -> Result<ServerPacket, SftpError> {
+        self.responses
+            .pop_front()
+            .unwrap_or_else(|| Err(SftpError::ClientError("No more responses".into())))
+    }
+
+    fn next_request_id(&mut self) -> u32 {
+        let id = self.request_id_counter;
+        self.request_id_counter += 1;
+        id
+    }
+
+    fn version(&self) -> u32 {
+        self.version
+    }
+
+    fn extensions(&self) -> &[(String, String)] {
+        &self.extensions
+    }
+
+    fn set_max_packet_size(&mut self, max_packet_size: u32) {
+        self.max_packet_size = max_packet_size;
+    }
+
+    fn max_packet_size(&self) -> u32 {
+        self.max_packet_size
+    }
+
+    fn open_channel(&mut self) -> Option<Result<Self, SftpError>> {
+        self.channels.pop_front().map(Ok)
+    }
+}
+
+impl Drop for MockTransport {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        assert!(
+            self.expected_requests.is_empty(),
+            "Expected requests never sent: {:?}",
+            self.expected_requests
+        );
+        assert!(
+            self.channels.is_empty(),
+            "{} channels never opened",
+            self.channels.len()
+        );
+        assert!(
+            self.responses.is_empty(),
+            "Responses never consumed: {:?}",
+            self.responses
+        );
+    }
+}
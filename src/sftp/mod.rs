@@ -1,9 +1,20 @@
+#[cfg(test)]
+pub(crate) mod chaos;
 pub mod client;
 pub mod constants;
 pub mod error;
+pub mod grep;
+#[cfg(test)]
+pub(crate) mod mock;
 pub mod packet;
+pub mod pipeline;
+pub mod process;
+pub mod progress;
 pub mod protocol;
+pub mod remote_path;
 pub mod session;
+pub mod snapshot;
+pub mod sync;
 pub mod types;
 
 pub use client::SftpClient;
@@ -1,8 +1,9 @@
 use super::constants::*;
 use super::error::SftpError;
 use super::session::SftpSession;
-use super::types::{FileAttributes, FileInfo};
-use log::info;
+use super::types::{FileAttributes, FileInfo, FileType};
+use log::{info, warn};
+use std::io::Read;
 
 pub trait SftpPacketInfo {
     fn packet_type(&self) -> u8;
@@ -46,12 +47,73 @@ pub enum ClientPacket {
         offset: u64,
         len: u32,
     },
+    Rename {
+        request_id: u32,
+        old_path: String,
+        new_path: String,
+        /// SSH_FXF_RENAME_* bits, only sent from v5 on
+        flags: u32,
+    },
+    /// Vendor extension request; `data` is the extension-specific payload.
+    Extended {
+        request_id: u32,
+        request: String,
+        data: Vec<u8>,
+    },
+    Write {
+        request_id: u32,
+        handle: Vec<u8>,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    Remove {
+        request_id: u32,
+        path: String,
+    },
+    MkDir {
+        request_id: u32,
+        path: String,
+        attrs: FileAttributes,
+    },
+    RmDir {
+        request_id: u32,
+        path: String,
+    },
+    ReadLink {
+        request_id: u32,
+        path: String,
+    },
+    /// Creates `link_path` pointing at `target_path`.
+    Symlink {
+        request_id: u32,
+        target_path: String,
+        link_path: String,
+    },
+    SetStat {
+        request_id: u32,
+        path: String,
+        attrs: FileAttributes,
+    },
+    FStat {
+        request_id: u32,
+        handle: Vec<u8>,
+    },
+    FSetStat {
+        request_id: u32,
+        handle: Vec<u8>,
+        attrs: FileAttributes,
+    },
+    LStat {
+        request_id: u32,
+        path: String,
+    },
 }
 
 #[derive(Debug)]
 pub enum ServerPacket {
     Version {
         version: u32,
+        extensions: Vec<(String, String)>,
     },
     Handle {
         request_id: u32,
@@ -74,6 +136,15 @@ pub enum ServerPacket {
         request_id: u32,
         data: Vec<u8>,
     },
+    /// Reply to an `Extended` request; `data` is the extension-specific payload.
+    ExtendedReply {
+        request_id: u32,
+        data: Vec<u8>,
+    },
+    /// A packet type this client doesn't know; its payload was skipped.
+    Unknown {
+        packet_type: u8,
+    },
 }
 
 impl SftpPacketInfo for ClientPacket {
@@ -87,6 +158,18 @@ impl SftpPacketInfo for ClientPacket {
             ClientPacket::Stat { .. } => SSH_FXP_STAT,
             ClientPacket::Open { .. } => SSH_FXP_OPEN,
             ClientPacket::Read { .. } => SSH_FXP_READ,
+            ClientPacket::Rename { .. } => SSH_FXP_RENAME,
+            ClientPacket::Extended { .. } => SSH_FXP_EXTENDED,
+            ClientPacket::Write { .. } => SSH_FXP_WRITE,
+            ClientPacket::Remove { .. } => SSH_FXP_REMOVE,
+            ClientPacket::MkDir { .. } => SSH_FXP_MKDIR,
+            ClientPacket::RmDir { .. } => SSH_FXP_RMDIR,
+            ClientPacket::ReadLink { .. } => SSH_FXP_READLINK,
+            ClientPacket::Symlink { .. } => SSH_FXP_SYMLINK,
+            ClientPacket::SetStat { .. } => SSH_FXP_SETSTAT,
+            ClientPacket::FStat { .. } => SSH_FXP_FSTAT,
+            ClientPacket::FSetStat { .. } => SSH_FXP_FSETSTAT,
+            ClientPacket::LStat { .. } => SSH_FXP_LSTAT,
         }
     }
 
@@ -100,6 +183,18 @@ impl SftpPacketInfo for ClientPacket {
             ClientPacket::Stat { .. } => "SSH_FXP_STAT",
             ClientPacket::Open { .. } => "SSH_FXP_OPEN",
             ClientPacket::Read { .. } => "SSH_FXP_READ",
+            ClientPacket::Rename { .. } => "SSH_FXP_RENAME",
+            ClientPacket::Extended { .. } => "SSH_FXP_EXTENDED",
+            ClientPacket::Write { .. } => "SSH_FXP_WRITE",
+            ClientPacket::Remove { .. } => "SSH_FXP_REMOVE",
+            ClientPacket::MkDir { .. } => "SSH_FXP_MKDIR",
+            ClientPacket::RmDir { .. } => "SSH_FXP_RMDIR",
+            ClientPacket::ReadLink { .. } => "SSH_FXP_READLINK",
+            ClientPacket::Symlink { .. } => "SSH_FXP_SYMLINK",
+            ClientPacket::SetStat { .. } => "SSH_FXP_SETSTAT",
+            ClientPacket::FStat { .. } => "SSH_FXP_FSTAT",
+            ClientPacket::FSetStat { .. } => "SSH_FXP_FSETSTAT",
+            ClientPacket::LStat { .. } => "SSH_FXP_LSTAT",
         }
     }
 }
@@ -113,6 +208,8 @@ impl SftpPacketInfo for ServerPacket {
             ServerPacket::Status { .. } => SSH_FXP_STATUS,
             ServerPacket::Attrs { .. } => SSH_FXP_ATTRS,
             ServerPacket::Data { .. } => SSH_FXP_DATA,
+            ServerPacket::ExtendedReply { .. } => SSH_FXP_EXTENDED_REPLY,
+            ServerPacket::Unknown { packet_type } => *packet_type,
         }
     }
 
@@ -124,6 +221,8 @@ impl SftpPacketInfo for ServerPacket {
             ServerPacket::Status { .. } => "SSH_FXP_STATUS",
             ServerPacket::Attrs { .. } => "SSH_FXP_ATTRS",
             ServerPacket::Data { .. } => "SSH_FXP_DATA",
+            ServerPacket::ExtendedReply { .. } => "SSH_FXP_EXTENDED_REPLY",
+            ServerPacket::Unknown { .. } => "unknown",
         }
     }
 }
@@ -133,44 +232,234 @@ pub trait SftpReader {
     fn read_u8(&mut self) -> Result<u8, SftpError>;
     fn read_string(&mut self) -> Result<Vec<u8>, SftpError>;
     fn read_u64(&mut self) -> Result<u64, SftpError>;
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, SftpError>;
     fn discard(&mut self, bytes: &usize) -> Result<(), SftpError>;
-    fn parse_file_attributes(&mut self, flags: &u32) -> Result<(usize, FileAttributes), SftpError>;
-}
 
-impl SftpReader for SftpSession {
-    fn read_u32(&mut self) -> Result<u32, SftpError> {
-        self.read_u32()
-    }
+    /// Reads the attributes named by `flags`, laid out as protocol `version`
+    /// sends them. Returns the attributes with the number of bytes read.
+    fn parse_file_attributes(
+        &mut self,
+        flags: &u32,
+        version: u32,
+    ) -> Result<(usize, FileAttributes), SftpError> {
+        let mut attrs = FileAttributes::default();
+        let mut len: usize = 0;
 
-    fn read_u8(&mut self) -> Result<u8, SftpError> {
-        self.read_u8()
+        let mut file_type = FileType::Unknown;
+        if version >= 4 {
+            file_type = FileType::from_type_byte(self.read_u8()?);
+            len += 1;
+        }
+
+        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+            attrs.size = Some(self.read_u64()?);
+            len += 8;
+        }
+
+        if version >= 6 && flags & SSH_FILEXFER_ATTR_ALLOCATION_SIZE != 0 {
+            self.read_u64()?;
+            len += 8;
+        }
+
+        if version < 4 && flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+            attrs.uid = Some(self.read_u32()?);
+            attrs.gid = Some(self.read_u32()?);
+            len += 8;
+        }
+
+        if version >= 4 && flags & SSH_FILEXFER_ATTR_OWNERGROUP != 0 {
+            let owner = self.read_string()?;
+            let group = self.read_string()?;
+            len += 8 + owner.len() + group.len();
+            attrs.owner = Some(String::from_utf8_lossy(&owner).into_owned());
+            attrs.group = Some(String::from_utf8_lossy(&group).into_owned());
+        }
+
+        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+            attrs.set_permissions(self.read_u32()?);
+            len += 4;
+        }
+
+        if version < 4 {
+            if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+                attrs.access_time = Some(self.read_u32()?);
+                attrs.modify_time = Some(self.read_u32()?);
+                len += 8;
+            }
+        } else {
+            let subsecond = flags & SSH_FILEXFER_ATTR_SUBSECOND_TIMES != 0;
+            if flags & SSH_FILEXFER_ATTR_ACCESSTIME != 0 {
+                attrs.access_time = Some(read_time(self, subsecond, &mut len)?);
+            }
+            if flags & SSH_FILEXFER_ATTR_CREATETIME != 0 {
+                read_time(self, subsecond, &mut len)?;
+            }
+            if flags & SSH_FILEXFER_ATTR_MODIFYTIME != 0 {
+                attrs.modify_time = Some(read_time(self, subsecond, &mut len)?);
+            }
+            if version >= 6 && flags & SSH_FILEXFER_ATTR_CTIME != 0 {
+                read_time(self, subsecond, &mut len)?;
+            }
+
+            // Nothing here acts on ACLs or the fields after them, but they
+            // have to be read to find the extended pairs
+            if flags & SSH_FILEXFER_ATTR_ACL != 0 {
+                len += 4 + self.read_string()?.len();
+            }
+            if version >= 5 && flags & SSH_FILEXFER_ATTR_BITS != 0 {
+                self.read_u32()?;
+                len += 4;
+                if version >= 6 {
+                    self.read_u32()?;
+                    len += 4;
+                }
+            }
+            if version >= 6 {
+                if flags & SSH_FILEXFER_ATTR_TEXT_HINT != 0 {
+                    self.read_u8()?;
+                    len += 1;
+                }
+                if flags & SSH_FILEXFER_ATTR_MIME_TYPE != 0 {
+                    len += 4 + self.read_string()?.len();
+                }
+                if flags & SSH_FILEXFER_ATTR_LINK_COUNT != 0 {
+                    self.read_u32()?;
+                    len += 4;
+                }
+                if flags & SSH_FILEXFER_ATTR_UNTRANSLATED_NAME != 0 {
+                    len += 4 + self.read_string()?.len();
+                }
+            }
+        }
+
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            let extended_count = self.read_u32()?;
+            len += 4;
+            for _ in 0..extended_count {
+                let name = self.read_string()?;
+                let value = self.read_string()?;
+                len += 8 + name.len() + value.len();
+                attrs
+                    .extended
+                    .push((String::from_utf8_lossy(&name).into_owned(), value));
+            }
+        }
+
+        // v4+ permissions need not carry the S_IFMT bits, so the type byte
+        // wins unless it only says "special" or "unknown"
+        if file_type != FileType::Unknown {
+            attrs.set_file_type(file_type);
+        }
+
+        Ok((len, attrs))
     }
+}
 
-    fn read_string(&mut self) -> Result<Vec<u8>, SftpError> {
-        self.read_string()
+// v4+ times are 64-bit seconds, optionally followed by nanoseconds, kept
+// here at the v3 resolution
+fn read_time<R: SftpReader + ?Sized>(
+    reader: &mut R,
+    subsecond: bool,
+    len: &mut usize,
+) -> Result<u32, SftpError> {
+    let seconds = reader.read_u64()? as i64;
+    *len += 8;
+    if subsecond {
+        reader.read_u32()?;
+        *len += 4;
     }
+    Ok(seconds.clamp(0, u32::MAX as i64) as u32)
+}
 
-    fn read_u64(&mut self) -> Result<u64, SftpError> {
-        self.read_u64()
+/// Reads one length-prefixed packet, prefix included. A length over
+/// `max_packet_size` is refused before anything is allocated, and that many
+/// bytes are skipped so the next packet can still be read.
+pub fn read_frame<R: Read>(reader: &mut R, max_packet_size: u32) -> Result<Vec<u8>, SftpError> {
+    let mut frame = Vec::new();
+    read_frame_into(reader, max_packet_size, &mut frame)?;
+    Ok(frame)
+}
+
+/// As [`read_frame`], but into `frame`, whose allocation is kept between
+/// packets. The whole packet arrives in two reads however many fields it has.
+pub fn read_frame_into<R: Read>(
+    reader: &mut R,
+    max_packet_size: u32,
+    frame: &mut Vec<u8>,
+) -> Result<(), SftpError> {
+    let mut length = [0; 4];
+    reader.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length);
+
+    if length > max_packet_size {
+        warn!("Skipping {} byte packet", length);
+        std::io::copy(&mut reader.take(length as u64), &mut std::io::sink())?;
+        return Err(SftpError::PacketTooLarge {
+            length,
+            limit: max_packet_size,
+        });
     }
 
-    fn discard(&mut self, bytes: &usize) -> Result<(), SftpError> {
-        self.discard(bytes)
+    frame.clear();
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.resize(4 + length as usize, 0);
+    reader.read_exact(&mut frame[4..])?;
+    Ok(())
+}
+
+// Spare buffers a pool keeps, enough for a full window of DATA replies
+const POOL_CAPACITY: usize = 64;
+// Strings shorter than this, such as names and handles, are cheap to
+// allocate and often kept, so they never draw from the pool
+const POOLED_MIN_LEN: usize = 1024;
+
+/// Spare buffers for decoding long strings and DATA payloads into.
+///
+/// Buffers come back through `TransportLayer::recycle` once their contents
+/// have been used, so a long download settles on one window's worth of
+/// allocations instead of making a new one per packet.
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    free: Vec<Vec<u8>>,
+}
+
+impl BufferPool {
+    /// An empty buffer, reusing a returned one if there is one.
+    pub fn take(&mut self) -> Vec<u8> {
+        self.free.pop().unwrap_or_default()
     }
 
-    fn parse_file_attributes(&mut self, flags: &u32) -> Result<(usize, FileAttributes), SftpError> {
-        self.parse_file_attributes(flags)
+    /// Keeps `buffer` for reuse, unless the pool is already full.
+    pub fn give(&mut self, mut buffer: Vec<u8>) {
+        if buffer.capacity() > 0 && self.free.len() < POOL_CAPACITY {
+            buffer.clear();
+            self.free.push(buffer);
+        }
     }
 }
 
 pub struct BufferReader<'a> {
     data: &'a [u8],
     position: usize,
+    pool: Option<&'a mut BufferPool>,
 }
 
 impl<'a> BufferReader<'a> {
     pub fn new(data: &'a [u8]) -> Self {
-        Self { data, position: 0 }
+        Self {
+            data,
+            position: 0,
+            pool: None,
+        }
+    }
+
+    /// Like `new`, but long strings are copied into buffers from `pool`.
+    pub fn with_pool(data: &'a [u8], pool: &'a mut BufferPool) -> Self {
+        Self {
+            data,
+            position: 0,
+            pool: Some(pool),
+        }
     }
 }
 
@@ -215,7 +504,15 @@ impl<'a> SftpReader for BufferReader<'a> {
                 .into(),
             ));
         }
-        let result = self.data[self.position..self.position + len].to_vec();
+        let bytes = &self.data[self.position..self.position + len];
+        let result = match self.pool.as_deref_mut() {
+            Some(pool) if len >= POOLED_MIN_LEN => {
+                let mut buffer = pool.take();
+                buffer.extend_from_slice(bytes);
+                buffer
+            }
+            _ => bytes.to_vec(),
+        };
         self.position += len;
         Ok(result)
     }
@@ -263,6 +560,21 @@ impl<'a> SftpReader for BufferReader<'a> {
         Ok(u64::from_be_bytes(bytes))
     }
 
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, SftpError> {
+        if self.position + len > self.data.len() {
+            return Err(SftpError::ClientError(
+                std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Not enough data for bytes",
+                )
+                .into(),
+            ));
+        }
+        let result = self.data[self.position..self.position + len].to_vec();
+        self.position += len;
+        Ok(result)
+    }
+
     fn discard(&mut self, bytes: &usize) -> Result<(), SftpError> {
         if self.position + bytes > self.data.len() {
             return Err(SftpError::ClientError(
@@ -276,102 +588,145 @@ impl<'a> SftpReader for BufferReader<'a> {
         self.position += bytes;
         Ok(())
     }
+}
 
-    fn parse_file_attributes(&mut self, flags: &u32) -> Result<(usize, FileAttributes), SftpError> {
-        let mut attrs = FileAttributes::default();
-        let mut len: usize = 0;
+/// Counterpart to `SftpReader`, appending values in SFTP wire format.
+pub trait SftpWriter {
+    fn write_u8(&mut self, value: u8);
+    fn write_u32(&mut self, value: u32);
+    fn write_u64(&mut self, value: u64);
+    /// A u32 length followed by the bytes themselves.
+    fn write_bytes(&mut self, bytes: &[u8]);
 
-        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
-            attrs.size = Some(self.read_u64()?);
-            len += 8;
-        }
-
-        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
-            let _uid = self.read_u32()?;
-            let _gid = self.read_u32()?;
-            len += 8;
-        }
+    fn write_string(&mut self, value: &str) {
+        self.write_bytes(value.as_bytes());
+    }
 
-        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
-            attrs.permissions = Some(self.read_u32()?);
-            len += 4;
+    /// ATTRS as protocol `version` lays them out. Unlike strings they carry
+    /// no length prefix: the flags word says which fields follow.
+    fn write_attrs(&mut self, attrs: &FileAttributes, version: u32) {
+        for byte in attrs.to_bytes_for(version) {
+            self.write_u8(byte);
         }
+    }
+}
 
-        if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
-            let _atime = self.read_u32()?;
-            attrs.modify_time = Some(self.read_u32()?);
-            len += 8;
-        }
+impl SftpWriter for Vec<u8> {
+    fn write_u8(&mut self, value: u8) {
+        self.push(value);
+    }
 
-        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
-            let extended_count = self.read_u32()?;
-            len += 4;
-            for _ in 0..extended_count {
-                let _name = self.read_string()?;
-                let _value = self.read_string()?;
-                len += 8 + _name.len() + _value.len();
-            }
-        }
+    fn write_u32(&mut self, value: u32) {
+        self.extend_from_slice(&value.to_be_bytes());
+    }
 
-        Ok((len, attrs))
+    fn write_u64(&mut self, value: u64) {
+        self.extend_from_slice(&value.to_be_bytes());
     }
-}
 
-impl ClientPacket {
-    fn add_header(&self, payload: Vec<u8>) -> Vec<u8> {
-        let mut packet: Vec<u8> = Vec::new();
-        let length = payload.len() as u32;
-        packet.extend_from_slice(&length.to_be_bytes());
-        packet.extend(payload);
-        packet
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.extend_from_slice(bytes);
     }
 
-    fn add_u32(&self, payload: &mut Vec<u8>, num: &u32) {
-        payload.extend_from_slice(&num.to_be_bytes());
+    fn write_attrs(&mut self, attrs: &FileAttributes, version: u32) {
+        self.extend_from_slice(&attrs.to_bytes_for(version));
     }
+}
 
-    fn add_u64(&self, payload: &mut Vec<u8>, num: &u64) {
-        payload.extend_from_slice(&num.to_be_bytes());
+impl ClientPacket {
+    /// Id the reply will carry; INIT is answered by VERSION, which has none.
+    pub fn request_id(&self) -> Option<u32> {
+        match self {
+            ClientPacket::Init { .. } => None,
+            ClientPacket::OpenDir { request_id, .. }
+            | ClientPacket::ReadDir { request_id, .. }
+            | ClientPacket::Close { request_id, .. }
+            | ClientPacket::RealPath { request_id, .. }
+            | ClientPacket::Stat { request_id, .. }
+            | ClientPacket::Open { request_id, .. }
+            | ClientPacket::Read { request_id, .. }
+            | ClientPacket::Rename { request_id, .. }
+            | ClientPacket::Extended { request_id, .. }
+            | ClientPacket::Write { request_id, .. }
+            | ClientPacket::Remove { request_id, .. }
+            | ClientPacket::MkDir { request_id, .. }
+            | ClientPacket::RmDir { request_id, .. }
+            | ClientPacket::ReadLink { request_id, .. }
+            | ClientPacket::Symlink { request_id, .. }
+            | ClientPacket::SetStat { request_id, .. }
+            | ClientPacket::FStat { request_id, .. }
+            | ClientPacket::FSetStat { request_id, .. }
+            | ClientPacket::LStat { request_id, .. } => Some(*request_id),
+        }
     }
 
-    fn add_string(&self, payload: &mut Vec<u8>, string: &str) {
-        payload.extend_from_slice(&(string.len() as u32).to_be_bytes());
-        payload.extend_from_slice(string.as_bytes());
+    // Room for the whole packet, so encoding never has to grow the buffer.
+    // Requests other than WRITE and extensions carry a path or two at most
+    fn encoded_size_hint(&self) -> usize {
+        const OVERHEAD: usize = 64;
+        match self {
+            ClientPacket::Write { handle, data, .. } => OVERHEAD + handle.len() + data.len(),
+            ClientPacket::Extended { request, data, .. } => OVERHEAD + request.len() + data.len(),
+            _ => OVERHEAD + 512,
+        }
     }
 
-    fn add_bytes(&self, payload: &mut Vec<u8>, bytes: &[u8]) {
-        payload.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
-        payload.extend_from_slice(bytes);
+    // From v4, STAT and friends name the attributes wanted; ask for the ones
+    // FileAttributes keeps
+    fn add_stat_flags(&self, payload: &mut Vec<u8>, version: u32) {
+        if version >= 4 {
+            let flags = SSH_FILEXFER_ATTR_SIZE
+                | SSH_FILEXFER_ATTR_PERMISSIONS
+                | SSH_FILEXFER_ATTR_ACCESSTIME
+                | SSH_FILEXFER_ATTR_MODIFYTIME
+                | SSH_FILEXFER_ATTR_OWNERGROUP;
+            payload.write_u32(flags);
+        }
     }
 
+    /// Wire format for protocol v3.
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut payload: Vec<u8> = Vec::new();
+        self.to_bytes_for(SFTP_MIN_VERSION)
+    }
+
+    /// Wire format for the negotiated protocol `version`.
+    pub fn to_bytes_for(&self, version: u32) -> Vec<u8> {
+        // Encoded straight into the final buffer, with the length filled in
+        // once the rest is known
+        let mut packet: Vec<u8> = Vec::with_capacity(self.encoded_size_hint());
+        packet.write_u32(0);
 
-        payload.push(self.packet_type());
+        // v6 folds SYMLINK into the more general LINK
+        packet.write_u8(match self {
+            ClientPacket::Symlink { .. } if version >= 6 => SSH_FXP_LINK,
+            _ => self.packet_type(),
+        });
 
         match self {
             ClientPacket::Init { version } => {
-                payload.extend_from_slice(&version.to_be_bytes());
+                packet.write_u32(*version);
             }
             ClientPacket::OpenDir { request_id, path } => {
-                self.add_u32(&mut payload, request_id);
-                self.add_string(&mut payload, path);
+                packet.write_u32(*request_id);
+                packet.write_string(path);
             }
             ClientPacket::ReadDir { request_id, handle } => {
-                self.add_u32(&mut payload, request_id);
-                self.add_bytes(&mut payload, handle);
+                packet.write_u32(*request_id);
+                packet.write_bytes(handle);
             }
             ClientPacket::Close { request_id, handle } => {
-                self.add_u32(&mut payload, request_id);
-                self.add_bytes(&mut payload, handle);
+                packet.write_u32(*request_id);
+                packet.write_bytes(handle);
             }
             ClientPacket::RealPath { request_id, path } => {
-                self.add_u32(&mut payload, request_id);
-                self.add_string(&mut payload, path);
+                packet.write_u32(*request_id);
+                packet.write_string(path);
             }
             ClientPacket::Stat { request_id, path } => {
-                self.add_u32(&mut payload, request_id);
-                self.add_string(&mut payload, path);
+                packet.write_u32(*request_id);
+                packet.write_string(path);
+                self.add_stat_flags(&mut packet, version);
             }
             ClientPacket::Open {
                 request_id,
@@ -379,16 +734,18 @@ impl ClientPacket {
                 pflags,
                 attrs,
             } => {
-                self.add_u32(&mut payload, request_id);
-                self.add_string(&mut payload, path);
-                self.add_u32(&mut payload, pflags);
-
-                if attrs.exists() {
-                    let attrs_bytes = attrs.to_bytes();
-                    self.add_bytes(&mut payload, &attrs_bytes);
-                } else {
-                    self.add_u32(&mut payload, &0u32);
+                packet.write_u32(*request_id);
+                packet.write_string(path);
+                match version {
+                    5.. => {
+                        let (access, disposition) = open_disposition(*pflags);
+                        packet.write_u32(access);
+                        packet.write_u32(disposition);
+                    }
+                    _ => packet.write_u32(*pflags),
                 }
+
+                packet.write_attrs(attrs, version);
             }
             ClientPacket::Read {
                 request_id,
@@ -396,72 +753,301 @@ impl ClientPacket {
                 offset,
                 len,
             } => {
-                self.add_u32(&mut payload, request_id);
-                self.add_bytes(&mut payload, handle);
-                self.add_u64(&mut payload, offset);
-                self.add_u32(&mut payload, len);
+                packet.write_u32(*request_id);
+                packet.write_bytes(handle);
+                packet.write_u64(*offset);
+                packet.write_u32(*len);
+            }
+            ClientPacket::Rename {
+                request_id,
+                old_path,
+                new_path,
+                flags,
+            } => {
+                packet.write_u32(*request_id);
+                packet.write_string(old_path);
+                packet.write_string(new_path);
+                if version >= 5 {
+                    packet.write_u32(*flags);
+                }
+            }
+            ClientPacket::Extended {
+                request_id,
+                request,
+                data,
+            } => {
+                packet.write_u32(*request_id);
+                packet.write_string(request);
+                packet.extend_from_slice(data);
+            }
+            ClientPacket::Write {
+                request_id,
+                handle,
+                offset,
+                data,
+            } => {
+                packet.write_u32(*request_id);
+                packet.write_bytes(handle);
+                packet.write_u64(*offset);
+                packet.write_bytes(data);
+            }
+            ClientPacket::Remove { request_id, path } => {
+                packet.write_u32(*request_id);
+                packet.write_string(path);
+            }
+            ClientPacket::MkDir {
+                request_id,
+                path,
+                attrs,
+            } => {
+                packet.write_u32(*request_id);
+                packet.write_string(path);
+
+                packet.write_attrs(attrs, version);
+            }
+            ClientPacket::RmDir { request_id, path } => {
+                packet.write_u32(*request_id);
+                packet.write_string(path);
+            }
+            ClientPacket::ReadLink { request_id, path } => {
+                packet.write_u32(*request_id);
+                packet.write_string(path);
+            }
+            ClientPacket::Symlink {
+                request_id,
+                target_path,
+                link_path,
+            } => {
+                // The draft puts the link first, but OpenSSH shipped with the
+                // two swapped and other servers followed it, so the target
+                // goes first on the wire. Servers speaking v4+ aren't OpenSSH
+                // and follow the draft, whose v6 LINK adds a symlink flag
+                packet.write_u32(*request_id);
+                match version {
+                    ..=3 => {
+                        packet.write_string(target_path);
+                        packet.write_string(link_path);
+                    }
+                    _ => {
+                        packet.write_string(link_path);
+                        packet.write_string(target_path);
+                    }
+                }
+                if version >= 6 {
+                    packet.write_u8(1);
+                }
+            }
+            ClientPacket::SetStat {
+                request_id,
+                path,
+                attrs,
+            } => {
+                packet.write_u32(*request_id);
+                packet.write_string(path);
+                packet.write_attrs(attrs, version);
+            }
+            ClientPacket::FStat { request_id, handle } => {
+                packet.write_u32(*request_id);
+                packet.write_bytes(handle);
+                self.add_stat_flags(&mut packet, version);
+            }
+            ClientPacket::FSetStat {
+                request_id,
+                handle,
+                attrs,
+            } => {
+                packet.write_u32(*request_id);
+                packet.write_bytes(handle);
+                packet.write_attrs(attrs, version);
+            }
+            ClientPacket::LStat { request_id, path } => {
+                packet.write_u32(*request_id);
+                packet.write_string(path);
+                self.add_stat_flags(&mut packet, version);
             }
         }
-        self.add_header(payload)
+        let length = (packet.len() - 4) as u32;
+        packet[..4].copy_from_slice(&length.to_be_bytes());
+        packet
+    }
+}
+
+// v5 replaced OPEN's pflags with the access wanted plus what to do about an
+// existing or missing file. The client keeps thinking in v3 pflags
+fn open_disposition(pflags: u32) -> (u32, u32) {
+    let mut access = 0;
+    if pflags & SSH_FXF_READ != 0 {
+        access |= ACE4_READ_DATA | ACE4_READ_ATTRIBUTES;
+    }
+    if pflags & SSH_FXF_WRITE != 0 {
+        access |= ACE4_WRITE_DATA | ACE4_WRITE_ATTRIBUTES;
+    }
+
+    let create = pflags & SSH_FXF_CREAT != 0;
+    let disposition = match (
+        create,
+        pflags & SSH_FXF_EXCL != 0,
+        pflags & SSH_FXF_TRUNC != 0,
+    ) {
+        (true, true, _) => SSH_FXF_CREATE_NEW,
+        (true, false, true) => SSH_FXF_CREATE_TRUNCATE,
+        (true, false, false) => SSH_FXF_OPEN_OR_CREATE,
+        (false, _, true) => SSH_FXF_TRUNCATE_EXISTING,
+        (false, _, false) => SSH_FXF_OPEN_EXISTING,
+    };
+    (access, disposition)
+}
+
+// Bytes of the packet body that the length field says are still unread.
+// Fields are taken off with checks, so a length that disagrees with the
+// contents is reported instead of underflowing
+struct Remaining {
+    bytes: usize,
+    packet_type: u8,
+}
+
+impl Remaining {
+    fn take(&mut self, used: usize, field: &str) -> Result<(), SftpError> {
+        self.bytes = self
+            .bytes
+            .checked_sub(used)
+            .ok_or_else(|| SftpError::MalformedPacket {
+                packet_type: self.packet_type,
+                detail: format!("{} runs past the packet length", field),
+            })?;
+        Ok(())
     }
 }
 
 impl ServerPacket {
+    /// Id of the request this answers; VERSION answers INIT and has none.
+    pub fn request_id(&self) -> Option<u32> {
+        match self {
+            ServerPacket::Version { .. } | ServerPacket::Unknown { .. } => None,
+            ServerPacket::Handle { request_id, .. }
+            | ServerPacket::Name { request_id, .. }
+            | ServerPacket::Status { request_id, .. }
+            | ServerPacket::Attrs { request_id, .. }
+            | ServerPacket::Data { request_id, .. }
+            | ServerPacket::ExtendedReply { request_id, .. } => Some(*request_id),
+        }
+    }
+
+    /// Parses a protocol v3 packet.
     pub fn from_bytes(data: &[u8]) -> Result<Self, SftpError> {
+        Self::from_bytes_for(data, SFTP_MIN_VERSION)
+    }
+
+    /// Parses a packet from a server speaking protocol `version`.
+    pub fn from_bytes_for(data: &[u8], version: u32) -> Result<Self, SftpError> {
         let mut reader = BufferReader::new(data);
-        Self::from_reader(&mut reader)
+        Self::from_reader(&mut reader, version)
     }
 
-    pub fn from_reader<R: SftpReader>(reader: &mut R) -> Result<Self, SftpError> {
+    /// Like `from_bytes_for`, decoding long strings and DATA payloads into
+    /// buffers from `pool`.
+    pub fn from_bytes_pooled(
+        data: &[u8],
+        version: u32,
+        pool: &mut BufferPool,
+    ) -> Result<Self, SftpError> {
+        let mut reader = BufferReader::with_pool(data, pool);
+        Self::from_reader(&mut reader, version)
+    }
+
+    pub fn from_reader<R: SftpReader>(reader: &mut R, version: u32) -> Result<Self, SftpError> {
         let message_length = reader.read_u32()? as usize;
+        if message_length == 0 {
+            return Err(SftpError::MalformedPacket {
+                packet_type: 0,
+                detail: "no type byte".to_string(),
+            });
+        }
         let message_type = reader.read_u8()?;
-        let mut remaining_bytes = message_length - 1;
+        let mut remaining = Remaining {
+            bytes: message_length - 1,
+            packet_type: message_type,
+        };
 
         match message_type {
             SSH_FXP_VERSION => {
-                let version = reader.read_u32()?;
-                remaining_bytes -= 4;
-                reader.discard(&remaining_bytes)?;
-                Ok(ServerPacket::Version { version })
+                let server_version = reader.read_u32()?;
+                remaining.take(4, "version")?;
+
+                // The rest of the packet is extension name/data string pairs
+                let mut extensions = Vec::new();
+                while remaining.bytes > 0 {
+                    let name = reader.read_string()?;
+                    let data = reader.read_string()?;
+                    remaining.take(8 + name.len() + data.len(), "extension")?;
+
+                    extensions.push((
+                        String::from_utf8_lossy(&name).into_owned(),
+                        String::from_utf8_lossy(&data).into_owned(),
+                    ));
+                }
+
+                Ok(ServerPacket::Version {
+                    version: server_version,
+                    extensions,
+                })
             }
             SSH_FXP_HANDLE => {
                 let request_id = reader.read_u32()?;
-                remaining_bytes -= 4;
+                remaining.take(4, "request id")?;
                 let handle = reader.read_string()?;
-                remaining_bytes -= 4 + handle.len();
+                remaining.take(4 + handle.len(), "handle")?;
+
+                if remaining.bytes > 0 {
+                    reader.discard(&remaining.bytes)?;
+                }
+
                 Ok(ServerPacket::Handle { request_id, handle })
             }
             SSH_FXP_NAME => {
                 let request_id = reader.read_u32()?;
-                remaining_bytes -= 4;
+                remaining.take(4, "request id")?;
 
                 let count = reader.read_u32()?;
-                remaining_bytes -= 4;
+                remaining.take(4, "count")?;
 
                 let mut files: Vec<FileInfo> = Vec::new();
                 for _ in 0..count {
                     let name = reader.read_string()?;
-                    let display_name = reader.read_string()?;
-                    remaining_bytes -= 8 + name.len() + display_name.len();
+                    remaining.take(4 + name.len(), "file name")?;
+                    let name =
+                        String::from_utf8(name).map_err(|e| SftpError::ClientError(e.into()))?;
+
+                    // Only v3 sends a longname
+                    let display_name = match version {
+                        3 => {
+                            let display_name = reader.read_string()?;
+                            remaining.take(4 + display_name.len(), "long name")?;
+                            Some(
+                                String::from_utf8(display_name)
+                                    .map_err(|e| SftpError::ClientError(e.into()))?,
+                            )
+                        }
+                        _ => None,
+                    };
 
                     let attr_flags = reader.read_u32()?;
-                    remaining_bytes -= 4;
+                    remaining.take(4, "attribute flags")?;
 
-                    let (attrs_length, attrs) = reader.parse_file_attributes(&attr_flags)?;
-                    remaining_bytes -= attrs_length;
+                    let (attrs_length, attrs) =
+                        reader.parse_file_attributes(&attr_flags, version)?;
+                    remaining.take(attrs_length, "attributes")?;
 
                     let file = FileInfo {
-                        name: String::from_utf8(name)
-                            .map_err(|e| SftpError::ClientError(e.into()))?,
-                        display_name: String::from_utf8(display_name)
-                            .map_err(|e| SftpError::ClientError(e.into()))?,
+                        display_name: display_name.unwrap_or_else(|| attrs.long_name(&name)),
+                        name,
                         attrs,
                     };
                     files.push(file);
                 }
 
-                if remaining_bytes > 0 {
-                    reader.discard(&remaining_bytes)?;
+                if remaining.bytes > 0 {
+                    reader.discard(&remaining.bytes)?;
                 }
 
                 Ok(ServerPacket::Name { request_id, files })
@@ -469,7 +1055,7 @@ impl ServerPacket {
 
             SSH_FXP_STATUS => {
                 let request_id = reader.read_u32()?;
-                remaining_bytes -= 4;
+                remaining.take(4, "request id")?;
 
                 let status_code = reader.read_u32()?;
 
@@ -477,16 +1063,20 @@ impl ServerPacket {
                     "Status Response to request_id: {} with code: {}",
                     request_id, status_code
                 );
-                remaining_bytes -= 4;
+                remaining.take(4, "status code")?;
 
                 let message = String::from_utf8(reader.read_string()?)
                     .map_err(|e| SftpError::ClientError(e.into()))?;
 
-                remaining_bytes -= 1 + message.len();
+                remaining.take(4 + message.len(), "message")?;
 
                 let lang = reader.read_string()?;
 
-                remaining_bytes -= 1 + lang.len();
+                remaining.take(4 + lang.len(), "language tag")?;
+
+                if remaining.bytes > 0 {
+                    reader.discard(&remaining.bytes)?;
+                }
 
                 Ok(ServerPacket::Status {
                     request_id,
@@ -496,44 +1086,72 @@ impl ServerPacket {
             }
             SSH_FXP_ATTRS => {
                 let request_id = reader.read_u32()?;
-                remaining_bytes -= 4;
+                remaining.take(4, "request id")?;
 
                 let attr_flags = reader.read_u32()?;
-                remaining_bytes -= 4;
+                remaining.take(4, "attribute flags")?;
 
                 let (attrs_length, attrs): (usize, FileAttributes) =
-                    reader.parse_file_attributes(&attr_flags)?;
-                remaining_bytes -= attrs_length;
+                    reader.parse_file_attributes(&attr_flags, version)?;
+                remaining.take(attrs_length, "attributes")?;
+
+                if remaining.bytes > 0 {
+                    reader.discard(&remaining.bytes)?;
+                }
 
                 Ok(ServerPacket::Attrs { request_id, attrs })
             }
             SSH_FXP_DATA => {
                 let request_id = reader.read_u32()?;
-                remaining_bytes -= 4;
+                remaining.take(4, "request id")?;
 
                 let data = reader.read_string()?;
+                remaining.take(4 + data.len(), "data")?;
+
+                if remaining.bytes > 0 {
+                    reader.discard(&remaining.bytes)?;
+                }
 
                 Ok(ServerPacket::Data { request_id, data })
             }
+            SSH_FXP_EXTENDED_REPLY => {
+                let request_id = reader.read_u32()?;
+                remaining.take(4, "request id")?;
 
-            // ... other packet types (copy from your existing from_session)
-            _ => Err(SftpError::ClientError(
-                std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("Unknown message type: {}", message_type),
-                )
-                .into(),
-            )),
+                // The payload is whatever is left; its layout depends on the
+                // request this answers
+                let data = reader.read_bytes(remaining.bytes)?;
+
+                Ok(ServerPacket::ExtendedReply { request_id, data })
+            }
+
+            // Skipped rather than refused, so a server sending something
+            // newer than this client models doesn't end the session
+            _ => {
+                warn!(
+                    "Skipping packet of unknown type {} ({} bytes)",
+                    message_type, remaining.bytes
+                );
+                reader.discard(&remaining.bytes)?;
+                Ok(ServerPacket::Unknown {
+                    packet_type: message_type,
+                })
+            }
         }
     }
     pub fn from_session(session: &mut SftpSession) -> Result<Self, SftpError> {
-        Self::from_reader(session)
+        let mut frame = std::mem::take(&mut session.frame);
+        let packet = read_frame_into(&mut session.channel, session.max_packet_size, &mut frame)
+            .and_then(|()| Self::from_bytes_pooled(&frame, session.version, &mut session.pool));
+        session.frame = frame;
+        packet
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sftp::types::FileType;
 
     fn assert_packet_length(bytes: &[u8], expected_payload_length: usize) {
         let length = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
@@ -596,8 +1214,14 @@ mod tests {
     fn create_test_attrs() -> FileAttributes {
         FileAttributes {
             size: Some(1024),
+            uid: Some(1000),
+            gid: Some(1000),
             permissions: Some(0o755),
+            access_time: Some(1234567800),
             modify_time: Some(1234567890),
+            owner: None,
+            group: None,
+            extended: Vec::new(),
             file_type: FileType::RegularFile,
             is_directory: false,
             is_regular_file: true,
@@ -629,7 +1253,10 @@ mod tests {
 
     #[test]
     fn test_server_packet_info() {
-        let version = ServerPacket::Version { version: 3 };
+        let version = ServerPacket::Version {
+            version: 3,
+            extensions: Vec::new(),
+        };
         assert_eq!(version.packet_type(), SSH_FXP_VERSION);
         assert_eq!(version.packet_name(), "SSH_FXP_VERSION");
 
@@ -762,10 +1389,257 @@ mod tests {
         assert_u32_field(&bytes, 24, chunk_size);
     }
 
-    //#[test]
-    //fn test_client_packet_open() {
-    //    todo!();
-    //}
+    #[test]
+    fn test_client_packet_write_fits_one_allocation() {
+        let handle = vec![0x01, 0x02, 0x03];
+        let data = vec![0x5a; 32768];
+
+        let write = ClientPacket::Write {
+            request_id: 7,
+            handle: handle.clone(),
+            offset: 4096,
+            data: data.clone(),
+        };
+        let bytes = write.to_bytes();
+
+        assert_packet_length(&bytes, 1 + 4 + 4 + 3 + 8 + 4 + 32768);
+        assert_packet_type(&bytes, SSH_FXP_WRITE);
+        assert_request_id(&bytes, 7);
+        assert_bytes_field(&bytes, 9, &handle);
+        assert_u64_field(&bytes, 16, 4096);
+        assert_bytes_field(&bytes, 24, &data);
+        // The buffer was sized up front and never grown
+        assert_eq!(bytes.capacity(), write.encoded_size_hint());
+    }
+
+    #[test]
+    fn test_client_packet_mkdir() {
+        let mkdir = ClientPacket::MkDir {
+            request_id: 100,
+            path: "/home".to_string(),
+            attrs: FileAttributes::default(),
+        };
+        let bytes = mkdir.to_bytes();
+
+        assert_packet_length(&bytes, 18); // 1 + 4 + 4 + 5 + 4 = 18
+        assert_packet_type(&bytes, SSH_FXP_MKDIR);
+        assert_request_id(&bytes, 100);
+        assert_string_field(&bytes, 9, "/home");
+        assert_u32_field(&bytes, 18, 0);
+    }
+
+    #[test]
+    fn test_client_packet_setstat() {
+        let setstat = ClientPacket::SetStat {
+            request_id: 100,
+            path: "/home".to_string(),
+            attrs: FileAttributes {
+                permissions: Some(0o750),
+                ..Default::default()
+            },
+        };
+        let bytes = setstat.to_bytes();
+
+        assert_packet_length(&bytes, 22); // 1 + 4 + 4 + 5 + 4 + 4 = 22
+        assert_packet_type(&bytes, SSH_FXP_SETSTAT);
+        assert_request_id(&bytes, 100);
+        assert_string_field(&bytes, 9, "/home");
+        assert_u32_field(&bytes, 18, SSH_FILEXFER_ATTR_PERMISSIONS);
+        assert_u32_field(&bytes, 22, 0o750);
+    }
+
+    #[test]
+    fn test_client_packet_fsetstat_sends_both_times() {
+        let handle = vec![0x01, 0x02, 0x03];
+        let fsetstat = ClientPacket::FSetStat {
+            request_id: 100,
+            handle: handle.clone(),
+            attrs: FileAttributes {
+                modify_time: Some(1700000000),
+                ..Default::default()
+            },
+        };
+        let bytes = fsetstat.to_bytes();
+
+        assert_packet_length(&bytes, 24); // 1 + 4 + 4 + 3 + 4 + 4 + 4 = 24
+        assert_packet_type(&bytes, SSH_FXP_FSETSTAT);
+        assert_request_id(&bytes, 100);
+        assert_bytes_field(&bytes, 9, &handle);
+        assert_u32_field(&bytes, 16, SSH_FILEXFER_ATTR_ACMODTIME);
+        assert_u32_field(&bytes, 20, 1700000000);
+        assert_u32_field(&bytes, 24, 1700000000);
+    }
+
+    #[test]
+    fn test_client_packet_symlink_sends_target_first() {
+        let symlink = ClientPacket::Symlink {
+            request_id: 100,
+            target_path: "../a".to_string(),
+            link_path: "/b".to_string(),
+        };
+        let bytes = symlink.to_bytes();
+
+        assert_packet_length(&bytes, 19); // 1 + 4 + 4 + 4 + 4 + 2 = 19
+        assert_packet_type(&bytes, SSH_FXP_SYMLINK);
+        assert_request_id(&bytes, 100);
+        assert_string_field(&bytes, 9, "../a");
+        assert_string_field(&bytes, 17, "/b");
+    }
+
+    #[test]
+    fn test_client_packet_symlink_becomes_link_in_v6() {
+        let symlink = ClientPacket::Symlink {
+            request_id: 100,
+            target_path: "../a".to_string(),
+            link_path: "/b".to_string(),
+        };
+        let bytes = symlink.to_bytes_for(6);
+
+        assert_packet_length(&bytes, 20); // 1 + 4 + 4 + 2 + 4 + 4 + 1 = 20
+        assert_packet_type(&bytes, SSH_FXP_LINK);
+        assert_string_field(&bytes, 9, "/b");
+        assert_string_field(&bytes, 15, "../a");
+        assert_eq!(bytes[23], 1); // symlink, not hard link
+    }
+
+    #[test]
+    fn test_client_packet_open_v5_sends_disposition() {
+        let open = ClientPacket::Open {
+            request_id: 100,
+            path: "/f".to_string(),
+            pflags: SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC,
+            attrs: FileAttributes::default(),
+        };
+        let bytes = open.to_bytes_for(5);
+
+        assert_packet_length(&bytes, 24); // 1 + 4 + 6 + 4 + 4 + 4 + 1 = 24
+        assert_string_field(&bytes, 9, "/f");
+        assert_u32_field(&bytes, 15, ACE4_WRITE_DATA | ACE4_WRITE_ATTRIBUTES);
+        assert_u32_field(&bytes, 19, SSH_FXF_CREATE_TRUNCATE);
+        assert_u32_field(&bytes, 23, 0);
+        assert_eq!(bytes[27], SSH_FILEXFER_TYPE_UNKNOWN);
+    }
+
+    #[test]
+    fn test_attrs_v3_keeps_every_field() {
+        let flags =
+            SSH_FILEXFER_ATTR_UIDGID | SSH_FILEXFER_ATTR_ACMODTIME | SSH_FILEXFER_ATTR_EXTENDED;
+        let mut bytes = Vec::new();
+        for value in [1000u32, 100, 1700000000, 1700000100, 1] {
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        for field in [&b"acl@example.com"[..], &[0, 1]] {
+            bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(field);
+        }
+
+        let mut reader = BufferReader::new(&bytes);
+        let (len, attrs) = reader.parse_file_attributes(&flags, 3).unwrap();
+
+        assert_eq!(len, bytes.len());
+        assert_eq!(attrs.uid, Some(1000));
+        assert_eq!(attrs.gid, Some(100));
+        assert_eq!(attrs.access_time, Some(1700000000));
+        assert_eq!(attrs.modify_time, Some(1700000100));
+        assert_eq!(
+            attrs.extended,
+            vec![("acl@example.com".to_string(), vec![0, 1])]
+        );
+    }
+
+    #[test]
+    fn test_attrs_v4_round_trip() {
+        let mut attrs = FileAttributes {
+            size: Some(4096),
+            permissions: Some(0o750),
+            access_time: Some(1700000000),
+            modify_time: Some(1700000100),
+            owner: Some("alice".to_string()),
+            group: Some("staff".to_string()),
+            ..Default::default()
+        };
+        attrs.set_file_type(FileType::Directory);
+        let bytes = attrs.to_bytes_for(4);
+
+        let mut reader = BufferReader::new(&bytes);
+        let flags = reader.read_u32().unwrap();
+        let (len, parsed) = reader.parse_file_attributes(&flags, 4).unwrap();
+
+        assert_eq!(len, bytes.len() - 4);
+        assert_eq!(parsed.size, Some(4096));
+        assert_eq!(parsed.permissions, Some(0o750));
+        assert_eq!(parsed.access_time, Some(1700000000));
+        assert_eq!(parsed.modify_time, Some(1700000100));
+        assert_eq!(parsed.owner.as_deref(), Some("alice"));
+        assert_eq!(parsed.group.as_deref(), Some("staff"));
+        assert_eq!(parsed.uid, None);
+        // The permissions lack S_IFMT bits; the type byte still says directory
+        assert!(parsed.is_directory);
+    }
+
+    #[test]
+    fn test_server_packet_name_v6() {
+        let mut body = vec![SSH_FXP_NAME];
+        body.extend_from_slice(&7u32.to_be_bytes()); // request id
+        body.extend_from_slice(&1u32.to_be_bytes()); // count
+        body.extend_from_slice(&1u32.to_be_bytes());
+        body.push(b'f'); // no longname follows in v6
+        let flags = SSH_FILEXFER_ATTR_SIZE
+            | SSH_FILEXFER_ATTR_MODIFYTIME
+            | SSH_FILEXFER_ATTR_SUBSECOND_TIMES
+            | SSH_FILEXFER_ATTR_MIME_TYPE
+            | SSH_FILEXFER_ATTR_LINK_COUNT;
+        body.extend_from_slice(&flags.to_be_bytes());
+        body.push(SSH_FILEXFER_TYPE_REGULAR);
+        body.extend_from_slice(&5u64.to_be_bytes()); // size
+        body.extend_from_slice(&1700000000u64.to_be_bytes()); // mtime
+        body.extend_from_slice(&500u32.to_be_bytes()); // mtime nanoseconds
+        body.extend_from_slice(&10u32.to_be_bytes());
+        body.extend_from_slice(b"text/plain");
+        body.extend_from_slice(&2u32.to_be_bytes()); // link count
+        let mut data = (body.len() as u32).to_be_bytes().to_vec();
+        data.extend(body);
+
+        let packet = ServerPacket::from_bytes_for(&data, 6).unwrap();
+        let ServerPacket::Name { request_id, files } = packet else {
+            panic!("Expected Name packet");
+        };
+        assert_eq!(request_id, 7);
+        assert_eq!(files[0].name, "f");
+        assert_eq!(files[0].attrs.size, Some(5));
+        assert_eq!(files[0].attrs.modify_time, Some(1700000000));
+        assert!(files[0].attrs.is_regular_file);
+        assert!(files[0].display_name.starts_with("----------"));
+        assert!(files[0].display_name.ends_with(" f"));
+    }
+
+    #[test]
+    fn test_client_packet_open_sends_creation_attrs() {
+        let open = ClientPacket::Open {
+            request_id: 100,
+            path: "/f".to_string(),
+            pflags: SSH_FXF_WRITE | SSH_FXF_CREAT,
+            attrs: FileAttributes {
+                permissions: Some(0o600),
+                modify_time: Some(1700000000),
+                ..Default::default()
+            },
+        };
+        let bytes = open.to_bytes();
+
+        assert_packet_length(&bytes, 31); // 1 + 4 + 6 + 4 + 4 + 4 + 4 + 4 = 31
+        assert_packet_type(&bytes, SSH_FXP_OPEN);
+        assert_string_field(&bytes, 9, "/f");
+        assert_u32_field(&bytes, 15, SSH_FXF_WRITE | SSH_FXF_CREAT);
+        assert_u32_field(
+            &bytes,
+            19,
+            SSH_FILEXFER_ATTR_PERMISSIONS | SSH_FILEXFER_ATTR_ACMODTIME,
+        );
+        assert_u32_field(&bytes, 23, 0o600);
+        assert_u32_field(&bytes, 27, 1700000000);
+        assert_u32_field(&bytes, 31, 1700000000);
+    }
 
     #[test]
     fn test_server_packet_version() {
@@ -782,7 +1656,36 @@ mod tests {
         ];
 
         let packet = ServerPacket::from_bytes(&data).unwrap();
-        assert!(matches!(packet, ServerPacket::Version { version: 3 }));
+        assert!(matches!(packet, ServerPacket::Version { version: 3, .. }));
+    }
+
+    #[test]
+    fn test_server_packet_version_extensions() {
+        let mut payload = vec![SSH_FXP_VERSION, 0, 0, 0, 3];
+        for field in ["posix-rename@openssh.com", "1", "statvfs@openssh.com", "2"] {
+            payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            payload.extend_from_slice(field.as_bytes());
+        }
+        let mut data = (payload.len() as u32).to_be_bytes().to_vec();
+        data.extend(payload);
+
+        let packet = ServerPacket::from_bytes(&data).unwrap();
+        if let ServerPacket::Version {
+            version,
+            extensions,
+        } = packet
+        {
+            assert_eq!(version, 3);
+            assert_eq!(
+                extensions,
+                vec![
+                    ("posix-rename@openssh.com".to_string(), "1".to_string()),
+                    ("statvfs@openssh.com".to_string(), "2".to_string()),
+                ]
+            );
+        } else {
+            panic!("Expected Version packet");
+        }
     }
     #[test]
     fn test_server_packet_handle() {
@@ -790,7 +1693,7 @@ mod tests {
             0,
             0,
             0,
-            13, // length = 13
+            12, // length = 12
             SSH_FXP_HANDLE,
             0,
             0,
@@ -813,4 +1716,219 @@ mod tests {
             panic!("Expected Handle packet");
         }
     }
+
+    #[test]
+    fn test_server_packet_name() {
+        let expected = create_test_file_info();
+        let mut payload = vec![SSH_FXP_NAME];
+        payload.extend_from_slice(&7u32.to_be_bytes()); // request id
+        payload.extend_from_slice(&1u32.to_be_bytes()); // count
+        for field in [&expected.name, &expected.display_name] {
+            payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            payload.extend_from_slice(field.as_bytes());
+        }
+        payload.extend_from_slice(
+            &(SSH_FILEXFER_ATTR_SIZE | SSH_FILEXFER_ATTR_PERMISSIONS).to_be_bytes(),
+        );
+        payload.extend_from_slice(&expected.attrs.size.unwrap().to_be_bytes());
+        payload.extend_from_slice(&expected.attrs.permissions.unwrap().to_be_bytes());
+
+        let mut data = (payload.len() as u32).to_be_bytes().to_vec();
+        data.extend(payload);
+
+        let packet = ServerPacket::from_bytes(&data).unwrap();
+        if let ServerPacket::Name { request_id, files } = packet {
+            assert_eq!(request_id, 7);
+            assert_eq!(files.len(), 1);
+            assert_eq!(files[0].name, expected.name);
+            assert_eq!(files[0].display_name, expected.display_name);
+            assert_eq!(files[0].attrs.size, expected.attrs.size);
+            assert_eq!(files[0].attrs.permissions, expected.attrs.permissions);
+        } else {
+            panic!("Expected Name packet");
+        }
+    }
+
+    #[test]
+    fn test_writer_round_trips_through_reader() {
+        let mut buffer = Vec::new();
+        buffer.write_u8(7);
+        buffer.write_u32(0xdeadbeef);
+        buffer.write_u64(1 << 40);
+        buffer.write_string("name");
+        buffer.write_bytes(&[1, 2]);
+
+        let mut reader = BufferReader::new(&buffer);
+        assert_eq!(reader.read_u8().unwrap(), 7);
+        assert_eq!(reader.read_u32().unwrap(), 0xdeadbeef);
+        assert_eq!(reader.read_u64().unwrap(), 1 << 40);
+        assert_eq!(reader.read_string().unwrap(), b"name");
+        assert_eq!(reader.read_string().unwrap(), [1, 2]);
+        assert_eq!(buffer.len(), 1 + 4 + 8 + 8 + 6);
+    }
+
+    #[test]
+    fn test_server_packet_length_too_short_is_malformed() {
+        // Claims 9 bytes, but the data string alone needs 7 after the id
+        let data = [
+            0,
+            0,
+            0,
+            9,
+            SSH_FXP_DATA,
+            0,
+            0,
+            0,
+            2,
+            0,
+            0,
+            0,
+            3,
+            b'a',
+            b'b',
+            b'c',
+        ];
+
+        match ServerPacket::from_bytes(&data) {
+            Err(SftpError::MalformedPacket {
+                packet_type,
+                detail,
+            }) => {
+                assert_eq!(packet_type, SSH_FXP_DATA);
+                assert!(detail.starts_with("data"));
+            }
+            other => panic!("Expected MalformedPacket, got {:?}", other),
+        }
+        assert!(matches!(
+            ServerPacket::from_bytes(&[0, 0, 0, 0]),
+            Err(SftpError::MalformedPacket { packet_type: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_server_packet_unknown_type_is_skipped() {
+        let data = [0, 0, 0, 5, 150, 1, 2, 3, 4, 0, 0, 0, 1, SSH_FXP_VERSION];
+        let mut reader = BufferReader::new(&data);
+
+        let packet = ServerPacket::from_reader(&mut reader, 3).unwrap();
+        assert!(matches!(packet, ServerPacket::Unknown { packet_type: 150 }));
+        // The payload was consumed, so the next packet starts where expected
+        assert_eq!(reader.read_u32().unwrap(), 1);
+        assert_eq!(reader.read_u8().unwrap(), SSH_FXP_VERSION);
+    }
+
+    #[test]
+    fn test_read_frame_skips_oversized_packet() {
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&100u32.to_be_bytes());
+        stream.extend_from_slice(&[0xff; 100]);
+        stream.extend_from_slice(&[0, 0, 0, 5, SSH_FXP_VERSION, 0, 0, 0, 3]);
+        let mut reader = std::io::Cursor::new(stream);
+
+        assert!(matches!(
+            read_frame(&mut reader, 64),
+            Err(SftpError::PacketTooLarge {
+                length: 100,
+                limit: 64
+            })
+        ));
+        let frame = read_frame(&mut reader, 64).unwrap();
+        assert!(matches!(
+            ServerPacket::from_bytes(&frame).unwrap(),
+            ServerPacket::Version { version: 3, .. }
+        ));
+    }
+
+    #[test]
+    fn test_pooled_data_reuses_recycled_buffer() {
+        let data_packet = |request_id: u32, data: &[u8]| {
+            let mut payload = vec![SSH_FXP_DATA];
+            payload.write_u32(request_id);
+            payload.write_bytes(data);
+            let mut frame = Vec::new();
+            frame.write_bytes(&payload);
+            frame
+        };
+        let mut pool = BufferPool::default();
+
+        let first = ServerPacket::from_bytes_pooled(&data_packet(1, &[1; 4096]), 3, &mut pool);
+        let Ok(ServerPacket::Data { data, .. }) = first else {
+            panic!("Expected DATA, got {:?}", first);
+        };
+        let address = data.as_ptr();
+        pool.give(data);
+
+        let second = ServerPacket::from_bytes_pooled(&data_packet(2, &[2; 4096]), 3, &mut pool);
+        let Ok(ServerPacket::Data { data, .. }) = second else {
+            panic!("Expected DATA, got {:?}", second);
+        };
+        assert_eq!(data.as_ptr(), address);
+        assert_eq!(data, vec![2; 4096]);
+
+        // Short strings are allocated afresh, leaving the pool alone
+        pool.give(data);
+        let handle = ServerPacket::from_bytes_pooled(
+            &[0, 0, 0, 10, SSH_FXP_HANDLE, 0, 0, 0, 3, 0, 0, 0, 1, 9],
+            3,
+            &mut pool,
+        );
+        assert!(matches!(handle, Ok(ServerPacket::Handle { .. })));
+        let spare = pool.take();
+        assert_eq!(spare.as_ptr(), address);
+    }
+
+    #[test]
+    fn test_read_frame_into_reuses_buffer() {
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&[0, 0, 0, 13, SSH_FXP_DATA, 0, 0, 0, 1, 0, 0, 0, 4]);
+        stream.extend_from_slice(b"data");
+        stream.extend_from_slice(&[0, 0, 0, 5, SSH_FXP_VERSION, 0, 0, 0, 3]);
+        let mut reader = std::io::Cursor::new(stream);
+        let mut frame = Vec::new();
+
+        read_frame_into(&mut reader, 64, &mut frame).unwrap();
+        assert!(matches!(
+            ServerPacket::from_bytes(&frame).unwrap(),
+            ServerPacket::Data { request_id: 1, ref data } if data == b"data"
+        ));
+        let capacity = frame.capacity();
+
+        read_frame_into(&mut reader, 64, &mut frame).unwrap();
+        assert_eq!(frame.len(), 9);
+        assert_eq!(frame.capacity(), capacity);
+        assert!(matches!(
+            ServerPacket::from_bytes(&frame).unwrap(),
+            ServerPacket::Version { version: 3, .. }
+        ));
+    }
+
+    #[test]
+    fn test_server_packet_data() {
+        let data = vec![
+            0,
+            0,
+            0,
+            12, // length = 12
+            SSH_FXP_DATA,
+            0,
+            0,
+            0,
+            2, // request id = 2
+            0,
+            0,
+            0,
+            3, // data length
+            b'a',
+            b'b',
+            b'c',
+        ];
+
+        let packet = ServerPacket::from_bytes(&data).unwrap();
+        if let ServerPacket::Data { request_id, data } = packet {
+            assert_eq!(request_id, 2);
+            assert_eq!(data, b"abc".to_vec());
+        } else {
+            panic!("Expected Data packet");
+        }
+    }
 }
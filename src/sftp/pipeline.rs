@@ -0,0 +1,169 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Upper bound on READ or WRITE requests kept in flight at once.
+pub const MAX_IN_FLIGHT: usize = 64;
+
+/// READDIR requests kept in flight on one handle once a directory turns out
+/// to span several NAME batches. Each batch is already up to a hundred or so
+/// entries, so a shallow window is enough.
+pub const READDIR_IN_FLIGHT: usize = 4;
+
+/// Largest chunk the tuner will grow to. Servers are free to return less than
+/// was asked for, so this only needs to be a sensible ceiling.
+pub const MAX_ADAPTIVE_CHUNK_SIZE: u32 = 131072;
+
+// Round trips within this much of the best seen mean the link is not yet
+// queueing our requests, so there is room to send more
+const HEADROOM_SLACK: Duration = Duration::from_millis(5);
+// Round trips this far above the best seen mean requests are piling up
+const CONGESTION_SLACK: Duration = Duration::from_millis(20);
+
+/// Picks how many requests to keep in flight and how large to make them,
+/// based on the round-trip time of each completed request.
+///
+/// While round trips stay close to the fastest one seen, the pipeline is
+/// deepened one request at a time, and once it is as deep as allowed the
+/// chunk size is doubled. When round trips grow well past the minimum the
+/// depth is halved. On a LAN this ends at full depth with large chunks, and
+/// on a high-latency link the window grows until it covers the round trip.
+#[derive(Debug, Clone)]
+pub struct PipelineTuner {
+    depth: usize,
+    max_depth: usize,
+    chunk_size: u32,
+    max_chunk_size: u32,
+    adapt_chunk: bool,
+    min_rtt: Option<Duration>,
+}
+
+impl PipelineTuner {
+    /// `adapt_chunk` is false when the chunk size was chosen explicitly and
+    /// must be left alone.
+    pub fn new(chunk_size: u32, adapt_chunk: bool, max_depth: usize) -> Self {
+        Self {
+            depth: 1,
+            max_depth: max_depth.max(1),
+            chunk_size,
+            max_chunk_size: MAX_ADAPTIVE_CHUNK_SIZE,
+            adapt_chunk,
+            min_rtt: None,
+        }
+    }
+
+    /// Replaces `MAX_ADAPTIVE_CHUNK_SIZE` as the size chunks may grow to, for
+    /// when the server has said how much it returns per READ.
+    pub fn with_max_chunk_size(mut self, max_chunk_size: u32) -> Self {
+        self.max_chunk_size = max_chunk_size.max(1);
+        if self.adapt_chunk {
+            self.chunk_size = self.chunk_size.min(self.max_chunk_size);
+        }
+        self
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+
+    /// Feeds back the round trip of one completed request.
+    pub fn record(&mut self, rtt: Duration) {
+        let min_rtt = *self.min_rtt.get_or_insert(rtt);
+        let min_rtt = min_rtt.min(rtt);
+        self.min_rtt = Some(min_rtt);
+
+        if rtt <= (min_rtt * 2).max(min_rtt + HEADROOM_SLACK) {
+            if self.depth < self.max_depth {
+                self.depth += 1;
+            } else if self.adapt_chunk && self.chunk_size < self.max_chunk_size {
+                self.chunk_size = (self.chunk_size * 2).min(self.max_chunk_size);
+            }
+        } else if rtt > (min_rtt * 4).max(min_rtt + CONGESTION_SLACK) {
+            self.depth = (self.depth / 2).max(1);
+        }
+    }
+
+    /// Called when the server returned fewer bytes than requested, which may
+    /// be a server-side cap on read length. Never asking for more than that
+    /// avoids a second round trip for the remainder of every chunk.
+    pub fn cap_chunk_size(&mut self, received: u32) {
+        if self.adapt_chunk && received > 0 {
+            self.chunk_size = self.chunk_size.min(received);
+        }
+    }
+}
+
+/// Asks running transfers to pause. Cloned handles share one flag, so it
+/// can be set from another thread or a signal handler while a transfer runs
+/// on this one.
+///
+/// A paused pipeline sends no further requests, waits for the ones already
+/// in flight and then stops with `SftpError::Paused`, having kept everything
+/// up to the first gap.
+#[derive(Debug, Clone, Default)]
+pub struct PauseHandle(Arc<AtomicBool>);
+
+impl PauseHandle {
+    pub fn pause(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deepens_then_grows_chunks_on_fast_link() {
+        let mut tuner = PipelineTuner::new(32768, true, 4);
+        for _ in 0..4 {
+            tuner.record(Duration::from_micros(200));
+        }
+
+        assert_eq!(tuner.depth(), 4);
+        assert_eq!(tuner.chunk_size(), 65536);
+    }
+
+    #[test]
+    fn test_grows_chunks_up_to_server_limit() {
+        let mut tuner = PipelineTuner::new(32768, true, 1).with_max_chunk_size(261120);
+        for _ in 0..8 {
+            tuner.record(Duration::from_micros(200));
+        }
+
+        assert_eq!(tuner.chunk_size(), 261120);
+    }
+
+    #[test]
+    fn test_backs_off_when_latency_climbs() {
+        let mut tuner = PipelineTuner::new(32768, true, 64);
+        for _ in 0..9 {
+            tuner.record(Duration::from_millis(300));
+        }
+        assert_eq!(tuner.depth(), 10);
+
+        tuner.record(Duration::from_millis(1500));
+        assert_eq!(tuner.depth(), 5);
+    }
+
+    #[test]
+    fn test_fixed_chunk_size_is_left_alone() {
+        let mut tuner = PipelineTuner::new(4096, false, 1);
+        tuner.record(Duration::from_micros(100));
+        tuner.cap_chunk_size(100);
+
+        assert_eq!(tuner.chunk_size(), 4096);
+    }
+}
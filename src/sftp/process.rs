@@ -0,0 +1,180 @@
+use super::constants::{DEFAULT_MAX_PACKET_SIZE, SFTP_MIN_VERSION};
+use super::error::SftpError;
+use super::packet::{self, BufferPool, ClientPacket, ServerPacket};
+use super::session::TransportLayer;
+use std::io::{BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+/// Speaks SFTP over the stdin/stdout of a child process instead of a
+/// libssh2 channel.
+///
+/// The usual child is `ssh -s user@host sftp`, which leaves authentication
+/// to OpenSSH and its configuration, so GSSAPI, hardware tokens, jump hosts
+/// and the like all work without this client knowing about them. Any
+/// command that ends up connected to an SFTP server will do, as with
+/// OpenSSH's ProxyCommand. The child's stderr is passed through so password
+/// prompts and connection errors reach the user.
+pub struct ProcessTransport {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    version: u32,
+    extensions: Vec<(String, String)>,
+    next_request_id: u32,
+    max_packet_size: u32,
+    // Reused for every incoming packet
+    frame: Vec<u8>,
+    pool: BufferPool,
+}
+
+impl ProcessTransport {
+    /// Runs `ssh -s <destination> sftp`, where `destination` is anything ssh
+    /// accepts, such as `user@host` or a `Host` alias from ~/.ssh/config.
+    pub fn ssh(destination: &str, version: u32) -> Result<Self, SftpError> {
+        let mut command = Command::new("ssh");
+        command.args(["-s", destination, "sftp"]);
+        Self::spawn(command, version)
+    }
+
+    /// The host in an ssh `destination`, without any `user@` before it.
+    pub fn host(destination: &str) -> &str {
+        destination
+            .rsplit_once('@')
+            .map_or(destination, |(_, host)| host)
+    }
+
+    /// Runs `command_line` through the shell and expects an SFTP server on
+    /// the other end of its stdio.
+    pub fn shell(command_line: &str, version: u32) -> Result<Self, SftpError> {
+        let (shell, flag) = match cfg!(windows) {
+            true => ("cmd", "/C"),
+            false => ("sh", "-c"),
+        };
+        let mut command = Command::new(shell);
+        command.args([flag, command_line]);
+        Self::spawn(command, version)
+    }
+
+    /// Starts `command` and performs the INIT/VERSION exchange with it,
+    /// offering protocol `version`.
+    pub fn spawn(mut command: Command, version: u32) -> Result<Self, SftpError> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(SftpError::ClientError("Child process has no stdio".into()));
+        };
+
+        let mut transport = Self {
+            child,
+            stdin,
+            stdout: BufReader::new(stdout),
+            version: SFTP_MIN_VERSION,
+            extensions: Vec::new(),
+            next_request_id: 0,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            frame: Vec::new(),
+            pool: BufferPool::default(),
+        };
+        transport.send_packet(ClientPacket::Init { version })?;
+
+        match transport.receive_packet()? {
+            ServerPacket::Version {
+                version: server_version,
+                extensions,
+            } => {
+                transport.version = version.min(server_version);
+                if transport.version < SFTP_MIN_VERSION {
+                    return Err(SftpError::ClientError(
+                        format!("Server only speaks SFTP version {}", server_version).into(),
+                    ));
+                }
+                transport.extensions = extensions;
+                Ok(transport)
+            }
+            _ => Err(SftpError::UnexpectedPacket("Version response")),
+        }
+    }
+}
+
+impl TransportLayer for ProcessTransport {
+    fn send_packet(&mut self, packet: ClientPacket) -> Result<(), SftpError> {
+        self.stdin.write_all(&packet.to_bytes_for(self.version))?;
+        self.stdin.flush()?;
+        Ok(())
+    }
+
+    fn receive_packet(&mut self) -> Result<ServerPacket, SftpError> {
+        packet::read_frame_into(&mut self.stdout, self.max_packet_size, &mut self.frame)?;
+        ServerPacket::from_bytes_pooled(&self.frame, self.version, &mut self.pool)
+    }
+
+    fn next_request_id(&mut self) -> u32 {
+        let id = self.next_request_id;
+        self.next_request_id += 1;
+        id
+    }
+
+    fn version(&self) -> u32 {
+        self.version
+    }
+
+    fn extensions(&self) -> &[(String, String)] {
+        &self.extensions
+    }
+
+    fn set_max_packet_size(&mut self, max_packet_size: u32) {
+        self.max_packet_size = max_packet_size;
+    }
+
+    fn max_packet_size(&self) -> u32 {
+        self.max_packet_size
+    }
+
+    fn recycle(&mut self, buffer: Vec<u8>) {
+        self.pool.give(buffer);
+    }
+}
+
+impl Drop for ProcessTransport {
+    fn drop(&mut self) {
+        // ssh would exit on its own once stdin closes, but an arbitrary
+        // command might not, and leaving it running would hang the caller
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::sftp::constants::SFTP_SUPPORTED_VERSION;
+
+    #[test]
+    fn test_handshake_with_child_process() {
+        // A VERSION packet for protocol 3 advertising one extension, then
+        // hold stdin open like a real server would
+        let mut version = vec![2, 0, 0, 0, 3];
+        for field in ["posix-rename@openssh.com", "1"] {
+            version.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            version.extend_from_slice(field.as_bytes());
+        }
+        let mut packet = (version.len() as u32).to_be_bytes().to_vec();
+        packet.extend_from_slice(&version);
+        let escaped: String = packet.iter().map(|b| format!("\\{:03o}", b)).collect();
+
+        let transport = ProcessTransport::shell(
+            &format!("printf '{}'; cat > /dev/null", escaped),
+            SFTP_SUPPORTED_VERSION,
+        )
+        .unwrap();
+
+        assert_eq!(transport.version(), 3);
+        assert_eq!(
+            transport.extensions(),
+            &[("posix-rename@openssh.com".to_string(), "1".to_string())]
+        );
+    }
+}
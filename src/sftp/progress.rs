@@ -0,0 +1,478 @@
+use crate::terminal;
+use serde::Serialize;
+use std::io::{self, Read, Write};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+// Minimum gap between "progress" events for a single file
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+// Width of the bar drawn by TerminalProgress, in characters
+const BAR_WIDTH: usize = 24;
+
+/// Receives progress for each file a transfer moves. `rate` is the average
+/// speed so far, in bytes per second.
+pub trait ProgressObserver: Send {
+    fn start(&mut self, file: &str, total: Option<u64>);
+    fn progress(&mut self, file: &str, bytes: u64, total: Option<u64>, rate: f64);
+    fn done(&mut self, file: &str, bytes: u64);
+    fn error(&mut self, file: &str, message: &str);
+}
+
+/// Emits newline-delimited JSON progress events (`start`, `progress`, `done`,
+/// `error`) so GUI wrappers and CI jobs can render their own progress.
+pub struct JsonProgress {
+    out: Box<dyn Write + Send>,
+}
+
+impl JsonProgress {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self { out }
+    }
+
+    pub fn stderr() -> Self {
+        Self::new(Box::new(io::stderr()))
+    }
+
+    // Progress output is best effort and must never fail a transfer
+    fn emit(&mut self, event: Event) {
+        if let Ok(line) = serde_json::to_string(&event) {
+            let _ = writeln!(self.out, "{}", line);
+            let _ = self.out.flush();
+        }
+    }
+}
+
+/// One line of `JsonProgress` output, tagged with its `event` name.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Event<'a> {
+    Start {
+        file: &'a str,
+        total: Option<u64>,
+    },
+    Progress {
+        file: &'a str,
+        bytes: u64,
+        total: Option<u64>,
+    },
+    Done {
+        file: &'a str,
+        bytes: u64,
+    },
+    Error {
+        file: &'a str,
+        message: &'a str,
+    },
+}
+
+impl ProgressObserver for JsonProgress {
+    fn start(&mut self, file: &str, total: Option<u64>) {
+        self.emit(Event::Start { file, total });
+    }
+
+    fn progress(&mut self, file: &str, bytes: u64, total: Option<u64>, _rate: f64) {
+        self.emit(Event::Progress { file, bytes, total });
+    }
+
+    fn done(&mut self, file: &str, bytes: u64) {
+        self.emit(Event::Done { file, bytes });
+    }
+
+    fn error(&mut self, file: &str, message: &str) {
+        self.emit(Event::Error { file, message });
+    }
+}
+
+/// Draws a live progress bar with the percentage done and the transfer rate,
+/// redrawn in place on one line per file.
+pub struct TerminalProgress {
+    out: Box<dyn Write + Send>,
+    started: Instant,
+    // Whether a bar is on screen without its closing newline
+    drawn: bool,
+}
+
+impl TerminalProgress {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self {
+            out,
+            started: Instant::now(),
+            drawn: false,
+        }
+    }
+
+    pub fn stderr() -> Self {
+        Self::new(Box::new(io::stderr()))
+    }
+
+    fn draw(&mut self, file: &str, bytes: u64, total: Option<u64>, rate: f64) {
+        let line = render_bar(file, bytes, total, rate);
+        // Return to the start of the line and clear what the last draw left
+        let _ = write!(self.out, "\r{}\x1b[K", line);
+        let _ = self.out.flush();
+        self.drawn = true;
+    }
+
+    fn finish_line(&mut self) {
+        if std::mem::take(&mut self.drawn) {
+            let _ = writeln!(self.out);
+            let _ = self.out.flush();
+        }
+    }
+}
+
+impl ProgressObserver for TerminalProgress {
+    fn start(&mut self, file: &str, total: Option<u64>) {
+        self.finish_line();
+        self.started = Instant::now();
+        self.draw(file, 0, total, 0.0);
+    }
+
+    fn progress(&mut self, file: &str, bytes: u64, total: Option<u64>, rate: f64) {
+        self.draw(file, bytes, total, rate);
+    }
+
+    fn done(&mut self, file: &str, bytes: u64) {
+        let rate = bytes as f64 / self.started.elapsed().as_secs_f64().max(f64::EPSILON);
+        self.draw(file, bytes, Some(bytes), rate);
+        self.finish_line();
+    }
+
+    fn error(&mut self, _file: &str, _message: &str) {
+        // The error itself is reported with the command's result
+        self.finish_line();
+    }
+}
+
+/// One line of `TerminalProgress` output, e.g.
+/// `report.csv [##########--------------]  42%  1.5M/s`.
+fn render_bar(file: &str, bytes: u64, total: Option<u64>, rate: f64) -> String {
+    let name = file.rsplit('/').next().unwrap_or(file);
+    let name = terminal::escape(name);
+    let rate = format_bytes(rate as u64);
+
+    match total {
+        Some(total) => {
+            let fraction = match total {
+                0 => 1.0,
+                total => (bytes as f64 / total as f64).min(1.0),
+            };
+            let filled = (fraction * BAR_WIDTH as f64) as usize;
+            format!(
+                "{} [{}{}] {:>3}% {:>6}/s",
+                name,
+                "#".repeat(filled),
+                "-".repeat(BAR_WIDTH - filled),
+                (fraction * 100.0) as u32,
+                rate
+            )
+        }
+        None => format!("{} {:>6} {:>6}/s", name, format_bytes(bytes), rate),
+    }
+}
+
+/// Renders a byte count with a binary unit, e.g. `1.5G`, as `df -h` does.
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["", "K", "M", "G", "T", "P"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => bytes.to_string(),
+        _ if value < 10.0 => format!("{:.1}{}", value, UNITS[unit]),
+        _ => format!("{:.0}{}", value, UNITS[unit]),
+    }
+}
+
+/// Counts the bytes of one file and passes them on to the observer, at most
+/// once per PROGRESS_INTERVAL. The adapters below drive one; a transfer that
+/// hands data over in slices can drive its own.
+pub struct Meter<'a> {
+    progress: Option<&'a mut (dyn ProgressObserver + 'static)>,
+    file: &'a str,
+    total: Option<u64>,
+    bytes: u64,
+    started: Instant,
+    last_report: Instant,
+}
+
+impl<'a> Meter<'a> {
+    pub fn new(
+        progress: Option<&'a mut (dyn ProgressObserver + 'static)>,
+        file: &'a str,
+        total: Option<u64>,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            progress,
+            file,
+            total,
+            bytes: 0,
+            started: now,
+            last_report: now,
+        }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn add(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+
+        if let Some(progress) = self.progress.as_deref_mut() {
+            if self.last_report.elapsed() >= PROGRESS_INTERVAL {
+                let elapsed = self.started.elapsed().as_secs_f64();
+                let rate = self.bytes as f64 / elapsed.max(f64::EPSILON);
+                progress.progress(self.file, self.bytes, self.total, rate);
+                self.last_report = Instant::now();
+            }
+        }
+    }
+}
+
+/// Writer adapter that reports bytes passing through it.
+pub struct ProgressWriter<'a, W: Write> {
+    inner: W,
+    meter: Meter<'a>,
+}
+
+impl<'a, W: Write> ProgressWriter<'a, W> {
+    pub fn new(
+        inner: W,
+        progress: Option<&'a mut (dyn ProgressObserver + 'static)>,
+        file: &'a str,
+        total: Option<u64>,
+    ) -> Self {
+        Self {
+            inner,
+            meter: Meter::new(progress, file, total),
+        }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.meter.bytes
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for ProgressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.meter.add(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader adapter that reports bytes taken from it, for uploads.
+pub struct ProgressReader<'a, R: Read> {
+    inner: R,
+    meter: Meter<'a>,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    pub fn new(
+        inner: R,
+        progress: Option<&'a mut (dyn ProgressObserver + 'static)>,
+        file: &'a str,
+        total: Option<u64>,
+    ) -> Self {
+        Self {
+            inner,
+            meter: Meter::new(progress, file, total),
+        }
+    }
+
+    // Not `bytes`, which `Read` already has
+    pub fn bytes_read(&self) -> u64 {
+        self.meter.bytes
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.meter.add(read);
+        Ok(read)
+    }
+}
+
+/// Adds up the bytes moved by several transfers running at once and reports
+/// them as one, under `label`.
+pub struct AggregateProgress<'a> {
+    meter: Mutex<Meter<'a>>,
+}
+
+impl<'a> AggregateProgress<'a> {
+    pub fn new(
+        mut progress: Option<&'a mut (dyn ProgressObserver + 'static)>,
+        label: &'a str,
+        total: Option<u64>,
+    ) -> Self {
+        if let Some(progress) = progress.as_deref_mut() {
+            progress.start(label, total);
+        }
+        Self {
+            meter: Mutex::new(Meter::new(progress, label, total)),
+        }
+    }
+
+    /// Wraps a reader or writer so the bytes passing through it count
+    /// towards the total.
+    pub fn tally<T>(&self, inner: T) -> Tally<'_, 'a, T> {
+        Tally {
+            inner,
+            progress: self,
+        }
+    }
+
+    /// Reports the total as done and returns it.
+    pub fn finish(self) -> u64 {
+        let mut meter = self
+            .meter
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(progress) = meter.progress.as_deref_mut() {
+            progress.done(meter.file, meter.bytes);
+        }
+        meter.bytes
+    }
+
+    fn add(&self, bytes: usize) {
+        self.meter
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .add(bytes);
+    }
+}
+
+/// Reader or writer adapter counting into an `AggregateProgress`.
+pub struct Tally<'p, 'a, T> {
+    inner: T,
+    progress: &'p AggregateProgress<'a>,
+}
+
+impl<T> Tally<'_, '_, T> {
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Tally<'_, '_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.progress.add(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Tally<'_, '_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.progress.add(read);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_events_are_one_json_object_per_line() {
+        let buffer = SharedBuffer::default();
+        let mut progress = JsonProgress::new(Box::new(buffer.clone()));
+
+        progress.start("/data/a.bin", Some(10));
+        progress.done("/data/a.bin", 10);
+        progress.error("/data/b.bin", "No such file");
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"event":"start","file":"/data/a.bin","total":10}"#,
+                r#"{"event":"done","file":"/data/a.bin","bytes":10}"#,
+                r#"{"event":"error","file":"/data/b.bin","message":"No such file"}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_bar_shows_percentage_and_rate() {
+        assert_eq!(
+            render_bar("/data/report.csv", 512, Some(1024), 2048.0),
+            "report.csv [############------------]  50%   2.0K/s"
+        );
+        // Without a size only the running count can be shown
+        assert_eq!(
+            render_bar("stream", 3 * 1024 * 1024, None, 1024.0),
+            "stream   3.0M   1.0K/s"
+        );
+    }
+
+    #[test]
+    fn test_terminal_progress_redraws_one_line_per_file() {
+        let buffer = SharedBuffer::default();
+        let mut progress = TerminalProgress::new(Box::new(buffer.clone()));
+
+        progress.start("/data/a.bin", Some(10));
+        progress.progress("/data/a.bin", 5, Some(10), 5.0);
+        progress.done("/data/a.bin", 10);
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(output.matches('\r').count(), 3);
+        assert_eq!(output.lines().count(), 1);
+        assert!(output.ends_with('\n'));
+        assert!(output.contains("100%"));
+    }
+
+    #[test]
+    fn test_progress_reader_counts_bytes() {
+        let mut reader = ProgressReader::new(&b"hello world"[..], None, "/data/a.bin", Some(11));
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+
+        assert_eq!(reader.meter.bytes, 11);
+        assert_eq!(read, b"hello world".to_vec());
+    }
+
+    #[test]
+    fn test_progress_writer_counts_bytes() {
+        let mut writer = ProgressWriter::new(Vec::new(), None, "/data/a.bin", None);
+        writer.write_all(b"hello").unwrap();
+        writer.write_all(b" world").unwrap();
+
+        assert_eq!(writer.bytes(), 11);
+        assert_eq!(writer.into_inner(), b"hello world".to_vec());
+    }
+}
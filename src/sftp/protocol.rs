@@ -1,17 +1,178 @@
+use super::constants::{
+    max_chunk_size, DEFAULT_CHUNK_SIZE, EXT_CHECK_FILE, EXT_CHECK_FILE_NAME, EXT_COPY_DATA,
+    EXT_HARDLINK, EXT_LIMITS, EXT_POSIX_RENAME, EXT_STATVFS, SSH_FXF_RENAME_ATOMIC,
+    SSH_FXF_RENAME_OVERWRITE,
+};
 use super::error::SftpError;
-use super::packet::{ClientPacket, ServerPacket};
-use super::session::TransportLayer;
+use super::packet::{BufferReader, ClientPacket, ServerPacket, SftpReader, SftpWriter};
+use super::pipeline::{PauseHandle, PipelineTuner, MAX_IN_FLIGHT, READDIR_IN_FLIGHT};
+use super::session::{ReplyRouter, TransportLayer};
 use super::types::FileAttributes;
-use super::types::{FileInfo, SftpStatus};
-use log::info;
+use super::types::{FileInfo, ServerLimits, SessionStats, SftpStatus, StatVfs};
+use log::debug;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
 
 pub struct SftpProtocol<T: TransportLayer> {
     transport: T,
+    chunk_size: u32,
+    adaptive_chunk: bool,
+    max_in_flight: usize,
+    stats: SessionStats,
+    replies: ReplyRouter,
+    limits: ServerLimits,
+    pause: PauseHandle,
+}
+
+// A READ that has been sent but not yet answered
+struct PendingRead {
+    request_id: u32,
+    offset: u64,
+    len: u32,
+    sent: Instant,
+    // Re-request for the rest of a chunk the server answered short
+    remainder: bool,
+}
+
+// A WRITE that has been sent but not yet acknowledged
+struct PendingWrite {
+    request_id: u32,
+    len: u32,
+    sent: Instant,
 }
 
 impl<T: TransportLayer> SftpProtocol<T> {
     pub fn new(transport: T) -> Self {
-        Self { transport }
+        Self {
+            transport,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            adaptive_chunk: true,
+            max_in_flight: MAX_IN_FLIGHT,
+            stats: SessionStats::new(),
+            replies: ReplyRouter::default(),
+            limits: ServerLimits::default(),
+            pause: PauseHandle::default(),
+        }
+    }
+
+    /// Sets the number of bytes per READ or WRITE and stops downloads from
+    /// adjusting it. Without this the chunk size starts at the default and
+    /// adapts to the link.
+    pub fn set_chunk_size(&mut self, chunk_size: u32) {
+        self.chunk_size = chunk_size.max(1);
+        self.adaptive_chunk = false;
+    }
+
+    /// Opens another channel to the server with the same transfer settings,
+    /// or `None` if the transport cannot.
+    pub fn open_sibling(&mut self) -> Option<Result<Self, SftpError>> {
+        let transport = match self.transport.open_channel()? {
+            Ok(transport) => transport,
+            Err(e) => return Some(Err(e)),
+        };
+        let mut sibling = Self::new(transport);
+        sibling.chunk_size = self.chunk_size;
+        sibling.adaptive_chunk = self.adaptive_chunk;
+        sibling.max_in_flight = self.max_in_flight;
+        sibling.limits = self.limits.clone();
+        sibling.pause = self.pause.clone();
+        Some(Ok(sibling))
+    }
+
+    /// Goes back to adapting the chunk size, starting from the default.
+    pub fn set_adaptive_chunk_size(&mut self) {
+        self.chunk_size = DEFAULT_CHUNK_SIZE;
+        self.adaptive_chunk = true;
+    }
+
+    /// Caps how many READ or WRITE requests a transfer keeps in flight. The
+    /// actual depth adapts up to this limit; 1 disables pipelining.
+    pub fn set_max_in_flight(&mut self, max_in_flight: usize) {
+        self.max_in_flight = max_in_flight.max(1);
+    }
+
+    /// Bounds how long each reply is waited for; `None` waits indefinitely.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.transport.set_timeout(timeout);
+    }
+
+    pub fn chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+
+    /// Largest READ whose reply fits in the packets the transport accepts.
+    pub fn max_chunk_size(&self) -> u32 {
+        max_chunk_size(self.transport.max_packet_size())
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight
+    }
+
+    /// Handle that pauses this channel's transfers, and those of any
+    /// sibling opened from it, while they run.
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.pause.clone()
+    }
+
+    /// Pauses this channel's transfers through `pause` instead, so one
+    /// handle can stop transfers that span several sessions.
+    pub fn set_pause_handle(&mut self, pause: PauseHandle) {
+        self.pause = pause;
+    }
+
+    /// Protocol version agreed with the server.
+    pub fn version(&self) -> u32 {
+        self.transport.version()
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn stats(&self) -> &SessionStats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut SessionStats {
+        &mut self.stats
+    }
+
+    pub fn supports_extension(&self, name: &str) -> bool {
+        self.transport
+            .extensions()
+            .iter()
+            .any(|(extension, _)| extension == name)
+    }
+
+    // Handles replies that carry nothing but a status code
+    fn expect_status(&mut self, request_id: u32, context: &'static str) -> Result<(), SftpError> {
+        match self.receive_reply(request_id)? {
+            ServerPacket::Status { status_code, .. } if status_code == SftpStatus::Ok as u32 => {
+                Ok(())
+            }
+            ServerPacket::Status {
+                status_code,
+                request_id,
+                message,
+            } => Err(SftpError::ServerError {
+                code: status_code,
+                request_id,
+                message,
+            }),
+            _ => Err(SftpError::UnexpectedPacket(context)),
+        }
+    }
+
+    fn send_request(&mut self, packet: ClientPacket) -> Result<(), SftpError> {
+        let request_id = packet.request_id();
+        self.stats.requests += 1;
+        self.transport.send_packet(packet)?;
+        if let Some(request_id) = request_id {
+            self.replies.sent(request_id);
+        }
+        Ok(())
     }
 
     pub fn realpath(&mut self, path: &str) -> Result<String, SftpError> {
@@ -21,9 +182,9 @@ impl<T: TransportLayer> SftpProtocol<T> {
             path: path.to_string(),
         };
 
-        self.transport.send_packet(packet)?;
+        self.send_request(packet)?;
 
-        match self.transport.receive_packet()? {
+        match self.receive_reply(request_id)? {
             ServerPacket::Name { files, .. } if files.len() == 1 => {
                 Ok(String::from(&files[0].name))
             }
@@ -40,6 +201,46 @@ impl<T: TransportLayer> SftpProtocol<T> {
         }
     }
 
+    pub fn readlink(&mut self, path: &str) -> Result<String, SftpError> {
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::ReadLink {
+            request_id,
+            path: path.to_string(),
+        };
+
+        self.send_request(packet)?;
+
+        match self.receive_reply(request_id)? {
+            ServerPacket::Name { files, .. } if files.len() == 1 => {
+                Ok(String::from(&files[0].name))
+            }
+            ServerPacket::Status {
+                status_code,
+                request_id,
+                message,
+            } => Err(SftpError::ServerError {
+                code: status_code,
+                request_id,
+                message,
+            }),
+            _ => Err(SftpError::UnexpectedResponse("ReadLink response")),
+        }
+    }
+
+    /// Creates `link_path` as a symlink to `target_path`, which is stored
+    /// as given, so a relative target stays relative to the link.
+    pub fn symlink(&mut self, target_path: &str, link_path: &str) -> Result<(), SftpError> {
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::Symlink {
+            request_id,
+            target_path: target_path.to_string(),
+            link_path: link_path.to_string(),
+        };
+
+        self.send_request(packet)?;
+        self.expect_status(request_id, "Symlink response")
+    }
+
     pub fn open_dir(&mut self, path: &str) -> Result<Vec<u8>, SftpError> {
         let request_id = self.transport.next_request_id();
         let packet = ClientPacket::OpenDir {
@@ -47,10 +248,13 @@ impl<T: TransportLayer> SftpProtocol<T> {
             path: path.to_string(),
         };
 
-        self.transport.send_packet(packet)?;
+        self.send_request(packet)?;
 
-        match self.transport.receive_packet()? {
-            ServerPacket::Handle { handle, .. } => Ok(handle),
+        match self.receive_reply(request_id)? {
+            ServerPacket::Handle { handle, .. } => {
+                self.stats.open_handles += 1;
+                Ok(handle)
+            }
             ServerPacket::Status {
                 status_code,
                 request_id,
@@ -71,9 +275,9 @@ impl<T: TransportLayer> SftpProtocol<T> {
             handle: handle.to_vec(),
         };
 
-        self.transport.send_packet(packet)?;
+        self.send_request(packet)?;
 
-        match self.transport.receive_packet()? {
+        match self.receive_reply(request_id)? {
             ServerPacket::Name { files, .. } => Ok(files),
             ServerPacket::Status {
                 status_code,
@@ -94,14 +298,93 @@ impl<T: TransportLayer> SftpProtocol<T> {
         }
     }
 
+    /// Reads every entry behind the directory `handle`, passing each NAME
+    /// batch to `on_page` as it arrives.
+    ///
+    /// A directory that needs more than one batch is likely to be large, so
+    /// from then on several READDIRs are kept in flight. Batches still reach
+    /// `on_page` in the order they were requested.
+    pub fn read_dir_all<F>(
+        &mut self,
+        handle: &[u8],
+        mut on_page: F,
+    ) -> Result<Vec<FileInfo>, SftpError>
+    where
+        F: FnMut(&[FileInfo]),
+    {
+        let mut all_files = Vec::new();
+        let mut in_flight: VecDeque<u32> = VecDeque::new();
+        let mut depth = 1;
+        let mut batches = 0;
+        let mut eof = false;
+
+        loop {
+            while !eof && in_flight.len() < depth {
+                let request_id = self.transport.next_request_id();
+                self.send_request(ClientPacket::ReadDir {
+                    request_id,
+                    handle: handle.to_vec(),
+                })?;
+                in_flight.push_back(request_id);
+            }
+            let Some(request_id) = in_flight.pop_front() else {
+                break;
+            };
+
+            let reply = match self.receive_reply(request_id) {
+                Ok(reply) => reply,
+                Err(e) => {
+                    for request_id in in_flight {
+                        self.replies.abandon(request_id);
+                    }
+                    return Err(e);
+                }
+            };
+            match reply {
+                // Taken as the end of the listing as well, since a server
+                // that sends these would otherwise be asked forever
+                ServerPacket::Name { files, .. } if files.is_empty() => eof = true,
+                ServerPacket::Name { files, .. } => {
+                    batches += 1;
+                    if batches > 1 {
+                        depth = READDIR_IN_FLIGHT.min(self.max_in_flight);
+                    }
+                    on_page(&files);
+                    all_files.extend(files);
+                }
+                ServerPacket::Status { status_code, .. }
+                    if status_code == SftpStatus::Eof as u32 =>
+                {
+                    eof = true;
+                }
+                ServerPacket::Status {
+                    status_code,
+                    request_id,
+                    message,
+                } => {
+                    self.drain(in_flight);
+                    return Err(SftpError::ServerError {
+                        code: status_code,
+                        request_id,
+                        message,
+                    });
+                }
+                _ => return Err(SftpError::UnexpectedPacket("ReadDir response")),
+            }
+        }
+
+        Ok(all_files)
+    }
+
     pub fn close(&mut self, handle: Vec<u8>) -> Result<(), SftpError> {
         let request_id = self.transport.next_request_id();
         let packet = ClientPacket::Close { request_id, handle };
 
-        self.transport.send_packet(packet)?;
+        self.send_request(packet)?;
 
-        match self.transport.receive_packet()? {
+        match self.receive_reply(request_id)? {
             ServerPacket::Status { status_code, .. } if status_code == SftpStatus::Ok as u32 => {
+                self.stats.open_handles = self.stats.open_handles.saturating_sub(1);
                 Ok(())
             }
             ServerPacket::Status {
@@ -124,9 +407,50 @@ impl<T: TransportLayer> SftpProtocol<T> {
             path: path.to_string(),
         };
 
-        self.transport.send_packet(packet)?;
+        self.send_request(packet)?;
+        self.receive_attrs(request_id)
+    }
+
+    /// Like `stat`, but describes a symlink itself rather than its target.
+    pub fn lstat(&mut self, path: &str) -> Result<FileAttributes, SftpError> {
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::LStat {
+            request_id,
+            path: path.to_string(),
+        };
+
+        self.send_request(packet)?;
+        self.receive_attrs(request_id)
+    }
+
+    fn receive_attrs(&mut self, request_id: u32) -> Result<FileAttributes, SftpError> {
+        match self.receive_reply(request_id)? {
+            ServerPacket::Attrs { attrs, .. } => Ok(attrs),
+            ServerPacket::Status {
+                request_id,
+                status_code,
+                message,
+            } => Err(SftpError::ServerError {
+                code: status_code,
+                request_id,
+                message,
+            }),
+            _ => Err(SftpError::UnexpectedPacket("Unexpected Attrs response")),
+        }
+    }
 
-        match self.transport.receive_packet()? {
+    /// Like `stat`, but for an open handle, so the answer is about the file
+    /// being read or written even if its path has since been replaced.
+    pub fn fstat(&mut self, handle: &[u8]) -> Result<FileAttributes, SftpError> {
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::FStat {
+            request_id,
+            handle: handle.to_vec(),
+        };
+
+        self.send_request(packet)?;
+
+        match self.receive_reply(request_id)? {
             ServerPacket::Attrs { attrs, .. } => Ok(attrs),
             ServerPacket::Status {
                 request_id,
@@ -137,23 +461,53 @@ impl<T: TransportLayer> SftpProtocol<T> {
                 request_id,
                 message,
             }),
-            _ => Err(SftpError::UnexpectedPacket("Unexpected Stat response")),
+            _ => Err(SftpError::UnexpectedPacket("Unexpected FStat response")),
         }
     }
 
+    /// Changes attributes through an open handle. Setting times this way
+    /// just before closing an upload can't race with another client
+    /// replacing the path.
+    pub fn fsetstat(&mut self, handle: &[u8], attrs: FileAttributes) -> Result<(), SftpError> {
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::FSetStat {
+            request_id,
+            handle: handle.to_vec(),
+            attrs,
+        };
+
+        self.send_request(packet)?;
+        self.expect_status(request_id, "FSetStat response")
+    }
+
     pub fn open(&mut self, path: &str, pflags: u32) -> Result<Vec<u8>, SftpError> {
+        self.open_with_attrs(path, pflags, FileAttributes::default())
+    }
+
+    /// Opens `path`, giving the attributes (say a mode or mtime) that a file
+    /// created by this open should start with. Servers ignore them when the
+    /// file already exists.
+    pub fn open_with_attrs(
+        &mut self,
+        path: &str,
+        pflags: u32,
+        attrs: FileAttributes,
+    ) -> Result<Vec<u8>, SftpError> {
         let request_id = self.transport.next_request_id();
         let packet = ClientPacket::Open {
             request_id,
             path: path.to_string(),
             pflags,
-            attrs: FileAttributes::default(),
+            attrs,
         };
 
-        self.transport.send_packet(packet)?;
+        self.send_request(packet)?;
 
-        match self.transport.receive_packet()? {
-            ServerPacket::Handle { handle, .. } => Ok(handle),
+        match self.receive_reply(request_id)? {
+            ServerPacket::Handle { handle, .. } => {
+                self.stats.open_handles += 1;
+                Ok(handle)
+            }
             ServerPacket::Status {
                 status_code,
                 request_id,
@@ -167,51 +521,824 @@ impl<T: TransportLayer> SftpProtocol<T> {
         }
     }
 
+    /// Reads the whole file behind `handle` into memory. Files that may be
+    /// large are better streamed with `read_chunks` or `read_to_writer`.
     pub fn read(&mut self, handle: &[u8]) -> Result<Vec<u8>, SftpError> {
-        let mut offset: u64 = 0;
-        let chunk_size: u32 = 32768;
         let mut result: Vec<u8> = Vec::new();
+        self.read_to_writer(handle, &mut result)?;
+        Ok(result)
+    }
+
+    /// Hands the file behind `handle` to `on_chunk` one DATA reply at a time,
+    /// in file order, returning the number of bytes read. An error from
+    /// `on_chunk` stops the read.
+    pub fn read_chunks<F>(&mut self, handle: &[u8], on_chunk: F) -> Result<u64, SftpError>
+    where
+        F: FnMut(&[u8]) -> std::io::Result<()>,
+    {
+        self.read_to_writer(handle, &mut ChunkWriter(on_chunk))
+    }
+
+    /// Streams the file behind `handle` into `writer`, returning the number of
+    /// bytes written.
+    ///
+    /// Several READs are kept in flight at once, with the depth and chunk size
+    /// tuned from measured round trips. Replies may arrive in any order but
+    /// reach `writer` in file order, so memory use is bounded by the window.
+    pub fn read_to_writer<W: Write>(
+        &mut self,
+        handle: &[u8],
+        writer: &mut W,
+    ) -> Result<u64, SftpError> {
+        self.read_to_writer_at(handle, 0, writer)
+    }
+
+    /// Like `read_to_writer`, but starts `offset` bytes into the file.
+    pub fn read_to_writer_at<W: Write>(
+        &mut self,
+        handle: &[u8],
+        offset: u64,
+        writer: &mut W,
+    ) -> Result<u64, SftpError> {
+        self.read_range_to_writer(handle, offset, u64::MAX, writer)
+    }
+
+    /// Reads at most `len` bytes starting at `offset`. Fewer are returned if
+    /// the file ends first.
+    pub fn read_range(
+        &mut self,
+        handle: &[u8],
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, SftpError> {
+        let mut result = Vec::new();
+        self.read_range_to_writer(handle, offset, len, &mut result)?;
+        Ok(result)
+    }
+
+    /// Streams at most `len` bytes starting at `offset` into `writer`,
+    /// returning the number written.
+    pub fn read_range_to_writer<W: Write>(
+        &mut self,
+        handle: &[u8],
+        offset: u64,
+        len: u64,
+        writer: &mut W,
+    ) -> Result<u64, SftpError> {
+        let end = offset.saturating_add(len);
+        let mut tuner = PipelineTuner::new(
+            self.limits.clamp_read(self.chunk_size),
+            self.adaptive_chunk,
+            self.max_in_flight,
+        );
+        // A server that states its READ limit can be asked for that much, as
+        // long as the reply still fits in a packet
+        if self.limits.max_read_length > 0 {
+            tuner = tuner.with_max_chunk_size(self.limits.clamp_read(self.max_chunk_size()));
+        }
+        let mut in_flight: Vec<PendingRead> = Vec::new();
+        // Chunks that arrived ahead of an earlier, still outstanding one
+        let mut completed: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+        let mut next_offset = offset;
+        let mut position = offset;
+        let mut outstanding_remainders = 0;
+        let mut eof = false;
+        let mut paused = false;
+        // Set when pausing left the rest of a short chunk unrequested
+        let mut gap = false;
+        let started = Instant::now();
+
         loop {
-            let request_id = self.transport.next_request_id();
-            let packet = ClientPacket::Read {
-                request_id,
-                handle: handle.to_vec(),
-                offset,
-                len: chunk_size,
-            };
+            paused |= self.pause.is_paused();
+            // Hold off on new chunks while a short reply is being chased, since
+            // it may turn out to be the end of the file
+            while !paused
+                && !eof
+                && outstanding_remainders == 0
+                && next_offset < end
+                && in_flight.len() < tuner.depth()
+            {
+                let len = (tuner.chunk_size() as u64).min(end - next_offset) as u32;
+                in_flight.push(self.send_read(handle, next_offset, len, false)?);
+                next_offset += len as u64;
+            }
+            if in_flight.is_empty() {
+                break;
+            }
 
-            self.transport.send_packet(packet)?;
+            let packet = match self.receive_any() {
+                Ok(packet) => packet,
+                Err(e) => {
+                    self.abandon_reads(&in_flight);
+                    return Err(e);
+                }
+            };
+            let request_id = match &packet {
+                ServerPacket::Data { request_id, .. } | ServerPacket::Status { request_id, .. } => {
+                    *request_id
+                }
+                _ => {
+                    self.abandon_reads(&in_flight);
+                    return Err(SftpError::UnexpectedPacket("Read response"));
+                }
+            };
+            let request = match in_flight.iter().position(|r| r.request_id == request_id) {
+                Some(index) => in_flight.swap_remove(index),
+                None => {
+                    self.abandon_reads(&in_flight);
+                    return Err(SftpError::UnexpectedResponse("Read response"));
+                }
+            };
+            if request.remainder {
+                outstanding_remainders -= 1;
+            }
 
-            match self.transport.receive_packet()? {
+            match packet {
+                // EOF is signalled by a status, so an empty DATA reply would
+                // otherwise be chased with the same request forever
+                ServerPacket::Data { data, .. } if data.is_empty() => {
+                    self.drain(in_flight.iter().map(|r| r.request_id));
+                    return Err(SftpError::UnexpectedResponse("Empty read response"));
+                }
                 ServerPacket::Data { data, .. } => {
-                    let data_len = data.len() as u64;
-                    result.extend_from_slice(&data);
+                    tuner.record(request.sent.elapsed());
+                    let received = data.len() as u64;
+                    self.stats.bytes_received += received;
 
-                    if data_len < chunk_size as u64 {
-                        break;
+                    if received < request.len as u64 && paused {
+                        gap = true;
+                    } else if received < request.len as u64 {
+                        tuner.cap_chunk_size(received as u32);
+                        let remainder = self.send_read(
+                            handle,
+                            request.offset + received,
+                            request.len - received as u32,
+                            true,
+                        )?;
+                        in_flight.push(remainder);
+                        outstanding_remainders += 1;
                     }
-                    offset += data_len;
+                    completed.insert(request.offset, data);
+                }
+                ServerPacket::Status { status_code, .. }
+                    if status_code == SftpStatus::Eof as u32 =>
+                {
+                    eof = true;
                 }
                 ServerPacket::Status {
                     status_code,
                     request_id,
                     message,
                 } => {
-                    if status_code == SftpStatus::Eof as u32 {
-                        break;
-                    } else {
-                        return Err(SftpError::ServerError {
-                            code: status_code,
-                            request_id,
-                            message,
-                        });
+                    self.drain(in_flight.iter().map(|r| r.request_id));
+                    return Err(SftpError::ServerError {
+                        code: status_code,
+                        request_id,
+                        message,
+                    });
+                }
+                _ => unreachable!(),
+            }
+
+            while let Some(data) = completed.remove(&position) {
+                if let Err(e) = writer.write_all(&data) {
+                    self.drain(in_flight.iter().map(|r| r.request_id));
+                    return Err(e.into());
+                }
+                position += data.len() as u64;
+                self.transport.recycle(data);
+            }
+        }
+
+        let written = position - offset;
+        debug!(
+            "Read {} bytes in {:?} (depth {}, chunk {})",
+            written,
+            started.elapsed(),
+            tuner.depth(),
+            tuner.chunk_size()
+        );
+        if paused && (gap || !eof && next_offset < end) {
+            return Err(SftpError::Paused);
+        }
+        Ok(written)
+    }
+
+    fn send_read(
+        &mut self,
+        handle: &[u8],
+        offset: u64,
+        len: u32,
+        remainder: bool,
+    ) -> Result<PendingRead, SftpError> {
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::Read {
+            request_id,
+            handle: handle.to_vec(),
+            offset,
+            len,
+        };
+
+        self.send_request(packet)?;
+        Ok(PendingRead {
+            request_id,
+            offset,
+            len,
+            sent: Instant::now(),
+            remainder,
+        })
+    }
+
+    // Receives the reply to `request_id`, whatever order the server answers
+    // in. Should it never come, the request is abandoned so a late reply is
+    // dropped rather than handed to a later request
+    fn receive_reply(&mut self, request_id: u32) -> Result<ServerPacket, SftpError> {
+        let result = self.replies.receive_for(&mut self.transport, request_id);
+        if result.is_err() {
+            self.replies.abandon(request_id);
+        }
+        self.check_timeout(result)
+    }
+
+    // Receives the reply to whichever outstanding request is answered next
+    fn receive_any(&mut self) -> Result<ServerPacket, SftpError> {
+        let result = self.replies.receive_any(&mut self.transport);
+        self.check_timeout(result)
+    }
+
+    fn check_timeout(
+        &mut self,
+        result: Result<ServerPacket, SftpError>,
+    ) -> Result<ServerPacket, SftpError> {
+        if let Err(SftpError::IoError(e)) = &result {
+            if e.kind() == ErrorKind::TimedOut {
+                // The outstanding replies may still turn up later, and are
+                // dropped when they do
+                self.replies.abandon_all();
+            }
+        }
+        result
+    }
+
+    /// Stops waiting for `in_flight` reads after the reply stream can no
+    /// longer be trusted, so their replies are dropped if they turn up.
+    fn abandon_reads(&mut self, in_flight: &[PendingRead]) {
+        for request in in_flight {
+            self.replies.abandon(request.request_id);
+        }
+    }
+
+    // Consumes replies to requests that are being abandoned, so the
+    // transport is left with nothing owed
+    fn drain(&mut self, request_ids: impl IntoIterator<Item = u32>) {
+        let mut lost = false;
+        for request_id in request_ids {
+            if lost {
+                self.replies.abandon(request_id);
+            } else if self.receive_reply(request_id).is_err() {
+                lost = true;
+            }
+        }
+    }
+
+    /// Writes `data` to the file behind `handle` in chunk-sized WRITE
+    /// requests, returning the number of bytes written.
+    pub fn write_all(&mut self, handle: &[u8], data: &[u8]) -> Result<u64, SftpError> {
+        self.write_all_at(handle, 0, data)
+    }
+
+    /// Like `write_all`, but starts `offset` bytes into the file.
+    pub fn write_all_at(
+        &mut self,
+        handle: &[u8],
+        offset: u64,
+        data: &[u8],
+    ) -> Result<u64, SftpError> {
+        self.write_all_reporting(handle, offset, data, |_| {})
+    }
+
+    /// Like `write_all_at`, calling `on_chunk` with the length of each chunk
+    /// as it is sent. Each WRITE payload is cut straight from `data`, with
+    /// no reader in between, which is what makes a memory-mapped source
+    /// worth having.
+    pub fn write_all_reporting<F>(
+        &mut self,
+        handle: &[u8],
+        offset: u64,
+        data: &[u8],
+        mut on_chunk: F,
+    ) -> Result<u64, SftpError>
+    where
+        F: FnMut(usize),
+    {
+        let mut position = 0;
+        self.write_chunks_at(handle, offset, |len| {
+            let end = data.len().min(position + len as usize);
+            let chunk = data[position..end].to_vec();
+            position = end;
+            on_chunk(chunk.len());
+            Ok(chunk)
+        })
+    }
+
+    /// Uploads everything `reader` yields to the file behind `handle`,
+    /// returning the number of bytes written. Only the chunks in flight are
+    /// held in memory, so the source may be a file, stdin or a pipe of any
+    /// length. Chunks are the chunk size, capped at the server's WRITE limit.
+    pub fn write_from_reader<R: Read>(
+        &mut self,
+        handle: &[u8],
+        reader: R,
+    ) -> Result<u64, SftpError> {
+        self.write_from_reader_at(handle, 0, reader)
+    }
+
+    /// Like `write_from_reader`, but starts `offset` bytes into the file.
+    ///
+    /// As with reads, several WRITEs are kept in flight at once. Success is
+    /// only reported once the server has acknowledged every chunk; on the
+    /// first failed chunk the rest are drained and its status returned.
+    pub fn write_from_reader_at<R: Read>(
+        &mut self,
+        handle: &[u8],
+        offset: u64,
+        mut reader: R,
+    ) -> Result<u64, SftpError> {
+        self.write_chunks_at(handle, offset, |len| read_chunk(&mut reader, len))
+    }
+
+    // The WRITE pipeline behind the methods above. `next_chunk` is asked for
+    // up to the given number of bytes, and a shorter chunk ends the input
+    fn write_chunks_at<F>(
+        &mut self,
+        handle: &[u8],
+        offset: u64,
+        mut next_chunk: F,
+    ) -> Result<u64, SftpError>
+    where
+        F: FnMut(u32) -> std::io::Result<Vec<u8>>,
+    {
+        let chunk_size = self.limits.clamp_write(self.chunk_size);
+        let mut tuner = PipelineTuner::new(chunk_size, false, self.max_in_flight);
+        let mut in_flight: Vec<PendingWrite> = Vec::new();
+        let mut next_offset = offset;
+        let mut acknowledged = 0;
+        let mut end_of_input = false;
+        let mut paused = false;
+
+        loop {
+            paused |= self.pause.is_paused();
+            while !paused && !end_of_input && in_flight.len() < tuner.depth() {
+                let chunk = match next_chunk(chunk_size) {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        self.drain(in_flight.iter().map(|r| r.request_id));
+                        return Err(e.into());
                     }
+                };
+                end_of_input = chunk.len() < chunk_size as usize;
+                if chunk.is_empty() {
+                    break;
                 }
-                _ => {
-                    return Err(SftpError::UnexpectedPacket("Read response"));
+                let len = chunk.len() as u64;
+                in_flight.push(self.send_write(handle, next_offset, chunk)?);
+                next_offset += len;
+            }
+            if in_flight.is_empty() {
+                break;
+            }
+
+            let packet = match self.receive_any() {
+                Ok(packet) => packet,
+                Err(e) => {
+                    for request in &in_flight {
+                        self.replies.abandon(request.request_id);
+                    }
+                    return Err(e);
                 }
+            };
+            let ServerPacket::Status {
+                request_id,
+                status_code,
+                message,
+            } = packet
+            else {
+                return Err(SftpError::UnexpectedPacket("Write response"));
+            };
+            let request = match in_flight.iter().position(|r| r.request_id == request_id) {
+                Some(index) => in_flight.swap_remove(index),
+                None => return Err(SftpError::UnexpectedResponse("Write response")),
+            };
+
+            if status_code != SftpStatus::Ok as u32 {
+                self.drain(in_flight.iter().map(|r| r.request_id));
+                return Err(SftpError::ServerError {
+                    code: status_code,
+                    request_id,
+                    message,
+                });
             }
+            tuner.record(request.sent.elapsed());
+            acknowledged += request.len as u64;
+            self.stats.bytes_sent += request.len as u64;
         }
-        Ok(result)
+        if paused && !end_of_input {
+            return Err(SftpError::Paused);
+        }
+        Ok(acknowledged)
+    }
+
+    fn send_write(
+        &mut self,
+        handle: &[u8],
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<PendingWrite, SftpError> {
+        let request_id = self.transport.next_request_id();
+        let len = data.len() as u32;
+        let packet = ClientPacket::Write {
+            request_id,
+            handle: handle.to_vec(),
+            offset,
+            data,
+        };
+
+        self.send_request(packet)?;
+        Ok(PendingWrite {
+            request_id,
+            len,
+            sent: Instant::now(),
+        })
+    }
+
+    pub fn remove(&mut self, path: &str) -> Result<(), SftpError> {
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::Remove {
+            request_id,
+            path: path.to_string(),
+        };
+
+        self.send_request(packet)?;
+        self.expect_status(request_id, "Remove response")
+    }
+
+    /// Changes whichever attributes are set in `attrs`.
+    pub fn setstat(&mut self, path: &str, attrs: FileAttributes) -> Result<(), SftpError> {
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::SetStat {
+            request_id,
+            path: path.to_string(),
+            attrs,
+        };
+
+        self.send_request(packet)?;
+        self.expect_status(request_id, "SetStat response")
+    }
+
+    /// Sends a SETSTAT of `attrs` for each of `paths`, keeping up to the
+    /// in-flight limit outstanding, and returns each one's outcome in order.
+    /// Only a failure of the connection itself ends the batch early.
+    pub fn setstat_all(
+        &mut self,
+        paths: &[String],
+        attrs: &FileAttributes,
+    ) -> Result<Vec<Result<(), SftpError>>, SftpError> {
+        let mut results = Vec::with_capacity(paths.len());
+        let mut in_flight: VecDeque<u32> = VecDeque::new();
+        let mut pending = paths.iter();
+
+        loop {
+            while in_flight.len() < self.max_in_flight {
+                let Some(path) = pending.next() else {
+                    break;
+                };
+                let request_id = self.transport.next_request_id();
+                let packet = ClientPacket::SetStat {
+                    request_id,
+                    path: path.clone(),
+                    attrs: attrs.clone(),
+                };
+                if let Err(e) = self.send_request(packet) {
+                    self.drain(in_flight);
+                    return Err(e);
+                }
+                in_flight.push_back(request_id);
+            }
+            let Some(request_id) = in_flight.pop_front() else {
+                break;
+            };
+
+            match self.expect_status(request_id, "SetStat response") {
+                Err(e @ SftpError::ServerError { .. }) => results.push(Err(e)),
+                Err(e) => {
+                    for request_id in in_flight {
+                        self.replies.abandon(request_id);
+                    }
+                    return Err(e);
+                }
+                Ok(()) => results.push(Ok(())),
+            }
+        }
+
+        Ok(results)
     }
+
+    /// Creates a directory with the server's default permissions.
+    pub fn mkdir(&mut self, path: &str) -> Result<(), SftpError> {
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::MkDir {
+            request_id,
+            path: path.to_string(),
+            attrs: FileAttributes::default(),
+        };
+
+        self.send_request(packet)?;
+        self.expect_status(request_id, "MkDir response")
+    }
+
+    pub fn rmdir(&mut self, path: &str) -> Result<(), SftpError> {
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::RmDir {
+            request_id,
+            path: path.to_string(),
+        };
+
+        self.send_request(packet)?;
+        self.expect_status(request_id, "RmDir response")
+    }
+
+    /// Plain SSH_FXP_RENAME. Servers refuse to replace an existing target.
+    pub fn rename(&mut self, old_path: &str, new_path: &str) -> Result<(), SftpError> {
+        self.rename_with_flags(old_path, new_path, 0)
+    }
+
+    fn rename_with_flags(
+        &mut self,
+        old_path: &str,
+        new_path: &str,
+        flags: u32,
+    ) -> Result<(), SftpError> {
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::Rename {
+            request_id,
+            old_path: old_path.to_string(),
+            new_path: new_path.to_string(),
+            flags,
+        };
+
+        self.send_request(packet)?;
+        self.expect_status(request_id, "Rename response")
+    }
+
+    /// Atomic rename that replaces an existing target. From v5 RENAME can
+    /// say so itself; before that it needs the posix-rename@openssh.com
+    /// extension.
+    pub fn posix_rename(&mut self, old_path: &str, new_path: &str) -> Result<(), SftpError> {
+        if self.version() >= 5 {
+            return self.rename_with_flags(
+                old_path,
+                new_path,
+                SSH_FXF_RENAME_OVERWRITE | SSH_FXF_RENAME_ATOMIC,
+            );
+        }
+        if !self.supports_extension(EXT_POSIX_RENAME) {
+            return Err(SftpError::UnsupportedExtension(EXT_POSIX_RENAME));
+        }
+
+        let mut data = Vec::new();
+        data.write_string(old_path);
+        data.write_string(new_path);
+
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::Extended {
+            request_id,
+            request: EXT_POSIX_RENAME.to_string(),
+            data,
+        };
+
+        self.send_request(packet)?;
+        self.expect_status(request_id, "posix-rename response")
+    }
+
+    /// Creates `link_path` as another name for the file at `target_path`.
+    pub fn hardlink(&mut self, target_path: &str, link_path: &str) -> Result<(), SftpError> {
+        if !self.supports_extension(EXT_HARDLINK) {
+            return Err(SftpError::UnsupportedExtension(EXT_HARDLINK));
+        }
+
+        let mut data = Vec::new();
+        data.write_string(target_path);
+        data.write_string(link_path);
+
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::Extended {
+            request_id,
+            request: EXT_HARDLINK.to_string(),
+            data,
+        };
+
+        self.send_request(packet)?;
+        self.expect_status(request_id, "hardlink response")
+    }
+
+    /// Has the server copy everything from `read_handle` into `write_handle`,
+    /// both from offset 0, without the data passing through the client.
+    pub fn copy_data(&mut self, read_handle: &[u8], write_handle: &[u8]) -> Result<(), SftpError> {
+        if !self.supports_extension(EXT_COPY_DATA) {
+            return Err(SftpError::UnsupportedExtension(EXT_COPY_DATA));
+        }
+
+        // A length of 0 copies up to the end of the source
+        let mut data = Vec::new();
+        data.write_bytes(read_handle);
+        data.write_u64(0);
+        data.write_u64(0);
+        data.write_bytes(write_handle);
+        data.write_u64(0);
+
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::Extended {
+            request_id,
+            request: EXT_COPY_DATA.to_string(),
+            data,
+        };
+
+        self.send_request(packet)?;
+        self.expect_status(request_id, "copy-data response")
+    }
+
+    /// Asks for the server's packet, READ, WRITE and open-handle limits when
+    /// it offers limits@openssh.com, so transfers stay within them. Other
+    /// servers are left at no limits. `SftpClient` calls this once on connecting.
+    pub fn load_limits(&mut self) -> Result<(), SftpError> {
+        if !self.supports_extension(EXT_LIMITS) {
+            return Ok(());
+        }
+
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::Extended {
+            request_id,
+            request: EXT_LIMITS.to_string(),
+            data: Vec::new(),
+        };
+
+        self.send_request(packet)?;
+
+        match self.receive_reply(request_id)? {
+            ServerPacket::ExtendedReply { data, .. } => {
+                self.limits = parse_limits(&data)?;
+                debug!("Server limits: {:?}", self.limits);
+                Ok(())
+            }
+            ServerPacket::Status {
+                status_code,
+                request_id,
+                message,
+            } => Err(SftpError::ServerError {
+                code: status_code,
+                request_id,
+                message,
+            }),
+            _ => Err(SftpError::UnexpectedPacket("limits response")),
+        }
+    }
+
+    pub fn limits(&self) -> &ServerLimits {
+        &self.limits
+    }
+
+    /// Reports usage of the filesystem holding `path`.
+    pub fn statvfs(&mut self, path: &str) -> Result<StatVfs, SftpError> {
+        if !self.supports_extension(EXT_STATVFS) {
+            return Err(SftpError::UnsupportedExtension(EXT_STATVFS));
+        }
+
+        let mut data = Vec::new();
+        data.write_string(path);
+
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::Extended {
+            request_id,
+            request: EXT_STATVFS.to_string(),
+            data,
+        };
+
+        self.send_request(packet)?;
+
+        match self.receive_reply(request_id)? {
+            ServerPacket::ExtendedReply { data, .. } => parse_statvfs(&data),
+            ServerPacket::Status {
+                status_code,
+                request_id,
+                message,
+            } => Err(SftpError::ServerError {
+                code: status_code,
+                request_id,
+                message,
+            }),
+            _ => Err(SftpError::UnexpectedPacket("statvfs response")),
+        }
+    }
+
+    /// Asks the server to hash `path` using the check-file extension,
+    /// returning the raw digest.
+    pub fn check_file(&mut self, path: &str, algorithm: &str) -> Result<Vec<u8>, SftpError> {
+        if !self.supports_extension(EXT_CHECK_FILE) {
+            return Err(SftpError::UnsupportedExtension(EXT_CHECK_FILE));
+        }
+
+        // Whole file (offset 0, length 0) hashed as a single block
+        let mut data = Vec::new();
+        data.write_string(path);
+        data.write_string(algorithm);
+        data.write_u64(0);
+        data.write_u64(0);
+        data.write_u32(0);
+
+        let request_id = self.transport.next_request_id();
+        let packet = ClientPacket::Extended {
+            request_id,
+            request: EXT_CHECK_FILE_NAME.to_string(),
+            data,
+        };
+
+        self.send_request(packet)?;
+
+        match self.receive_reply(request_id)? {
+            ServerPacket::ExtendedReply { data, .. } => {
+                let mut reader = BufferReader::new(&data);
+                let reply_name = reader.read_string()?;
+                if reply_name != EXT_CHECK_FILE.as_bytes() {
+                    return Err(SftpError::UnexpectedResponse("check-file reply"));
+                }
+                let used = reader.read_string()?;
+                if used != algorithm.as_bytes() {
+                    return Err(SftpError::UnexpectedResponse(
+                        "check-file used another algorithm",
+                    ));
+                }
+                // The hash runs from after the two strings to the end
+                Ok(data[8 + reply_name.len() + used.len()..].to_vec())
+            }
+            ServerPacket::Status {
+                status_code,
+                request_id,
+                message,
+            } => Err(SftpError::ServerError {
+                code: status_code,
+                request_id,
+                message,
+            }),
+            _ => Err(SftpError::UnexpectedPacket("check-file response")),
+        }
+    }
+}
+
+// Reads `len` bytes, or whatever is left before the end of `reader`. Pipes
+// and stdin hand data over in pieces of any size, so one read is not enough
+fn read_chunk<R: Read>(reader: &mut R, len: u32) -> std::io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(len as usize);
+    reader.by_ref().take(len as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+// Feeds the read pipeline's output to a chunk callback
+struct ChunkWriter<F>(F);
+
+impl<F: FnMut(&[u8]) -> std::io::Result<()>> Write for ChunkWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        (self.0)(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn parse_limits(data: &[u8]) -> Result<ServerLimits, SftpError> {
+    let mut reader = BufferReader::new(data);
+    Ok(ServerLimits {
+        max_packet_length: reader.read_u64()?,
+        max_read_length: reader.read_u64()?,
+        max_write_length: reader.read_u64()?,
+        max_open_handles: reader.read_u64()?,
+    })
+}
+
+fn parse_statvfs(data: &[u8]) -> Result<StatVfs, SftpError> {
+    let mut reader = BufferReader::new(data);
+    Ok(StatVfs {
+        block_size: reader.read_u64()?,
+        fragment_size: reader.read_u64()?,
+        blocks: reader.read_u64()?,
+        blocks_free: reader.read_u64()?,
+        blocks_available: reader.read_u64()?,
+        files: reader.read_u64()?,
+        files_free: reader.read_u64()?,
+        files_available: reader.read_u64()?,
+        filesystem_id: reader.read_u64()?,
+        flags: reader.read_u64()?,
+        max_name_length: reader.read_u64()?,
+    })
 }
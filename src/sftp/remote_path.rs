@@ -0,0 +1,86 @@
+//! Remote path handling.
+//!
+//! SFTP paths are always `/`-separated, whatever the local platform.
+//! `std::path` would join them with `\` on Windows and treat `/srv` as
+//! relative there, so remote paths are built with these helpers instead.
+
+use std::path::Path;
+
+pub fn is_absolute(path: &str) -> bool {
+    path.starts_with('/')
+}
+
+/// Resolves `path` against the directory `base`, collapsing `.`, `..` and
+/// repeated slashes. `..` at the root stays at the root.
+pub fn join(base: &str, path: &str) -> String {
+    if is_absolute(path) {
+        return normalize(path);
+    }
+    normalize(&format!("{}/{}", base, path))
+}
+
+/// Appends a relative local path, such as one found walking a local tree,
+/// with `/` between its components.
+pub fn join_relative(base: &str, relative: &Path) -> String {
+    join(base, &to_slash(relative))
+}
+
+/// Writes a relative local path with `/` separators on every platform.
+pub fn to_slash(relative: &Path) -> String {
+    let components: Vec<_> = relative
+        .iter()
+        .map(|component| component.to_string_lossy())
+        .collect();
+    components.join("/")
+}
+
+pub fn normalize(path: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    format!("/{}", components.join("/"))
+}
+
+/// Splits a normalized path into its directory and final component.
+pub fn split(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(index) => (&path[..index], &path[index + 1..]),
+        None => (".", path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_join() {
+        assert_eq!(join("/home/demo", "docs/a.txt"), "/home/demo/docs/a.txt");
+        assert_eq!(join("/home/demo", "/etc//hosts"), "/etc/hosts");
+        assert_eq!(join("/home/demo", ".."), "/home");
+        assert_eq!(join("/home/demo", "./x/../y"), "/home/demo/y");
+        assert_eq!(join("/", "../.."), "/");
+        assert_eq!(join("/srv", ""), "/srv");
+    }
+
+    #[test]
+    fn test_join_relative_uses_forward_slashes() {
+        let relative: PathBuf = ["sub", "dir", "file.txt"].iter().collect();
+        assert_eq!(join_relative("/srv", &relative), "/srv/sub/dir/file.txt");
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split("/srv/logs/app.log"), ("/srv/logs", "app.log"));
+        assert_eq!(split("/app.log"), ("/", "app.log"));
+    }
+}
@@ -1,21 +1,167 @@
 use super::constants::*;
 use super::error::SftpError;
-use super::packet::{ClientPacket, ServerPacket};
-use super::types::{FileAttributes, FileType};
-use log::info;
-use ssh2::Channel;
-use std::io::{Read, Write};
+use super::packet::{BufferPool, ClientPacket, ServerPacket};
+use log::debug;
+use ssh2::{Channel, Session};
+use std::collections::{HashSet, VecDeque};
+use std::io::Write;
+use std::time::Duration;
 
 pub struct SftpSession {
     pub channel: Channel,
-    //pub version: u32,
+    pub version: u32,
     pub next_request_id: u32,
+    pub max_packet_size: u32,
+    // Reused for every incoming packet
+    pub(crate) frame: Vec<u8>,
+    pub(crate) pool: BufferPool,
+    extensions: Vec<(String, String)>,
+    server_banner: Option<String>,
+    ssh: Option<Session>,
 }
 
 pub trait TransportLayer: Send {
     fn send_packet(&mut self, packet: ClientPacket) -> Result<(), SftpError>;
     fn receive_packet(&mut self) -> Result<ServerPacket, SftpError>;
     fn next_request_id(&mut self) -> u32;
+
+    /// Protocol version agreed with the server during INIT/VERSION.
+    fn version(&self) -> u32 {
+        SFTP_SUPPORTED_VERSION
+    }
+
+    /// Extension name/data pairs advertised in the server's VERSION packet.
+    fn extensions(&self) -> &[(String, String)] {
+        &[]
+    }
+
+    /// SSH identification string sent by the server, if known.
+    fn server_banner(&self) -> Option<&str> {
+        None
+    }
+
+    /// Limits how long a single receive waits for the server; `None` waits
+    /// indefinitely. Transports that cannot time out ignore this.
+    fn set_timeout(&mut self, _timeout: Option<Duration>) {}
+
+    /// Sets the longest packet accepted from the server. Transports that
+    /// parse from memory they were handed ignore this.
+    fn set_max_packet_size(&mut self, _max_packet_size: u32) {}
+
+    /// Longest packet accepted from the server.
+    fn max_packet_size(&self) -> u32 {
+        DEFAULT_MAX_PACKET_SIZE
+    }
+
+    /// Opens another SFTP channel to the same server, for transfers split
+    /// across several. `None` when the transport has no connection to share.
+    fn open_channel(&mut self) -> Option<Result<Self, SftpError>>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
+    /// Hands back a buffer taken from a received packet once its contents
+    /// have been used, for the transport to decode into again.
+    fn recycle(&mut self, _buffer: Vec<u8>) {}
+}
+
+/// Matches replies to the requests they answer.
+///
+/// Servers may answer outstanding requests in any order, so replies are
+/// paired up by request_id rather than by arrival. A reply to another
+/// outstanding request is held until that request asks for it, and replies
+/// to abandoned requests (say after a timeout) are dropped whenever they
+/// turn up.
+#[derive(Debug, Default)]
+pub struct ReplyRouter {
+    outstanding: HashSet<u32>,
+    abandoned: HashSet<u32>,
+    // Replies that arrived while waiting for another, oldest first
+    held: VecDeque<ServerPacket>,
+}
+
+impl ReplyRouter {
+    /// Notes that `request_id` has been sent and its reply is due.
+    pub fn sent(&mut self, request_id: u32) {
+        self.outstanding.insert(request_id);
+    }
+
+    /// Gives up on `request_id`; its reply is discarded if it ever arrives.
+    pub fn abandon(&mut self, request_id: u32) {
+        if self.outstanding.remove(&request_id) {
+            self.abandoned.insert(request_id);
+        }
+        self.held
+            .retain(|packet| packet.request_id() != Some(request_id));
+    }
+
+    /// Gives up on every request still waiting for a reply.
+    pub fn abandon_all(&mut self) {
+        self.abandoned.extend(self.outstanding.drain());
+        self.held.clear();
+    }
+
+    /// Returns the reply to `request_id`, holding on to replies to other
+    /// outstanding requests that arrive first.
+    pub fn receive_for<T: TransportLayer>(
+        &mut self,
+        transport: &mut T,
+        request_id: u32,
+    ) -> Result<ServerPacket, SftpError> {
+        if let Some(index) = self
+            .held
+            .iter()
+            .position(|packet| packet.request_id() == Some(request_id))
+        {
+            return Ok(self.held.remove(index).expect("index is in range"));
+        }
+
+        loop {
+            let packet = self.receive_new(transport)?;
+            match packet.request_id() {
+                Some(id) if id != request_id => self.held.push_back(packet),
+                _ => return Ok(packet),
+            }
+        }
+    }
+
+    /// Returns the next reply to any outstanding request.
+    pub fn receive_any<T: TransportLayer>(
+        &mut self,
+        transport: &mut T,
+    ) -> Result<ServerPacket, SftpError> {
+        match self.held.pop_front() {
+            Some(packet) => Ok(packet),
+            None => self.receive_new(transport),
+        }
+    }
+
+    // Reads until a reply to an outstanding request arrives. A reply nobody
+    // is waiting for means the stream can't be trusted
+    fn receive_new<T: TransportLayer>(
+        &mut self,
+        transport: &mut T,
+    ) -> Result<ServerPacket, SftpError> {
+        loop {
+            let packet = transport.receive_packet()?;
+            if let ServerPacket::Unknown { .. } = packet {
+                continue;
+            }
+            let Some(id) = packet.request_id() else {
+                return Ok(packet);
+            };
+            if self.outstanding.remove(&id) {
+                return Ok(packet);
+            }
+            if self.abandoned.remove(&id) {
+                debug!("Discarding late reply to request {}", id);
+                continue;
+            }
+            return Err(SftpError::UnexpectedResponse("reply to another request"));
+        }
+    }
 }
 
 impl TransportLayer for SftpSession {
@@ -32,214 +178,198 @@ impl TransportLayer for SftpSession {
         self.next_request_id += 1;
         id
     }
-}
 
-impl SftpSession {
-    pub fn new(mut channel: Channel, version: u32) -> Result<Self, SftpError> {
-        let init_packet = ClientPacket::Init { version };
-        channel
-            .write_all(&init_packet.to_bytes())
-            .map_err(|e| SftpError::ClientError(e.into()))?;
+    fn version(&self) -> u32 {
+        self.version
+    }
 
-        let mut session = Self {
-            channel,
-            //version,
-            next_request_id: 0,
-        };
-        match ServerPacket::from_session(&mut session)? {
-            ServerPacket::Version { version: _ } => Ok(session),
-            _ => Err(SftpError::ClientError(
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Error when creating SFTP session",
-                )
-                .into(),
-            )),
-        }
+    fn extensions(&self) -> &[(String, String)] {
+        &self.extensions
     }
 
-    pub fn send_packet(&mut self, packet: ClientPacket) -> Result<(), SftpError> {
-        self.channel
-            .write_all(&packet.to_bytes())
-            .map_err(|e| SftpError::IoError(e))?;
-        self.channel.flush().map_err(|e| SftpError::IoError(e))?;
-        Ok(())
+    fn server_banner(&self) -> Option<&str> {
+        self.server_banner.as_deref()
     }
 
-    pub fn read_u32(&mut self) -> Result<u32, SftpError> {
-        let mut buffer: [u8; 4] = [0; 4];
-        self.channel
-            .read_exact(&mut buffer)
-            .map_err(|e| SftpError::ClientError(e.into()))?;
-        Ok(u32::from_be_bytes(buffer))
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        if let Some(ssh) = &self.ssh {
+            // libssh2 takes milliseconds, with 0 meaning no timeout
+            let millis = timeout.map_or(0, |t| t.as_millis().clamp(1, u32::MAX as u128) as u32);
+            ssh.set_timeout(millis);
+        }
     }
 
-    pub fn read_u8(&mut self) -> Result<u8, SftpError> {
-        let mut buffer: [u8; 1] = [0; 1];
-        self.channel
-            .read_exact(&mut buffer)
-            .map_err(|e| SftpError::ClientError(e.into()))?;
-        Ok(buffer[0])
+    fn set_max_packet_size(&mut self, max_packet_size: u32) {
+        self.max_packet_size = max_packet_size;
     }
 
-    pub fn read_string(&mut self) -> Result<Vec<u8>, SftpError> {
-        let buffer_length = self.read_u32()? as usize;
-        let mut buffer: Vec<u8> = vec![0; buffer_length];
-        self.channel
-            .read_exact(&mut buffer)
-            .map_err(|e| SftpError::ClientError(e.into()))?;
-        Ok(buffer)
+    fn max_packet_size(&self) -> u32 {
+        self.max_packet_size
     }
 
-    pub fn read_i64(&mut self) -> Result<i64, SftpError> {
-        let mut buffer: [u8; 8] = [0; 8];
-        self.channel
-            .read_exact(&mut buffer)
-            .map_err(|e| SftpError::ClientError(e.into()))?;
-        Ok(i64::from_be_bytes(buffer))
+    fn open_channel(&mut self) -> Option<Result<Self, SftpError>> {
+        let ssh = self.ssh.clone()?;
+        let channel = ssh.channel_session().and_then(|mut channel| {
+            channel.subsystem("sftp")?;
+            Ok(channel)
+        });
+        let session = channel
+            .map_err(|e| SftpError::ClientError(e.into()))
+            .and_then(|channel| SftpSession::new(channel, self.version))
+            .map(|mut session| {
+                session.max_packet_size = self.max_packet_size;
+                session.server_banner = self.server_banner.clone();
+                session.ssh = Some(ssh);
+                session
+            });
+        Some(session)
     }
 
-    pub fn read_u64(&mut self) -> Result<u64, SftpError> {
-        let mut buffer: [u8; 8] = [0; 8];
-        self.channel
-            .read_exact(&mut buffer)
-            .map_err(|e| SftpError::ClientError(e.into()))?;
-        Ok(u64::from_be_bytes(buffer))
+    fn recycle(&mut self, buffer: Vec<u8>) {
+        self.pool.give(buffer);
     }
+}
 
-    pub fn discard(&mut self, bytes: &usize) -> Result<(), SftpError> {
-        let mut buffer = vec![0; *bytes];
-        self.channel
-            .read_exact(&mut buffer)
-            .map_err(|e| SftpError::ClientError(e.into()))?;
-        Ok(())
+/// Lets sessions over different transports be kept together, as the extra
+/// ones opened with `connect` are. Such a session cannot open sibling
+/// channels.
+impl TransportLayer for Box<dyn TransportLayer> {
+    fn send_packet(&mut self, packet: ClientPacket) -> Result<(), SftpError> {
+        (**self).send_packet(packet)
     }
 
-    pub fn parse_file_attributes(
-        &mut self,
-        flags: &u32,
-    ) -> Result<(usize, FileAttributes), SftpError> {
-        let mut attrs = FileAttributes::default();
+    fn receive_packet(&mut self) -> Result<ServerPacket, SftpError> {
+        (**self).receive_packet()
+    }
 
-        let mut len: usize = 0;
+    fn next_request_id(&mut self) -> u32 {
+        (**self).next_request_id()
+    }
 
-        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
-            attrs.size = Some(self.read_u64()?);
-            len += 8;
-        }
+    fn version(&self) -> u32 {
+        (**self).version()
+    }
 
-        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
-            self.read_u32()?; // uid
-            len += 4;
-            self.read_u32()?; // gid
-            len += 4;
-        }
+    fn extensions(&self) -> &[(String, String)] {
+        (**self).extensions()
+    }
+
+    fn server_banner(&self) -> Option<&str> {
+        (**self).server_banner()
+    }
 
-        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
-            let perms = self.read_u32()?;
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        (**self).set_timeout(timeout)
+    }
 
-            attrs.permissions = Some(perms);
-            len += 4;
+    fn set_max_packet_size(&mut self, max_packet_size: u32) {
+        (**self).set_max_packet_size(max_packet_size)
+    }
 
-            attrs.file_type = Self::file_type_from_permissions(perms);
-            attrs.is_directory = attrs.file_type == FileType::Directory;
-            attrs.is_regular_file = attrs.file_type == FileType::RegularFile;
-            attrs.is_symlink = attrs.file_type == FileType::Symlink;
-        }
+    fn max_packet_size(&self) -> u32 {
+        (**self).max_packet_size()
+    }
 
-        if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
-            self.read_u32()?; // atime
-            len += 4;
-            attrs.modify_time = Some(self.read_u32()?);
-            len += 4;
-        }
+    fn recycle(&mut self, buffer: Vec<u8>) {
+        (**self).recycle(buffer)
+    }
+}
 
-        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
-            let extended_count = self.read_u32()?;
-            len += 4;
+impl SftpSession {
+    /// Opens the SFTP subsystem on `channel`, offering protocol `version`.
+    /// The session speaks the lower of that and the server's version, and
+    /// fails if that is older than v3.
+    pub fn new(mut channel: Channel, version: u32) -> Result<Self, SftpError> {
+        let init_packet = ClientPacket::Init { version };
+        channel
+            .write_all(&init_packet.to_bytes())
+            .map_err(|e| SftpError::ClientError(e.into()))?;
 
-            for _ in 0..extended_count {
-                let name = self.read_string()?;
-                let value = self.read_string()?;
-                len += 8 + name.len() + value.len();
+        let mut session = Self {
+            channel,
+            version,
+            next_request_id: 0,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            frame: Vec::new(),
+            pool: BufferPool::default(),
+            extensions: Vec::new(),
+            server_banner: None,
+            ssh: None,
+        };
+        match ServerPacket::from_session(&mut session)? {
+            ServerPacket::Version {
+                version: server_version,
+                extensions,
+            } => {
+                session.version = version.min(server_version);
+                if session.version < SFTP_MIN_VERSION {
+                    return Err(SftpError::ClientError(
+                        format!("Server only speaks SFTP version {}", server_version).into(),
+                    ));
+                }
+                session.extensions = extensions;
+                Ok(session)
             }
+            _ => Err(SftpError::ClientError(
+                std::io::Error::other("Error when creating SFTP session").into(),
+            )),
         }
+    }
 
-        Ok((len, attrs))
+    /// Records the SSH server's identification string, which is only known to
+    /// the `ssh2::Session` the channel was opened from.
+    pub fn set_server_banner(&mut self, banner: Option<&str>) {
+        self.server_banner = banner.map(String::from);
     }
 
-    fn file_type_from_permissions(perms: u32) -> FileType {
-        match perms & S_IFMT {
-            S_IFDIR => FileType::Directory,
-            S_IFREG => FileType::RegularFile,
-            S_IFLNK => FileType::Symlink,
-            S_IFCHR => FileType::CharacterDevice,
-            S_IFBLK => FileType::BlockDevice,
-            S_IFIFO => FileType::Fifo,
-            S_IFSOCK => FileType::Socket,
-            _ => FileType::Unknown,
-        }
+    /// Keeps a handle on the owning `ssh2::Session`, whose blocking timeout is
+    /// what bounds each wait for a reply on the channel.
+    pub fn set_ssh_session(&mut self, session: Session) {
+        self.ssh = Some(session);
+    }
+
+    pub fn send_packet(&mut self, packet: ClientPacket) -> Result<(), SftpError> {
+        self.channel
+            .write_all(&packet.to_bytes_for(self.version))
+            .map_err(SftpError::IoError)?;
+        self.channel.flush().map_err(SftpError::IoError)?;
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::sftp::{
-        types::{FileInfo, SftpStatus},
+        mock::MockTransport,
+        pipeline::PauseHandle,
+        protocol::SftpProtocol,
+        types::{
+            ByteRange, DirectoryCache, FileAttributes, FileInfo, FileType, Overwrite,
+            PolicyOverride, SessionPath, SftpStatus, SyncDirection, SyncOptions,
+        },
         SftpClient, SftpCommand,
     };
 
     use super::*;
-    use std::{collections::VecDeque, path::PathBuf};
-
-    struct MockTransport {
-        expected_requests: VecDeque<ClientPacket>,
-        responses: VecDeque<ServerPacket>,
-        request_id_counter: u32,
-    }
-
-    impl MockTransport {
-        fn new() -> Self {
-            Self {
-                expected_requests: VecDeque::new(),
-                responses: VecDeque::new(),
-                request_id_counter: 0,
-            }
-        }
-
-        fn expect_request(mut self, packet: ClientPacket) -> Self {
-            self.expected_requests.push_back(packet);
-            self
-        }
-
-        fn respond_with(mut self, response: ServerPacket) -> Self {
-            self.responses.push_back(response);
-            self
-        }
-    }
-
-    impl TransportLayer for MockTransport {
-        fn send_packet(&mut self, packet: ClientPacket) -> Result<(), SftpError> {
-            if let Some(expected) = self.expected_requests.pop_front() {
-                assert_eq!(
-                    std::mem::discriminant(&expected),
-                    std::mem::discriminant(&packet)
-                );
-            }
-            Ok(())
-        }
+    use crate::journal::Journal;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
 
-        fn receive_packet(&mut self) -> Result<ServerPacket, SftpError> {
-            self.responses
-                .pop_front()
-                .ok_or_else(|| SftpError::ClientError("No more responses".into()))
-        }
-
-        fn next_request_id(&mut self) -> u32 {
-            let id = self.request_id_counter;
-            self.request_id_counter += 1;
-            id
-        }
+    // Every client starts by resolving its initial directory
+    fn connected_at(path: &str) -> MockTransport {
+        MockTransport::new()
+            .expect_request(ClientPacket::RealPath {
+                request_id: 0,
+                path: path.to_string(),
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 0,
+                files: vec![FileInfo {
+                    name: path.to_string(),
+                    display_name: path.to_string(),
+                    attrs: FileAttributes::default(),
+                }],
+            })
     }
 
     #[test]
@@ -278,20 +408,20 @@ mod tests {
                 }],
             })
             .expect_request(ClientPacket::ReadDir {
-                request_id: 2,
+                request_id: 3,
                 handle: vec![1, 2, 3],
             })
             .respond_with(ServerPacket::Status {
-                request_id: 2,
+                request_id: 3,
                 status_code: 1, // EOF
                 message: "".to_string(),
             })
             .expect_request(ClientPacket::Close {
-                request_id: 3,
+                request_id: 4,
                 handle: vec![1, 2, 3],
             })
             .respond_with(ServerPacket::Status {
-                request_id: 3,
+                request_id: 4,
                 status_code: SftpStatus::Ok as u32,
                 message: "OK".to_string(),
             });
@@ -300,8 +430,3455 @@ mod tests {
 
         let cmd = SftpCommand::Ls {
             path: Some(PathBuf::from("test")),
+            order: Default::default(),
         };
         let result = client.execute_command(&cmd);
         assert!(result.is_ok());
+
+        // The listing is stored once and shared with the directory cache
+        assert_eq!(client.current_listing.len(), 1);
+        let cached = &client.directory_cache[&PathBuf::from("/test")];
+        assert!(std::sync::Arc::ptr_eq(
+            &client.current_listing,
+            &cached.files
+        ));
+    }
+
+    fn read_request(request_id: u32, offset: u64, len: u32) -> ClientPacket {
+        ClientPacket::Read {
+            request_id,
+            handle: vec![7],
+            offset,
+            len,
+        }
+    }
+
+    fn eof(request_id: u32) -> ServerPacket {
+        ServerPacket::Status {
+            request_id,
+            status_code: 1, // SSH_FX_EOF
+            message: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_read_to_writer_streams_chunks() {
+        // After the first reply the window deepens to two requests; the short
+        // reply at offset 4 is followed up to confirm the end of the file
+        let mock_transport = MockTransport::new()
+            .expect_request(read_request(0, 0, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 0,
+                data: b"abcd".to_vec(),
+            })
+            .expect_request(read_request(1, 4, 4))
+            .expect_request(read_request(2, 8, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 1,
+                data: b"ef".to_vec(),
+            })
+            .expect_request(read_request(3, 6, 2))
+            .respond_with(eof(2))
+            .respond_with(eof(3));
+
+        let mut protocol = SftpProtocol::new(mock_transport);
+        protocol.set_chunk_size(4);
+
+        let mut sink: Vec<u8> = Vec::new();
+        let written = protocol.read_to_writer(&[7], &mut sink).unwrap();
+        assert_eq!(written, 6);
+        assert_eq!(sink, b"abcdef".to_vec());
+    }
+
+    #[test]
+    fn test_read_to_writer_reorders_replies() {
+        let mock_transport = MockTransport::new()
+            .expect_request(read_request(0, 0, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 0,
+                data: b"abcd".to_vec(),
+            })
+            .expect_request(read_request(1, 4, 4))
+            .expect_request(read_request(2, 8, 4))
+            .respond_with(eof(2))
+            .respond_with(ServerPacket::Data {
+                request_id: 1,
+                data: b"efgh".to_vec(),
+            });
+
+        let mut protocol = SftpProtocol::new(mock_transport);
+        protocol.set_chunk_size(4);
+
+        let mut sink: Vec<u8> = Vec::new();
+        assert_eq!(protocol.read_to_writer(&[7], &mut sink).unwrap(), 8);
+        assert_eq!(sink, b"abcdefgh".to_vec());
+    }
+
+    // Asks for a pause once `after` writes have gone through
+    struct PausingWriter {
+        data: Vec<u8>,
+        pause: PauseHandle,
+        after: usize,
+    }
+
+    impl Write for PausingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.data.extend_from_slice(buf);
+            self.after = self.after.saturating_sub(1);
+            if self.after == 0 {
+                self.pause.pause();
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_read_to_writer_pause_finishes_in_flight_reads() {
+        // The pause comes while the read at offset 8 is still outstanding; its
+        // reply is kept, and nothing further is requested
+        let mock_transport = MockTransport::new()
+            .expect_request(read_request(0, 0, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 0,
+                data: b"abcd".to_vec(),
+            })
+            .expect_request(read_request(1, 4, 4))
+            .expect_request(read_request(2, 8, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 1,
+                data: b"efgh".to_vec(),
+            })
+            .respond_with(ServerPacket::Data {
+                request_id: 2,
+                data: b"ijkl".to_vec(),
+            });
+
+        let mut protocol = SftpProtocol::new(mock_transport);
+        protocol.set_chunk_size(4);
+        let mut writer = PausingWriter {
+            data: Vec::new(),
+            pause: protocol.pause_handle(),
+            after: 2,
+        };
+
+        let result = protocol.read_to_writer(&[7], &mut writer);
+        assert!(matches!(result, Err(SftpError::Paused)));
+        assert_eq!(writer.data, b"abcdefghijkl".to_vec());
+    }
+
+    #[test]
+    fn test_read_chunks_delivers_in_file_order() {
+        let mock_transport = MockTransport::new()
+            .expect_request(read_request(0, 0, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 0,
+                data: b"abcd".to_vec(),
+            })
+            .expect_request(read_request(1, 4, 4))
+            .expect_request(read_request(2, 8, 4))
+            .respond_with(eof(2))
+            .respond_with(ServerPacket::Data {
+                request_id: 1,
+                data: b"efgh".to_vec(),
+            });
+
+        let mut protocol = SftpProtocol::new(mock_transport);
+        protocol.set_chunk_size(4);
+
+        let mut chunks: Vec<Vec<u8>> = Vec::new();
+        let read = protocol
+            .read_chunks(&[7], |chunk| {
+                chunks.push(chunk.to_vec());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(read, 8);
+        assert_eq!(chunks, [b"abcd".to_vec(), b"efgh".to_vec()]);
+    }
+
+    #[test]
+    fn test_read_to_writer_drains_after_server_error() {
+        let mock_transport = MockTransport::new()
+            .expect_request(read_request(0, 0, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 0,
+                data: b"abcd".to_vec(),
+            })
+            .expect_request(read_request(1, 4, 4))
+            .expect_request(read_request(2, 8, 4))
+            .respond_with(ServerPacket::Status {
+                request_id: 1,
+                status_code: 3, // SSH_FX_PERMISSION_DENIED
+                message: "Permission denied".to_string(),
+            })
+            .respond_with(eof(2));
+
+        let mut protocol = SftpProtocol::new(mock_transport);
+        protocol.set_chunk_size(4);
+
+        let mut sink: Vec<u8> = Vec::new();
+        let result = protocol.read_to_writer(&[7], &mut sink);
+        assert!(matches!(
+            result,
+            Err(SftpError::ServerError { code: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn test_read_to_writer_rejects_empty_data() {
+        let mock_transport = MockTransport::new()
+            .expect_request(read_request(0, 0, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 0,
+                data: b"abcd".to_vec(),
+            })
+            .expect_request(read_request(1, 4, 4))
+            .expect_request(read_request(2, 8, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 1,
+                data: Vec::new(),
+            })
+            .respond_with(eof(2));
+
+        let mut protocol = SftpProtocol::new(mock_transport);
+        protocol.set_chunk_size(4);
+
+        let mut sink: Vec<u8> = Vec::new();
+        let result = protocol.read_to_writer(&[7], &mut sink);
+        assert!(matches!(result, Err(SftpError::UnexpectedResponse(_))));
+        assert_eq!(sink, b"abcd".to_vec());
+    }
+
+    #[test]
+    fn test_change_directory_rejects_file() {
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Stat {
+                request_id: 1,
+                path: "/notes.txt".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 1,
+                attrs: FileAttributes {
+                    file_type: FileType::RegularFile,
+                    is_regular_file: true,
+                    ..Default::default()
+                },
+            });
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Cd {
+            path: Some(PathBuf::from("notes.txt")),
+        });
+
+        assert!(matches!(result, Err(SftpError::NotADirectory(_))));
+        assert_eq!(client.working_dir, PathBuf::from("/"));
+    }
+
+    #[test]
+    fn test_list_directory_server_error() {
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::OpenDir {
+                request_id: 1,
+                path: "/missing".to_string(),
+            })
+            .respond_with(ServerPacket::Status {
+                request_id: 1,
+                status_code: 2, // SSH_FX_NO_SUCH_FILE
+                message: "No such file".to_string(),
+            });
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Ls {
+            path: Some(PathBuf::from("missing")),
+            order: Default::default(),
+        });
+
+        assert!(matches!(
+            result,
+            Err(SftpError::ServerError {
+                code: 2,
+                request_id: 1,
+                ..
+            })
+        ));
+        assert!(client.directory_cache.is_empty());
+    }
+
+    #[test]
+    fn test_read_surfaces_transport_failure() {
+        let mock_transport = MockTransport::new()
+            .expect_request(ClientPacket::Read {
+                request_id: 0,
+                handle: vec![7],
+                offset: 0,
+                len: DEFAULT_CHUNK_SIZE,
+            })
+            .fail_with(SftpError::IoError(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "truncated DATA packet",
+            )));
+
+        let mut protocol = SftpProtocol::new(mock_transport);
+        let result = protocol.read(&[7]);
+
+        assert!(matches!(result, Err(SftpError::IoError(_))));
+    }
+
+    #[test]
+    fn test_rename_onto_existing_target_is_typed() {
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Rename {
+                request_id: 1,
+                old_path: "/a.txt".to_string(),
+                new_path: "/b.txt".to_string(),
+                flags: 0,
+            })
+            .respond_with(ServerPacket::Status {
+                request_id: 1,
+                status_code: 4, // SSH_FX_FAILURE
+                message: "Failure".to_string(),
+            })
+            .expect_request(ClientPacket::Stat {
+                request_id: 2,
+                path: "/b.txt".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 2,
+                attrs: FileAttributes::default(),
+            });
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Rename {
+            old_path: PathBuf::from("a.txt"),
+            new_path: PathBuf::from("b.txt"),
+            overwrite: false,
+        });
+
+        assert!(matches!(result, Err(SftpError::AlreadyExists(path)) if path == "/b.txt"));
+    }
+
+    #[test]
+    fn test_mkdir_existing_directory_is_typed() {
+        let mock_transport = connected_at("/srv")
+            .expect_request(ClientPacket::MkDir {
+                request_id: 1,
+                path: "/srv/logs".to_string(),
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Status {
+                request_id: 1,
+                status_code: 4, // SSH_FX_FAILURE
+                message: "Failure".to_string(),
+            })
+            .expect_request(ClientPacket::Stat {
+                request_id: 2,
+                path: "/srv/logs".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 2,
+                attrs: FileAttributes::default(),
+            });
+
+        let mut client = SftpClient::new(mock_transport, Some("/srv")).unwrap();
+        let result = client.execute_command(&SftpCommand::MkDir {
+            path: PathBuf::from("logs"),
+        });
+
+        assert!(matches!(result, Err(SftpError::AlreadyExists(path)) if path == "/srv/logs"));
+    }
+
+    #[test]
+    fn test_rmdir_non_empty_directory_is_typed() {
+        let mut attrs = FileAttributes::default();
+        attrs.set_permissions(S_IFDIR | 0o755);
+
+        let mock_transport = connected_at("/srv")
+            .expect_request(ClientPacket::RmDir {
+                request_id: 1,
+                path: "/srv/logs".to_string(),
+            })
+            .respond_with(ServerPacket::Status {
+                request_id: 1,
+                status_code: 4, // SSH_FX_FAILURE
+                message: "Failure".to_string(),
+            })
+            .expect_request(ClientPacket::Stat {
+                request_id: 2,
+                path: "/srv/logs".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 2,
+                attrs,
+            });
+
+        let mut client = SftpClient::new(mock_transport, Some("/srv")).unwrap();
+        let result = client.execute_command(&SftpCommand::RmDir {
+            path: PathBuf::from("logs"),
+        });
+
+        assert!(matches!(result, Err(SftpError::DirectoryNotEmpty(path)) if path == "/srv/logs"));
+    }
+
+    #[test]
+    fn test_rm_recursive_removes_tree_depth_first() {
+        let mut dir = FileAttributes::default();
+        dir.set_permissions(S_IFDIR | 0o755);
+        let mut file = FileAttributes::default();
+        file.set_permissions(S_IFREG | 0o644);
+        let entry = |name: &str, attrs: &FileAttributes| FileInfo {
+            name: name.to_string(),
+            display_name: name.to_string(),
+            attrs: attrs.clone(),
+        };
+
+        let mock_transport = connected_at("/srv")
+            .expect_request(ClientPacket::Remove {
+                request_id: 1,
+                path: "/srv/old".to_string(),
+            })
+            .respond_with(ServerPacket::Status {
+                request_id: 1,
+                status_code: 4, // SSH_FX_FAILURE
+                message: "Failure".to_string(),
+            })
+            .expect_request(ClientPacket::Stat {
+                request_id: 2,
+                path: "/srv/old".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 2,
+                attrs: dir.clone(),
+            })
+            .expect_request(ClientPacket::OpenDir {
+                request_id: 3,
+                path: "/srv/old".to_string(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 3,
+                handle: vec![7],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 4,
+                handle: vec![7],
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 4,
+                files: vec![entry(".", &dir), entry("a.txt", &file), entry("sub", &dir)],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 5,
+                handle: vec![7],
+            })
+            .respond_with(eof(5))
+            .expect_request(ClientPacket::Close {
+                request_id: 6,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(6))
+            .expect_request(ClientPacket::Remove {
+                request_id: 7,
+                path: "/srv/old/a.txt".to_string(),
+            })
+            .respond_with(status_ok(7))
+            .expect_request(ClientPacket::OpenDir {
+                request_id: 8,
+                path: "/srv/old/sub".to_string(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 8,
+                handle: vec![8],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 9,
+                handle: vec![8],
+            })
+            .respond_with(eof(9))
+            .expect_request(ClientPacket::Close {
+                request_id: 10,
+                handle: vec![8],
+            })
+            .respond_with(status_ok(10))
+            .expect_request(ClientPacket::RmDir {
+                request_id: 11,
+                path: "/srv/old/sub".to_string(),
+            })
+            .respond_with(status_ok(11))
+            .expect_request(ClientPacket::RmDir {
+                request_id: 12,
+                path: "/srv/old".to_string(),
+            })
+            .respond_with(status_ok(12));
+
+        let mut client = SftpClient::new(mock_transport, Some("/srv")).unwrap();
+        let result = client.execute_command(&SftpCommand::Rm {
+            path: PathBuf::from("old"),
+            recursive: true,
+        });
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_complete_path_lists_matching_entries() {
+        let mut dir = FileAttributes::default();
+        dir.set_permissions(S_IFDIR | 0o755);
+        let mut file = FileAttributes::default();
+        file.set_permissions(S_IFREG | 0o644);
+        let entry = |name: &str, attrs: &FileAttributes| FileInfo {
+            name: name.to_string(),
+            display_name: name.to_string(),
+            attrs: attrs.clone(),
+        };
+
+        let mock_transport = connected_at("/srv")
+            .expect_request(ClientPacket::OpenDir {
+                request_id: 1,
+                path: "/srv/docs".to_string(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![7],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 2,
+                handle: vec![7],
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 2,
+                files: vec![
+                    entry(".", &dir),
+                    entry("Reports", &dir),
+                    entry("readme.txt", &file),
+                    entry("notes.txt", &file),
+                ],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 3,
+                handle: vec![7],
+            })
+            .respond_with(eof(3))
+            .expect_request(ClientPacket::Close {
+                request_id: 4,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(4));
+
+        let mut client = SftpClient::new(mock_transport, Some("/srv")).unwrap();
+        client
+            .settings
+            .apply("matching", "case-insensitive")
+            .unwrap();
+
+        assert_eq!(
+            client.complete_path("docs/re"),
+            vec!["docs/Reports/".to_string(), "docs/readme.txt".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_script_loops_over_listing_and_keeps_client_errors() {
+        let mock_transport = connected_at("/srv")
+            .expect_request(ClientPacket::OpenDir {
+                request_id: 1,
+                path: "/srv/docs".to_string(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![7],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 2,
+                handle: vec![7],
+            })
+            .respond_with(name_batch(2, "a"))
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 3,
+                handle: vec![7],
+            })
+            .respond_with(eof(3))
+            .expect_request(ClientPacket::Close {
+                request_id: 4,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(4))
+            .expect_request(ClientPacket::MkDir {
+                request_id: 5,
+                path: "/srv/docs/a.v1".to_string(),
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(status_ok(5))
+            .expect_request(ClientPacket::Remove {
+                request_id: 6,
+                path: "/srv/missing".to_string(),
+            })
+            .respond_with(ServerPacket::Status {
+                request_id: 6,
+                status_code: 2, // SSH_FX_NO_SUCH_FILE
+                message: "No such file".to_string(),
+            });
+
+        let client = SftpClient::new(mock_transport, Some("/srv")).unwrap();
+        let params = HashMap::from([("VERSION".to_string(), "v1".to_string())]);
+        let result = crate::script::run(
+            client,
+            r#"
+                for name in ls("docs") {
+                    mkdir("docs/" + name + "." + VERSION);
+                }
+                rm("missing");
+            "#,
+            &params,
+        );
+
+        assert!(matches!(
+            result,
+            Err(SftpError::ServerError { code: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_rename_invalidates_cached_listings() {
+        let mock_transport = connected_at("/srv")
+            .expect_request(ClientPacket::Rename {
+                request_id: 1,
+                old_path: "/srv/logs".to_string(),
+                new_path: "/archive/logs".to_string(),
+                flags: 0,
+            })
+            .respond_with(status_ok(1));
+
+        let mut client = SftpClient::new(mock_transport, Some("/srv")).unwrap();
+        for path in ["/srv", "/srv/logs/2024", "/archive", "/other"] {
+            client.directory_cache.insert(
+                PathBuf::from(path),
+                DirectoryCache {
+                    files: Default::default(),
+                },
+            );
+        }
+        client
+            .execute_command(&SftpCommand::Rename {
+                old_path: PathBuf::from("logs"),
+                new_path: PathBuf::from("/archive/logs"),
+                overwrite: false,
+            })
+            .unwrap();
+
+        let cached: Vec<_> = client.directory_cache.keys().collect();
+        assert_eq!(cached, vec![&PathBuf::from("/other")]);
+    }
+
+    #[test]
+    fn test_tree_descends_directories_first() {
+        let mut dir = FileAttributes::default();
+        dir.set_permissions(S_IFDIR | 0o755);
+        let mut file = FileAttributes::default();
+        file.set_permissions(S_IFREG | 0o644);
+        let mut link = FileAttributes::default();
+        link.set_permissions(S_IFLNK | 0o777);
+        let entry = |name: &str, attrs: &FileAttributes| FileInfo {
+            name: name.to_string(),
+            display_name: name.to_string(),
+            attrs: attrs.clone(),
+        };
+
+        let mock_transport = connected_at("/srv")
+            .expect_request(ClientPacket::OpenDir {
+                request_id: 1,
+                path: "/srv/site".to_string(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![1],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 2,
+                handle: vec![1],
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 2,
+                files: vec![
+                    entry("index.html", &file),
+                    entry("current", &link),
+                    entry("docs", &dir),
+                ],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 3,
+                handle: vec![1],
+            })
+            .respond_with(eof(3))
+            .expect_request(ClientPacket::Close {
+                request_id: 4,
+                handle: vec![1],
+            })
+            .respond_with(status_ok(4))
+            // Directories come first, so docs is walked before the link is read
+            .expect_request(ClientPacket::OpenDir {
+                request_id: 5,
+                path: "/srv/site/docs".to_string(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 5,
+                handle: vec![2],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 6,
+                handle: vec![2],
+            })
+            .respond_with(eof(6))
+            .expect_request(ClientPacket::Close {
+                request_id: 7,
+                handle: vec![2],
+            })
+            .respond_with(status_ok(7))
+            .expect_request(ClientPacket::ReadLink {
+                request_id: 8,
+                path: "/srv/site/current".to_string(),
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 8,
+                files: vec![entry("releases/42", &FileAttributes::default())],
+            });
+
+        let mut client = SftpClient::new(mock_transport, Some("/srv")).unwrap();
+        client
+            .execute_command(&SftpCommand::Tree {
+                path: Some(PathBuf::from("site")),
+                depth: None,
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_realpath_leaves_dot_dot_to_the_server() {
+        let mock_transport = connected_at("/srv")
+            .expect_request(ClientPacket::RealPath {
+                request_id: 1,
+                path: "/srv/current/..".to_string(),
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 1,
+                files: vec![FileInfo {
+                    name: "/srv/releases".to_string(),
+                    display_name: "/srv/releases".to_string(),
+                    attrs: FileAttributes::default(),
+                }],
+            });
+
+        let mut client = SftpClient::new(mock_transport, Some("/srv")).unwrap();
+        client
+            .execute_command(&SftpCommand::RealPath {
+                path: PathBuf::from("current/.."),
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_stat_describes_symlink_unless_followed() {
+        let mut link = FileAttributes::default();
+        link.set_permissions(S_IFLNK | 0o777);
+
+        let mock_transport = connected_at("/srv")
+            .expect_request(ClientPacket::LStat {
+                request_id: 1,
+                path: "/srv/current".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 1,
+                attrs: link,
+            })
+            .expect_request(ClientPacket::ReadLink {
+                request_id: 2,
+                path: "/srv/current".to_string(),
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 2,
+                files: vec![FileInfo {
+                    name: "releases/42".to_string(),
+                    display_name: "releases/42".to_string(),
+                    attrs: FileAttributes::default(),
+                }],
+            })
+            .expect_request(ClientPacket::Stat {
+                request_id: 3,
+                path: "/srv/current".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 3,
+                attrs: FileAttributes::default(),
+            });
+
+        let mut client = SftpClient::new(mock_transport, Some("/srv")).unwrap();
+        for follow in [false, true] {
+            client
+                .execute_command(&SftpCommand::Stat {
+                    path: PathBuf::from("current"),
+                    follow,
+                })
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_hardlink_requires_extension() {
+        let mut client = SftpClient::new(connected_at("/"), Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::HardLink {
+            target: PathBuf::from("a.txt"),
+            link_path: PathBuf::from("b.txt"),
+        });
+
+        assert!(matches!(
+            result,
+            Err(SftpError::UnsupportedExtension(EXT_HARDLINK))
+        ));
+    }
+
+    #[test]
+    fn test_rename_overwrite_uses_posix_rename() {
+        let mut data = Vec::new();
+        for path in ["/a.txt", "/b.txt"] {
+            data.extend_from_slice(&(path.len() as u32).to_be_bytes());
+            data.extend_from_slice(path.as_bytes());
+        }
+
+        let mock_transport = connected_at("/")
+            .with_extension(EXT_POSIX_RENAME, "1")
+            .expect_request(ClientPacket::Extended {
+                request_id: 1,
+                request: EXT_POSIX_RENAME.to_string(),
+                data,
+            })
+            .respond_with(ServerPacket::Status {
+                request_id: 1,
+                status_code: 0,
+                message: String::new(),
+            });
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Rename {
+            old_path: PathBuf::from("a.txt"),
+            new_path: PathBuf::from("b.txt"),
+            overwrite: true,
+        });
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_rename_overwrite_uses_rename_flags_from_v5() {
+        let mock_transport = connected_at("/")
+            .with_version(5)
+            .expect_request(ClientPacket::Rename {
+                request_id: 1,
+                old_path: "/a.txt".to_string(),
+                new_path: "/b.txt".to_string(),
+                flags: SSH_FXF_RENAME_OVERWRITE | SSH_FXF_RENAME_ATOMIC,
+            })
+            .respond_with(ServerPacket::Status {
+                request_id: 1,
+                status_code: 0,
+                message: String::new(),
+            });
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Rename {
+            old_path: PathBuf::from("a.txt"),
+            new_path: PathBuf::from("b.txt"),
+            overwrite: true,
+        });
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_rename_overwrite_requires_extension() {
+        let mut client = SftpClient::new(connected_at("/"), Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Rename {
+            old_path: PathBuf::from("a.txt"),
+            new_path: PathBuf::from("b.txt"),
+            overwrite: true,
+        });
+
+        assert!(matches!(
+            result,
+            Err(SftpError::UnsupportedExtension(EXT_POSIX_RENAME))
+        ));
+    }
+
+    #[test]
+    fn test_put_exclusive_onto_existing_target() {
+        let local = std::env::temp_dir().join(format!("ferric_put_excl_{}", std::process::id()));
+        std::fs::write(&local, b"payload").unwrap();
+
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Open {
+                request_id: 1,
+                path: "/report.csv".to_string(),
+                pflags: SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_EXCL,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Status {
+                request_id: 1,
+                status_code: 4, // SSH_FX_FAILURE
+                message: "Failure".to_string(),
+            })
+            .expect_request(ClientPacket::Stat {
+                request_id: 2,
+                path: "/report.csv".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 2,
+                attrs: FileAttributes::default(),
+            });
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Put {
+            local_path: local.clone(),
+            remote_path: Some(PathBuf::from("report.csv")),
+            exclusive: true,
+            policy: PolicyOverride::default(),
+        });
+        std::fs::remove_file(&local).unwrap();
+
+        assert!(matches!(result, Err(SftpError::AlreadyExists(path)) if path == "/report.csv"));
+    }
+
+    #[test]
+    fn test_put_many_needs_remote_directory() {
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Stat {
+                request_id: 1,
+                path: "/report.csv".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 1,
+                attrs: FileAttributes {
+                    file_type: FileType::RegularFile,
+                    is_regular_file: true,
+                    ..Default::default()
+                },
+            });
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::PutMany {
+            local_paths: vec![PathBuf::from("a.csv"), PathBuf::from("b.csv")],
+            remote_dir: PathBuf::from("report.csv"),
+            exclusive: false,
+            policy: PolicyOverride::default(),
+        });
+
+        assert!(matches!(result, Err(SftpError::NotADirectory(path)) if path == "/report.csv"));
+    }
+
+    #[test]
+    fn test_chmod_recursive_pipelines_contents_before_directory() {
+        let mut dir = FileAttributes::default();
+        dir.set_permissions(S_IFDIR | 0o755);
+        let mut file = FileAttributes::default();
+        file.set_permissions(S_IFREG | 0o644);
+        let mut link = FileAttributes::default();
+        link.set_permissions(S_IFLNK | 0o777);
+        let entry = |name: &str, attrs: &FileAttributes| FileInfo {
+            name: name.to_string(),
+            display_name: name.to_string(),
+            attrs: attrs.clone(),
+        };
+        let mode = FileAttributes {
+            permissions: Some(0o750),
+            ..Default::default()
+        };
+
+        let mock_transport = connected_at("/srv")
+            .expect_request(ClientPacket::Stat {
+                request_id: 1,
+                path: "/srv/site".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 1,
+                attrs: dir.clone(),
+            })
+            .expect_request(ClientPacket::OpenDir {
+                request_id: 2,
+                path: "/srv/site".to_string(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 2,
+                handle: vec![5],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 3,
+                handle: vec![5],
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 3,
+                files: vec![
+                    entry(".", &dir),
+                    entry("app.py", &file),
+                    entry("latest", &link),
+                ],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 4,
+                handle: vec![5],
+            })
+            .respond_with(eof(4))
+            .expect_request(ClientPacket::Close {
+                request_id: 5,
+                handle: vec![5],
+            })
+            .respond_with(status_ok(5))
+            .expect_request(ClientPacket::SetStat {
+                request_id: 6,
+                path: "/srv/site/app.py".to_string(),
+                attrs: mode.clone(),
+            })
+            .expect_request(ClientPacket::SetStat {
+                request_id: 7,
+                path: "/srv/site".to_string(),
+                attrs: mode,
+            })
+            .respond_with(ServerPacket::Status {
+                request_id: 6,
+                status_code: 3, // SSH_FX_PERMISSION_DENIED
+                message: "Permission denied".to_string(),
+            })
+            .respond_with(status_ok(7));
+
+        let mut client = SftpClient::new(mock_transport, Some("/srv")).unwrap();
+        let result = client.execute_command(&SftpCommand::Chmod {
+            mode: 0o750,
+            path: PathBuf::from("site"),
+            recursive: true,
+        });
+
+        assert!(matches!(
+            result,
+            Err(SftpError::ServerError {
+                code: 3,
+                request_id: 6,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_touch_creates_missing_file_without_truncating() {
+        let mock_transport = connected_at("/jobs")
+            .expect_request(ClientPacket::Open {
+                request_id: 1,
+                path: "/jobs/done.flag".to_string(),
+                pflags: SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_EXCL,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![4],
+            })
+            .expect_request(ClientPacket::Close {
+                request_id: 2,
+                handle: vec![4],
+            })
+            .respond_with(status_ok(2));
+
+        let mut client = SftpClient::new(mock_transport, Some("/jobs")).unwrap();
+        client
+            .execute_command(&SftpCommand::Touch {
+                path: PathBuf::from("done.flag"),
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_put_skip_leaves_existing_target() {
+        let local = std::env::temp_dir().join(format!("ferric_put_skip_{}", std::process::id()));
+        std::fs::write(&local, b"payload").unwrap();
+
+        // The remote file exists, so nothing is opened for writing
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Stat {
+                request_id: 1,
+                path: "/report.csv".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 1,
+                attrs: FileAttributes::default(),
+            });
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Put {
+            local_path: local.clone(),
+            remote_path: Some(PathBuf::from("report.csv")),
+            exclusive: false,
+            policy: PolicyOverride {
+                overwrite: Some(Overwrite::Skip),
+                ..PolicyOverride::default()
+            },
+        });
+        std::fs::remove_file(&local).unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(client.stats().bytes_sent, 0);
+    }
+
+    #[test]
+    fn test_get_newer_only_keeps_fresher_local_file() {
+        let local = std::env::temp_dir().join(format!("ferric_newer_{}", std::process::id()));
+        std::fs::write(&local, b"local copy").unwrap();
+
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Stat {
+                request_id: 1,
+                path: "/report.csv".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 1,
+                attrs: FileAttributes {
+                    modify_time: Some(1_000_000),
+                    ..FileAttributes::default()
+                },
+            });
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        client.settings.apply("overwrite", "newer-only").unwrap();
+        let result = client.execute_command(&SftpCommand::Get {
+            remote_path: PathBuf::from("report.csv"),
+            local_path: Some(local.clone()),
+            policy: PolicyOverride::default(),
+        });
+        let contents = std::fs::read(&local).unwrap();
+        std::fs::remove_file(&local).unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(contents, b"local copy");
+    }
+
+    #[test]
+    fn test_prompt_declined_skips_upload() {
+        let local = std::env::temp_dir().join(format!("ferric_prompt_{}", std::process::id()));
+        std::fs::write(&local, b"payload").unwrap();
+
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Stat {
+                request_id: 1,
+                path: "/report.csv".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 1,
+                attrs: FileAttributes::default(),
+            });
+
+        let asked = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let questions = asked.clone();
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        client.settings.apply("overwrite", "prompt").unwrap();
+        client.set_confirm(Some(Box::new(move |question| {
+            questions.lock().unwrap().push(question.to_string());
+            false
+        })));
+        let result = client.execute_command(&SftpCommand::Put {
+            local_path: local.clone(),
+            remote_path: Some(PathBuf::from("report.csv")),
+            exclusive: false,
+            policy: PolicyOverride::default(),
+        });
+        std::fs::remove_file(&local).unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(*asked.lock().unwrap(), ["Overwrite /report.csv?"]);
+    }
+
+    #[test]
+    fn test_reput_continues_from_remote_size() {
+        let local = std::env::temp_dir().join(format!("ferric_reput_{}", std::process::id()));
+        std::fs::write(&local, b"abcdef").unwrap();
+
+        // The three bytes already on the server are checked, then the rest
+        // is written after them
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Open {
+                request_id: 1,
+                path: "/big.bin".to_string(),
+                pflags: SSH_FXF_READ | SSH_FXF_WRITE | SSH_FXF_CREAT,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![7],
+            })
+            .expect_request(ClientPacket::FStat {
+                request_id: 2,
+                handle: vec![7],
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 2,
+                attrs: FileAttributes {
+                    size: Some(3),
+                    ..Default::default()
+                },
+            })
+            .expect_request(read_request(3, 0, 3))
+            .respond_with(ServerPacket::Data {
+                request_id: 3,
+                data: b"abc".to_vec(),
+            })
+            .expect_request(write_request(4, 3, b"def"))
+            .respond_with(status_ok(4))
+            .expect_request(ClientPacket::Close {
+                request_id: 5,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(5));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Reput {
+            local_path: local.clone(),
+            remote_path: Some(PathBuf::from("big.bin")),
+        });
+        std::fs::remove_file(&local).unwrap();
+
+        result.unwrap();
+        assert_eq!(client.stats().bytes_sent, 3);
+    }
+
+    #[test]
+    fn test_put_from_memory_map() {
+        let local = std::env::temp_dir().join(format!("ferric_put_mmap_{}", std::process::id()));
+        std::fs::write(&local, b"mapped").unwrap();
+
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Open {
+                request_id: 1,
+                path: "/mapped.bin".to_string(),
+                pflags: SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![7],
+            })
+            .expect_request(write_request(2, 0, b"mapped"))
+            .respond_with(status_ok(2))
+            .expect_request(ClientPacket::Close {
+                request_id: 3,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(3));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        client.settings.apply("mmap", "on").unwrap();
+        let result = client.execute_command(&SftpCommand::Put {
+            local_path: local.clone(),
+            remote_path: Some(PathBuf::from("mapped.bin")),
+            exclusive: false,
+            policy: PolicyOverride::default(),
+        });
+        std::fs::remove_file(&local).unwrap();
+
+        result.unwrap();
+        assert_eq!(client.stats().bytes_sent, 6);
+    }
+
+    #[test]
+    fn test_write_all_sends_chunks() {
+        let mock_transport = MockTransport::new()
+            .expect_request(ClientPacket::Write {
+                request_id: 0,
+                handle: vec![7],
+                offset: 0,
+                data: b"abcd".to_vec(),
+            })
+            .respond_with(ServerPacket::Status {
+                request_id: 0,
+                status_code: 0,
+                message: String::new(),
+            })
+            .expect_request(ClientPacket::Write {
+                request_id: 1,
+                handle: vec![7],
+                offset: 4,
+                data: b"ef".to_vec(),
+            })
+            .respond_with(ServerPacket::Status {
+                request_id: 1,
+                status_code: 0,
+                message: String::new(),
+            });
+
+        let mut protocol = SftpProtocol::new(mock_transport);
+        protocol.set_chunk_size(4);
+
+        assert_eq!(protocol.write_all(&[7], b"abcdef").unwrap(), 6);
+        assert_eq!(protocol.stats().bytes_sent, 6);
+    }
+
+    // Asks for a pause once `after` bytes have been read
+    struct PausingReader<'a> {
+        data: &'a [u8],
+        pause: PauseHandle,
+        after: usize,
+    }
+
+    impl std::io::Read for PausingReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let read = self.data.read(buf)?;
+            self.after = self.after.saturating_sub(read);
+            if self.after == 0 {
+                self.pause.pause();
+            }
+            Ok(read)
+        }
+    }
+
+    #[test]
+    fn test_write_from_reader_pause_waits_for_acknowledgements() {
+        // The pause comes while the window is being filled, so the writes
+        // already sent are acknowledged and the rest is never read
+        let mock_transport = MockTransport::new()
+            .expect_request(write_request(0, 0, b"ab"))
+            .respond_with(status_ok(0))
+            .expect_request(write_request(1, 2, b"cd"))
+            .expect_request(write_request(2, 4, b"ef"))
+            .respond_with(status_ok(1))
+            .respond_with(status_ok(2));
+
+        let mut protocol = SftpProtocol::new(mock_transport);
+        protocol.set_chunk_size(2);
+        let mut reader = PausingReader {
+            data: b"abcdefgh",
+            pause: protocol.pause_handle(),
+            after: 4,
+        };
+
+        let result = protocol.write_from_reader(&[7], &mut reader);
+        assert!(matches!(result, Err(SftpError::Paused)));
+        assert_eq!(protocol.stats().bytes_sent, 6);
+        assert_eq!(reader.data, b"gh");
+    }
+
+    fn write_request(request_id: u32, offset: u64, data: &[u8]) -> ClientPacket {
+        ClientPacket::Write {
+            request_id,
+            handle: vec![7],
+            offset,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_write_all_pipelines_chunks() {
+        // After the first acknowledgement the window deepens to two writes,
+        // whose statuses may come back in either order
+        let mock_transport = MockTransport::new()
+            .expect_request(write_request(0, 0, b"ab"))
+            .respond_with(status_ok(0))
+            .expect_request(write_request(1, 2, b"cd"))
+            .expect_request(write_request(2, 4, b"ef"))
+            .respond_with(status_ok(2))
+            .respond_with(status_ok(1));
+
+        let mut protocol = SftpProtocol::new(mock_transport);
+        protocol.set_chunk_size(2);
+
+        assert_eq!(protocol.write_all(&[7], b"abcdef").unwrap(), 6);
+        assert_eq!(protocol.stats().bytes_sent, 6);
+    }
+
+    fn read_dir_request(request_id: u32) -> ClientPacket {
+        ClientPacket::ReadDir {
+            request_id,
+            handle: vec![7],
+        }
+    }
+
+    fn name_batch(request_id: u32, name: &str) -> ServerPacket {
+        ServerPacket::Name {
+            request_id,
+            files: vec![FileInfo {
+                name: name.to_string(),
+                display_name: name.to_string(),
+                attrs: FileAttributes::default(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_read_dir_all_pipelines_after_second_batch() {
+        let mock_transport = MockTransport::new()
+            .expect_request(read_dir_request(0))
+            .respond_with(name_batch(0, "a"))
+            .expect_request(read_dir_request(1))
+            .respond_with(name_batch(1, "b"))
+            .expect_request(read_dir_request(2))
+            .expect_request(read_dir_request(3))
+            .expect_request(read_dir_request(4))
+            .expect_request(read_dir_request(5))
+            .respond_with(eof(3))
+            .respond_with(name_batch(2, "c"))
+            // The window is topped up before the held EOF is looked at
+            .expect_request(read_dir_request(6))
+            .respond_with(eof(5))
+            .respond_with(eof(4))
+            .respond_with(eof(6));
+
+        let mut protocol = SftpProtocol::new(mock_transport);
+        let mut pages = Vec::new();
+        let files = protocol
+            .read_dir_all(&[7], |page| pages.push(page.len()))
+            .unwrap();
+
+        let names: Vec<&str> = files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert_eq!(pages, [1, 1, 1]);
+    }
+
+    #[test]
+    fn test_read_dir_all_drains_after_server_error() {
+        let mock_transport = MockTransport::new()
+            .expect_request(read_dir_request(0))
+            .respond_with(name_batch(0, "a"))
+            .expect_request(read_dir_request(1))
+            .respond_with(name_batch(1, "b"))
+            .expect_request(read_dir_request(2))
+            .expect_request(read_dir_request(3))
+            .expect_request(read_dir_request(4))
+            .expect_request(read_dir_request(5))
+            .respond_with(ServerPacket::Status {
+                request_id: 2,
+                status_code: 4, // SSH_FX_FAILURE
+                message: "Stale handle".to_string(),
+            })
+            .respond_with(eof(3))
+            .respond_with(eof(4))
+            .respond_with(eof(5));
+
+        let mut protocol = SftpProtocol::new(mock_transport);
+        let result = protocol.read_dir_all(&[7], |_| {});
+        assert!(matches!(
+            result,
+            Err(SftpError::ServerError {
+                code: 4,
+                request_id: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_write_from_reader_fills_chunks_from_short_reads() {
+        // Hands over one byte per read, as a slow pipe might
+        struct Trickle<'a>(&'a [u8]);
+        impl std::io::Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                match (self.0.split_first(), buf.first_mut()) {
+                    (Some((&byte, rest)), Some(slot)) => {
+                        *slot = byte;
+                        self.0 = rest;
+                        Ok(1)
+                    }
+                    _ => Ok(0),
+                }
+            }
+        }
+
+        let mock_transport = MockTransport::new()
+            .expect_request(write_request(0, 0, b"abcd"))
+            .respond_with(status_ok(0))
+            .expect_request(write_request(1, 4, b"efgh"))
+            .expect_request(write_request(2, 8, b"ij"))
+            .respond_with(status_ok(1))
+            .respond_with(status_ok(2));
+
+        let mut protocol = SftpProtocol::new(mock_transport);
+        protocol.set_chunk_size(4);
+
+        let written = protocol
+            .write_from_reader(&[7], Trickle(b"abcdefghij"))
+            .unwrap();
+        assert_eq!(written, 10);
+        assert_eq!(protocol.stats().bytes_sent, 10);
+    }
+
+    #[test]
+    fn test_write_all_drains_after_failed_chunk() {
+        let mock_transport = MockTransport::new()
+            .expect_request(write_request(0, 0, b"ab"))
+            .respond_with(status_ok(0))
+            .expect_request(write_request(1, 2, b"cd"))
+            .expect_request(write_request(2, 4, b"ef"))
+            .respond_with(ServerPacket::Status {
+                request_id: 1,
+                status_code: 4, // SSH_FX_FAILURE
+                message: "No space left on device".to_string(),
+            })
+            .respond_with(status_ok(2));
+
+        let mut protocol = SftpProtocol::new(mock_transport);
+        protocol.set_chunk_size(2);
+
+        let result = protocol.write_all(&[7], b"abcdef");
+        assert!(matches!(
+            result,
+            Err(SftpError::ServerError {
+                code: 4,
+                request_id: 1,
+                ..
+            })
+        ));
+        assert_eq!(protocol.stats().bytes_sent, 2);
+    }
+
+    #[test]
+    fn test_verify_reports_missing_local_file() {
+        let local = std::env::temp_dir().join(format!("ferric_verify_{}", std::process::id()));
+        std::fs::create_dir_all(&local).unwrap();
+        std::fs::write(local.join("a.txt"), b"abc").unwrap();
+
+        let regular = |name: &str, size: u64| FileInfo {
+            name: name.to_string(),
+            display_name: name.to_string(),
+            attrs: FileAttributes {
+                size: Some(size),
+                file_type: FileType::RegularFile,
+                is_regular_file: true,
+                ..Default::default()
+            },
+        };
+
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::OpenDir {
+                request_id: 1,
+                path: "/backup".to_string(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![1],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 2,
+                handle: vec![1],
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 2,
+                files: vec![regular("a.txt", 3), regular("b.txt", 5)],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 3,
+                handle: vec![1],
+            })
+            .respond_with(ServerPacket::Status {
+                request_id: 3,
+                status_code: 1, // SSH_FX_EOF
+                message: String::new(),
+            })
+            .expect_request(ClientPacket::Close {
+                request_id: 4,
+                handle: vec![1],
+            })
+            .respond_with(ServerPacket::Status {
+                request_id: 4,
+                status_code: 0,
+                message: String::new(),
+            });
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Verify {
+            local_path: local.clone(),
+            remote_path: PathBuf::from("backup"),
+            checksum: false,
+        });
+        std::fs::remove_dir_all(&local).unwrap();
+
+        assert!(matches!(result, Err(SftpError::VerifyFailed(1))));
+    }
+
+    fn status_ok(request_id: u32) -> ServerPacket {
+        ServerPacket::Status {
+            request_id,
+            status_code: 0,
+            message: String::new(),
+        }
+    }
+
+    // The lookup of a downloaded tree's .ferricignore, which it lacks
+    fn open_ignore_file(mock: MockTransport, request_id: u32, root: &str) -> MockTransport {
+        mock.expect_request(ClientPacket::Open {
+            request_id,
+            path: format!("{}/.ferricignore", root),
+            pflags: SSH_FXF_READ,
+            attrs: FileAttributes::default(),
+        })
+        .respond_with(ServerPacket::Status {
+            request_id,
+            status_code: SftpStatus::NoSuchFile as u32,
+            message: "No such file".to_string(),
+        })
+    }
+
+    #[test]
+    fn test_checksum_falls_back_to_streaming() {
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Open {
+                request_id: 1,
+                path: "/abc.txt".to_string(),
+                pflags: SSH_FXF_READ,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![3],
+            })
+            .expect_request(ClientPacket::Read {
+                request_id: 2,
+                handle: vec![3],
+                offset: 0,
+                len: DEFAULT_CHUNK_SIZE,
+            })
+            .respond_with(ServerPacket::Data {
+                request_id: 2,
+                data: b"abc".to_vec(),
+            })
+            .expect_request(ClientPacket::Read {
+                request_id: 3,
+                handle: vec![3],
+                offset: 3,
+                len: DEFAULT_CHUNK_SIZE - 3,
+            })
+            .respond_with(eof(3))
+            .expect_request(ClientPacket::Close {
+                request_id: 4,
+                handle: vec![3],
+            })
+            .respond_with(status_ok(4));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+
+        assert_eq!(
+            client.remote_checksum("/abc.txt").unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_checksum_uses_check_file_extension() {
+        let mut request = Vec::new();
+        for field in ["/abc.txt", "sha256"] {
+            request.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            request.extend_from_slice(field.as_bytes());
+        }
+        request.extend_from_slice(&[0; 20]);
+
+        // string "check-file", string hash-algo-name, then the hash itself
+        let mut reply = Vec::new();
+        for field in ["check-file", "sha256"] {
+            reply.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            reply.extend_from_slice(field.as_bytes());
+        }
+        reply.extend_from_slice(&[0xab, 0xcd]);
+
+        let mock_transport = connected_at("/")
+            .with_extension(EXT_CHECK_FILE, "sha256")
+            .expect_request(ClientPacket::Extended {
+                request_id: 1,
+                request: EXT_CHECK_FILE_NAME.to_string(),
+                data: request,
+            })
+            .respond_with(ServerPacket::ExtendedReply {
+                request_id: 1,
+                data: reply,
+            });
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+
+        assert_eq!(client.remote_checksum("/abc.txt").unwrap(), "abcd");
+    }
+
+    #[test]
+    fn test_chunk_size_setting_fits_max_packet_size() {
+        let mut mock_transport = connected_at("/");
+        mock_transport.set_max_packet_size(32 * 1024);
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let set = |size: &str| SftpCommand::Set {
+            key: Some("chunk-size".to_string()),
+            value: Some(size.to_string()),
+        };
+
+        client.execute_command(&set("16K")).unwrap();
+        // A 32K READ would draw a DATA reply over the 32K packet limit
+        assert!(client.execute_command(&set("32K")).is_err());
+
+        assert_eq!(client.settings.chunk_size, Some(16 * 1024));
+    }
+
+    #[test]
+    fn test_write_chunks_respect_server_limits() {
+        // max-packet-length, max-read-length, max-write-length,
+        // max-open-handles
+        let fields: [u64; 4] = [0, 0, 2, 0];
+        let reply: Vec<u8> = fields.iter().flat_map(|f| f.to_be_bytes()).collect();
+
+        let mock_transport = MockTransport::new()
+            .with_extension(EXT_LIMITS, "1")
+            .expect_request(ClientPacket::Extended {
+                request_id: 0,
+                request: EXT_LIMITS.to_string(),
+                data: Vec::new(),
+            })
+            .respond_with(ServerPacket::ExtendedReply {
+                request_id: 0,
+                data: reply,
+            })
+            .expect_request(write_request(1, 0, b"ab"))
+            .respond_with(status_ok(1))
+            .expect_request(write_request(2, 2, b"cd"))
+            .expect_request(write_request(3, 4, b"e"))
+            .respond_with(status_ok(2))
+            .respond_with(status_ok(3));
+
+        let mut protocol = SftpProtocol::new(mock_transport);
+        protocol.load_limits().unwrap();
+        assert_eq!(protocol.limits().max_write_length, 2);
+
+        assert_eq!(protocol.write_all(&[7], b"abcde").unwrap(), 5);
+    }
+
+    #[test]
+    fn test_statvfs_parses_reply() {
+        let mut request = Vec::new();
+        request.extend_from_slice(&4u32.to_be_bytes());
+        request.extend_from_slice(b"/srv");
+
+        // bsize, frsize, blocks, bfree, bavail, files, ffree, favail, fsid,
+        // flag, namemax
+        let fields: [u64; 11] = [4096, 4096, 1000, 400, 300, 64, 32, 32, 7, 0, 255];
+        let reply: Vec<u8> = fields.iter().flat_map(|f| f.to_be_bytes()).collect();
+
+        let mock_transport = MockTransport::new()
+            .with_extension(EXT_STATVFS, "2")
+            .expect_request(ClientPacket::Extended {
+                request_id: 0,
+                request: EXT_STATVFS.to_string(),
+                data: request,
+            })
+            .respond_with(ServerPacket::ExtendedReply {
+                request_id: 0,
+                data: reply,
+            });
+
+        let usage = SftpProtocol::new(mock_transport).statvfs("/srv").unwrap();
+        assert_eq!(usage.total_bytes(), 1000 * 4096);
+        assert_eq!(usage.used_bytes(), 600 * 4096);
+        assert_eq!(usage.available_bytes(), 300 * 4096);
+        assert_eq!(usage.capacity_percent(), 67);
+        assert_eq!(usage.max_name_length, 255);
+
+        let mut unsupported = SftpProtocol::new(MockTransport::new());
+        assert!(matches!(
+            unsupported.statvfs("/srv"),
+            Err(SftpError::UnsupportedExtension(EXT_STATVFS))
+        ));
+    }
+
+    // A REALPATH for `path` that the server resolves to `resolved`, or fails
+    // as for a file that doesn't exist
+    fn resolve_path(
+        mock: MockTransport,
+        request_id: u32,
+        path: &str,
+        resolved: Option<&str>,
+    ) -> MockTransport {
+        let mock = mock.expect_request(ClientPacket::RealPath {
+            request_id,
+            path: path.to_string(),
+        });
+        match resolved {
+            Some(name) => mock.respond_with(ServerPacket::Name {
+                request_id,
+                files: vec![FileInfo {
+                    name: name.to_string(),
+                    display_name: name.to_string(),
+                    attrs: FileAttributes::default(),
+                }],
+            }),
+            None => mock.respond_with(ServerPacket::Status {
+                request_id,
+                status_code: SftpStatus::NoSuchFile as u32,
+                message: String::new(),
+            }),
+        }
+    }
+
+    // Resolves and opens /a.txt for reading as handle 1 and /b.txt for
+    // writing as handle 2, then asks the server to copy between them
+    fn copy_data_requested(mock: MockTransport, reply: ServerPacket) -> MockTransport {
+        let mut request = vec![0, 0, 0, 1, 1];
+        request.extend_from_slice(&[0; 16]);
+        request.extend_from_slice(&[0, 0, 0, 1, 2]);
+        request.extend_from_slice(&[0; 8]);
+
+        let mock = resolve_path(mock, 1, "/a.txt", Some("/a.txt"));
+        let mut mock = resolve_path(mock, 2, "/b.txt", None);
+        for (request_id, handle, path, pflags) in [
+            (3, 1, "/a.txt", SSH_FXF_READ),
+            (
+                4,
+                2,
+                "/b.txt",
+                SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC,
+            ),
+        ] {
+            mock = mock
+                .expect_request(ClientPacket::Open {
+                    request_id,
+                    path: path.to_string(),
+                    pflags,
+                    attrs: FileAttributes::default(),
+                })
+                .respond_with(ServerPacket::Handle {
+                    request_id,
+                    handle: vec![handle],
+                });
+        }
+        mock.expect_request(ClientPacket::Extended {
+            request_id: 5,
+            request: EXT_COPY_DATA.to_string(),
+            data: request,
+        })
+        .respond_with(reply)
+    }
+
+    fn close_handles(mock: MockTransport, request_id: u32) -> MockTransport {
+        mock.expect_request(ClientPacket::Close {
+            request_id,
+            handle: vec![1],
+        })
+        .respond_with(status_ok(request_id))
+        .expect_request(ClientPacket::Close {
+            request_id: request_id + 1,
+            handle: vec![2],
+        })
+        .respond_with(status_ok(request_id + 1))
+    }
+
+    #[test]
+    fn test_copy_uses_copy_data_extension() {
+        let mock_transport = connected_at("/").with_extension(EXT_COPY_DATA, "1");
+        let mock_transport = copy_data_requested(mock_transport, status_ok(5));
+        let mock_transport = close_handles(mock_transport, 6);
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Copy {
+            source: PathBuf::from("a.txt"),
+            destination: PathBuf::from("b.txt"),
+        });
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_copy_falls_back_when_copy_data_is_refused() {
+        let refused = ServerPacket::Status {
+            request_id: 5,
+            status_code: SftpStatus::OpUnsupported as u32,
+            message: String::new(),
+        };
+        let read = |request_id, offset, len| ClientPacket::Read {
+            request_id,
+            handle: vec![1],
+            offset,
+            len,
+        };
+
+        let mock_transport = connected_at("/").with_extension(EXT_COPY_DATA, "1");
+        let mock_transport = copy_data_requested(mock_transport, refused)
+            .expect_request(read(6, 0, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 6,
+                data: b"hi".to_vec(),
+            })
+            .expect_request(read(7, 2, 2))
+            .respond_with(eof(7))
+            .expect_request(ClientPacket::Write {
+                request_id: 8,
+                handle: vec![2],
+                offset: 0,
+                data: b"hi".to_vec(),
+            })
+            .respond_with(status_ok(8))
+            .expect_request(read(9, 2, 4))
+            .respond_with(eof(9));
+        let mock_transport = close_handles(mock_transport, 10);
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        client.set_chunk_size(Some(4));
+        let result = client.execute_command(&SftpCommand::Copy {
+            source: PathBuf::from("a.txt"),
+            destination: PathBuf::from("b.txt"),
+        });
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_copy_refuses_to_copy_file_onto_itself() {
+        // The destination is a symlink to the source, so opening it with
+        // TRUNC would empty both
+        let mock_transport = resolve_path(connected_at("/"), 1, "/a.txt", Some("/a.txt"));
+        let mock_transport = resolve_path(mock_transport, 2, "/link.txt", Some("/a.txt"));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Copy {
+            source: PathBuf::from("a.txt"),
+            destination: PathBuf::from("link.txt"),
+        });
+        let same_path = client.execute_command(&SftpCommand::Copy {
+            source: PathBuf::from("a.txt"),
+            destination: PathBuf::from("/a.txt"),
+        });
+
+        assert!(matches!(result, Err(SftpError::InvalidCommand(_))));
+        assert!(matches!(same_path, Err(SftpError::InvalidCommand(_))));
+    }
+
+    #[test]
+    fn test_watch_returns_when_paused() {
+        let local = std::env::temp_dir().join(format!("ferric_watch_{}", std::process::id()));
+        std::fs::create_dir_all(&local).unwrap();
+
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::MkDir {
+                request_id: 1,
+                path: "/site".to_string(),
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(status_ok(1));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let pause = client.pause_handle();
+        // As if Ctrl-C had been pressed while the watch was starting
+        pause.pause();
+        let result = client.execute_command(&SftpCommand::Watch {
+            local_path: local.clone(),
+            remote_path: PathBuf::from("site"),
+            delete: false,
+        });
+        std::fs::remove_dir_all(&local).unwrap();
+
+        assert!(result.is_ok());
+        assert!(!pause.is_paused());
+    }
+
+    #[test]
+    fn test_tail_prints_last_lines() {
+        let contents: String = (1..=12).map(|line| format!("{}\n", line)).collect();
+        let size = contents.len() as u64;
+
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Open {
+                request_id: 1,
+                path: "/app.log".to_string(),
+                pflags: SSH_FXF_READ,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![7],
+            })
+            .expect_request(ClientPacket::Stat {
+                request_id: 2,
+                path: "/app.log".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 2,
+                attrs: FileAttributes {
+                    size: Some(size),
+                    ..Default::default()
+                },
+            })
+            .expect_request(read_request(3, 0, size as u32))
+            .respond_with(ServerPacket::Data {
+                request_id: 3,
+                data: contents.into_bytes(),
+            })
+            .expect_request(ClientPacket::Close {
+                request_id: 4,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(4));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let mut output = Vec::new();
+        client.tail("/app.log", false, &mut output).unwrap();
+
+        let expected: String = (3..=12).map(|line| format!("{}\n", line)).collect();
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_tail_follow_reopens_rotated_file() {
+        let attrs_of_size = |request_id, size| ServerPacket::Attrs {
+            request_id,
+            attrs: FileAttributes {
+                size: Some(size),
+                ..Default::default()
+            },
+        };
+        let read_new = |request_id, offset, len| ClientPacket::Read {
+            request_id,
+            handle: vec![8],
+            offset,
+            len,
+        };
+
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Open {
+                request_id: 1,
+                path: "/app.log".to_string(),
+                pflags: SSH_FXF_READ,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![7],
+            })
+            .expect_request(ClientPacket::Stat {
+                request_id: 2,
+                path: "/app.log".to_string(),
+            })
+            .respond_with(attrs_of_size(2, 4))
+            .expect_request(read_request(3, 0, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 3,
+                data: b"old\n".to_vec(),
+            })
+            // The path now names a fresh, smaller log
+            .expect_request(ClientPacket::Stat {
+                request_id: 4,
+                path: "/app.log".to_string(),
+            })
+            .respond_with(attrs_of_size(4, 2))
+            .expect_request(ClientPacket::FStat {
+                request_id: 5,
+                handle: vec![7],
+            })
+            .respond_with(attrs_of_size(5, 4))
+            .expect_request(ClientPacket::Open {
+                request_id: 6,
+                path: "/app.log".to_string(),
+                pflags: SSH_FXF_READ,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 6,
+                handle: vec![8],
+            })
+            .expect_request(ClientPacket::Close {
+                request_id: 7,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(7))
+            .expect_request(read_new(8, 0, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 8,
+                data: b"b\n".to_vec(),
+            })
+            .expect_request(read_new(9, 2, 2))
+            .respond_with(eof(9))
+            // Stops the otherwise endless follow loop
+            .expect_request(ClientPacket::Stat {
+                request_id: 10,
+                path: "/app.log".to_string(),
+            })
+            .fail_with(SftpError::IoError(std::io::ErrorKind::BrokenPipe.into()))
+            .expect_request(ClientPacket::Close {
+                request_id: 11,
+                handle: vec![8],
+            })
+            .respond_with(status_ok(11));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        client.set_chunk_size(Some(4));
+        let mut output = Vec::new();
+        let result = client.tail("/app.log", true, &mut output);
+
+        assert!(result.is_err());
+        assert_eq!(output, b"old\nb\n");
+    }
+
+    #[test]
+    fn test_tail_follow_stops_when_paused() {
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Open {
+                request_id: 1,
+                path: "/app.log".to_string(),
+                pflags: SSH_FXF_READ,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![7],
+            })
+            .expect_request(ClientPacket::Stat {
+                request_id: 2,
+                path: "/app.log".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 2,
+                attrs: FileAttributes {
+                    size: Some(4),
+                    ..Default::default()
+                },
+            })
+            .expect_request(read_request(3, 0, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 3,
+                data: b"old\n".to_vec(),
+            })
+            // Ctrl-C arrives while the first lines are printed, so the file
+            // is never polled again
+            .expect_request(ClientPacket::Close {
+                request_id: 4,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(4));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let pause = client.pause_handle();
+        let mut output = PausingWriter {
+            data: Vec::new(),
+            pause: pause.clone(),
+            after: 1,
+        };
+        client.tail("/app.log", true, &mut output).unwrap();
+
+        assert_eq!(output.data, b"old\n");
+        assert!(!pause.is_paused());
+    }
+
+    #[test]
+    fn test_get_range_downloads_tail_of_file() {
+        let local = std::env::temp_dir().join(format!("ferric_range_{}", std::process::id()));
+
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Open {
+                request_id: 1,
+                path: "/app.log".to_string(),
+                pflags: SSH_FXF_READ,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![7],
+            })
+            .expect_request(ClientPacket::FStat {
+                request_id: 2,
+                handle: vec![7],
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 2,
+                attrs: FileAttributes {
+                    size: Some(12),
+                    ..Default::default()
+                },
+            })
+            .expect_request(read_request(3, 8, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 3,
+                data: b"tail".to_vec(),
+            })
+            .expect_request(ClientPacket::Close {
+                request_id: 4,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(4));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::GetRange {
+            remote_path: PathBuf::from("app.log"),
+            local_path: Some(local.clone()),
+            range: ByteRange {
+                offset: -4,
+                length: None,
+            },
+            policy: PolicyOverride::default(),
+        });
+        let contents = std::fs::read(&local);
+        std::fs::remove_file(&local).ok();
+
+        assert!(result.is_ok());
+        assert_eq!(contents.unwrap(), b"tail");
+    }
+
+    #[test]
+    fn test_transfer_to_streams_between_sessions() {
+        let source_transport = connected_at("/")
+            .expect_request(ClientPacket::Open {
+                request_id: 1,
+                path: "/src/app.conf".to_string(),
+                pflags: SSH_FXF_READ,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![7],
+            })
+            .expect_request(read_request(2, 0, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 2,
+                data: b"port".to_vec(),
+            })
+            .expect_request(read_request(3, 4, 4))
+            .expect_request(read_request(4, 8, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 3,
+                data: b"=22".to_vec(),
+            })
+            .expect_request(read_request(5, 7, 1))
+            .respond_with(eof(4))
+            .respond_with(eof(5))
+            .expect_request(ClientPacket::Close {
+                request_id: 6,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(6));
+        let target_transport = connected_at("/backup")
+            .expect_request(ClientPacket::Open {
+                request_id: 1,
+                path: "/backup/app.conf".to_string(),
+                pflags: SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![7],
+            })
+            .expect_request(write_request(2, 0, b"port"))
+            .respond_with(status_ok(2))
+            .expect_request(write_request(3, 4, b"=22"))
+            .respond_with(status_ok(3))
+            .expect_request(ClientPacket::Close {
+                request_id: 4,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(4));
+
+        let mut source = SftpClient::new(source_transport, Some("/")).unwrap();
+        source.set_chunk_size(Some(4));
+        let mut target = SftpClient::new(target_transport, Some("/backup")).unwrap();
+        target.set_chunk_size(Some(4));
+        let copied = source
+            .transfer_to(
+                &PathBuf::from("/src/app.conf"),
+                &mut target,
+                &PathBuf::from("app.conf"),
+            )
+            .unwrap();
+
+        assert_eq!(copied, 7);
+    }
+
+    #[test]
+    fn test_rget_streams_from_another_session_into_directory() {
+        let peer_transport = connected_at("/")
+            .expect_request(ClientPacket::Open {
+                request_id: 1,
+                path: "/src/app.conf".to_string(),
+                pflags: SSH_FXF_READ,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![7],
+            })
+            .expect_request(read_request(2, 0, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 2,
+                data: b"port".to_vec(),
+            })
+            .expect_request(read_request(3, 4, 4))
+            .expect_request(read_request(4, 8, 4))
+            .respond_with(eof(3))
+            .respond_with(eof(4))
+            .expect_request(ClientPacket::Close {
+                request_id: 5,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(5));
+        // The destination is a directory, so the file keeps its name
+        let mock_transport = connected_at("/backup")
+            .expect_request(ClientPacket::Stat {
+                request_id: 1,
+                path: "/backup".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 1,
+                attrs: FileAttributes {
+                    is_directory: true,
+                    ..Default::default()
+                },
+            })
+            .expect_request(ClientPacket::Open {
+                request_id: 2,
+                path: "/backup/app.conf".to_string(),
+                pflags: SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 2,
+                handle: vec![7],
+            })
+            .expect_request(write_request(3, 0, b"port"))
+            .respond_with(status_ok(3))
+            .expect_request(ClientPacket::Close {
+                request_id: 4,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(4));
+
+        let mut peer = SftpClient::new(
+            Box::new(peer_transport) as Box<dyn TransportLayer>,
+            Some("/"),
+        )
+        .unwrap();
+        peer.set_chunk_size(Some(4));
+        let mut client = SftpClient::new(mock_transport, Some("/backup")).unwrap();
+        client.set_chunk_size(Some(4));
+        assert_eq!(client.add_session("files.example", peer), "session2");
+
+        let rget = |source: &str| SftpCommand::Transfer {
+            source: SessionPath::parse(source),
+            destination: SessionPath::parse("."),
+        };
+        client
+            .execute_command(&rget("files.example:/src/app.conf"))
+            .unwrap();
+        assert!(client
+            .execute_command(&rget("session3:/src/app.conf"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_cat_streams_whole_file() {
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Open {
+                request_id: 1,
+                path: "/app.conf".to_string(),
+                pflags: SSH_FXF_READ,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![7],
+            })
+            .expect_request(read_request(2, 0, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 2,
+                data: b"port".to_vec(),
+            })
+            .expect_request(read_request(3, 4, 4))
+            .expect_request(read_request(4, 8, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 3,
+                data: b"=22".to_vec(),
+            })
+            .expect_request(read_request(5, 7, 1))
+            .respond_with(eof(4))
+            .respond_with(eof(5))
+            .expect_request(ClientPacket::Close {
+                request_id: 6,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(6));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        client.set_chunk_size(Some(4));
+        let mut output = Vec::new();
+        client.cat("/app.conf", &mut output).unwrap();
+
+        assert_eq!(output, b"port=22");
+    }
+
+    #[test]
+    fn test_reget_refuses_mismatched_local_file() {
+        let local = std::env::temp_dir().join(format!("ferric_reget_{}", std::process::id()));
+        std::fs::write(&local, b"abcd").unwrap();
+
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Stat {
+                request_id: 1,
+                path: "/big.iso".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 1,
+                attrs: FileAttributes {
+                    size: Some(8),
+                    ..Default::default()
+                },
+            })
+            .expect_request(ClientPacket::Open {
+                request_id: 2,
+                path: "/big.iso".to_string(),
+                pflags: SSH_FXF_READ,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 2,
+                handle: vec![7],
+            })
+            .expect_request(read_request(3, 0, 4))
+            .respond_with(ServerPacket::Data {
+                request_id: 3,
+                data: b"abzz".to_vec(),
+            })
+            .expect_request(ClientPacket::Close {
+                request_id: 4,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(4));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Reget {
+            remote_path: PathBuf::from("big.iso"),
+            local_path: Some(local.clone()),
+            policy: Default::default(),
+        });
+        let untouched = std::fs::read(&local).unwrap();
+        std::fs::remove_file(&local).unwrap();
+
+        assert!(matches!(result, Err(SftpError::ResumeMismatch(_))));
+        assert_eq!(untouched, b"abcd".to_vec());
+    }
+
+    #[test]
+    fn test_get_retry_continues_partial_download() {
+        let local = std::env::temp_dir().join(format!("ferric_retry_{}", std::process::id()));
+        let attrs = |request_id| ServerPacket::Attrs {
+            request_id,
+            attrs: FileAttributes {
+                size: Some(8),
+                ..Default::default()
+            },
+        };
+        let data = |request_id, data: &[u8]| ServerPacket::Data {
+            request_id,
+            data: data.to_vec(),
+        };
+
+        let stat_and_open = |mock: MockTransport, first_id: u32| {
+            mock.expect_request(ClientPacket::Stat {
+                request_id: first_id,
+                path: "/big.bin".to_string(),
+            })
+            .respond_with(attrs(first_id))
+            .expect_request(ClientPacket::Open {
+                request_id: first_id + 1,
+                path: "/big.bin".to_string(),
+                pflags: SSH_FXF_READ,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: first_id + 1,
+                handle: vec![7],
+            })
+        };
+
+        // The first attempt loses the connection after half the file
+        let mock_transport = stat_and_open(connected_at("/"), 1)
+            .expect_request(read_request(3, 0, DEFAULT_CHUNK_SIZE))
+            .respond_with(data(3, b"abcd"))
+            .expect_request(read_request(4, 4, DEFAULT_CHUNK_SIZE - 4))
+            .fail_with(SftpError::IoError(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "reset",
+            )))
+            .expect_request(ClientPacket::Close {
+                request_id: 5,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(5));
+        // The retry checks what it already has, then fetches the rest
+        let mock_transport = stat_and_open(mock_transport, 6)
+            .expect_request(read_request(8, 0, 4))
+            .respond_with(data(8, b"abcd"))
+            .expect_request(read_request(9, 4, DEFAULT_CHUNK_SIZE))
+            .respond_with(data(9, b"efgh"))
+            .expect_request(read_request(10, 8, DEFAULT_CHUNK_SIZE - 4))
+            .respond_with(eof(10))
+            .expect_request(ClientPacket::Close {
+                request_id: 11,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(11));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Get {
+            remote_path: PathBuf::from("big.bin"),
+            local_path: Some(local.clone()),
+            policy: PolicyOverride {
+                retries: Some(1),
+                ..Default::default()
+            },
+        });
+        let contents = std::fs::read(&local).unwrap();
+        std::fs::remove_file(&local).unwrap();
+
+        result.unwrap();
+        assert_eq!(contents, b"abcdefgh".to_vec());
+    }
+
+    #[test]
+    fn test_get_recursive_recreates_tree() {
+        let local = std::env::temp_dir().join(format!("ferric_get_tree_{}", std::process::id()));
+        let entry = |name: &str, file_type| {
+            let mut attrs = FileAttributes {
+                size: Some(3),
+                ..Default::default()
+            };
+            attrs.set_file_type(file_type);
+            FileInfo {
+                name: name.to_string(),
+                display_name: name.to_string(),
+                attrs,
+            }
+        };
+        let open_dir = |request_id, path: &str| ClientPacket::OpenDir {
+            request_id,
+            path: path.to_string(),
+        };
+
+        let mock_transport = open_ignore_file(connected_at("/"), 1, "/tree")
+            .expect_request(open_dir(2, "/tree"))
+            .respond_with(ServerPacket::Handle {
+                request_id: 2,
+                handle: vec![1],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 3,
+                handle: vec![1],
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 3,
+                files: vec![
+                    entry("a.txt", FileType::RegularFile),
+                    entry("sub", FileType::Directory),
+                    entry("sock", FileType::Socket),
+                    entry("../escape", FileType::RegularFile),
+                ],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 4,
+                handle: vec![1],
+            })
+            .respond_with(eof(4))
+            .expect_request(ClientPacket::Close {
+                request_id: 5,
+                handle: vec![1],
+            })
+            .respond_with(status_ok(5))
+            // The whole tree is listed before any file comes down
+            .expect_request(open_dir(6, "/tree/sub"))
+            .respond_with(ServerPacket::Handle {
+                request_id: 6,
+                handle: vec![2],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 7,
+                handle: vec![2],
+            })
+            .respond_with(eof(7))
+            .expect_request(ClientPacket::Close {
+                request_id: 8,
+                handle: vec![2],
+            })
+            .respond_with(status_ok(8))
+            .expect_request(ClientPacket::Stat {
+                request_id: 9,
+                path: "/tree/a.txt".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 9,
+                attrs: entry("a.txt", FileType::RegularFile).attrs,
+            })
+            .expect_request(ClientPacket::Open {
+                request_id: 10,
+                path: "/tree/a.txt".to_string(),
+                pflags: SSH_FXF_READ,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 10,
+                handle: vec![7],
+            })
+            .expect_request(read_request(11, 0, DEFAULT_CHUNK_SIZE))
+            .respond_with(ServerPacket::Data {
+                request_id: 11,
+                data: b"abc".to_vec(),
+            })
+            .expect_request(read_request(12, 3, DEFAULT_CHUNK_SIZE - 3))
+            .respond_with(eof(12))
+            .expect_request(ClientPacket::Close {
+                request_id: 13,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(13));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::GetRecursive {
+            remote_path: PathBuf::from("tree"),
+            local_path: Some(local.clone()),
+            ignore_file: None,
+            journal: None,
+            policy: PolicyOverride::default(),
+        });
+        let contents = std::fs::read(local.join("a.txt"));
+        let sub_is_dir = local.join("sub").is_dir();
+        let skipped = local.join("sock").exists();
+        std::fs::remove_dir_all(&local).unwrap();
+
+        result.unwrap();
+        assert_eq!(contents.unwrap(), b"abc".to_vec());
+        assert!(sub_is_dir);
+        assert!(!skipped);
+    }
+
+    #[test]
+    fn test_get_recursive_skips_files_in_journal() {
+        let local = std::env::temp_dir().join(format!("ferric_get_journal_{}", std::process::id()));
+        let journal_path = local.with_extension("journal");
+        let journal = Journal::open(
+            &journal_path,
+            &format!("get -r /tree -> {}", local.display()),
+        )
+        .unwrap();
+        journal.record("/tree/a.txt").unwrap();
+        drop(journal);
+
+        let mut attrs = FileAttributes {
+            size: Some(3),
+            ..Default::default()
+        };
+        attrs.set_file_type(FileType::RegularFile);
+        let mock_transport = open_ignore_file(connected_at("/"), 1, "/tree")
+            .expect_request(ClientPacket::OpenDir {
+                request_id: 2,
+                path: "/tree".to_string(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 2,
+                handle: vec![1],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 3,
+                handle: vec![1],
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 3,
+                files: vec![FileInfo {
+                    name: "a.txt".to_string(),
+                    display_name: "a.txt".to_string(),
+                    attrs,
+                }],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 4,
+                handle: vec![1],
+            })
+            .respond_with(eof(4))
+            .expect_request(ClientPacket::Close {
+                request_id: 5,
+                handle: vec![1],
+            })
+            .respond_with(status_ok(5));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::GetRecursive {
+            remote_path: PathBuf::from("/tree"),
+            local_path: Some(local.clone()),
+            ignore_file: None,
+            journal: Some(journal_path.clone()),
+            policy: PolicyOverride::default(),
+        });
+        let journal_left = journal_path.exists();
+        std::fs::remove_dir_all(&local).unwrap();
+
+        result.unwrap();
+        // A finished run leaves nothing to resume
+        assert!(!journal_left);
+    }
+
+    #[test]
+    fn test_get_archive_streams_tree_into_tar() {
+        let archive =
+            std::env::temp_dir().join(format!("ferric_archive_{}.tar", std::process::id()));
+        let entry = |name: &str, file_type| {
+            let mut attrs = FileAttributes {
+                size: Some(3),
+                ..Default::default()
+            };
+            attrs.set_file_type(file_type);
+            FileInfo {
+                name: name.to_string(),
+                display_name: name.to_string(),
+                attrs,
+            }
+        };
+        let open_dir = |request_id, path: &str| ClientPacket::OpenDir {
+            request_id,
+            path: path.to_string(),
+        };
+
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::Stat {
+                request_id: 1,
+                path: "/tree".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 1,
+                attrs: entry("tree", FileType::Directory).attrs,
+            })
+            .expect_request(ClientPacket::Open {
+                request_id: 2,
+                path: "/tree/.ferricignore".to_string(),
+                pflags: SSH_FXF_READ,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Status {
+                request_id: 2,
+                status_code: SftpStatus::NoSuchFile as u32,
+                message: "No such file".to_string(),
+            })
+            .expect_request(open_dir(3, "/tree"))
+            .respond_with(ServerPacket::Handle {
+                request_id: 3,
+                handle: vec![1],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 4,
+                handle: vec![1],
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 4,
+                files: vec![
+                    entry("a.txt", FileType::RegularFile),
+                    entry("sub", FileType::Directory),
+                ],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 5,
+                handle: vec![1],
+            })
+            .respond_with(eof(5))
+            .expect_request(ClientPacket::Close {
+                request_id: 6,
+                handle: vec![1],
+            })
+            .respond_with(status_ok(6))
+            .expect_request(ClientPacket::Open {
+                request_id: 7,
+                path: "/tree/a.txt".to_string(),
+                pflags: SSH_FXF_READ,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 7,
+                handle: vec![7],
+            })
+            .expect_request(read_request(8, 0, 3))
+            .respond_with(ServerPacket::Data {
+                request_id: 8,
+                data: b"abc".to_vec(),
+            })
+            .expect_request(ClientPacket::Close {
+                request_id: 9,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(9))
+            .expect_request(open_dir(10, "/tree/sub"))
+            .respond_with(ServerPacket::Handle {
+                request_id: 10,
+                handle: vec![2],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 11,
+                handle: vec![2],
+            })
+            .respond_with(eof(11))
+            .expect_request(ClientPacket::Close {
+                request_id: 12,
+                handle: vec![2],
+            })
+            .respond_with(status_ok(12));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::GetArchive {
+            remote_path: PathBuf::from("tree"),
+            archive: archive.clone(),
+            ignore_file: None,
+            policy: PolicyOverride::default(),
+        });
+        let contents = std::fs::read(&archive);
+        std::fs::remove_file(&archive).unwrap();
+
+        result.unwrap();
+        let contents = contents.unwrap();
+        let name_at = |block: usize| {
+            let header = &contents[block * 512..block * 512 + 100];
+            let end = header.iter().position(|&b| b == 0).unwrap();
+            String::from_utf8(header[..end].to_vec()).unwrap()
+        };
+        // Header and data for a.txt, then the subdirectory and end marker
+        assert_eq!(contents.len(), 6 * 512);
+        assert_eq!(name_at(0), "tree/");
+        assert_eq!(name_at(1), "tree/a.txt");
+        assert_eq!(&contents[2 * 512..2 * 512 + 3], b"abc");
+        assert_eq!(name_at(3), "tree/sub/");
+    }
+
+    #[test]
+    fn test_sync_up_uploads_only_changed_files_and_keeps_mtimes() {
+        let local = std::env::temp_dir().join(format!("ferric_sync_up_{}", std::process::id()));
+        std::fs::create_dir_all(local.join("sub")).unwrap();
+        std::fs::write(local.join("same.txt"), b"12345").unwrap();
+        std::fs::write(local.join("sub").join("new.txt"), b"hi").unwrap();
+        crate::filesystem::set_modified(&local.join("same.txt"), 1_700_000_000).unwrap();
+        crate::filesystem::set_modified(&local.join("sub").join("new.txt"), 1_700_000_500).unwrap();
+
+        let mut same = FileAttributes {
+            size: Some(5),
+            modify_time: Some(1_700_000_000),
+            ..Default::default()
+        };
+        same.set_file_type(FileType::RegularFile);
+
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::OpenDir {
+                request_id: 1,
+                path: "/dst".to_string(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![1],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 2,
+                handle: vec![1],
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 2,
+                files: vec![FileInfo {
+                    name: "same.txt".to_string(),
+                    display_name: "same.txt".to_string(),
+                    attrs: same,
+                }],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 3,
+                handle: vec![1],
+            })
+            .respond_with(eof(3))
+            .expect_request(ClientPacket::Close {
+                request_id: 4,
+                handle: vec![1],
+            })
+            .respond_with(status_ok(4))
+            .expect_request(ClientPacket::MkDir {
+                request_id: 5,
+                path: "/dst/sub".to_string(),
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(status_ok(5))
+            .expect_request(ClientPacket::Open {
+                request_id: 6,
+                path: "/dst/sub/new.txt".to_string(),
+                pflags: SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 6,
+                handle: vec![7],
+            })
+            .expect_request(write_request(7, 0, b"hi"))
+            .respond_with(status_ok(7))
+            .expect_request(ClientPacket::Close {
+                request_id: 8,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(8))
+            .expect_request(ClientPacket::SetStat {
+                request_id: 9,
+                path: "/dst/sub/new.txt".to_string(),
+                attrs: FileAttributes {
+                    modify_time: Some(1_700_000_500),
+                    ..Default::default()
+                },
+            })
+            .respond_with(status_ok(9));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Sync {
+            direction: SyncDirection::Up,
+            local_path: local.clone(),
+            remote_path: PathBuf::from("/dst"),
+            options: SyncOptions::default(),
+        });
+        std::fs::remove_dir_all(&local).unwrap();
+
+        result.unwrap();
+    }
+
+    #[test]
+    fn test_sync_checksum_replaces_same_sized_file() {
+        let local = std::env::temp_dir().join(format!("ferric_sync_sum_{}", std::process::id()));
+        std::fs::create_dir_all(&local).unwrap();
+        std::fs::write(local.join("abc.txt"), b"abc").unwrap();
+        crate::filesystem::set_modified(&local.join("abc.txt"), 1_700_000_000).unwrap();
+
+        // Same size and mtime, which the quick check would skip
+        let mut attrs = FileAttributes {
+            size: Some(3),
+            modify_time: Some(1_700_000_000),
+            ..Default::default()
+        };
+        attrs.set_file_type(FileType::RegularFile);
+
+        let mut request = Vec::new();
+        for field in ["/dst/abc.txt", "sha256"] {
+            request.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            request.extend_from_slice(field.as_bytes());
+        }
+        request.extend_from_slice(&[0; 20]);
+        let mut reply = Vec::new();
+        for field in ["check-file", "sha256"] {
+            reply.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            reply.extend_from_slice(field.as_bytes());
+        }
+        reply.extend_from_slice(&[0xab, 0xcd]);
+
+        let mock_transport = connected_at("/")
+            .with_extension(EXT_CHECK_FILE, "sha256")
+            .expect_request(ClientPacket::OpenDir {
+                request_id: 1,
+                path: "/dst".to_string(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![1],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 2,
+                handle: vec![1],
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 2,
+                files: vec![FileInfo {
+                    name: "abc.txt".to_string(),
+                    display_name: "abc.txt".to_string(),
+                    attrs,
+                }],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 3,
+                handle: vec![1],
+            })
+            .respond_with(eof(3))
+            .expect_request(ClientPacket::Close {
+                request_id: 4,
+                handle: vec![1],
+            })
+            .respond_with(status_ok(4))
+            .expect_request(ClientPacket::Extended {
+                request_id: 5,
+                request: EXT_CHECK_FILE_NAME.to_string(),
+                data: request,
+            })
+            .respond_with(ServerPacket::ExtendedReply {
+                request_id: 5,
+                data: reply,
+            })
+            .expect_request(ClientPacket::Open {
+                request_id: 6,
+                path: "/dst/abc.txt".to_string(),
+                pflags: SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 6,
+                handle: vec![7],
+            })
+            .expect_request(write_request(7, 0, b"abc"))
+            .respond_with(status_ok(7))
+            .expect_request(ClientPacket::Close {
+                request_id: 8,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(8))
+            .expect_request(ClientPacket::SetStat {
+                request_id: 9,
+                path: "/dst/abc.txt".to_string(),
+                attrs: FileAttributes {
+                    modify_time: Some(1_700_000_000),
+                    ..Default::default()
+                },
+            })
+            .respond_with(status_ok(9));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Sync {
+            direction: SyncDirection::Up,
+            local_path: local.clone(),
+            remote_path: PathBuf::from("/dst"),
+            options: SyncOptions {
+                checksum: true,
+                ..SyncOptions::default()
+            },
+        });
+        std::fs::remove_dir_all(&local).unwrap();
+
+        result.unwrap();
+    }
+
+    #[test]
+    fn test_sync_delete_needs_force_without_a_prompt() {
+        let local = std::env::temp_dir().join(format!("ferric_sync_del_{}", std::process::id()));
+        std::fs::create_dir_all(&local).unwrap();
+        std::fs::write(local.join("stale.txt"), b"old").unwrap();
+        let empty_remote = || {
+            open_ignore_file(connected_at("/"), 1, "/src")
+                .expect_request(ClientPacket::OpenDir {
+                    request_id: 2,
+                    path: "/src".to_string(),
+                })
+                .respond_with(ServerPacket::Handle {
+                    request_id: 2,
+                    handle: vec![1],
+                })
+                .expect_request(ClientPacket::ReadDir {
+                    request_id: 3,
+                    handle: vec![1],
+                })
+                .respond_with(eof(3))
+                .expect_request(ClientPacket::Close {
+                    request_id: 4,
+                    handle: vec![1],
+                })
+                .respond_with(status_ok(4))
+        };
+        let mirror = |force| SftpCommand::Sync {
+            direction: SyncDirection::Down,
+            local_path: local.clone(),
+            remote_path: PathBuf::from("/src"),
+            options: SyncOptions {
+                delete: true,
+                force,
+                ..SyncOptions::default()
+            },
+        };
+
+        let mut client = SftpClient::new(empty_remote(), Some("/")).unwrap();
+        let listed_only = client.execute_command(&mirror(false));
+        let kept = local.join("stale.txt").exists();
+        let mut client = SftpClient::new(empty_remote(), Some("/")).unwrap();
+        let forced = client.execute_command(&mirror(true));
+        let deleted = !local.join("stale.txt").exists();
+        std::fs::remove_dir_all(&local).unwrap();
+
+        assert!(listed_only.is_ok() && kept);
+        assert!(forced.is_ok() && deleted);
+    }
+
+    #[test]
+    fn test_mget_downloads_each_match() {
+        let local = std::env::temp_dir().join(format!("ferric_mget_{}", std::process::id()));
+        std::fs::create_dir_all(&local).unwrap();
+        let regular = |name: &str| {
+            let mut attrs = FileAttributes {
+                size: Some(1),
+                ..Default::default()
+            };
+            attrs.set_file_type(FileType::RegularFile);
+            FileInfo {
+                name: name.to_string(),
+                display_name: name.to_string(),
+                attrs,
+            }
+        };
+        let download = |mock: MockTransport, first_id: u32, name: &str| {
+            let path = format!("/logs/{}", name);
+            mock.expect_request(ClientPacket::Stat {
+                request_id: first_id,
+                path: path.clone(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: first_id,
+                attrs: regular(name).attrs,
+            })
+            .expect_request(ClientPacket::Open {
+                request_id: first_id + 1,
+                path,
+                pflags: SSH_FXF_READ,
+                attrs: FileAttributes::default(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: first_id + 1,
+                handle: vec![7],
+            })
+            .expect_request(read_request(first_id + 2, 0, DEFAULT_CHUNK_SIZE))
+            .respond_with(ServerPacket::Data {
+                request_id: first_id + 2,
+                data: name.as_bytes()[..1].to_vec(),
+            })
+            .expect_request(read_request(first_id + 3, 1, DEFAULT_CHUNK_SIZE - 1))
+            .respond_with(eof(first_id + 3))
+            .expect_request(ClientPacket::Close {
+                request_id: first_id + 4,
+                handle: vec![7],
+            })
+            .respond_with(status_ok(first_id + 4))
+        };
+
+        let mock_transport = connected_at("/")
+            .expect_request(ClientPacket::OpenDir {
+                request_id: 1,
+                path: "/logs".to_string(),
+            })
+            .respond_with(ServerPacket::Handle {
+                request_id: 1,
+                handle: vec![1],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 2,
+                handle: vec![1],
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 2,
+                files: vec![regular("c.log"), regular("b.txt"), regular("a.log")],
+            })
+            .expect_request(ClientPacket::ReadDir {
+                request_id: 3,
+                handle: vec![1],
+            })
+            .respond_with(eof(3))
+            .expect_request(ClientPacket::Close {
+                request_id: 4,
+                handle: vec![1],
+            })
+            .respond_with(status_ok(4));
+        let mock_transport = download(mock_transport, 5, "a.log");
+        let mock_transport = download(mock_transport, 10, "c.log");
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        let result = client.execute_command(&SftpCommand::Mget {
+            pattern: PathBuf::from("logs/*.log"),
+            local_dir: Some(local.clone()),
+            policy: PolicyOverride::default(),
+        });
+        let first = std::fs::read(local.join("a.log"));
+        let second = std::fs::read(local.join("c.log"));
+        let unmatched = local.join("b.txt").exists();
+        std::fs::remove_dir_all(&local).unwrap();
+
+        result.unwrap();
+        assert_eq!(first.unwrap(), b"a".to_vec());
+        assert_eq!(second.unwrap(), b"c".to_vec());
+        assert!(!unmatched);
+    }
+
+    /// Serves a fixed set of files in one directory, answering on whichever
+    /// channel asks. Parallel workers take files in no fixed order, which a
+    /// scripted MockTransport can't follow.
+    struct MemoryServer {
+        files: std::sync::Arc<Vec<(String, Vec<u8>)>>,
+        channels_opened: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+        replies: VecDeque<ServerPacket>,
+        next_request_id: u32,
+        listed: bool,
+    }
+
+    impl MemoryServer {
+        fn new(files: Vec<(String, Vec<u8>)>) -> Self {
+            Self {
+                files: std::sync::Arc::new(files),
+                channels_opened: Default::default(),
+                replies: VecDeque::new(),
+                next_request_id: 0,
+                listed: false,
+            }
+        }
+    }
+
+    impl TransportLayer for MemoryServer {
+        fn send_packet(&mut self, packet: ClientPacket) -> Result<(), SftpError> {
+            let reply = match packet {
+                ClientPacket::RealPath { request_id, path } => ServerPacket::Name {
+                    request_id,
+                    files: vec![FileInfo {
+                        name: path.clone(),
+                        display_name: path,
+                        attrs: FileAttributes::default(),
+                    }],
+                },
+                ClientPacket::OpenDir { request_id, .. } => ServerPacket::Handle {
+                    request_id,
+                    handle: b"dir".to_vec(),
+                },
+                ClientPacket::ReadDir { request_id, .. } if !self.listed => {
+                    self.listed = true;
+                    let files = self.files.iter().map(|(path, data)| {
+                        let mut attrs = FileAttributes {
+                            size: Some(data.len() as u64),
+                            ..Default::default()
+                        };
+                        attrs.set_file_type(FileType::RegularFile);
+                        let (_, name) = path.rsplit_once('/').unwrap();
+                        FileInfo {
+                            name: name.to_string(),
+                            display_name: name.to_string(),
+                            attrs,
+                        }
+                    });
+                    ServerPacket::Name {
+                        request_id,
+                        files: files.collect(),
+                    }
+                }
+                ClientPacket::ReadDir { request_id, .. } => eof(request_id),
+                ClientPacket::Open {
+                    request_id, path, ..
+                } => ServerPacket::Handle {
+                    request_id,
+                    handle: path.into_bytes(),
+                },
+                ClientPacket::Read {
+                    request_id,
+                    handle,
+                    offset,
+                    len,
+                } => {
+                    let (_, data) = self
+                        .files
+                        .iter()
+                        .find(|(path, _)| path.as_bytes() == handle)
+                        .unwrap();
+                    let start = (offset as usize).min(data.len());
+                    let end = (start + len as usize).min(data.len());
+                    match start == end {
+                        true => eof(request_id),
+                        false => ServerPacket::Data {
+                            request_id,
+                            data: data[start..end].to_vec(),
+                        },
+                    }
+                }
+                ClientPacket::Close { request_id, .. } => status_ok(request_id),
+                other => panic!("Unexpected {:?}", other),
+            };
+            self.replies.push_back(reply);
+            Ok(())
+        }
+
+        fn receive_packet(&mut self) -> Result<ServerPacket, SftpError> {
+            Ok(self.replies.pop_front().expect("a reply is queued"))
+        }
+
+        fn next_request_id(&mut self) -> u32 {
+            let id = self.next_request_id;
+            self.next_request_id += 1;
+            id
+        }
+
+        fn open_channel(&mut self) -> Option<Result<Self, SftpError>> {
+            self.channels_opened
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Some(Ok(Self {
+                files: self.files.clone(),
+                channels_opened: self.channels_opened.clone(),
+                replies: VecDeque::new(),
+                next_request_id: 0,
+                listed: false,
+            }))
+        }
+    }
+
+    #[test]
+    fn test_mget_spreads_files_across_channels() {
+        let local = std::env::temp_dir().join(format!("ferric_parallel_{}", std::process::id()));
+        std::fs::create_dir_all(&local).unwrap();
+        let files: Vec<(String, Vec<u8>)> = (0..6)
+            .map(|index| (format!("/logs/{}.log", index), vec![index as u8; 1000]))
+            .collect();
+        let server = MemoryServer::new(files.clone());
+        let channels_opened = server.channels_opened.clone();
+
+        let mut client = SftpClient::new(server, Some("/")).unwrap();
+        client.settings.apply("parallel", "3").unwrap();
+        let result = client.execute_command(&SftpCommand::Mget {
+            pattern: PathBuf::from("logs/*.log"),
+            local_dir: Some(local.clone()),
+            policy: PolicyOverride::default(),
+        });
+        let downloaded: Vec<_> = (0..6)
+            .map(|index| std::fs::read(local.join(format!("{}.log", index))))
+            .collect();
+        std::fs::remove_dir_all(&local).unwrap();
+
+        result.unwrap();
+        for ((_, expected), downloaded) in files.iter().zip(downloaded) {
+            assert_eq!(&downloaded.unwrap(), expected);
+        }
+        assert_eq!(
+            channels_opened.load(std::sync::atomic::Ordering::Relaxed),
+            2
+        );
+        assert_eq!(client.stats().bytes_received, 6000);
+    }
+
+    #[test]
+    fn test_get_splits_large_file_across_channels() {
+        let local = std::env::temp_dir().join(format!("ferric_segments_{}", std::process::id()));
+        let half = SEGMENT_MIN_SIZE;
+        let open = |request_id| ClientPacket::Open {
+            request_id,
+            path: "/big.bin".to_string(),
+            pflags: SSH_FXF_READ,
+            attrs: FileAttributes::default(),
+        };
+        let handle = |request_id| ServerPacket::Handle {
+            request_id,
+            handle: vec![7],
+        };
+        let close = |request_id| ClientPacket::Close {
+            request_id,
+            handle: vec![7],
+        };
+
+        // The second half is fetched on its own channel, which numbers its
+        // requests from zero
+        let channel = MockTransport::new()
+            .expect_request(open(0))
+            .respond_with(handle(0))
+            .expect_request(read_request(1, half, half as u32))
+            .respond_with(ServerPacket::Data {
+                request_id: 1,
+                data: vec![2; half as usize],
+            })
+            .expect_request(close(2))
+            .respond_with(status_ok(2));
+        let mock_transport = connected_at("/")
+            .with_channel(channel)
+            .expect_request(ClientPacket::Stat {
+                request_id: 1,
+                path: "/big.bin".to_string(),
+            })
+            .respond_with(ServerPacket::Attrs {
+                request_id: 1,
+                attrs: FileAttributes {
+                    size: Some(2 * half),
+                    ..Default::default()
+                },
+            })
+            .expect_request(open(2))
+            .respond_with(handle(2))
+            .expect_request(read_request(3, 0, half as u32))
+            .respond_with(ServerPacket::Data {
+                request_id: 3,
+                data: vec![1; half as usize],
+            })
+            .expect_request(close(4))
+            .respond_with(status_ok(4));
+
+        let mut client = SftpClient::new(mock_transport, Some("/")).unwrap();
+        client.set_chunk_size(Some(half as u32));
+        client.settings.apply("segments", "4").unwrap();
+        let result = client.execute_command(&SftpCommand::Get {
+            remote_path: PathBuf::from("big.bin"),
+            local_path: Some(local.clone()),
+            policy: PolicyOverride::default(),
+        });
+        let contents = std::fs::read(&local).unwrap();
+        std::fs::remove_file(&local).unwrap();
+
+        result.unwrap();
+        assert_eq!(contents.len() as u64, 2 * half);
+        assert!(contents[..half as usize].iter().all(|&b| b == 1));
+        assert!(contents[half as usize..].iter().all(|&b| b == 2));
+        assert_eq!(client.stats().bytes_received, 2 * half);
+    }
+
+    #[test]
+    fn test_reply_after_timeout_is_skipped() {
+        let mock_transport = MockTransport::new()
+            .expect_request(ClientPacket::Stat {
+                request_id: 0,
+                path: "/slow".to_string(),
+            })
+            .fail_with(SftpError::IoError(std::io::ErrorKind::TimedOut.into()))
+            // The late reply to the stat arrives before the realpath's
+            .respond_with(ServerPacket::Attrs {
+                request_id: 0,
+                attrs: FileAttributes::default(),
+            })
+            .expect_request(ClientPacket::RealPath {
+                request_id: 1,
+                path: "/".to_string(),
+            })
+            .respond_with(ServerPacket::Name {
+                request_id: 1,
+                files: vec![FileInfo {
+                    name: "/".to_string(),
+                    display_name: "/".to_string(),
+                    attrs: FileAttributes::default(),
+                }],
+            });
+
+        let mut protocol = SftpProtocol::new(mock_transport);
+        assert!(protocol.stat("/slow").is_err());
+        assert_eq!(protocol.realpath("/").unwrap(), "/");
+    }
+
+    #[test]
+    fn test_router_matches_out_of_order_replies() {
+        let mut transport = MockTransport::new()
+            .respond_with(status_ok(3))
+            .respond_with(status_ok(2))
+            .respond_with(status_ok(1));
+        let mut router = ReplyRouter::default();
+        for id in 1..=3 {
+            router.sent(id);
+        }
+        router.abandon(3);
+
+        // 3 was given up on and is dropped; 2 is held until asked for
+        let first = router.receive_for(&mut transport, 1).unwrap();
+        assert_eq!(first.request_id(), Some(1));
+        let second = router.receive_for(&mut transport, 2).unwrap();
+        assert_eq!(second.request_id(), Some(2));
+    }
+
+    #[test]
+    fn test_router_passes_over_unknown_packets() {
+        let mut transport = MockTransport::new()
+            .respond_with(ServerPacket::Unknown { packet_type: 150 })
+            .respond_with(status_ok(1));
+        let mut router = ReplyRouter::default();
+        router.sent(1);
+
+        let reply = router.receive_for(&mut transport, 1).unwrap();
+        assert_eq!(reply.request_id(), Some(1));
+    }
+
+    #[test]
+    fn test_router_rejects_reply_nobody_awaits() {
+        let mut transport = MockTransport::new().respond_with(status_ok(9));
+        let mut router = ReplyRouter::default();
+        router.sent(1);
+
+        assert!(matches!(
+            router.receive_for(&mut transport, 1),
+            Err(SftpError::UnexpectedResponse(_))
+        ));
     }
 }
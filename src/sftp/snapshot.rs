@@ -0,0 +1,237 @@
+use super::error::SftpError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// One regular file in a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    pub size: u64,
+    #[serde(rename = "mtime")]
+    pub modify_time: Option<u64>,
+    pub sha256: Option<String>,
+}
+
+/// Recursive listing of a remote tree, saved as JSON so a later run can tell
+/// what changed on the server since, e.g. between two deployments.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Remote directory the listing was taken from
+    pub root: String,
+    /// Files keyed by their path relative to `root`
+    #[serde(rename = "files", with = "file_list")]
+    pub entries: BTreeMap<String, SnapshotEntry>,
+}
+
+/// A difference between a saved snapshot and the live tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Drift {
+    Added(String),
+    Removed(String),
+    SizeChanged { path: String, was: u64, now: u64 },
+    ContentChanged(String),
+    TimeChanged(String),
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Drift::Added(path) => write!(f, "added: {}", path),
+            Drift::Removed(path) => write!(f, "removed: {}", path),
+            Drift::SizeChanged { path, was, now } => {
+                write!(f, "size changed: {} (was {}, now {})", path, was, now)
+            }
+            Drift::ContentChanged(path) => write!(f, "content changed: {}", path),
+            Drift::TimeChanged(path) => write!(f, "mtime changed: {}", path),
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn new(root: &str) -> Self {
+        Self {
+            root: root.to_string(),
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Serialises as pretty-printed JSON, so saved snapshots diff cleanly in
+    /// version control.
+    pub fn to_json(&self) -> String {
+        // Every key is a string and every value plain data, which cannot fail
+        let mut json = serde_json::to_string_pretty(self).expect("snapshot serialises as JSON");
+        json.push('\n');
+        json
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, SftpError> {
+        serde_json::from_str(text).map_err(|e| malformed(&e.to_string()))
+    }
+
+    /// Compares this saved snapshot with `live`. Content is compared when
+    /// this snapshot recorded a hash and the sizes match, using `hash` to get
+    /// the live file's hash; otherwise modification times are compared.
+    pub fn diff<F>(&self, live: &Snapshot, mut hash: F) -> Result<Vec<Drift>, SftpError>
+    where
+        F: FnMut(&str) -> Result<String, SftpError>,
+    {
+        let mut drift = Vec::new();
+        for (path, saved) in &self.entries {
+            let Some(current) = live.entries.get(path) else {
+                drift.push(Drift::Removed(path.clone()));
+                continue;
+            };
+
+            if saved.size != current.size {
+                drift.push(Drift::SizeChanged {
+                    path: path.clone(),
+                    was: saved.size,
+                    now: current.size,
+                });
+            } else if let Some(saved_hash) = &saved.sha256 {
+                let current_hash = match &current.sha256 {
+                    Some(current_hash) => current_hash.clone(),
+                    None => hash(path)?,
+                };
+                if *saved_hash != current_hash {
+                    drift.push(Drift::ContentChanged(path.clone()));
+                }
+            } else if saved.modify_time != current.modify_time {
+                drift.push(Drift::TimeChanged(path.clone()));
+            }
+        }
+        for path in live.entries.keys() {
+            if !self.entries.contains_key(path) {
+                drift.push(Drift::Added(path.clone()));
+            }
+        }
+        Ok(drift)
+    }
+}
+
+fn malformed(reason: &str) -> SftpError {
+    SftpError::ClientError(format!("Malformed snapshot: {}", reason).into())
+}
+
+// On disk the files are a list of records that each carry their path, rather
+// than an object keyed by path
+mod file_list {
+    use super::SnapshotEntry;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize)]
+    struct FileRef<'a> {
+        path: &'a str,
+        #[serde(flatten)]
+        entry: &'a SnapshotEntry,
+    }
+
+    #[derive(Deserialize)]
+    struct File {
+        path: String,
+        #[serde(flatten)]
+        entry: SnapshotEntry,
+    }
+
+    pub fn serialize<S: Serializer>(
+        entries: &BTreeMap<String, SnapshotEntry>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(entries.iter().map(|(path, entry)| FileRef { path, entry }))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<String, SnapshotEntry>, D::Error> {
+        let files = Vec::<File>::deserialize(deserializer)?;
+        Ok(files
+            .into_iter()
+            .map(|file| (file.path, file.entry))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(size: u64, modify_time: u64, sha256: Option<&str>) -> SnapshotEntry {
+        SnapshotEntry {
+            size,
+            modify_time: Some(modify_time),
+            sha256: sha256.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let mut snapshot = Snapshot::new("/srv/app");
+        snapshot
+            .entries
+            .insert("bin/app".to_string(), entry(1024, 1700000000, Some("ab12")));
+        snapshot.entries.insert(
+            "conf/\"quoted\" name.txt".to_string(),
+            SnapshotEntry {
+                size: 0,
+                modify_time: None,
+                sha256: None,
+            },
+        );
+
+        let parsed = Snapshot::from_json(&snapshot.to_json()).unwrap();
+        assert_eq!(parsed, snapshot);
+        assert!(Snapshot::from_json("{\"root\": \"/\"}").is_err());
+    }
+
+    #[test]
+    fn test_diff_reports_drift() {
+        let mut saved = Snapshot::new("/srv");
+        saved
+            .entries
+            .insert("same".to_string(), entry(3, 1, Some("aaa")));
+        saved
+            .entries
+            .insert("edited".to_string(), entry(3, 1, Some("bbb")));
+        saved.entries.insert("grown".to_string(), entry(3, 1, None));
+        saved
+            .entries
+            .insert("touched".to_string(), entry(3, 1, None));
+        saved
+            .entries
+            .insert("deleted".to_string(), entry(3, 1, None));
+
+        let mut live = Snapshot::new("/srv");
+        live.entries.insert("same".to_string(), entry(3, 9, None));
+        live.entries.insert("edited".to_string(), entry(3, 1, None));
+        live.entries.insert("grown".to_string(), entry(5, 1, None));
+        live.entries
+            .insert("touched".to_string(), entry(3, 2, None));
+        live.entries.insert("new".to_string(), entry(1, 1, None));
+
+        let drift = saved
+            .diff(&live, |path| {
+                Ok(match path {
+                    "same" => "aaa",
+                    _ => "ccc",
+                }
+                .to_string())
+            })
+            .unwrap();
+
+        assert_eq!(
+            drift,
+            vec![
+                Drift::Removed("deleted".to_string()),
+                Drift::ContentChanged("edited".to_string()),
+                Drift::SizeChanged {
+                    path: "grown".to_string(),
+                    was: 3,
+                    now: 5
+                },
+                Drift::TimeChanged("touched".to_string()),
+                Drift::Added("new".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,584 @@
+use super::progress::format_bytes;
+use super::remote_path;
+use super::types::{ConflictPolicy, FileAttributes, SyncDirection};
+use crate::filesystem::LocalEntry;
+use serde::{Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// File in the local directory of a two-way sync that records what both
+/// sides held after the last run.
+pub const BASELINE_FILE_NAME: &str = ".ferricsync";
+
+/// Size and modification time of a file on either side of a sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncEntry {
+    pub size: u64,
+    pub modify_time: Option<u64>,
+}
+
+impl From<LocalEntry> for SyncEntry {
+    fn from(entry: LocalEntry) -> Self {
+        Self {
+            size: entry.size,
+            modify_time: entry.modify_time,
+        }
+    }
+}
+
+impl From<&FileAttributes> for SyncEntry {
+    fn from(attrs: &FileAttributes) -> Self {
+        Self {
+            size: attrs.size.unwrap_or_default(),
+            modify_time: attrs.modify_time.map(u64::from),
+        }
+    }
+}
+
+/// What a sync does with one file of the source tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncAction {
+    /// Missing from the destination, so it is copied over
+    Create,
+    /// Differs from the destination copy, which is replaced
+    Update,
+    /// Already the same on both sides
+    Skip,
+}
+
+/// Decides what to do with each file of `source`, in path order. Both trees
+/// are keyed by path relative to their root. Modification times up to
+/// `tolerance` seconds apart count as the same.
+pub fn plan(
+    source: &BTreeMap<PathBuf, SyncEntry>,
+    destination: &BTreeMap<PathBuf, SyncEntry>,
+    tolerance: u64,
+) -> Vec<(PathBuf, SyncAction)> {
+    source
+        .iter()
+        .map(|(path, entry)| {
+            let action = match destination.get(path) {
+                None => SyncAction::Create,
+                Some(existing) if differs(entry, existing, tolerance) => SyncAction::Update,
+                Some(_) => SyncAction::Skip,
+            };
+            (path.clone(), action)
+        })
+        .collect()
+}
+
+/// Files of `destination` that `source` does not have, in path order, which
+/// a mirroring sync removes.
+pub fn extraneous(
+    source: &BTreeMap<PathBuf, SyncEntry>,
+    destination: &BTreeMap<PathBuf, SyncEntry>,
+) -> Vec<PathBuf> {
+    destination
+        .keys()
+        .filter(|path| !source.contains_key(*path))
+        .cloned()
+        .collect()
+}
+
+/// What a two-way sync does with one path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TwoWayAction {
+    Skip,
+    Upload,
+    Download,
+    DeleteLocal,
+    DeleteRemote,
+    /// Changed on both sides since the baseline, in different ways
+    Conflict,
+}
+
+/// Decides what to do with every path found locally, remotely or in the
+/// `baseline` of the last run, in path order. A side whose file differs
+/// from the baseline has changed it, deleting included; a change made on
+/// one side only is carried to the other. A file edited on one side and
+/// deleted on the other is copied back, so the edit is not lost.
+pub fn plan_two_way(
+    local: &BTreeMap<PathBuf, SyncEntry>,
+    remote: &BTreeMap<PathBuf, SyncEntry>,
+    baseline: &BTreeMap<PathBuf, SyncEntry>,
+    tolerance: u64,
+) -> Vec<(PathBuf, TwoWayAction)> {
+    let paths: BTreeSet<&PathBuf> = local
+        .keys()
+        .chain(remote.keys())
+        .chain(baseline.keys())
+        .collect();
+    let changed = |entry: Option<&SyncEntry>, base: Option<&SyncEntry>| match (entry, base) {
+        (Some(entry), Some(base)) => differs(entry, base, tolerance),
+        (None, None) => false,
+        _ => true,
+    };
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let (l, r, base) = (local.get(path), remote.get(path), baseline.get(path));
+            let action = match (l, r) {
+                (Some(l), Some(r)) if !differs(l, r, tolerance) => TwoWayAction::Skip,
+                _ => match (changed(l, base), changed(r, base), l, r) {
+                    (false, false, _, _) | (_, _, None, None) => TwoWayAction::Skip,
+                    (true, false, Some(_), _) => TwoWayAction::Upload,
+                    (true, false, None, _) => TwoWayAction::DeleteRemote,
+                    (false, true, _, Some(_)) => TwoWayAction::Download,
+                    (false, true, _, None) => TwoWayAction::DeleteLocal,
+                    (true, true, Some(_), Some(_)) => TwoWayAction::Conflict,
+                    (true, true, Some(_), None) => TwoWayAction::Upload,
+                    (true, true, None, Some(_)) => TwoWayAction::Download,
+                },
+            };
+            (path.clone(), action)
+        })
+        .collect()
+}
+
+/// How a conflict is settled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    KeepLocal,
+    KeepRemote,
+    /// The local copy moves aside to its `conflict_name` and both versions
+    /// end up on both sides
+    KeepBoth,
+    /// Each copy moves aside to its `tagged_name`, `local` or `remote`, and
+    /// both versions end up on both sides in place of the original
+    RenameBoth,
+    /// Left as it is, to come up again on the next run
+    Unresolved,
+}
+
+/// Settles a conflict by `policy`. Files the policy cannot tell apart, and
+/// any conflict under `Prompt`, which needs someone to ask, are unresolved.
+pub fn resolve(policy: ConflictPolicy, local: &SyncEntry, remote: &SyncEntry) -> Resolution {
+    let pick = |ordering: std::cmp::Ordering| match ordering {
+        std::cmp::Ordering::Greater => Resolution::KeepLocal,
+        std::cmp::Ordering::Less => Resolution::KeepRemote,
+        std::cmp::Ordering::Equal => Resolution::Unresolved,
+    };
+    match policy {
+        ConflictPolicy::NewerWins => pick(local.modify_time.cmp(&remote.modify_time)),
+        ConflictPolicy::LargerWins => pick(local.size.cmp(&remote.size)),
+        ConflictPolicy::LocalWins => Resolution::KeepLocal,
+        ConflictPolicy::RemoteWins => Resolution::KeepRemote,
+        ConflictPolicy::KeepBoth => Resolution::KeepBoth,
+        ConflictPolicy::RenameBoth => Resolution::RenameBoth,
+        ConflictPolicy::Prompt => Resolution::Unresolved,
+    }
+}
+
+/// Name the local copy of a conflicting file is kept under, with
+/// `.conflict` ahead of its extension: `notes.txt` becomes
+/// `notes.conflict.txt`.
+pub fn conflict_name(path: &Path) -> PathBuf {
+    tagged_name(path, "conflict")
+}
+
+/// `path` with `.tag` ahead of its extension, or at the end when it has
+/// none.
+pub fn tagged_name(path: &Path, tag: &str) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let renamed = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{}.{}.{}", stem, tag, extension)
+        }
+        _ => format!("{}.{}", name, tag),
+    };
+    path.with_file_name(renamed)
+}
+
+// The quick check rsync makes: a file whose size and modification time both
+// match is taken to be unchanged without reading it. Sync gives each copy its
+// source's mtime, and the tolerance covers servers that round timestamps
+fn differs(source: &SyncEntry, destination: &SyncEntry, tolerance: u64) -> bool {
+    if source.size != destination.size {
+        return true;
+    }
+    match (source.modify_time, destination.modify_time) {
+        (Some(source_time), Some(destination_time)) => {
+            source_time.abs_diff(destination_time) > tolerance
+        }
+        _ => false,
+    }
+}
+
+/// What became of one file a sync looked at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Create,
+    Update,
+    Skip,
+    Delete,
+    /// Changed on both sides of a two-way sync and left as it was
+    Unresolved,
+}
+
+impl From<SyncAction> for Outcome {
+    fn from(action: SyncAction) -> Self {
+        match action {
+            SyncAction::Create => Outcome::Create,
+            SyncAction::Update => Outcome::Update,
+            SyncAction::Skip => Outcome::Skip,
+        }
+    }
+}
+
+/// One file of a sync report.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileReport {
+    #[serde(serialize_with = "slash_path")]
+    pub path: PathBuf,
+    #[serde(rename = "action")]
+    pub outcome: Outcome,
+    /// Which way the file was copied, or for a deletion, `Up` when it was
+    /// removed from the remote tree and `Down` from the local one
+    pub direction: Option<SyncDirection>,
+    /// Bytes copied in this run
+    pub bytes: u64,
+    pub error: Option<String>,
+}
+
+/// Everything a sync did, file by file, so automated runs can be audited
+/// afterwards.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub files: Vec<FileReport>,
+    /// Files changed on both sides of a two-way sync
+    pub conflicts: usize,
+}
+
+impl SyncReport {
+    /// Adds `path` to the report and returns its index in `files`.
+    pub fn record(
+        &mut self,
+        path: &Path,
+        outcome: Outcome,
+        direction: Option<SyncDirection>,
+    ) -> usize {
+        self.files.push(FileReport {
+            path: path.to_path_buf(),
+            outcome,
+            direction,
+            bytes: 0,
+            error: None,
+        });
+        self.files.len() - 1
+    }
+
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.files
+            .iter()
+            .filter(|file| file.outcome == outcome && file.error.is_none())
+            .count()
+    }
+
+    /// Files copied `direction` without error, and their bytes.
+    pub fn transferred(&self, direction: SyncDirection) -> (usize, u64) {
+        self.files
+            .iter()
+            .filter(|file| {
+                matches!(file.outcome, Outcome::Create | Outcome::Update)
+                    && file.direction == Some(direction)
+                    && file.error.is_none()
+            })
+            .fold((0, 0), |(count, bytes), file| {
+                (count + 1, bytes + file.bytes)
+            })
+    }
+
+    pub fn errors(&self) -> usize {
+        self.files
+            .iter()
+            .filter(|file| file.error.is_some())
+            .count()
+    }
+
+    /// Serialises as pretty-printed JSON with the totals ahead of the
+    /// files. `mode` is how the sync ran: `up`, `down` or `both`.
+    pub fn to_json(&self, mode: &str, local_root: &Path, remote_root: &str) -> String {
+        let (uploaded, uploaded_bytes) = self.transferred(SyncDirection::Up);
+        let (downloaded, downloaded_bytes) = self.transferred(SyncDirection::Down);
+        let local = local_root.to_string_lossy();
+        let report = ReportJson {
+            mode,
+            local: &local,
+            remote: remote_root,
+            uploaded: Totals {
+                files: uploaded,
+                bytes: uploaded_bytes,
+            },
+            downloaded: Totals {
+                files: downloaded,
+                bytes: downloaded_bytes,
+            },
+            created: self.count(Outcome::Create),
+            updated: self.count(Outcome::Update),
+            skipped: self.count(Outcome::Skip),
+            deleted: self.count(Outcome::Delete),
+            conflicts: self.conflicts,
+            errors: self.errors(),
+            files: &self.files,
+        };
+        // Every key is a string and every value plain data, which cannot fail
+        let mut json = serde_json::to_string_pretty(&report).expect("report serialises as JSON");
+        json.push('\n');
+        json
+    }
+}
+
+#[derive(Serialize)]
+struct Totals {
+    files: usize,
+    bytes: u64,
+}
+
+// Layout of the JSON report written by `sync --report`
+#[derive(Serialize)]
+struct ReportJson<'a> {
+    mode: &'a str,
+    local: &'a str,
+    remote: &'a str,
+    uploaded: Totals,
+    downloaded: Totals,
+    created: usize,
+    updated: usize,
+    skipped: usize,
+    deleted: usize,
+    conflicts: usize,
+    errors: usize,
+    files: &'a [FileReport],
+}
+
+// Report paths use `/` whatever the local separator
+fn slash_path<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&remote_path::to_slash(path))
+}
+
+impl fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} created, {} updated, {} skipped, {} deleted",
+            self.count(Outcome::Create),
+            self.count(Outcome::Update),
+            self.count(Outcome::Skip),
+            self.count(Outcome::Delete)
+        )?;
+        if self.conflicts > 0 {
+            write!(f, ", {} conflict(s)", self.conflicts)?;
+        }
+        if self.errors() > 0 {
+            write!(f, ", {} error(s)", self.errors())?;
+        }
+        for direction in [SyncDirection::Up, SyncDirection::Down] {
+            let (count, bytes) = self.transferred(direction);
+            if count > 0 {
+                let verb = match direction {
+                    SyncDirection::Up => "uploaded",
+                    SyncDirection::Down => "downloaded",
+                };
+                write!(f, "; {} {} ({})", count, verb, format_bytes(bytes))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(size: u64, modify_time: u64) -> SyncEntry {
+        SyncEntry {
+            size,
+            modify_time: Some(modify_time),
+        }
+    }
+
+    #[test]
+    fn test_plan_transfers_only_what_differs() {
+        let source = BTreeMap::from([
+            (PathBuf::from("new.txt"), entry(10, 100)),
+            (PathBuf::from("resized.txt"), entry(20, 100)),
+            (PathBuf::from("touched.txt"), entry(30, 500)),
+            (PathBuf::from("same.txt"), entry(40, 100)),
+        ]);
+        let destination = BTreeMap::from([
+            (PathBuf::from("resized.txt"), entry(25, 900)),
+            (PathBuf::from("touched.txt"), entry(30, 200)),
+            (PathBuf::from("same.txt"), entry(40, 100)),
+            (PathBuf::from("extra.txt"), entry(1, 100)),
+        ]);
+
+        let actions: BTreeMap<PathBuf, SyncAction> =
+            plan(&source, &destination, 0).into_iter().collect();
+        assert_eq!(actions.len(), 4);
+        assert_eq!(actions[&PathBuf::from("new.txt")], SyncAction::Create);
+        assert_eq!(actions[&PathBuf::from("resized.txt")], SyncAction::Update);
+        assert_eq!(actions[&PathBuf::from("touched.txt")], SyncAction::Update);
+        assert_eq!(actions[&PathBuf::from("same.txt")], SyncAction::Skip);
+        assert_eq!(
+            extraneous(&source, &destination),
+            vec![PathBuf::from("extra.txt")]
+        );
+    }
+
+    #[test]
+    fn test_plan_allows_for_clock_tolerance() {
+        let source = BTreeMap::from([
+            (PathBuf::from("rounded.txt"), entry(10, 101)),
+            (PathBuf::from("older.txt"), entry(10, 50)),
+        ]);
+        let destination = BTreeMap::from([
+            (PathBuf::from("rounded.txt"), entry(10, 100)),
+            (PathBuf::from("older.txt"), entry(10, 100)),
+        ]);
+
+        let actions: BTreeMap<PathBuf, SyncAction> =
+            plan(&source, &destination, 2).into_iter().collect();
+        assert_eq!(actions[&PathBuf::from("rounded.txt")], SyncAction::Skip);
+        // Unlike a newer-wins check, a destination that moved on is replaced
+        assert_eq!(actions[&PathBuf::from("older.txt")], SyncAction::Update);
+    }
+
+    #[test]
+    fn test_two_way_plan_against_baseline() {
+        let path = PathBuf::from;
+        let baseline = BTreeMap::from([
+            (path("same.txt"), entry(1, 100)),
+            (path("local-edit.txt"), entry(2, 100)),
+            (path("remote-edit.txt"), entry(3, 100)),
+            (path("both-edit.txt"), entry(4, 100)),
+            (path("local-gone.txt"), entry(5, 100)),
+            (path("edited-and-gone.txt"), entry(6, 100)),
+        ]);
+        let local = BTreeMap::from([
+            (path("same.txt"), entry(1, 100)),
+            (path("local-edit.txt"), entry(20, 300)),
+            (path("remote-edit.txt"), entry(3, 100)),
+            (path("both-edit.txt"), entry(40, 300)),
+            (path("edited-and-gone.txt"), entry(60, 300)),
+            (path("local-new.txt"), entry(7, 300)),
+        ]);
+        let remote = BTreeMap::from([
+            (path("same.txt"), entry(1, 100)),
+            (path("local-edit.txt"), entry(2, 100)),
+            (path("remote-edit.txt"), entry(30, 300)),
+            (path("both-edit.txt"), entry(41, 400)),
+            (path("local-gone.txt"), entry(5, 100)),
+        ]);
+
+        let actions: BTreeMap<PathBuf, TwoWayAction> = plan_two_way(&local, &remote, &baseline, 0)
+            .into_iter()
+            .collect();
+        assert_eq!(actions[&path("same.txt")], TwoWayAction::Skip);
+        assert_eq!(actions[&path("local-edit.txt")], TwoWayAction::Upload);
+        assert_eq!(actions[&path("remote-edit.txt")], TwoWayAction::Download);
+        assert_eq!(actions[&path("both-edit.txt")], TwoWayAction::Conflict);
+        assert_eq!(actions[&path("local-gone.txt")], TwoWayAction::DeleteRemote);
+        assert_eq!(actions[&path("edited-and-gone.txt")], TwoWayAction::Upload);
+        assert_eq!(actions[&path("local-new.txt")], TwoWayAction::Upload);
+
+        let (local, remote) = (entry(40, 300), entry(41, 400));
+        assert_eq!(
+            resolve(ConflictPolicy::NewerWins, &local, &remote),
+            Resolution::KeepRemote
+        );
+        assert_eq!(
+            resolve(ConflictPolicy::LargerWins, &entry(50, 1), &remote),
+            Resolution::KeepLocal
+        );
+        assert_eq!(
+            resolve(ConflictPolicy::LocalWins, &local, &remote),
+            Resolution::KeepLocal
+        );
+        assert_eq!(
+            resolve(ConflictPolicy::RemoteWins, &entry(50, 1), &remote),
+            Resolution::KeepRemote
+        );
+        assert_eq!(
+            resolve(ConflictPolicy::RenameBoth, &local, &remote),
+            Resolution::RenameBoth
+        );
+        assert_eq!(
+            resolve(ConflictPolicy::Prompt, &local, &remote),
+            Resolution::Unresolved
+        );
+        assert_eq!(
+            conflict_name(Path::new("docs/notes.txt")),
+            PathBuf::from("docs/notes.conflict.txt")
+        );
+        assert_eq!(
+            conflict_name(Path::new(".profile")),
+            PathBuf::from(".profile.conflict")
+        );
+        assert_eq!(
+            tagged_name(Path::new("docs/notes.txt"), "remote"),
+            PathBuf::from("docs/notes.remote.txt")
+        );
+    }
+
+    #[test]
+    fn test_report_totals_and_json() {
+        let mut report = SyncReport::default();
+        let up = report.record(
+            Path::new("site/index.html"),
+            Outcome::Update,
+            Some(SyncDirection::Up),
+        );
+        report.files[up].bytes = 2048;
+        report.record(Path::new("logo.png"), Outcome::Skip, None);
+        let down = report.record(
+            Path::new("new.txt"),
+            Outcome::Create,
+            Some(SyncDirection::Down),
+        );
+        report.files[down].error = Some("Permission denied".to_string());
+        report.record(
+            Path::new("old.txt"),
+            Outcome::Delete,
+            Some(SyncDirection::Up),
+        );
+
+        assert_eq!(report.transferred(SyncDirection::Up), (1, 2048));
+        assert_eq!(report.transferred(SyncDirection::Down), (0, 0));
+        assert_eq!(
+            report.to_string(),
+            "0 created, 1 updated, 1 skipped, 1 deleted, 1 error(s); 1 uploaded (2.0K)"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&report.to_json("up", Path::new("/home/me/site"), "/var/www"))
+                .unwrap();
+        assert_eq!(
+            json["uploaded"],
+            serde_json::json!({"files": 1, "bytes": 2048})
+        );
+        assert_eq!(json["errors"], 1);
+        assert_eq!(
+            json["files"][2],
+            serde_json::json!({
+                "path": "new.txt",
+                "action": "create",
+                "direction": "down",
+                "bytes": 0,
+                "error": "Permission denied"
+            })
+        );
+        assert_eq!(
+            json["files"][1],
+            serde_json::json!({
+                "path": "logo.png",
+                "action": "skip",
+                "direction": null,
+                "bytes": 0,
+                "error": null
+            })
+        );
+    }
+}
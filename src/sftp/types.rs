@@ -1,14 +1,23 @@
 use std::path::PathBuf;
-use std::time::SystemTime;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::sftp::constants::{
-    SSH_FILEXFER_ATTR_ACMODTIME, SSH_FILEXFER_ATTR_PERMISSIONS, SSH_FILEXFER_ATTR_SIZE,
-};
+use crate::sftp::constants::*;
+use crate::sftp::error::SftpError;
+use crate::sftp::packet::SftpWriter;
+use chrono::{DateTime, Local};
+use serde::Serialize;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum SftpCommand {
     Ls {
         path: Option<PathBuf>,
+        order: ListingOrder,
+    },
+    Tree {
+        path: Option<PathBuf>,
+        /// Levels below `path` to show, all of them when unset
+        depth: Option<usize>,
     },
     Cd {
         path: Option<PathBuf>,
@@ -16,15 +25,192 @@ pub enum SftpCommand {
     Get {
         remote_path: PathBuf,
         local_path: Option<PathBuf>,
+        policy: PolicyOverride,
     },
-    Put {
+    Reget {
+        remote_path: PathBuf,
+        local_path: Option<PathBuf>,
+        policy: PolicyOverride,
+    },
+    GetMany {
+        remote_paths: Vec<PathBuf>,
+        local_dir: PathBuf,
+        policy: PolicyOverride,
+    },
+    GetRecursive {
+        remote_path: PathBuf,
+        local_path: Option<PathBuf>,
+        ignore_file: Option<PathBuf>,
+        journal: Option<PathBuf>,
+        policy: PolicyOverride,
+    },
+    GetArchive {
+        remote_path: PathBuf,
+        archive: PathBuf,
+        ignore_file: Option<PathBuf>,
+        policy: PolicyOverride,
+    },
+    GetRange {
         remote_path: PathBuf,
         local_path: Option<PathBuf>,
+        range: ByteRange,
+        policy: PolicyOverride,
+    },
+    Mget {
+        pattern: PathBuf,
+        local_dir: Option<PathBuf>,
+        policy: PolicyOverride,
+    },
+    Put {
+        local_path: PathBuf,
+        remote_path: Option<PathBuf>,
+        exclusive: bool,
+        policy: PolicyOverride,
+    },
+    Reput {
+        local_path: PathBuf,
+        remote_path: Option<PathBuf>,
+    },
+    PutMany {
+        local_paths: Vec<PathBuf>,
+        remote_dir: PathBuf,
+        exclusive: bool,
+        policy: PolicyOverride,
+    },
+    Mput {
+        patterns: Vec<PathBuf>,
+        policy: PolicyOverride,
+    },
+    Verify {
+        local_path: PathBuf,
+        remote_path: PathBuf,
+        checksum: bool,
+    },
+    Sync {
+        direction: SyncDirection,
+        local_path: PathBuf,
+        remote_path: PathBuf,
+        options: SyncOptions,
+    },
+    SyncBoth {
+        local_path: PathBuf,
+        remote_path: PathBuf,
+        options: SyncOptions,
+    },
+    Watch {
+        local_path: PathBuf,
+        remote_path: PathBuf,
+        delete: bool,
+    },
+    Checksum {
+        path: PathBuf,
+    },
+    Grep {
+        pattern: String,
+        path: PathBuf,
+    },
+    Cat {
+        path: PathBuf,
+    },
+    Tail {
+        path: PathBuf,
+        follow: bool,
+    },
+    Open {
+        path: PathBuf,
+    },
+    Bench {
+        size: u64,
+        direction: BenchDirection,
+    },
+    Queue(QueueAction),
+    Snapshot {
+        action: SnapshotAction,
+        file: PathBuf,
+        path: Option<PathBuf>,
+    },
+    Copy {
+        source: PathBuf,
+        destination: PathBuf,
+    },
+    /// Streams a file from one open session to another
+    Transfer {
+        source: SessionPath,
+        destination: SessionPath,
+    },
+    Connect {
+        destination: String,
+    },
+    Close {
+        session: String,
+    },
+    Sessions,
+    MkDir {
+        path: PathBuf,
+    },
+    RmDir {
+        path: PathBuf,
+    },
+    Rm {
+        path: PathBuf,
+        recursive: bool,
+    },
+    Df {
+        path: Option<PathBuf>,
+        human: bool,
+    },
+    Chmod {
+        mode: u32,
+        path: PathBuf,
+        recursive: bool,
+    },
+    Touch {
+        path: PathBuf,
+    },
+    Symlink {
+        target: String,
+        link_path: PathBuf,
+    },
+    HardLink {
+        target: PathBuf,
+        link_path: PathBuf,
+    },
+    ReadLink {
+        path: PathBuf,
+    },
+    Rename {
+        old_path: PathBuf,
+        new_path: PathBuf,
+        overwrite: bool,
+    },
+    RealPath {
+        path: PathBuf,
+    },
+    Stat {
+        path: PathBuf,
+        follow: bool,
+    },
+    Set {
+        key: Option<String>,
+        value: Option<String>,
     },
     Pwd,
+    Version,
+    Extensions,
+    Stats,
     Help,
     Bye,
 }
+
+impl SftpCommand {
+    /// Whether the command only changes client state that later commands
+    /// depend on, such as the working directory. A resumed batch script
+    /// replays these rather than skipping them.
+    pub fn changes_session(&self) -> bool {
+        matches!(self, SftpCommand::Cd { .. } | SftpCommand::Set { .. })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct FileInfo {
     pub name: String,
@@ -32,11 +218,68 @@ pub struct FileInfo {
     pub attrs: FileAttributes,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortKey {
+    /// Server order, which lets listings stream as they arrive
+    #[default]
+    Unsorted,
+    Name,
+    /// Largest first
+    Size,
+    /// Newest first
+    Time,
+}
+
+/// How `ls` orders its output, set by its sort flags.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ListingOrder {
+    pub key: SortKey,
+    pub reverse: bool,
+    pub directories_first: bool,
+}
+
+impl ListingOrder {
+    pub fn is_unsorted(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Sorts `files` in place. As with GNU ls, `reverse` flips the sort key
+    /// but directories stay ahead of files when they are grouped.
+    pub fn sort(&self, files: &mut [&FileInfo]) {
+        files.sort_by(|a, b| {
+            let by_key = match self.key {
+                SortKey::Unsorted => std::cmp::Ordering::Equal,
+                SortKey::Name => a.name.cmp(&b.name),
+                SortKey::Size => b.attrs.size.cmp(&a.attrs.size),
+                SortKey::Time => b.attrs.modify_time.cmp(&a.attrs.modify_time),
+            };
+            let by_key = if self.reverse {
+                by_key.reverse()
+            } else {
+                by_key
+            };
+
+            let by_group = match self.directories_first {
+                true => b.attrs.is_directory.cmp(&a.attrs.is_directory),
+                false => std::cmp::Ordering::Equal,
+            };
+            by_group.then(by_key)
+        });
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct FileAttributes {
     pub size: Option<u64>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
     pub permissions: Option<u32>,
+    pub access_time: Option<u32>,
     pub modify_time: Option<u32>,
+    /// Owner and group names, which replace uid/gid from protocol v4 on
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub extended: Vec<(String, Vec<u8>)>,
     pub file_type: FileType,
     pub is_directory: bool,
     pub is_regular_file: bool,
@@ -44,11 +287,75 @@ pub struct FileAttributes {
 }
 
 impl FileAttributes {
+    /// Stores the permission bits and derives the file type from them.
+    pub fn set_permissions(&mut self, perms: u32) {
+        self.permissions = Some(perms);
+        self.set_file_type(FileType::from_permissions(perms));
+    }
+
+    pub fn set_file_type(&mut self, file_type: FileType) {
+        self.file_type = file_type;
+        self.is_directory = file_type == FileType::Directory;
+        self.is_regular_file = file_type == FileType::RegularFile;
+        self.is_symlink = file_type == FileType::Symlink;
+    }
+
+    /// Renders the mode `ls -l` style, e.g. `drwxr-xr-x`. Setuid, setgid
+    /// and sticky bits show in the execute columns as `s` and `t`, or `S`
+    /// and `T` where the execute bit itself is clear.
+    pub fn permissions_string(&self) -> String {
+        let perms = self.permissions.unwrap_or(0);
+        let mut out = String::with_capacity(10);
+        out.push(self.file_type.indicator());
+
+        for (shift, special, marker) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+            let bits = (perms >> shift) & 0o7;
+            out.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+            out.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+            out.push(match (bits & 0o1 != 0, perms & special != 0) {
+                (true, false) => 'x',
+                (false, false) => '-',
+                (true, true) => marker,
+                (false, true) => marker.to_ascii_uppercase(),
+            });
+        }
+        out
+    }
+
     pub fn exists(&self) -> bool {
         self.size.is_some() && self.permissions.is_some() && self.modify_time.is_some()
     }
 
+    /// An `ls -l` style line for `name`. Servers only send one up to v3;
+    /// later versions leave the client to build it.
+    pub fn long_name(&self, name: &str) -> String {
+        let mtime = self
+            .modify_time
+            .and_then(|secs| DateTime::from_timestamp(secs as i64, 0))
+            .map(|time| time.with_timezone(&Local).format("%b %e %H:%M").to_string())
+            .unwrap_or_else(|| "?".repeat(12));
+        format!(
+            "{} {:<8} {:<8} {:>8} {} {}",
+            self.permissions_string(),
+            self.owner.as_deref().unwrap_or("?"),
+            self.group.as_deref().unwrap_or("?"),
+            self.size.unwrap_or(0),
+            mtime,
+            name
+        )
+    }
+
+    /// Wire format for protocol v3.
     pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_for(SFTP_MIN_VERSION)
+    }
+
+    /// Wire format for the negotiated protocol `version`.
+    pub fn to_bytes_for(&self, version: u32) -> Vec<u8> {
+        if version >= 4 {
+            return self.to_v4_bytes();
+        }
+
         let mut bytes = Vec::new();
         let mut flags = 0u32;
 
@@ -62,16 +369,63 @@ impl FileAttributes {
             flags |= SSH_FILEXFER_ATTR_ACMODTIME;
         }
 
-        bytes.extend_from_slice(&flags.to_be_bytes());
+        bytes.write_u32(flags);
 
         if let Some(size) = self.size {
-            bytes.extend_from_slice(&size.to_be_bytes());
+            bytes.write_u64(size);
         }
         if let Some(perms) = self.permissions {
-            bytes.extend_from_slice(&perms.to_be_bytes());
+            bytes.write_u32(perms);
         }
+        // Access and modification times travel as a pair; an unknown access
+        // time is set to match the modification time
         if let Some(mtime) = self.modify_time {
-            bytes.extend_from_slice(&mtime.to_be_bytes());
+            let atime = self.access_time.unwrap_or(mtime);
+            bytes.write_u32(atime);
+            bytes.write_u32(mtime);
+        }
+
+        bytes
+    }
+
+    // v4 adds a type byte, sends names instead of ids and 64-bit times that
+    // no longer have to travel as a pair. The later additions (bits,
+    // allocation size, ...) are all optional, so one layout serves v4-v6
+    fn to_v4_bytes(&self) -> Vec<u8> {
+        let mut flags = 0u32;
+        if self.size.is_some() {
+            flags |= SSH_FILEXFER_ATTR_SIZE;
+        }
+        let owner_group = self.owner.as_deref().zip(self.group.as_deref());
+        if owner_group.is_some() {
+            flags |= SSH_FILEXFER_ATTR_OWNERGROUP;
+        }
+        if self.permissions.is_some() {
+            flags |= SSH_FILEXFER_ATTR_PERMISSIONS;
+        }
+        if self.access_time.is_some() {
+            flags |= SSH_FILEXFER_ATTR_ACCESSTIME;
+        }
+        if self.modify_time.is_some() {
+            flags |= SSH_FILEXFER_ATTR_MODIFYTIME;
+        }
+
+        let mut bytes = Vec::new();
+        bytes.write_u32(flags);
+        bytes.write_u8(self.file_type.type_byte());
+
+        if let Some(size) = self.size {
+            bytes.write_u64(size);
+        }
+        if let Some((owner, group)) = owner_group {
+            bytes.write_string(owner);
+            bytes.write_string(group);
+        }
+        if let Some(perms) = self.permissions {
+            bytes.write_u32(perms);
+        }
+        for time in [self.access_time, self.modify_time].into_iter().flatten() {
+            bytes.write_u64(time as u64);
         }
 
         bytes
@@ -91,16 +445,957 @@ pub enum FileType {
     Socket,
 }
 
+impl FileType {
+    pub fn from_permissions(perms: u32) -> Self {
+        match perms & S_IFMT {
+            S_IFDIR => FileType::Directory,
+            S_IFREG => FileType::RegularFile,
+            S_IFLNK => FileType::Symlink,
+            S_IFCHR => FileType::CharacterDevice,
+            S_IFBLK => FileType::BlockDevice,
+            S_IFIFO => FileType::Fifo,
+            S_IFSOCK => FileType::Socket,
+            _ => FileType::Unknown,
+        }
+    }
+
+    /// Reads the type byte that leads v4+ attributes. Devices, FIFOs and
+    /// sockets may only be reported as "special", which says nothing useful.
+    pub fn from_type_byte(byte: u8) -> Self {
+        match byte {
+            SSH_FILEXFER_TYPE_REGULAR => FileType::RegularFile,
+            SSH_FILEXFER_TYPE_DIRECTORY => FileType::Directory,
+            SSH_FILEXFER_TYPE_SYMLINK => FileType::Symlink,
+            SSH_FILEXFER_TYPE_SOCKET => FileType::Socket,
+            SSH_FILEXFER_TYPE_CHAR_DEVICE => FileType::CharacterDevice,
+            SSH_FILEXFER_TYPE_BLOCK_DEVICE => FileType::BlockDevice,
+            SSH_FILEXFER_TYPE_FIFO => FileType::Fifo,
+            _ => FileType::Unknown,
+        }
+    }
+
+    pub fn type_byte(&self) -> u8 {
+        match self {
+            FileType::Unknown => SSH_FILEXFER_TYPE_UNKNOWN,
+            FileType::RegularFile => SSH_FILEXFER_TYPE_REGULAR,
+            FileType::Directory => SSH_FILEXFER_TYPE_DIRECTORY,
+            FileType::Symlink => SSH_FILEXFER_TYPE_SYMLINK,
+            FileType::CharacterDevice => SSH_FILEXFER_TYPE_CHAR_DEVICE,
+            FileType::BlockDevice => SSH_FILEXFER_TYPE_BLOCK_DEVICE,
+            FileType::Fifo => SSH_FILEXFER_TYPE_FIFO,
+            FileType::Socket => SSH_FILEXFER_TYPE_SOCKET,
+        }
+    }
+
+    /// First character of an `ls -l` mode string.
+    pub fn indicator(&self) -> char {
+        match self {
+            FileType::Unknown => '?',
+            FileType::RegularFile => '-',
+            FileType::Directory => 'd',
+            FileType::Symlink => 'l',
+            FileType::CharacterDevice => 'c',
+            FileType::BlockDevice => 'b',
+            FileType::Fifo => 'p',
+            FileType::Socket => 's',
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            FileType::Unknown => "unknown",
+            FileType::RegularFile => "regular file",
+            FileType::Directory => "directory",
+            FileType::Symlink => "symbolic link",
+            FileType::CharacterDevice => "character device",
+            FileType::BlockDevice => "block device",
+            FileType::Fifo => "fifo",
+            FileType::Socket => "socket",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DirectoryCache {
-    pub files: Vec<FileInfo>,
+    pub files: Arc<Vec<FileInfo>>,
     //pub timestamp: SystemTime,
 }
 
+#[derive(Debug, Clone)]
+pub enum QueueAction {
+    Add {
+        priority: Priority,
+        transfer: Box<SftpCommand>,
+        description: String,
+    },
+    List,
+    Run,
+    Pause(u32),
+    Resume(u32),
+}
+
+/// A remote path written `session:path`, where `session` names one of the
+/// open sessions, as `session2` or by its host. Without a prefix the path is
+/// on the current session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionPath {
+    pub session: Option<String>,
+    pub path: PathBuf,
+}
+
+impl SessionPath {
+    pub fn parse(input: &str) -> Self {
+        match input.split_once(':') {
+            Some((session, path)) if !session.is_empty() && !session.contains('/') => Self {
+                session: Some(session.to_string()),
+                path: PathBuf::from(path),
+            },
+            _ => Self {
+                session: None,
+                path: PathBuf::from(input),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotAction {
+    /// Record the remote tree to a file
+    Save,
+    /// Compare the remote tree with a recorded one
+    Diff,
+}
+
+/// Which side of a sync is the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncDirection {
+    /// Make the remote tree match the local one
+    Up,
+    /// Make the local tree match the remote one
+    Down,
+}
+
+/// How a two-way sync settles a file changed on both sides.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep whichever copy was modified last
+    NewerWins,
+    /// Keep whichever copy is bigger
+    LargerWins,
+    /// Always keep the local copy
+    LocalWins,
+    /// Always keep the remote copy
+    RemoteWins,
+    /// Keep both, the local copy under a `.conflict` name
+    KeepBoth,
+    /// Keep both under new names, `.local` and `.remote`, on both sides
+    RenameBoth,
+    /// Ask in the REPL; leave the conflict for next time when nobody can be
+    /// asked
+    #[default]
+    Prompt,
+}
+
+impl ConflictPolicy {
+    pub fn parse(value: &str) -> Result<Self, SftpError> {
+        match value {
+            "newer-wins" => Ok(ConflictPolicy::NewerWins),
+            "larger-wins" => Ok(ConflictPolicy::LargerWins),
+            "local-wins" => Ok(ConflictPolicy::LocalWins),
+            "remote-wins" => Ok(ConflictPolicy::RemoteWins),
+            "keep-both" => Ok(ConflictPolicy::KeepBoth),
+            "rename-both" => Ok(ConflictPolicy::RenameBoth),
+            "prompt" => Ok(ConflictPolicy::Prompt),
+            _ => Err(SftpError::InvalidCommand(
+                "Expected newer-wins, larger-wins, local-wins, remote-wins, keep-both, rename-both or prompt",
+            )),
+        }
+    }
+}
+
+/// Flags that change how `sync` treats the destination.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncOptions {
+    /// Remove destination files the source does not have
+    pub delete: bool,
+    /// Delete without listing the files and asking first
+    pub force: bool,
+    /// Compare same-sized files by content digest rather than mtime
+    pub checksum: bool,
+    /// Ignore file to use instead of the source tree's `.ferricignore`
+    pub ignore_file: Option<PathBuf>,
+    /// Where to record finished files, so an interrupted sync can resume
+    pub journal: Option<PathBuf>,
+    /// How a two-way sync settles files changed on both sides
+    pub conflict: ConflictPolicy,
+    /// Where to write a JSON report of what was done to each file
+    pub report: Option<PathBuf>,
+}
+
+/// Which transfers `bench` measures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BenchDirection {
+    Upload,
+    Download,
+    Both,
+}
+
+impl BenchDirection {
+    pub fn parse(value: &str) -> Result<Self, SftpError> {
+        match value {
+            "up" | "upload" => Ok(BenchDirection::Upload),
+            "down" | "download" => Ok(BenchDirection::Download),
+            "both" => Ok(BenchDirection::Both),
+            _ => Err(SftpError::InvalidCommand("Direction is up, down or both")),
+        }
+    }
+
+    pub fn uploads(self) -> bool {
+        self != BenchDirection::Download
+    }
+
+    pub fn downloads(self) -> bool {
+        self != BenchDirection::Upload
+    }
+}
+
+/// Order in which queued transfers are run; higher goes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn parse(value: &str) -> Result<Self, SftpError> {
+        match value {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(SftpError::InvalidCommand("Expected low, normal or high")),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+/// What to do when a transfer's destination already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overwrite {
+    #[default]
+    Replace,
+    Skip,
+    /// Transfer only when the source was modified after the destination
+    NewerOnly,
+    /// Ask in the REPL; skip when nobody can be asked
+    Prompt,
+}
+
+impl Overwrite {
+    pub fn parse(value: &str) -> Result<Self, SftpError> {
+        match value {
+            "overwrite" => Ok(Overwrite::Replace),
+            "skip" => Ok(Overwrite::Skip),
+            "newer-only" => Ok(Overwrite::NewerOnly),
+            "prompt" => Ok(Overwrite::Prompt),
+            _ => Err(SftpError::InvalidCommand(
+                "Expected overwrite, skip, newer-only or prompt",
+            )),
+        }
+    }
+
+    /// Maps a command flag such as `--skip` to its policy.
+    pub fn from_flag(flag: &str) -> Option<Self> {
+        flag.strip_prefix("--")
+            .and_then(|name| Self::parse(name).ok())
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Overwrite::Replace => "overwrite",
+            Overwrite::Skip => "skip",
+            Overwrite::NewerOnly => "newer-only",
+            Overwrite::Prompt => "prompt",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct QueuedTransfer {
+    pub id: u32,
+    pub priority: Priority,
+    pub command: SftpCommand,
+    pub description: String,
+    /// Held back from `queue run` until resumed
+    pub paused: bool,
+    /// Bytes already downloaded by an earlier, interrupted run
+    pub offset: u64,
+}
+
+/// Transfers waiting for `queue run`.
+#[derive(Debug, Default)]
+pub struct TransferQueue {
+    jobs: Vec<QueuedTransfer>,
+    next_id: u32,
+}
+
+impl TransferQueue {
+    pub fn push(&mut self, priority: Priority, command: SftpCommand, description: String) -> u32 {
+        self.next_id += 1;
+        self.jobs.push(QueuedTransfer {
+            id: self.next_id,
+            priority,
+            command,
+            description,
+            paused: false,
+            offset: 0,
+        });
+        self.next_id
+    }
+
+    /// Puts a job taken with `pop_next` back, keeping its id and position.
+    pub fn requeue(&mut self, job: QueuedTransfer) {
+        self.jobs.push(job);
+    }
+
+    pub fn set_paused(&mut self, id: u32, paused: bool) -> Result<(), SftpError> {
+        let job = self
+            .jobs
+            .iter_mut()
+            .find(|job| job.id == id)
+            .ok_or(SftpError::InvalidCommand("No such job"))?;
+        job.paused = paused;
+        Ok(())
+    }
+
+    /// Removes the next transfer to run: highest priority first, and oldest
+    /// first within a priority. Paused transfers are skipped.
+    pub fn pop_next(&mut self) -> Option<QueuedTransfer> {
+        let index = (0..self.jobs.len())
+            .filter(|&i| !self.jobs[i].paused)
+            .max_by_key(|&i| {
+                let job = &self.jobs[i];
+                (job.priority, std::cmp::Reverse(job.id))
+            })?;
+        Some(self.jobs.remove(index))
+    }
+
+    /// Queued transfers in the order they will run.
+    pub fn pending(&self) -> Vec<&QueuedTransfer> {
+        let mut jobs: Vec<&QueuedTransfer> = self.jobs.iter().collect();
+        jobs.sort_by_key(|job| (std::cmp::Reverse(job.priority), job.id));
+        jobs
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+}
+
+/// How long to wait for each server reply, and how many times a failed
+/// transfer is retried before giving up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// `None` waits indefinitely
+    pub timeout: Option<Duration>,
+    pub retries: u32,
+}
+
+impl RetryPolicy {
+    pub fn with_override(self, policy: &PolicyOverride) -> Self {
+        Self {
+            timeout: policy.timeout.or(self.timeout),
+            retries: policy.retries.unwrap_or(self.retries),
+        }
+    }
+}
+
+/// Transfer settings replaced for a single command, as in
+/// `get --timeout 30s --retries 3` or `put --skip`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PolicyOverride {
+    pub timeout: Option<Duration>,
+    pub retries: Option<u32>,
+    pub overwrite: Option<Overwrite>,
+}
+
+/// Part of a remote file to download, as in `get --offset -10M --length 1M`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ByteRange {
+    /// Where the range starts; negative values count back from the end
+    pub offset: i64,
+    /// Bytes to read, or `None` for everything up to the end of the file
+    pub length: Option<u64>,
+}
+
+impl ByteRange {
+    /// Parses an offset such as `4096`, `512K` or `-10M`.
+    pub fn parse_offset(value: &str) -> Result<i64, SftpError> {
+        let (sign, size) = match value.strip_prefix('-') {
+            Some(size) => (-1, size),
+            None => (1, value),
+        };
+        i64::try_from(parse_size(size)?)
+            .map(|offset| sign * offset)
+            .map_err(|_| SftpError::InvalidCommand("Offset too large"))
+    }
+
+    /// Resolves the range against a file of `size` bytes, returning where to
+    /// start and how many bytes to read (`None` meaning up to the end).
+    /// Only an offset from the end needs the size to be known.
+    pub fn resolve(&self, size: Option<u64>) -> Result<(u64, Option<u64>), SftpError> {
+        let start = match (self.offset, size) {
+            (offset, _) if offset >= 0 => offset as u64,
+            (offset, Some(size)) => size.saturating_sub(offset.unsigned_abs()),
+            (_, None) => {
+                return Err(SftpError::ClientError(
+                    "Server did not report the file size".into(),
+                ))
+            }
+        };
+        let remaining = size.map(|size| size.saturating_sub(start));
+        let length = match (self.length, remaining) {
+            (Some(length), Some(remaining)) => Some(length.min(remaining)),
+            (length, remaining) => length.or(remaining),
+        };
+        Ok((start, length))
+    }
+}
+
+/// Parses durations such as `500ms`, `30s`, `2m` or `1h`. A bare number is
+/// taken as seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, SftpError> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| SftpError::InvalidCommand("Expected a duration such as 30s"))?;
+
+    match unit {
+        "ms" => Ok(Duration::from_millis(amount)),
+        "" | "s" => Ok(Duration::from_secs(amount)),
+        "m" => Ok(Duration::from_secs(amount * 60)),
+        "h" => Ok(Duration::from_secs(amount * 3600)),
+        _ => Err(SftpError::InvalidCommand(
+            "Duration units are ms, s, m or h",
+        )),
+    }
+}
+
+/// Parses sizes such as `4096`, `512K`, `100M` or `2G`, in powers of 1024.
+pub fn parse_size(value: &str) -> Result<u64, SftpError> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| SftpError::InvalidCommand("Expected a size such as 100M"))?;
+
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => return Err(SftpError::InvalidCommand("Size units are K, M or G")),
+    };
+    amount
+        .checked_mul(multiplier)
+        .ok_or(SftpError::InvalidCommand("Size is too large"))
+}
+
+fn format_duration(duration: Duration) -> String {
+    match duration.subsec_millis() {
+        0 => format!("{}s", duration.as_secs()),
+        _ => format!("{}ms", duration.as_millis()),
+    }
+}
+
+/// User-adjustable client behaviour, changed with the `set` command.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Expand `$VAR` / `${VAR}` in command lines before they are parsed
+    pub interpolate: bool,
+    /// Match wildcard patterns against remote names regardless of case
+    pub case_insensitive: bool,
+    /// Default timeout and retries, which `get` can override per command
+    pub retry: RetryPolicy,
+    /// Delete files downloaded by `open` when the client exits
+    pub cleanup_opened: bool,
+    /// Read files `put` uploads through a memory map
+    pub mmap: bool,
+    /// Bytes per READ/WRITE request, or `None` to adapt to the link
+    pub chunk_size: Option<u32>,
+    /// Channels a large download may be split across
+    pub segments: usize,
+    /// Files mget, mput and get -r transfer at once
+    pub parallel: usize,
+    /// What transfers do when the destination already exists
+    pub overwrite: Overwrite,
+    /// How far apart modification times may be for sync to call files equal
+    pub sync_tolerance: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            interpolate: true,
+            case_insensitive: false,
+            retry: RetryPolicy::default(),
+            cleanup_opened: false,
+            mmap: false,
+            chunk_size: None,
+            segments: 1,
+            parallel: DEFAULT_PARALLEL_TRANSFERS,
+            overwrite: Overwrite::Replace,
+            sync_tolerance: Duration::ZERO,
+        }
+    }
+}
+
+impl Settings {
+    pub fn apply(&mut self, key: &str, value: &str) -> Result<(), SftpError> {
+        match key {
+            "interpolate" => self.interpolate = parse_switch(value)?,
+            "cleanup-opened" => self.cleanup_opened = parse_switch(value)?,
+            "mmap" => self.mmap = parse_switch(value)?,
+            "matching" => {
+                self.case_insensitive = match value {
+                    "case-insensitive" => true,
+                    "case-sensitive" => false,
+                    _ => {
+                        return Err(SftpError::InvalidCommand(
+                            "Expected case-sensitive or case-insensitive",
+                        ))
+                    }
+                }
+            }
+            "timeout" => {
+                self.retry.timeout = match value {
+                    "off" | "0" => None,
+                    duration => Some(parse_duration(duration)?),
+                }
+            }
+            "chunk-size" => {
+                self.chunk_size = match value {
+                    "auto" => None,
+                    // The client also caps it at what fits in a packet
+                    size => match u32::try_from(parse_size(size)?) {
+                        Ok(size @ 1..) => Some(size),
+                        _ => {
+                            return Err(SftpError::InvalidCommand(
+                                "Expected a chunk size of at least 1 byte, or auto",
+                            ))
+                        }
+                    },
+                }
+            }
+            "segments" => {
+                self.segments = match value.parse() {
+                    Ok(segments @ 1..=MAX_SEGMENTS) => segments,
+                    _ => {
+                        return Err(SftpError::InvalidCommand(
+                            "Expected between 1 and 16 segments",
+                        ))
+                    }
+                }
+            }
+            "parallel" => {
+                self.parallel = match value.parse() {
+                    Ok(parallel @ 1..=MAX_PARALLEL_TRANSFERS) => parallel,
+                    _ => {
+                        return Err(SftpError::InvalidCommand(
+                            "Expected between 1 and 16 parallel transfers",
+                        ))
+                    }
+                }
+            }
+            "overwrite" => self.overwrite = Overwrite::parse(value)?,
+            "sync-tolerance" => self.sync_tolerance = parse_duration(value)?,
+            "retries" => {
+                self.retry.retries = value
+                    .parse()
+                    .map_err(|_| SftpError::InvalidCommand("Expected a number of retries"))?
+            }
+            _ => return Err(SftpError::InvalidCommand("Unknown setting")),
+        }
+        Ok(())
+    }
+
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        let matching = match self.case_insensitive {
+            true => "case-insensitive",
+            false => "case-sensitive",
+        };
+        vec![
+            ("interpolate", switch_name(self.interpolate).to_string()),
+            ("matching", matching.to_string()),
+            (
+                "timeout",
+                self.retry
+                    .timeout
+                    .map_or("off".to_string(), format_duration),
+            ),
+            ("retries", self.retry.retries.to_string()),
+            (
+                "cleanup-opened",
+                switch_name(self.cleanup_opened).to_string(),
+            ),
+            ("mmap", switch_name(self.mmap).to_string()),
+            (
+                "chunk-size",
+                self.chunk_size
+                    .map_or("auto".to_string(), |size| size.to_string()),
+            ),
+            ("segments", self.segments.to_string()),
+            ("parallel", self.parallel.to_string()),
+            ("overwrite", self.overwrite.name().to_string()),
+            ("sync-tolerance", format_duration(self.sync_tolerance)),
+        ]
+    }
+}
+
+fn parse_switch(value: &str) -> Result<bool, SftpError> {
+    match value {
+        "on" | "true" | "yes" => Ok(true),
+        "off" | "false" | "no" => Ok(false),
+        _ => Err(SftpError::InvalidCommand("Expected on or off")),
+    }
+}
+
+fn switch_name(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Limits a server reports through limits@openssh.com. Zero means the server
+/// sets no limit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerLimits {
+    pub max_packet_length: u64,
+    pub max_read_length: u64,
+    pub max_write_length: u64,
+    pub max_open_handles: u64,
+}
+
+impl ServerLimits {
+    /// Caps a READ length at what the server will return in one reply.
+    pub fn clamp_read(&self, len: u32) -> u32 {
+        clamp_to_limit(len, self.max_read_length)
+    }
+
+    /// Caps a WRITE length at what the server will accept in one request.
+    pub fn clamp_write(&self, len: u32) -> u32 {
+        clamp_to_limit(len, self.max_write_length)
+    }
+}
+
+fn clamp_to_limit(len: u32, limit: u64) -> u32 {
+    match limit {
+        0 => len,
+        limit => len.min(limit.min(u32::MAX as u64) as u32),
+    }
+}
+
+/// Filesystem usage as reported by statvfs@openssh.com, mirroring
+/// `struct statvfs`. Block counts are in units of `fragment_size`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatVfs {
+    pub block_size: u64,
+    pub fragment_size: u64,
+    pub blocks: u64,
+    pub blocks_free: u64,
+    pub blocks_available: u64,
+    pub files: u64,
+    pub files_free: u64,
+    pub files_available: u64,
+    pub filesystem_id: u64,
+    pub flags: u64,
+    pub max_name_length: u64,
+}
+
+impl StatVfs {
+    pub fn total_bytes(&self) -> u64 {
+        self.blocks.saturating_mul(self.fragment_size)
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.blocks
+            .saturating_sub(self.blocks_free)
+            .saturating_mul(self.fragment_size)
+    }
+
+    /// Space unprivileged users can still write, which excludes blocks
+    /// reserved for root.
+    pub fn available_bytes(&self) -> u64 {
+        self.blocks_available.saturating_mul(self.fragment_size)
+    }
+
+    /// Used share of the space ordinary users can reach, as `df` computes
+    /// it, rounded up.
+    pub fn capacity_percent(&self) -> u64 {
+        let used = self.blocks.saturating_sub(self.blocks_free);
+        let usable = used + self.blocks_available;
+        match usable {
+            0 => 0,
+            usable => (used * 100).div_ceil(usable),
+        }
+    }
+}
+
+/// Counters for the current session, shown by the `stats` command.
+#[derive(Debug, Clone)]
+pub struct SessionStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub requests: u64,
+    pub cache_hits: u64,
+    pub open_handles: u64,
+    pub started: Instant,
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        Self {
+            bytes_sent: 0,
+            bytes_received: 0,
+            requests: 0,
+            cache_hits: 0,
+            open_handles: 0,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug)]
 pub enum SftpStatus {
-    Ok = 0,            // SSH_FX_OK
-    Eof = 1,           // SSH_FX_EOF
-    InvalidHandle = 4, // SSH_FX_INVALID_HANDLE
+    Ok = 0,               // SSH_FX_OK
+    Eof = 1,              // SSH_FX_EOF
+    NoSuchFile = 2,       // SSH_FX_NO_SUCH_FILE
+    PermissionDenied = 3, // SSH_FX_PERMISSION_DENIED
+    Failure = 4,          // SSH_FX_FAILURE
+    BadMessage = 5,       // SSH_FX_BAD_MESSAGE
+    NoConnection = 6,     // SSH_FX_NO_CONNECTION
+    ConnectionLost = 7,   // SSH_FX_CONNECTION_LOST
+    OpUnsupported = 8,    // SSH_FX_OP_UNSUPPORTED
+    InvalidHandle = 9,    // SSH_FX_INVALID_HANDLE
+    // Codes added in v4+ for cases v3 reports as a plain Failure
+    FileAlreadyExists = 11, // SSH_FX_FILE_ALREADY_EXISTS
+    DirNotEmpty = 18,       // SSH_FX_DIR_NOT_EMPTY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permissions_string() {
+        let mut attrs = FileAttributes::default();
+        attrs.set_permissions(S_IFDIR | 0o755);
+        assert!(attrs.is_directory);
+        assert_eq!(attrs.permissions_string(), "drwxr-xr-x");
+
+        attrs.set_permissions(S_IFREG | 0o640);
+        assert!(attrs.is_regular_file);
+        assert_eq!(attrs.permissions_string(), "-rw-r-----");
+
+        attrs.set_permissions(S_IFREG | 0o4755);
+        assert_eq!(attrs.permissions_string(), "-rwsr-xr-x");
+        attrs.set_permissions(S_IFDIR | 0o3770);
+        assert_eq!(attrs.permissions_string(), "drwxrws--T");
+    }
+
+    #[test]
+    fn test_queue_runs_high_priority_first() {
+        let mut queue = TransferQueue::default();
+        let bye = || SftpCommand::Bye;
+        queue.push(Priority::Normal, bye(), "big.iso".to_string());
+        queue.push(Priority::Low, bye(), "logs".to_string());
+        queue.push(Priority::High, bye(), "app.conf".to_string());
+        queue.push(Priority::Normal, bye(), "big2.iso".to_string());
+
+        let order: Vec<String> = std::iter::from_fn(|| queue.pop_next())
+            .map(|job| job.description)
+            .collect();
+        assert_eq!(order, vec!["app.conf", "big.iso", "big2.iso", "logs"]);
+    }
+
+    #[test]
+    fn test_queue_skips_paused_jobs() {
+        let mut queue = TransferQueue::default();
+        let first = queue.push(Priority::High, SftpCommand::Bye, "a".to_string());
+        queue.push(Priority::Low, SftpCommand::Bye, "b".to_string());
+
+        queue.set_paused(first, true).unwrap();
+        assert_eq!(queue.pop_next().unwrap().description, "b");
+        assert!(queue.pop_next().is_none());
+
+        queue.set_paused(first, false).unwrap();
+        assert_eq!(queue.pop_next().unwrap().id, first);
+        assert!(queue.set_paused(99, true).is_err());
+    }
+
+    #[test]
+    fn test_listing_order_groups_directories_first() {
+        let entry = |name: &str, size: u64, is_directory: bool| FileInfo {
+            name: name.to_string(),
+            display_name: name.to_string(),
+            attrs: FileAttributes {
+                size: Some(size),
+                is_directory,
+                ..Default::default()
+            },
+        };
+        let files = [
+            entry("b.txt", 10, false),
+            entry("src", 4096, true),
+            entry("a.txt", 500, false),
+            entry("docs", 4096, true),
+        ];
+
+        let names = |order: ListingOrder| {
+            let mut sorted: Vec<&FileInfo> = files.iter().collect();
+            order.sort(&mut sorted);
+            sorted.iter().map(|f| f.name.as_str()).collect::<Vec<_>>()
+        };
+
+        let grouped = ListingOrder {
+            key: SortKey::Name,
+            directories_first: true,
+            ..Default::default()
+        };
+        assert_eq!(names(grouped), vec!["docs", "src", "a.txt", "b.txt"]);
+
+        let by_size_reversed = ListingOrder {
+            key: SortKey::Size,
+            reverse: true,
+            directories_first: true,
+        };
+        assert_eq!(
+            names(by_size_reversed),
+            vec!["src", "docs", "b.txt", "a.txt"]
+        );
+    }
+
+    #[test]
+    fn test_chunk_size_setting() {
+        let mut settings = Settings::default();
+        settings.apply("chunk-size", "64K").unwrap();
+        assert_eq!(settings.chunk_size, Some(65536));
+        assert!(settings
+            .entries()
+            .contains(&("chunk-size", "65536".to_string())));
+
+        assert!(settings.apply("chunk-size", "0").is_err());
+        assert!(settings.apply("chunk-size", "8G").is_err());
+        assert_eq!(settings.chunk_size, Some(65536));
+        settings.apply("chunk-size", "auto").unwrap();
+        assert_eq!(settings.chunk_size, None);
+    }
+
+    #[test]
+    fn test_segments_setting() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.segments, 1);
+        settings.apply("segments", "4").unwrap();
+        assert_eq!(settings.segments, 4);
+        assert!(settings.apply("segments", "0").is_err());
+        assert!(settings.apply("segments", "17").is_err());
+    }
+
+    #[test]
+    fn test_parallel_setting() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.parallel, DEFAULT_PARALLEL_TRANSFERS);
+        settings.apply("parallel", "1").unwrap();
+        assert_eq!(settings.parallel, 1);
+        assert!(settings.apply("parallel", "0").is_err());
+        assert!(settings.apply("parallel", "17").is_err());
+    }
+
+    #[test]
+    fn test_overwrite_setting() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.overwrite, Overwrite::Replace);
+        settings.apply("overwrite", "newer-only").unwrap();
+        assert_eq!(settings.overwrite, Overwrite::NewerOnly);
+        assert!(settings
+            .entries()
+            .contains(&("overwrite", "newer-only".to_string())));
+        assert!(settings.apply("overwrite", "always").is_err());
+        assert_eq!(Overwrite::from_flag("--skip"), Some(Overwrite::Skip));
+        assert_eq!(Overwrite::from_flag("--exclusive"), None);
+    }
+
+    #[test]
+    fn test_retry_settings() {
+        let mut settings = Settings::default();
+        settings.apply("timeout", "2m").unwrap();
+        settings.apply("retries", "3").unwrap();
+        assert_eq!(settings.retry.timeout, Some(Duration::from_secs(120)));
+        assert!(settings.apply("timeout", "soon").is_err());
+
+        let policy = settings.retry.with_override(&PolicyOverride {
+            timeout: Some(Duration::from_millis(500)),
+            retries: None,
+            overwrite: None,
+        });
+        assert_eq!(policy.timeout, Some(Duration::from_millis(500)));
+        assert_eq!(policy.retries, 3);
+
+        settings.apply("timeout", "off").unwrap();
+        assert!(settings.entries().contains(&("timeout", "off".to_string())));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("512K").unwrap(), 512 * 1024);
+        assert_eq!(parse_size("100m").unwrap(), 100 * 1024 * 1024);
+        assert_eq!(parse_size("2GiB").unwrap(), 2 << 30);
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("10T").is_err());
+    }
+
+    #[test]
+    fn test_byte_range_resolve() {
+        let tail = ByteRange {
+            offset: ByteRange::parse_offset("-1K").unwrap(),
+            length: None,
+        };
+        assert_eq!(tail.resolve(Some(4096)).unwrap(), (3072, Some(1024)));
+        assert_eq!(tail.resolve(Some(100)).unwrap(), (0, Some(100)));
+        assert!(tail.resolve(None).is_err());
+
+        let slice = ByteRange {
+            offset: 1000,
+            length: Some(500),
+        };
+        assert_eq!(slice.resolve(Some(1200)).unwrap(), (1000, Some(200)));
+        assert_eq!(slice.resolve(None).unwrap(), (1000, Some(500)));
+    }
 }
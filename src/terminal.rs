@@ -0,0 +1,53 @@
+//! Rendering of untrusted text, such as remote file names, on the terminal.
+
+use std::borrow::Cow;
+
+/// Escapes control characters before `text` is printed. ESC starts ANSI
+/// sequences that can move the cursor, rewrite earlier output or change the
+/// window title, and bidi overrides can make a name read differently from
+/// what it is, so a hostile file name could otherwise spoof the listing
+/// around it. Text without such characters is returned as is.
+pub fn escape(text: &str) -> Cow<'_, str> {
+    if !text.chars().any(needs_escape) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut escaped = String::with_capacity(text.len() + 8);
+    for c in text.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c if needs_escape(c) => escaped.push_str(&format!("\\u{{{:04x}}}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+// C0 and C1 controls, DEL, and the bidirectional formatting characters
+fn needs_escape(c: char) -> bool {
+    c.is_control()
+        || matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_names_are_borrowed() {
+        assert!(matches!(escape("report 2024 ü.pdf"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_control_sequences_are_escaped() {
+        assert_eq!(
+            escape("evil\x1b[2J\x1b]0;pwned\x07"),
+            "evil\\x1b[2J\\x1b]0;pwned\\x07"
+        );
+        assert_eq!(escape("a\nb\rc\u{9b}"), "a\\nb\\rc\\x9b");
+        assert_eq!(escape("invoice\u{202e}fdp.exe"), "invoice\\u{202e}fdp.exe");
+    }
+}
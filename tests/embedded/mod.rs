@@ -0,0 +1,4 @@
+#[path = "../utils/embedded_server.rs"]
+pub mod embedded_server;
+pub mod sftp_client;
+pub mod transport;
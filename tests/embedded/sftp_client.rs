@@ -0,0 +1,80 @@
+// tests/embedded/sftp_client.rs
+use super::embedded_server::EmbeddedSftpServer;
+use super::transport::TcpTransport;
+use ferric_ftp::sftp::client::SftpClient;
+use ferric_ftp::sftp::types::SftpCommand;
+use std::fs;
+use std::path::PathBuf;
+
+fn connect(server: &EmbeddedSftpServer) -> SftpClient<TcpTransport> {
+    let transport = TcpTransport::connect(server.address()).unwrap();
+    SftpClient::new(transport, None).unwrap()
+}
+
+#[test]
+fn test_list_directory() {
+    let server = EmbeddedSftpServer::start().unwrap();
+    fs::write(server.local_path("/notes.txt"), b"hello").unwrap();
+    fs::create_dir(server.local_path("/pub")).unwrap();
+
+    let mut client = connect(&server);
+    client
+        .execute_command(&SftpCommand::Ls {
+            path: None,
+            order: Default::default(),
+        })
+        .unwrap();
+
+    let mut names: Vec<_> = client
+        .current_listing
+        .iter()
+        .map(|file| file.name.as_str())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["notes.txt", "pub"]);
+}
+
+#[test]
+fn test_get_file() {
+    let server = EmbeddedSftpServer::start().unwrap();
+    // Several chunks, the last one short
+    let contents: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(server.local_path("/data.bin"), &contents).unwrap();
+
+    let local = tempfile::tempdir().unwrap();
+    let local_path = local.path().join("data.bin");
+    let mut client = connect(&server);
+    client
+        .execute_command(&SftpCommand::Get {
+            remote_path: PathBuf::from("data.bin"),
+            local_path: Some(local_path.clone()),
+            policy: Default::default(),
+        })
+        .unwrap();
+
+    assert_eq!(fs::read(local_path).unwrap(), contents);
+}
+
+#[test]
+fn test_put_file() {
+    let server = EmbeddedSftpServer::start().unwrap();
+    let contents: Vec<u8> = (0..100_000u32).map(|i| (i % 241) as u8).collect();
+    let local = tempfile::tempdir().unwrap();
+    let local_path = local.path().join("upload.bin");
+    fs::write(&local_path, &contents).unwrap();
+
+    let mut client = connect(&server);
+    client
+        .execute_command(&SftpCommand::Put {
+            local_path,
+            remote_path: None,
+            exclusive: false,
+            policy: Default::default(),
+        })
+        .unwrap();
+
+    assert_eq!(
+        fs::read(server.local_path("/upload.bin")).unwrap(),
+        contents
+    );
+}
@@ -0,0 +1,53 @@
+// tests/embedded/transport.rs
+use ferric_ftp::sftp::constants::SFTP_SUPPORTED_VERSION;
+use ferric_ftp::sftp::error::SftpError;
+use ferric_ftp::sftp::packet::{ClientPacket, ServerPacket};
+use ferric_ftp::sftp::session::TransportLayer;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+/// Speaks SFTP straight over a TCP connection, the way `SftpSession` does
+/// over an SSH channel.
+pub struct TcpTransport {
+    stream: TcpStream,
+    next_request_id: u32,
+}
+
+impl TcpTransport {
+    pub fn connect(address: SocketAddr) -> Result<Self, SftpError> {
+        let mut transport = Self {
+            stream: TcpStream::connect(address)?,
+            next_request_id: 0,
+        };
+        transport.send_packet(ClientPacket::Init {
+            version: SFTP_SUPPORTED_VERSION,
+        })?;
+
+        match transport.receive_packet()? {
+            ServerPacket::Version { .. } => Ok(transport),
+            _ => Err(SftpError::UnexpectedPacket("Version response")),
+        }
+    }
+}
+
+impl TransportLayer for TcpTransport {
+    fn send_packet(&mut self, packet: ClientPacket) -> Result<(), SftpError> {
+        self.stream.write_all(&packet.to_bytes())?;
+        Ok(())
+    }
+
+    fn receive_packet(&mut self) -> Result<ServerPacket, SftpError> {
+        let mut length = [0u8; 4];
+        self.stream.read_exact(&mut length)?;
+        let mut packet = vec![0; u32::from_be_bytes(length) as usize + 4];
+        packet[..4].copy_from_slice(&length);
+        self.stream.read_exact(&mut packet[4..])?;
+        ServerPacket::from_bytes(&packet)
+    }
+
+    fn next_request_id(&mut self) -> u32 {
+        let id = self.next_request_id;
+        self.next_request_id += 1;
+        id
+    }
+}
@@ -11,20 +11,21 @@ fn test_sftp_session_initialization() {
     let channel = test_utils::connect_and_auth().unwrap();
     let session = SftpSession::new(channel, SFTP_SUPPORTED_VERSION).unwrap();
     let client = SftpClient::new(session, None);
-    assert!(!client.is_err());
+    assert!(client.is_ok());
 }
 #[test]
 fn test_list_directory() {
     let mut client = test_utils::create_test_client().unwrap();
     let command = SftpCommand::Ls {
         path: Some(PathBuf::from(".")),
+        order: Default::default(),
     };
 
     let _ = client.execute_command(&command).unwrap();
 
     assert!(!client.current_listing.is_empty());
 
-    for file in client.current_listing {
+    for file in client.current_listing.iter() {
         assert!(!file.name.is_empty());
         assert!(!file.display_name.is_empty());
     }
@@ -61,8 +62,9 @@ fn test_get_file() {
     let test_filename = "readme.txt";
 
     let command = SftpCommand::Get {
-        remote_path: PathBuf::from("readme.txt"),
+        remote_path: PathBuf::from(test_filename),
         local_path: Some(PathBuf::from("test_readme.txt")),
+        policy: Default::default(),
     };
 
     client.execute_command(&command).unwrap();
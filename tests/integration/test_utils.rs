@@ -2,9 +2,8 @@
 use ferric_ftp::sftp::client::SftpClient;
 use ferric_ftp::sftp::constants::*;
 use ferric_ftp::sftp::session::SftpSession;
-use ssh2::{Channel, Session, Sftp};
+use ssh2::{Channel, Session};
 use std::net::TcpStream;
-use std::time::Duration;
 
 fn connect_to_test_server() -> Result<Session, Box<dyn std::error::Error>> {
     let tcp = TcpStream::connect("test.rebex.net:22")?;
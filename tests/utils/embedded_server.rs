@@ -0,0 +1,344 @@
+// tests/utils/embedded_server.rs
+//
+// An SFTP server that runs inside the test process and serves a temporary
+// directory, so end-to-end tests need neither Docker nor the network.
+use russh_sftp::protocol::{
+    Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode,
+};
+use std::collections::HashMap;
+use std::fs::{self, FileTimes, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use tokio::runtime::{Builder, Runtime};
+
+pub struct EmbeddedSftpServer {
+    // Dropped first, which stops the server before its directory goes away
+    _runtime: Runtime,
+    root: TempDir,
+    address: SocketAddr,
+}
+
+impl EmbeddedSftpServer {
+    /// Serves a fresh temporary directory as `/` on a loopback port. Every
+    /// connection gets its own SFTP session.
+    pub fn start() -> io::Result<Self> {
+        let root = TempDir::new()?;
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()?;
+        let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0"))?;
+        let address = listener.local_addr()?;
+
+        let served = root.path().to_path_buf();
+        runtime.spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                russh_sftp::server::run(stream, RootedFs::new(served.clone())).await;
+            }
+        });
+
+        Ok(Self {
+            _runtime: runtime,
+            root,
+            address,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Where the server keeps the file it shows as `remote_path`.
+    pub fn local_path(&self, remote_path: &str) -> PathBuf {
+        local_path(self.root.path(), remote_path)
+    }
+}
+
+/// `path` with `.` and `..` resolved, treating the served directory as `/`.
+/// Relative paths start from `/`, which doubles as the home directory.
+fn normalize(path: &str) -> String {
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+fn local_path(root: &Path, remote_path: &str) -> PathBuf {
+    root.join(normalize(remote_path).trim_start_matches('/'))
+}
+
+fn status_code(error: io::Error) -> StatusCode {
+    match error.kind() {
+        io::ErrorKind::NotFound => StatusCode::NoSuchFile,
+        io::ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
+        _ => StatusCode::Failure,
+    }
+}
+
+fn ok(id: u32) -> Status {
+    Status {
+        id,
+        status_code: StatusCode::Ok,
+        error_message: "Ok".to_string(),
+        language_tag: "en-US".to_string(),
+    }
+}
+
+enum OpenHandle {
+    File(fs::File),
+    // Sent in full by the first READDIR, so the second one reports EOF
+    Dir(Option<Vec<File>>),
+}
+
+struct RootedFs {
+    root: PathBuf,
+    handles: HashMap<String, OpenHandle>,
+    next_handle: u64,
+}
+
+impl RootedFs {
+    fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            handles: HashMap::new(),
+            next_handle: 0,
+        }
+    }
+
+    fn local(&self, remote_path: &str) -> PathBuf {
+        local_path(&self.root, remote_path)
+    }
+
+    fn insert(&mut self, id: u32, handle: OpenHandle) -> Handle {
+        let name = self.next_handle.to_string();
+        self.next_handle += 1;
+        self.handles.insert(name.clone(), handle);
+        Handle { id, handle: name }
+    }
+
+    fn file(&mut self, handle: &str) -> Result<&mut fs::File, StatusCode> {
+        match self.handles.get_mut(handle) {
+            Some(OpenHandle::File(file)) => Ok(file),
+            _ => Err(StatusCode::Failure),
+        }
+    }
+}
+
+fn apply_attrs(file: &fs::File, attrs: &FileAttributes) -> io::Result<()> {
+    if let Some(size) = attrs.size {
+        file.set_len(size)?;
+    }
+    #[cfg(unix)]
+    if let Some(permissions) = attrs.permissions {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(permissions & 0o7777))?;
+    }
+    if let (Some(atime), Some(mtime)) = (attrs.atime, attrs.mtime) {
+        let time = |secs: u32| SystemTime::UNIX_EPOCH + Duration::from_secs(secs.into());
+        file.set_times(
+            FileTimes::new()
+                .set_accessed(time(atime))
+                .set_modified(time(mtime)),
+        )?;
+    }
+    Ok(())
+}
+
+impl russh_sftp::server::Handler for RootedFs {
+    type Error = StatusCode;
+
+    fn unimplemented(&self) -> Self::Error {
+        StatusCode::OpUnsupported
+    }
+
+    async fn open(
+        &mut self,
+        id: u32,
+        filename: String,
+        pflags: OpenFlags,
+        _attrs: FileAttributes,
+    ) -> Result<Handle, Self::Error> {
+        let file = OpenOptions::from(pflags)
+            .open(self.local(&filename))
+            .map_err(status_code)?;
+        Ok(self.insert(id, OpenHandle::File(file)))
+    }
+
+    async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+        self.handles.remove(&handle).ok_or(StatusCode::Failure)?;
+        Ok(ok(id))
+    }
+
+    async fn read(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        len: u32,
+    ) -> Result<Data, Self::Error> {
+        let file = self.file(&handle)?;
+        file.seek(SeekFrom::Start(offset)).map_err(status_code)?;
+
+        let mut data = Vec::with_capacity(len as usize);
+        file.take(len.into())
+            .read_to_end(&mut data)
+            .map_err(status_code)?;
+        if data.is_empty() {
+            return Err(StatusCode::Eof);
+        }
+        Ok(Data { id, data })
+    }
+
+    async fn write(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<Status, Self::Error> {
+        let file = self.file(&handle)?;
+        file.seek(SeekFrom::Start(offset)).map_err(status_code)?;
+        file.write_all(&data).map_err(status_code)?;
+        Ok(ok(id))
+    }
+
+    async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let metadata = fs::symlink_metadata(self.local(&path)).map_err(status_code)?;
+        Ok(Attrs {
+            id,
+            attrs: FileAttributes::from(&metadata),
+        })
+    }
+
+    async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+        let metadata = self.file(&handle)?.metadata().map_err(status_code)?;
+        Ok(Attrs {
+            id,
+            attrs: FileAttributes::from(&metadata),
+        })
+    }
+
+    async fn setstat(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let file = fs::File::open(self.local(&path)).map_err(status_code)?;
+        apply_attrs(&file, &attrs).map_err(status_code)?;
+        Ok(ok(id))
+    }
+
+    async fn fsetstat(
+        &mut self,
+        id: u32,
+        handle: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        apply_attrs(self.file(&handle)?, &attrs).map_err(status_code)?;
+        Ok(ok(id))
+    }
+
+    async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(self.local(&path)).map_err(status_code)? {
+            let entry = entry.map_err(status_code)?;
+            let metadata = entry.metadata().map_err(status_code)?;
+            files.push(File::new(
+                entry.file_name().to_string_lossy(),
+                FileAttributes::from(&metadata),
+            ));
+        }
+        Ok(self.insert(id, OpenHandle::Dir(Some(files))))
+    }
+
+    async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+        match self.handles.get_mut(&handle) {
+            Some(OpenHandle::Dir(files)) => match files.take() {
+                Some(files) => Ok(Name { id, files }),
+                None => Err(StatusCode::Eof),
+            },
+            _ => Err(StatusCode::Failure),
+        }
+    }
+
+    async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
+        fs::remove_file(self.local(&filename)).map_err(status_code)?;
+        Ok(ok(id))
+    }
+
+    async fn mkdir(
+        &mut self,
+        id: u32,
+        path: String,
+        _attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        fs::create_dir(self.local(&path)).map_err(status_code)?;
+        Ok(ok(id))
+    }
+
+    async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
+        fs::remove_dir(self.local(&path)).map_err(status_code)?;
+        Ok(ok(id))
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        Ok(Name {
+            id,
+            files: vec![File::dummy(normalize(&path))],
+        })
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let metadata = fs::metadata(self.local(&path)).map_err(status_code)?;
+        Ok(Attrs {
+            id,
+            attrs: FileAttributes::from(&metadata),
+        })
+    }
+
+    async fn rename(
+        &mut self,
+        id: u32,
+        oldpath: String,
+        newpath: String,
+    ) -> Result<Status, Self::Error> {
+        // Version 3 servers refuse to replace an existing target
+        let target = self.local(&newpath);
+        if target.symlink_metadata().is_ok() {
+            return Err(StatusCode::Failure);
+        }
+        fs::rename(self.local(&oldpath), target).map_err(status_code)?;
+        Ok(ok(id))
+    }
+
+    async fn readlink(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        let target = fs::read_link(self.local(&path)).map_err(status_code)?;
+        Ok(Name {
+            id,
+            files: vec![File::dummy(target.to_string_lossy())],
+        })
+    }
+
+    #[cfg(unix)]
+    async fn symlink(
+        &mut self,
+        id: u32,
+        linkpath: String,
+        targetpath: String,
+    ) -> Result<Status, Self::Error> {
+        std::os::unix::fs::symlink(targetpath, self.local(&linkpath)).map_err(status_code)?;
+        Ok(ok(id))
+    }
+}
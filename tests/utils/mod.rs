@@ -1,30 +1,91 @@
-use std::process::{Child, Command};
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream};
+use std::process::Command;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+const COMPOSE_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_server/docker-compose.yml");
+const HOST: &str = "127.0.0.1";
+const PORT: u16 = 2222;
+const USERNAME: &str = "sftptest";
+const PASSWORD: &str = "pass";
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
 
 pub struct TestSftpServer {
-    process: Option<Child>,
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    // Only tear down containers we started ourselves
+    owns_container: bool,
 }
 
 impl TestSftpServer {
     pub fn start() -> Result<Self, Box<dyn std::error::Error>> {
-        println!("Make sure test SFTP server is running on localhost:2222");
+        let mut server = Self {
+            host: HOST.to_string(),
+            port: PORT,
+            username: USERNAME.to_string(),
+            password: PASSWORD.to_string(),
+            owns_container: false,
+        };
+
+        if server.is_ready() {
+            println!("Reusing test SFTP server on {}", server.address());
+            return Ok(server);
+        }
 
-        // Or start one programmatically:
-        // let process = Command::new("docker")
-        //     .args(&["compose", "-f", "test_server/docker-compose.yml", "up"])
-        //     .spawn()?;
+        let status = Command::new("docker")
+            .args(["compose", "-f", COMPOSE_FILE, "up", "-d", "--build"])
+            .status()?;
+        if !status.success() {
+            return Err(format!("docker compose up failed: {}", status).into());
+        }
+        server.owns_container = true;
+
+        server.wait_until_ready()?;
+        Ok(server)
+    }
+
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    fn wait_until_ready(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        while Instant::now() < deadline {
+            if self.is_ready() {
+                return Ok(());
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Err(format!("Test SFTP server not ready on {}", self.address()).into())
+    }
 
-        thread::sleep(Duration::from_secs(2)); // Wait for server to start
+    // Docker accepts connections on the mapped port before sshd is up, so
+    // wait for the SSH identification banner rather than just a TCP connect.
+    fn is_ready(&self) -> bool {
+        let addr: SocketAddr = match self.address().parse() {
+            Ok(addr) => addr,
+            Err(_) => return false,
+        };
+        let Ok(mut stream) = TcpStream::connect_timeout(&addr, POLL_INTERVAL) else {
+            return false;
+        };
+        let _ = stream.set_read_timeout(Some(POLL_INTERVAL));
 
-        Ok(Self { process: None })
+        let mut banner = [0u8; 4];
+        stream.read_exact(&mut banner).is_ok() && &banner == b"SSH-"
     }
 }
 
 impl Drop for TestSftpServer {
     fn drop(&mut self) {
-        if let Some(mut process) = self.process.take() {
-            let _ = process.kill();
+        if self.owns_container {
+            let _ = Command::new("docker")
+                .args(["compose", "-f", COMPOSE_FILE, "down"])
+                .status();
         }
     }
 }